@@ -1,12 +1,15 @@
-import { useEffect, useMemo, useState } from "react";
+import { useCallback, useEffect, useMemo, useRef, useState } from "react";
 import { BrowserRouter as Router, Routes, Route, Navigate } from "react-router-dom";
 import Sidebar from "./components/Sidebar";
 import ChatArea from "./components/ChatArea";
 import UserList from "./components/UserList";
 import LoginPage from "./components/LoginPage";
 import OAuthCallback from "./components/OAuthCallback";
+import ResetPassword from "./components/ResetPassword";
 import "./styles/Rustcord.css";
-import { generateAesKey, encryptMessageAes, decryptMessageAes } from "./utils/aes";
+import { encryptMessageAes, decryptMessageAes } from "./utils/aes";
+import { loadGroupKey, loadIdentity, type GroupKey } from "./utils/e2e";
+import { loadDeviceKey, signMessage, verifyMessage, type DeviceKey } from "./utils/signing";
 
 interface Message {
   id: string;
@@ -14,6 +17,8 @@ interface Message {
   content: string;
   timestamp: Date;
   avatar?: string;
+  // An encrypted message no registered device of its author signed
+  unverified?: boolean;
 }
 
 interface Channel {
@@ -39,6 +44,7 @@ interface PresenceUserPayload {
   status?: UserStatus;
   avatar?: string | null;
   email?: string | null;
+  bot?: boolean;
 }
 
 interface PresenceSnapshotMessage {
@@ -53,11 +59,43 @@ interface PresenceUpdateMessage {
 
 interface ChatMessageEnvelope {
   type: 'chat_message';
-  ciphertext: string;
+  channel?: string;
+  // End-to-end encrypted messages carry ciphertext; integration posts carry content
+  ciphertext?: string;
+  content?: string;
+  // Group key epoch of an encrypted message, when the channel has one
+  epoch?: number;
+  author_id?: string;
   author?: string;
+  // The author's device signature over the ciphertext
+  device_key?: string;
+  signature?: string;
+  id?: number;
+  avatar?: string | null;
+  sent_at?: number;
+}
+
+interface KeyRotatedMessage {
+  type: 'key_rotated';
+  conversation: string;
+  epoch: number;
+}
+
+interface ErrorEnvelope {
+  type: 'error';
+  code: string;
+  message: string;
 }
 
-type ServerEnvelope = PresenceSnapshotMessage | PresenceUpdateMessage | ChatMessageEnvelope;
+type ServerEnvelope =
+  | PresenceSnapshotMessage
+  | PresenceUpdateMessage
+  | ChatMessageEnvelope
+  | KeyRotatedMessage
+  | ErrorEnvelope;
+
+// Refresh the session token when it is this close to expiring
+const SESSION_REFRESH_MARGIN_SECS = 10 * 60;
 
 const isPresenceSnapshot = (payload: unknown): payload is PresenceSnapshotMessage => {
   if (!payload || typeof payload !== 'object') {
@@ -80,7 +118,24 @@ const isChatMessageEnvelope = (payload: unknown): payload is ChatMessageEnvelope
     return false;
   }
   const data = payload as Record<string, unknown>;
-  return data.type === 'chat_message' && typeof data.ciphertext === 'string';
+  return data.type === 'chat_message'
+    && (typeof data.ciphertext === 'string' || typeof data.content === 'string');
+};
+
+const isKeyRotated = (payload: unknown): payload is KeyRotatedMessage => {
+  if (!payload || typeof payload !== 'object') {
+    return false;
+  }
+  const data = payload as Record<string, unknown>;
+  return data.type === 'key_rotated' && typeof data.conversation === 'string';
+};
+
+const isErrorEnvelope = (payload: unknown): payload is ErrorEnvelope => {
+  if (!payload || typeof payload !== 'object') {
+    return false;
+  }
+  const data = payload as Record<string, unknown>;
+  return data.type === 'error' && typeof data.code === 'string';
 };
 
 const normalizePresenceUser = (user: PresenceUserPayload): User => ({
@@ -89,6 +144,7 @@ const normalizePresenceUser = (user: PresenceUserPayload): User => ({
   status: user.status ?? 'offline',
   avatar: user.avatar ?? undefined,
   email: user.email ?? undefined,
+  role: user.bot ? 'BOT' : undefined,
 });
 
 interface CurrentUser {
@@ -116,7 +172,11 @@ function App() {
   const [activeVoiceChannel, setActiveVoiceChannel] = useState<string | null>(null);
   const [isAuthenticated, setIsAuthenticated] = useState(false);
   const [currentUser, setCurrentUser] = useState<CurrentUser | null>(null);
-  const [aesKey, setAesKey] = useState<CryptoKey | null>(null);
+  const [identity, setIdentity] = useState<CryptoKeyPair | null>(null);
+  const [device, setDevice] = useState<DeviceKey | null>(null);
+  // One lookup per conversation and epoch, "current" for the one we send with;
+  // failed ones are forgotten so they are retried
+  const groupKeys = useRef(new Map<string, Promise<GroupKey>>());
   const [users, setUsers] = useState<User[]>([]);
 
   const channels: Channel[] = [
@@ -146,6 +206,16 @@ function App() {
   };
 
   const handleLogout = () => {
+    // Revoke the session server-side; the local state is cleared either way
+    const token = localStorage.getItem("sessionToken");
+    if (token) {
+      fetch("http://localhost:8080/api/logout", {
+        method: "POST",
+        headers: { Authorization: `Bearer ${token}` },
+      }).catch(() => undefined);
+    }
+    localStorage.removeItem("sessionToken");
+    localStorage.removeItem("sessionExpiresAt");
     localStorage.removeItem("currentUser");
     localStorage.removeItem("isAuthenticated");
     setIsAuthenticated(false);
@@ -157,6 +227,9 @@ function App() {
     setMessages([]);
     setActiveVoiceChannel(null);
     setUsers([]);
+    setIdentity(null);
+    setDevice(null);
+    groupKeys.current.clear();
   };
 
   useEffect(() => {
@@ -169,30 +242,83 @@ function App() {
     }
   }, []);
 
+  // Keep the session alive while the app is open
   useEffect(() => {
     if (!isAuthenticated) return;
-    
-    // Generate AES key for encryption
+
+    const refreshIfExpiring = async () => {
+      const token = localStorage.getItem("sessionToken");
+      const expiresAt = Number(localStorage.getItem("sessionExpiresAt") ?? 0);
+      if (!token || expiresAt - Date.now() / 1000 > SESSION_REFRESH_MARGIN_SECS) return;
+
+      try {
+        const response = await fetch("http://localhost:8080/api/session/refresh", {
+          method: "POST",
+          headers: { Authorization: `Bearer ${token}` },
+        });
+        if (response.status === 401) {
+          handleLogout();
+          return;
+        }
+        if (response.ok) {
+          const session = await response.json();
+          localStorage.setItem("sessionToken", session.token);
+          localStorage.setItem("sessionExpiresAt", String(session.expires_at));
+        }
+      } catch (error) {
+        console.error("Failed to refresh session:", error);
+      }
+    };
+
+    refreshIfExpiring();
+    const timer = window.setInterval(refreshIfExpiring, 60 * 1000);
+    return () => window.clearInterval(timer);
+  }, [isAuthenticated]);
+
+  useEffect(() => {
+    if (!isAuthenticated || !currentUser) return;
+
+    // Our identity and device keys stay in this browser; only their public halves are sent to the server
     const initEncryption = async () => {
       try {
-        const key = await generateAesKey("rustchatserver2024_aes_secure");
-        setAesKey(key);
+        setIdentity(await loadIdentity(currentUser.id));
+        setDevice(await loadDeviceKey(currentUser.id));
       } catch (error) {
-        console.error("Failed to generate AES key:", error);
+        console.error("Failed to set up end-to-end encryption:", error);
       }
     };
-    
+
     initEncryption();
-  }, [isAuthenticated]);
+  }, [isAuthenticated, currentUser]);
+
+  const groupKeyFor = useCallback((conversation: string, epoch?: number): Promise<GroupKey> => {
+    if (!identity || !currentUser) {
+      return Promise.reject(new Error("Encryption is not ready"));
+    }
+    const cacheKey = `${conversation}#${epoch ?? 'current'}`;
+    let pending = groupKeys.current.get(cacheKey);
+    if (!pending) {
+      pending = loadGroupKey(identity, currentUser.id, conversation, epoch);
+      pending.catch(() => groupKeys.current.delete(cacheKey));
+      groupKeys.current.set(cacheKey, pending);
+    }
+    return pending;
+  }, [identity, currentUser]);
 
   useEffect(() => {
-    if (!isAuthenticated || !aesKey) return;
+    if (!isAuthenticated || !identity) return;
 
     // Connect to WebSocket server (now on port 8081)
     const socket = new WebSocket("ws://127.0.0.1:8081");
 
     socket.onopen = () => {
       console.log("Connected to WebSocket");
+
+      // The server binds this connection to our session before anything else
+      socket.send(JSON.stringify({
+        type: 'authenticate',
+        token: localStorage.getItem("sessionToken") ?? '',
+      }));
       
       // Add welcome message
       const welcomeMessage: Message = {
@@ -245,47 +371,74 @@ function App() {
         return;
       }
 
+      // Someone lost access, so the next message needs a key they never had
+      if (isKeyRotated(parsed)) {
+        groupKeys.current.delete(`${parsed.conversation}#current`);
+        return;
+      }
+
+      // Logged out from another device, or the session ran out
+      if (isErrorEnvelope(parsed)) {
+        if (parsed.code === 'session_revoked' || parsed.code === 'unauthorized') {
+          handleLogout();
+          return;
+        }
+        // A rotation we missed; look the current keys up again
+        if (parsed.code === 'stale_key_epoch') {
+          for (const cacheKey of [...groupKeys.current.keys()]) {
+            if (cacheKey.endsWith('#current')) groupKeys.current.delete(cacheKey);
+          }
+        }
+        setMessages(prev => [...prev, {
+          id: Date.now().toString(),
+          author: 'Rustcord',
+          content: parsed.message,
+          timestamp: new Date(),
+        }]);
+        return;
+      }
+
       if (isChatMessageEnvelope(parsed)) {
         try {
-          const decryptedContent = await decryptMessageAes(parsed.ciphertext, aesKey);
+          const channel = parsed.channel ?? 'general';
+          const signed = parsed.ciphertext !== undefined
+            && parsed.author_id !== undefined
+            && parsed.device_key !== undefined
+            && parsed.signature !== undefined
+            && await verifyMessage(parsed.author_id, channel, parsed.ciphertext, {
+              device_key: parsed.device_key,
+              signature: parsed.signature,
+            });
+          const decryptedContent = parsed.ciphertext !== undefined
+            ? await decryptMessageAes(
+              parsed.ciphertext,
+              (await groupKeyFor(channel, parsed.epoch)).key
+            )
+            : parsed.content ?? '';
           const message: Message = {
-            id: Date.now().toString(),
+            id: parsed.id ? String(parsed.id) : Date.now().toString(),
             author: parsed.author || 'Server',
             content: decryptedContent,
-            timestamp: new Date(),
+            timestamp: parsed.sent_at ? new Date(parsed.sent_at * 1000) : new Date(),
+            avatar: parsed.avatar ?? undefined,
+            unverified: parsed.ciphertext !== undefined && !signed,
           };
           setMessages(prev => [...prev, message]);
           return;
         } catch (err) {
           console.error('Failed to decrypt chat message payload', err);
+          return;
         }
       }
 
-      try {
-        const decryptedContent = await decryptMessageAes(event.data, aesKey);
-        console.log("Server response (decrypted):", decryptedContent);
-        
-        const match = decryptedContent.match(/^([^:]+): (.*)$/);
-        if (match) {
-          const [, addr, content] = match;
-          const message: Message = {
-            id: Date.now().toString(),
-            author: addr,
-            content,
-            timestamp: new Date(),
-          };
-          setMessages((prev) => [...prev, message]);
-        }
-      } catch {
-        console.log("Server response (plain):", event.data);
-        const message: Message = {
-          id: Date.now().toString(),
-          author: 'Server',
-          content: event.data,
-          timestamp: new Date(),
-        };
-        setMessages((prev) => [...prev, message]);
-      }
+      console.log("Server response (plain):", event.data);
+      const message: Message = {
+        id: Date.now().toString(),
+        author: 'Server',
+        content: event.data,
+        timestamp: new Date(),
+      };
+      setMessages((prev) => [...prev, message]);
     };
 
     socket.onclose = () => {
@@ -299,15 +452,22 @@ function App() {
     setWs(socket);
 
     return () => socket.close();
-  }, [isAuthenticated, aesKey, currentUser]);
+  }, [isAuthenticated, identity, currentUser, groupKeyFor]);
 
   const sendMessage = async () => {
-    if (ws && input.trim() !== "" && aesKey) {
+    if (ws && input.trim() !== "" && identity && device && currentUser) {
       try {
-        // Encrypt the message before sending
-        const encryptedMessage = await encryptMessageAes(input, aesKey);
-        ws.send(JSON.stringify({ type: 'chat_message', ciphertext: encryptedMessage }));
-        
+        // Encrypt with the channel's group key; the server only relays the ciphertext
+        const { key, epoch } = await groupKeyFor(activeChannel);
+        const encryptedMessage = await encryptMessageAes(input, key, epoch);
+        const signature = await signMessage(device, currentUser.id, activeChannel, encryptedMessage);
+        ws.send(JSON.stringify({
+          type: 'chat_message',
+          channel: activeChannel,
+          ciphertext: encryptedMessage,
+          ...signature,
+        }));
+
         // Add to local messages
         const userMessage: Message = {
           id: Date.now().toString(),
@@ -320,6 +480,12 @@ function App() {
         setInput("");
       } catch (error) {
         console.error("Failed to encrypt message:", error);
+        setMessages(prev => [...prev, {
+          id: Date.now().toString(),
+          author: 'Rustcord',
+          content: error instanceof Error ? error.message : 'Message could not be encrypted',
+          timestamp: new Date(),
+        }]);
       }
     }
   };
@@ -346,8 +512,9 @@ function App() {
     <Router>
       <Routes>
         <Route path="/login" element={
-          !isAuthenticated ? <LoginPage /> : <Navigate to="/" />
+          !isAuthenticated ? <LoginPage onLoginSuccess={handleLoginSuccess} /> : <Navigate to="/" />
         } />
+        <Route path="/reset-password" element={<ResetPassword />} />
         <Route path="/oauth/callback" element={
           <OAuthCallback onLoginSuccess={handleLoginSuccess} />
         } />
@@ -17,6 +17,7 @@ interface Message {
   content: string;
   timestamp: Date;
   avatar?: string;
+  unverified?: boolean;
 }
 
 interface ChatAreaProps {
@@ -82,13 +83,22 @@ const ChatArea: React.FC<ChatAreaProps> = ({
       <div className="messages-container">
         {messages.map((message) => (
           <div key={message.id} className="message">
-            <div className="message-avatar">
-              {message.avatar || getInitials(message.author)}
-            </div>
+            {message.avatar && message.avatar.startsWith('http') ? (
+              <img src={message.avatar} alt={message.author} className="message-avatar" />
+            ) : (
+              <div className="message-avatar">
+                {message.avatar || getInitials(message.author)}
+              </div>
+            )}
             <div className="message-content">
               <div className="message-header">
                 <span className="message-author">{message.author}</span>
                 <span className="message-timestamp">{formatTime(message.timestamp)}</span>
+                {message.unverified && (
+                  <span className="message-unverified" title="Not signed by any of the author's devices">
+                    unverified
+                  </span>
+                )}
               </div>
               <div className="message-text">{message.content}</div>
             </div>
@@ -1,7 +1,147 @@
-import React from 'react';
+import React, { useState } from 'react';
+import { useSearchParams } from 'react-router-dom';
+import TwoFactorPrompt, { VerifiedSession } from './TwoFactorPrompt';
 import '../styles/LoginPage.css';
 
-const LoginPage: React.FC = () => {
+const API_URL = 'http://localhost:8080';
+
+interface LoggedInUser {
+  id: string;
+  username: string;
+  email?: string;
+  avatar?: string;
+  provider: string;
+}
+
+interface LoginPageProps {
+  onLoginSuccess: (user: LoggedInUser) => void;
+}
+
+type FormMode = 'login' | 'register' | 'forgot';
+
+interface PendingTwoFactor {
+  challenge: string;
+  setup_required: boolean;
+}
+
+// Messages for the stable error codes the account API answers with
+const accountErrorMessages: Record<string, string> = {
+  invalid_username: 'Usernames are 3-32 letters, digits, dots, dashes or underscores.',
+  invalid_email: 'Please enter a valid email address.',
+  weak_password: 'Passwords need at least 10 characters.',
+  username_taken: 'That username is taken.',
+  invalid_credentials: 'Wrong email or password.',
+  email_not_verified: 'Please verify your email first. Check your inbox for the link.',
+  account_locked: 'Too many failed attempts. Try again in a few minutes.',
+  invalid_token: 'That link is invalid or has expired.',
+  invite_required: 'This server is invite-only. Ask for an invite link to join.',
+  invite_not_found: 'That invite link is not valid.',
+  invite_expired: 'That invite link has expired.',
+  invite_exhausted: 'That invite link has already been used.',
+  invite_revoked: 'That invite link was revoked.',
+  invite_wrong_kind: 'That invite link cannot be used this way.',
+};
+
+const LoginPage: React.FC<LoginPageProps> = ({ onLoginSuccess }) => {
+  const [searchParams] = useSearchParams();
+  const [mode, setMode] = useState<FormMode>('login');
+  const [username, setUsername] = useState('');
+  const [email, setEmail] = useState('');
+  const [password, setPassword] = useState('');
+  const [error, setError] = useState<string | null>(() => {
+    const code = searchParams.get('error');
+    return code ? accountErrorMessages[code] ?? `Error: ${code}` : null;
+  });
+  const [twoFactor, setTwoFactor] = useState<PendingTwoFactor | null>(null);
+  const [notice, setNotice] = useState<string | null>(
+    searchParams.get('verified') ? 'Email verified, you can sign in now.' : null
+  );
+
+  const postJson = (path: string, body: unknown) =>
+    fetch(`${API_URL}${path}`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(body),
+    });
+
+  const errorMessage = async (response: Response) => {
+    const body = await response.json().catch(() => null);
+    const code = body?.error as string | undefined;
+    return code ? accountErrorMessages[code] ?? `Error: ${code}` : 'Something went wrong, please try again.';
+  };
+
+  const completeLogin = (session: VerifiedSession) => {
+    localStorage.setItem('sessionToken', session.token);
+    localStorage.setItem('sessionExpiresAt', String(session.expires_at));
+    onLoginSuccess({
+      id: session.user.id,
+      username: session.user.username,
+      email: session.user.email || undefined,
+      avatar: session.user.avatar || undefined,
+      provider: session.user.provider,
+    });
+  };
+
+  const handleSubmit = async (event: React.FormEvent) => {
+    event.preventDefault();
+    setError(null);
+    setNotice(null);
+
+    try {
+      if (mode === 'register') {
+        const response = await postJson('/api/accounts/register', {
+          username,
+          email,
+          password,
+          invite: searchParams.get('invite') ?? undefined,
+        });
+        if (!response.ok) {
+          setError(await errorMessage(response));
+          return;
+        }
+        setMode('login');
+        setNotice('Check your email for a link to finish signing up.');
+        return;
+      }
+
+      if (mode === 'forgot') {
+        await postJson('/api/accounts/password-reset', { email });
+        setMode('login');
+        setNotice('If an account exists for that email, a reset link is on its way.');
+        return;
+      }
+
+      const response = await postJson('/api/accounts/login', { email, password });
+      if (!response.ok) {
+        setError(await errorMessage(response));
+        return;
+      }
+      const body = await response.json();
+      // Accounts with two-factor authentication finish logging in with a code
+      if (body.status === 'two_factor_required') {
+        setTwoFactor(body);
+        return;
+      }
+      completeLogin(body);
+    } catch {
+      setError('Could not reach the server, please try again.');
+    }
+  };
+
+  // Guest invites skip accounts entirely; the server picks a display name
+  const handleJoinAsGuest = async () => {
+    setError(null);
+    try {
+      const response = await postJson('/api/guests', { invite: searchParams.get('invite') });
+      if (!response.ok) {
+        setError(await errorMessage(response));
+        return;
+      }
+      completeLogin(await response.json());
+    } catch {
+      setError('Could not reach the server, please try again.');
+    }
+  };
 
   const handleGoogleLogin = () => {
     console.log('Google OAuth login initiated');
@@ -15,6 +155,12 @@ const LoginPage: React.FC = () => {
     window.location.href = 'http://localhost:8080/auth/github';
   };
 
+  const handleSsoLogin = () => {
+    console.log('SSO login initiated');
+    // Redirect to backend OpenID Connect endpoint
+    window.location.href = 'http://localhost:8080/auth/oidc';
+  };
+
   return (
     <div className="login-container">
       <div className="login-background">
@@ -28,7 +174,78 @@ const LoginPage: React.FC = () => {
             <p className="subtitle">This app is experimental.</p>
           </div>
 
+          {twoFactor ? (
           <div className="login-form">
+            <TwoFactorPrompt
+              challenge={twoFactor.challenge}
+              setupRequired={twoFactor.setup_required}
+              onVerified={completeLogin}
+            />
+          </div>
+          ) : searchParams.get('guest') === 'true' ? (
+          <div className="login-form">
+            <div className="password-form">
+              {error && <p className="form-error">{error}</p>}
+              <p className="form-notice">You were invited as a guest. You will only see the channel you were invited to, and your access expires automatically.</p>
+              <button type="button" className="submit-button" onClick={handleJoinAsGuest}>
+                Join as guest
+              </button>
+            </div>
+          </div>
+          ) : (
+          <div className="login-form">
+            <form className="password-form" onSubmit={handleSubmit}>
+              {notice && <p className="form-notice">{notice}</p>}
+              {error && <p className="form-error">{error}</p>}
+              {mode === 'register' && (
+                <input
+                  className="form-input"
+                  placeholder="Username"
+                  value={username}
+                  onChange={(e) => setUsername(e.target.value)}
+                  autoComplete="username"
+                />
+              )}
+              <input
+                className="form-input"
+                type="email"
+                placeholder="Email"
+                value={email}
+                onChange={(e) => setEmail(e.target.value)}
+                autoComplete="email"
+              />
+              {mode !== 'forgot' && (
+                <input
+                  className="form-input"
+                  type="password"
+                  placeholder="Password"
+                  value={password}
+                  onChange={(e) => setPassword(e.target.value)}
+                  autoComplete={mode === 'register' ? 'new-password' : 'current-password'}
+                />
+              )}
+              <button type="submit" className="submit-button">
+                {mode === 'login' ? 'Log In' : mode === 'register' ? 'Create Account' : 'Send Reset Link'}
+              </button>
+              <div className="form-links">
+                {mode !== 'login' && (
+                  <button type="button" className="link-button" onClick={() => setMode('login')}>
+                    Back to login
+                  </button>
+                )}
+                {mode === 'login' && (
+                  <>
+                    <button type="button" className="link-button" onClick={() => setMode('register')}>
+                      Create an account
+                    </button>
+                    <button type="button" className="link-button" onClick={() => setMode('forgot')}>
+                      Forgot your password?
+                    </button>
+                  </>
+                )}
+              </div>
+            </form>
+
             <div className="oauth-buttons">
               <button 
                 className="oauth-button google-button"
@@ -52,8 +269,16 @@ const LoginPage: React.FC = () => {
                 </svg>
                 Continue with GitHub
               </button>
+
+              <button 
+                className="oauth-button sso-button"
+                onClick={handleSsoLogin}
+              >
+                Continue with SSO
+              </button>
             </div>
           </div>
+          )}
         </div>
       </div>
     </div>
@@ -1,6 +1,7 @@
 // src/components/OAuthCallback.tsx
-import React, { useEffect, useState } from 'react';
+import React, { useCallback, useEffect, useRef, useState } from 'react';
 import { useNavigate, useSearchParams } from 'react-router-dom';
+import TwoFactorPrompt, { VerifiedSession } from './TwoFactorPrompt';
 
 interface OAuthUser {
   id: string;
@@ -10,6 +11,24 @@ interface OAuthUser {
   provider: string;
 }
 
+// Messages for the stable error codes the server redirects with
+const authErrorMessages: Record<string, string> = {
+  access_denied: 'Login was cancelled.',
+  provider_error: 'The login provider reported an error. Please try again.',
+  unknown_provider: 'That login provider is not supported.',
+  provider_not_configured: 'That login provider is not configured on this server.',
+  invalid_state: 'Your login session expired. Please try again.',
+  missing_code: 'The login provider did not return an authorization code.',
+  token_exchange_failed: 'We could not complete login with the provider. Please try again.',
+  profile_fetch_failed: 'We could not load your profile from the provider.',
+  email_missing: 'Your account has no email address we can use. Please add one with the provider.',
+  invite_required: 'This server is invite-only. Ask for an invite link to join.',
+  provider_unavailable: 'The login provider could not be reached. Please try again later.',
+  invalid_id_token: 'The login provider returned an identity we could not verify.',
+  link_expired: 'That account link request expired. Please start again from your settings.',
+  identity_already_linked: 'That account already belongs to another Rustcord user.',
+};
+
 interface OAuthCallbackProps {
   onLoginSuccess: (user: OAuthUser) => void;
 }
@@ -18,39 +37,62 @@ const OAuthCallback: React.FC<OAuthCallbackProps> = ({ onLoginSuccess }) => {
   const [searchParams] = useSearchParams();
   const [error, setError] = useState<string | null>(null);
   const navigate = useNavigate();
+  // Exchange codes are single use, so only try once
+  const exchanged = useRef(false);
+  // Set when the provider login still needs a second factor
+  const twoFactorChallenge = searchParams.get('two_factor');
+
+  const completeLogin = useCallback((session: VerifiedSession) => {
+    localStorage.setItem('sessionToken', session.token);
+    localStorage.setItem('sessionExpiresAt', String(session.expires_at));
+
+    const user: OAuthUser = {
+      id: session.user.id,
+      username: session.user.username,
+      email: session.user.email && session.user.email.trim() !== '' ? session.user.email : undefined,
+      avatar: session.user.avatar || undefined,
+      provider: session.user.provider,
+    };
+
+    // Notify the parent component of successful login
+    onLoginSuccess(user);
+    // Navigate back to the main app
+    navigate('/');
+  }, [navigate, onLoginSuccess]);
 
   useEffect(() => {
     const handleCallback = async () => {
       try {
         // Get parameters from URL
-        const provider = searchParams.get('provider');
-        const token = searchParams.get('token');
-        const username = searchParams.get('username');
-        const email = searchParams.get('email');
-        const avatar = searchParams.get('avatar');
+        const code = searchParams.get('code');
         const oauthError = searchParams.get('error');
         
         // Check if there was an OAuth error
         if (oauthError) {
-          setError(`OAuth error: ${oauthError}`);
+          setError(authErrorMessages[oauthError] ?? `OAuth error: ${oauthError}`);
           return;
         }
         
-        // Check if we have the required parameters
-        if (provider && token && username) {
-          // Create user object from the parameters
-          const user: OAuthUser = {
-            id: `oauth_${provider}_${Date.now()}`,
-            username,
-            email: email && email.trim() !== '' ? email : undefined,
-            avatar: avatar || undefined,
-            provider,
-          };
-          
-          // Notify the parent component of successful login
-          onLoginSuccess(user);
-          // Navigate back to the main app
+        // A provider was added to the logged-in user's account; their session carries on
+        if (searchParams.get('linked')) {
           navigate('/');
+          return;
+        }
+
+        // Trade the one-time code for a session token
+        if (code && !exchanged.current) {
+          exchanged.current = true;
+          const response = await fetch('http://localhost:8080/api/session/exchange', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ code }),
+          });
+          if (!response.ok) {
+            setError('Your login link has expired, please sign in again');
+            return;
+          }
+
+          completeLogin((await response.json()) as VerifiedSession);
         }
         // If we don't have the parameters, this might be the initial OAuth callback
         // from the provider to our backend, which will then redirect back to /login
@@ -62,7 +104,7 @@ const OAuthCallback: React.FC<OAuthCallbackProps> = ({ onLoginSuccess }) => {
     };
     
     handleCallback();
-  }, [searchParams, navigate, onLoginSuccess]);
+  }, [searchParams, completeLogin, navigate]);
   
   if (error) {
     return (
@@ -76,6 +118,18 @@ const OAuthCallback: React.FC<OAuthCallbackProps> = ({ onLoginSuccess }) => {
     );
   }
   
+  if (twoFactorChallenge) {
+    return (
+      <div className="oauth-callback">
+        <TwoFactorPrompt
+          challenge={twoFactorChallenge}
+          setupRequired={searchParams.get('setup') === 'true'}
+          onVerified={completeLogin}
+        />
+      </div>
+    );
+  }
+
   return (
     <div className="oauth-callback">
       <div className="loading-message">
@@ -0,0 +1,60 @@
+// src/components/ResetPassword.tsx
+import React, { useState } from 'react';
+import { useNavigate, useSearchParams } from 'react-router-dom';
+import '../styles/LoginPage.css';
+
+// Landing page for the link in a password reset email
+const ResetPassword: React.FC = () => {
+  const [searchParams] = useSearchParams();
+  const navigate = useNavigate();
+  const [password, setPassword] = useState('');
+  const [error, setError] = useState<string | null>(null);
+
+  const handleSubmit = async (event: React.FormEvent) => {
+    event.preventDefault();
+    setError(null);
+
+    const response = await fetch('http://localhost:8080/api/accounts/password-reset/confirm', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ token: searchParams.get('token') ?? '', password }),
+    }).catch(() => null);
+
+    if (response?.ok) {
+      navigate('/login');
+      return;
+    }
+    const body = await response?.json().catch(() => null);
+    setError(
+      body?.error === 'weak_password'
+        ? 'Passwords need at least 10 characters.'
+        : 'This reset link is invalid or has expired.'
+    );
+  };
+
+  return (
+    <div className="login-container">
+      <div className="login-background">
+        <div className="login-card">
+          <div className="login-header">
+            <h2 className="welcome-text">Choose a new password</h2>
+          </div>
+          <form className="password-form" onSubmit={handleSubmit}>
+            {error && <p className="form-error">{error}</p>}
+            <input
+              className="form-input"
+              type="password"
+              placeholder="New password"
+              value={password}
+              onChange={(e) => setPassword(e.target.value)}
+              autoComplete="new-password"
+            />
+            <button type="submit" className="submit-button">Reset Password</button>
+          </form>
+        </div>
+      </div>
+    </div>
+  );
+};
+
+export default ResetPassword;
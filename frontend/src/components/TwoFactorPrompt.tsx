@@ -0,0 +1,138 @@
+// src/components/TwoFactorPrompt.tsx
+import React, { useEffect, useRef, useState } from 'react';
+import '../styles/LoginPage.css';
+
+const API_URL = 'http://localhost:8080';
+
+export interface VerifiedSession {
+  token: string;
+  expires_at: number;
+  user: {
+    id: string;
+    username: string;
+    email: string;
+    avatar?: string | null;
+    provider: string;
+  };
+  recovery_codes?: string[];
+}
+
+interface Provisioning {
+  secret: string;
+  otpauth_uri: string;
+}
+
+interface TwoFactorPromptProps {
+  challenge: string;
+  setupRequired: boolean;
+  onVerified: (session: VerifiedSession) => void;
+}
+
+const twoFactorErrorMessages: Record<string, string> = {
+  invalid_code: 'That code is not valid. Check your authenticator app and try again.',
+  invalid_challenge: 'This login has expired. Please sign in again.',
+  account_locked: 'Too many wrong codes. Try again in a few minutes.',
+};
+
+// Second login step: a code from an authenticator app, or a recovery code
+const TwoFactorPrompt: React.FC<TwoFactorPromptProps> = ({ challenge, setupRequired, onVerified }) => {
+  const [code, setCode] = useState('');
+  const [error, setError] = useState<string | null>(null);
+  const [provisioning, setProvisioning] = useState<Provisioning | null>(null);
+  const [verified, setVerified] = useState<VerifiedSession | null>(null);
+  // A new setup secret replaces the previous one, so only ask once
+  const setupRequested = useRef(false);
+
+  useEffect(() => {
+    if (!setupRequired || setupRequested.current) {
+      return;
+    }
+    setupRequested.current = true;
+    fetch(`${API_URL}/api/2fa/challenge/setup`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ challenge }),
+    })
+      .then(async (response) => {
+        if (!response.ok) {
+          setError(twoFactorErrorMessages.invalid_challenge);
+          return;
+        }
+        setProvisioning(await response.json());
+      })
+      .catch(() => setError('Could not reach the server, please try again.'));
+  }, [challenge, setupRequired]);
+
+  const handleSubmit = async (event: React.FormEvent) => {
+    event.preventDefault();
+    setError(null);
+
+    const response = await fetch(`${API_URL}/api/2fa/challenge/verify`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ challenge, code }),
+    }).catch(() => null);
+
+    if (!response) {
+      setError('Could not reach the server, please try again.');
+      return;
+    }
+    const body = await response.json().catch(() => null);
+    if (!response.ok) {
+      setError(twoFactorErrorMessages[body?.error] ?? 'Something went wrong, please try again.');
+      return;
+    }
+
+    const session = body as VerifiedSession;
+    // Recovery codes are only ever shown once; make sure they were seen before moving on
+    if (session.recovery_codes?.length) {
+      setVerified(session);
+    } else {
+      onVerified(session);
+    }
+  };
+
+  if (verified) {
+    return (
+      <div className="password-form">
+        <p className="form-notice">Two-factor authentication is on. Save these recovery codes somewhere safe, each works once if you lose your device.</p>
+        <ul className="recovery-codes">
+          {verified.recovery_codes?.map((recoveryCode) => (
+            <li key={recoveryCode}>{recoveryCode}</li>
+          ))}
+        </ul>
+        <button type="button" className="submit-button" onClick={() => onVerified(verified)}>
+          I saved my recovery codes
+        </button>
+      </div>
+    );
+  }
+
+  return (
+    <form className="password-form" onSubmit={handleSubmit}>
+      {setupRequired && (
+        <p className="form-notice">This server requires two-factor authentication for your role. Add this account to your authenticator app to continue.</p>
+      )}
+      {provisioning && (
+        <div className="two-factor-setup">
+          <a href={provisioning.otpauth_uri}>Open in authenticator app</a>
+          <p>Or enter this key manually:</p>
+          <code>{provisioning.secret}</code>
+        </div>
+      )}
+      {error && <p className="form-error">{error}</p>}
+      <input
+        className="form-input"
+        placeholder={setupRequired ? '6-digit code' : '6-digit code or recovery code'}
+        value={code}
+        onChange={(e) => setCode(e.target.value)}
+        autoComplete="one-time-code"
+        inputMode={setupRequired ? 'numeric' : 'text'}
+        autoFocus
+      />
+      <button type="submit" className="submit-button">Verify</button>
+    </form>
+  );
+};
+
+export default TwoFactorPrompt;
@@ -1,8 +1,11 @@
 // src/utils/aes.ts
-// AES-256-CBC encryption utilities for the frontend
+// Message encryption for the frontend. Messages are versioned envelopes:
+// version (1 byte) | algorithm (1 byte) | key epoch (4 bytes) | nonce | AEAD ciphertext and tag,
+// with the header before the nonce authenticated. Version 1 had no epoch. Older
+// messages are a bare AES-256-CBC IV and ciphertext, and still decrypt while clients migrate.
 
 // Convert ArrayBuffer to base64 string
-function arrayBufferToBase64(buffer: ArrayBuffer): string {
+export function arrayBufferToBase64(buffer: ArrayBuffer): string {
   const bytes = new Uint8Array(buffer);
   let binary = '';
   for (let i = 0; i < bytes.byteLength; i++) {
@@ -12,7 +15,7 @@ function arrayBufferToBase64(buffer: ArrayBuffer): string {
 }
 
 // Convert base64 string to ArrayBuffer
-function base64ToArrayBuffer(base64: string): ArrayBuffer {
+export function base64ToArrayBuffer(base64: string): ArrayBuffer {
   const binary = atob(base64);
   const bytes = new Uint8Array(binary.length);
   for (let i = 0; i < binary.length; i++) {
@@ -21,81 +24,89 @@ function base64ToArrayBuffer(base64: string): ArrayBuffer {
   return bytes.buffer;
 }
 
-// Generate AES-256 key from password using PBKDF2
-export async function generateAesKey(password: string): Promise<CryptoKey> {
-  const encoder = new TextEncoder();
-  const data = encoder.encode(password);
-  
-  // Import the password as a crypto key
-  const keyMaterial = await crypto.subtle.importKey(
-    'raw',
-    data,
-    { name: 'PBKDF2' },
-    false,
-    ['deriveKey']
-  );
-  
-  // Derive a 256-bit AES key using PBKDF2
-  return crypto.subtle.deriveKey(
-    {
-      name: 'PBKDF2',
-      salt: encoder.encode('rustchatserver2024_aes_secure'), // Same salt as server
-      iterations: 100000,
-      hash: 'SHA-256'
-    },
-    keyMaterial,
-    { name: 'AES-CBC', length: 256 },
+const ENVELOPE_VERSION = 2;
+const ENVELOPE_VERSION_WITHOUT_EPOCH = 1;
+const ALGORITHM_AES_256_GCM = 1;
+const ALGORITHM_XCHACHA20_POLY1305 = 2;
+const GCM_NONCE_LENGTH = 12;
+const TAG_LENGTH = 16;
+
+// Generate a random AES-256 key for a conversation; it is only ever shared wrapped
+export async function generateAesKey(): Promise<CryptoKey> {
+  return crypto.subtle.generateKey(
+    { name: 'AES-GCM', length: 256 },
     true,
     ['encrypt', 'decrypt']
   );
 }
 
-// Encrypt message using AES-256-CBC
-export async function encryptMessageAes(message: string, key: CryptoKey): Promise<string> {
-  const encoder = new TextEncoder();
-  const data = encoder.encode(message);
-  
-  // Generate a random IV
-  const iv = crypto.getRandomValues(new Uint8Array(16));
-  
-  // Encrypt the data
-  const encrypted = await crypto.subtle.encrypt(
-    { name: 'AES-CBC', iv },
+// Import a raw AES-256 key unwrapped from a member's copy
+export async function importAesKey(raw: ArrayBuffer): Promise<CryptoKey> {
+  return crypto.subtle.importKey('raw', raw, { name: 'AES-GCM' }, true, ['encrypt', 'decrypt']);
+}
+
+// Seal a message with AES-256-GCM, under the group key of the given epoch
+export async function encryptMessageAes(message: string, key: CryptoKey, epoch: number): Promise<string> {
+  const header = new Uint8Array(6);
+  header[0] = ENVELOPE_VERSION;
+  header[1] = ALGORITHM_AES_256_GCM;
+  new DataView(header.buffer).setUint32(2, epoch);
+  const nonce = crypto.getRandomValues(new Uint8Array(GCM_NONCE_LENGTH));
+
+  const encrypted = new Uint8Array(await crypto.subtle.encrypt(
+    { name: 'AES-GCM', iv: nonce, additionalData: header },
     key,
-    data
-  );
-  
-  // Combine IV and encrypted data
-  const encryptedArray = new Uint8Array(encrypted);
-  const result = new Uint8Array(iv.length + encryptedArray.length);
-  result.set(iv, 0);
-  result.set(encryptedArray, iv.length);
-  
-  // Convert to base64 for transmission
-  return arrayBufferToBase64(result);
+    new TextEncoder().encode(message)
+  ));
+
+  const result = new Uint8Array(header.length + nonce.length + encrypted.length);
+  result.set(header, 0);
+  result.set(nonce, header.length);
+  result.set(encrypted, header.length + nonce.length);
+  return arrayBufferToBase64(result.buffer);
 }
 
-// Decrypt message using AES-256-CBC
+// Old messages: a 16 byte IV, then AES-256-CBC with PKCS7 padding and no MAC
+async function decryptLegacyCbc(data: Uint8Array, key: CryptoKey): Promise<ArrayBuffer> {
+  const raw = await crypto.subtle.exportKey('raw', key);
+  const cbcKey = await crypto.subtle.importKey('raw', raw, { name: 'AES-CBC' }, false, ['decrypt']);
+  return crypto.subtle.decrypt({ name: 'AES-CBC', iv: data.slice(0, 16) }, cbcKey, data.slice(16));
+}
+
+// Open an envelope, falling back to the old CBC format
 export async function decryptMessageAes(encryptedMessage: string, key: CryptoKey): Promise<string> {
+  const data = new Uint8Array(base64ToArrayBuffer(encryptedMessage));
+  const [version, algorithm] = data;
+  const headerLength = version === ENVELOPE_VERSION ? 6 : version === ENVELOPE_VERSION_WITHOUT_EPOCH ? 2 : 0;
+  // An IV and at least one whole block
+  const legacyShaped = data.length >= 32 && data.length % 16 === 0;
+
+  if (headerLength && algorithm === ALGORITHM_XCHACHA20_POLY1305 && !legacyShaped) {
+    throw new Error('XChaCha20-Poly1305 messages are not supported in the browser');
+  }
+  if (
+    headerLength
+    && algorithm === ALGORITHM_AES_256_GCM
+    && data.length >= headerLength + GCM_NONCE_LENGTH + TAG_LENGTH
+  ) {
+    const nonceEnd = headerLength + GCM_NONCE_LENGTH;
+    try {
+      const decrypted = await crypto.subtle.decrypt(
+        { name: 'AES-GCM', iv: data.slice(headerLength, nonceEnd), additionalData: data.slice(0, headerLength) },
+        key,
+        data.slice(nonceEnd)
+      );
+      return new TextDecoder().decode(decrypted);
+    } catch {
+      // A random CBC IV can look like an envelope header
+    }
+  }
+
+  if (!legacyShaped) {
+    throw new Error('Decryption failed');
+  }
   try {
-    // Convert base64 to ArrayBuffer
-    const data = base64ToArrayBuffer(encryptedMessage);
-    const dataArray = new Uint8Array(data);
-    
-    // Extract IV and encrypted data
-    const iv = dataArray.slice(0, 16);
-    const encrypted = dataArray.slice(16);
-    
-    // Decrypt the data
-    const decrypted = await crypto.subtle.decrypt(
-      { name: 'AES-CBC', iv },
-      key,
-      encrypted
-    );
-    
-    // Convert to string
-    const decoder = new TextDecoder();
-    return decoder.decode(decrypted);
+    return new TextDecoder().decode(await decryptLegacyCbc(data, key));
   } catch {
     throw new Error('Decryption failed');
   }
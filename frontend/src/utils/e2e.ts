@@ -0,0 +1,203 @@
+// src/utils/e2e.ts
+// End-to-end encryption: X25519 identity keys and per-conversation group keys.
+// The server stores public keys and wrapped copies of group keys, never a key it can use.
+
+import {
+  arrayBufferToBase64,
+  base64ToArrayBuffer,
+  generateAesKey,
+  importAesKey,
+} from './aes';
+
+const API_BASE = 'http://localhost:8080';
+const IDENTITY_STORAGE_PREFIX = 'identityKey:';
+
+interface IdentityKeyPayload {
+  user_id: string;
+  public_key: string;
+}
+
+interface WrappedKeyPayload {
+  epoch: number;
+  key_id: string;
+  sender: string;
+  sender_key: string;
+  wrapped: string;
+}
+
+interface GroupKeyPayload {
+  epoch: number;
+  key_id: string | null;
+  key: WrappedKeyPayload | null;
+  missing: IdentityKeyPayload[];
+}
+
+export interface GroupKey {
+  epoch: number;
+  keyId: string;
+  key: CryptoKey;
+}
+
+// Thrown while a conversation has a key but nobody has wrapped a copy for us yet
+export class KeyPendingError extends Error {
+  constructor(conversation: string) {
+    super(`Waiting for a member of ${conversation} to share its key`);
+  }
+}
+
+const authHeaders = () => ({
+  Authorization: `Bearer ${localStorage.getItem('sessionToken') ?? ''}`,
+  'Content-Type': 'application/json',
+});
+
+// Load this browser's identity key pair, creating one on first use, and publish its public half
+export async function loadIdentity(userId: string): Promise<CryptoKeyPair> {
+  const storageKey = IDENTITY_STORAGE_PREFIX + userId;
+  const stored = localStorage.getItem(storageKey);
+  let pair: CryptoKeyPair;
+  if (stored) {
+    const { privateKey, publicKey } = JSON.parse(stored);
+    pair = {
+      privateKey: await crypto.subtle.importKey('jwk', privateKey, { name: 'X25519' }, true, ['deriveBits']),
+      publicKey: await crypto.subtle.importKey('jwk', publicKey, { name: 'X25519' }, true, []),
+    };
+  } else {
+    pair = await crypto.subtle.generateKey({ name: 'X25519' }, true, ['deriveBits']) as CryptoKeyPair;
+    localStorage.setItem(storageKey, JSON.stringify({
+      privateKey: await crypto.subtle.exportKey('jwk', pair.privateKey),
+      publicKey: await crypto.subtle.exportKey('jwk', pair.publicKey),
+    }));
+  }
+
+  const publicKey = arrayBufferToBase64(await crypto.subtle.exportKey('raw', pair.publicKey));
+  const response = await fetch(`${API_BASE}/api/keys/identity`, {
+    method: 'PUT',
+    headers: authHeaders(),
+    body: JSON.stringify({ public_key: publicKey }),
+  });
+  if (!response.ok) {
+    throw new Error(`Publishing identity key failed with ${response.status}`);
+  }
+  return pair;
+}
+
+// An AES-GCM key only the two parties can derive, bound to one copy of one group key
+async function wrappingKey(privateKey: CryptoKey, peerPublicKey: string, info: string): Promise<CryptoKey> {
+  const peer = await crypto.subtle.importKey(
+    'raw',
+    base64ToArrayBuffer(peerPublicKey),
+    { name: 'X25519' },
+    false,
+    []
+  );
+  const shared = await crypto.subtle.deriveBits({ name: 'X25519', public: peer }, privateKey, 256);
+  const material = await crypto.subtle.importKey('raw', shared, 'HKDF', false, ['deriveKey']);
+  return crypto.subtle.deriveKey(
+    { name: 'HKDF', hash: 'SHA-256', salt: new Uint8Array(), info: new TextEncoder().encode(info) },
+    material,
+    { name: 'AES-GCM', length: 256 },
+    false,
+    ['encrypt', 'decrypt']
+  );
+}
+
+const wrapInfo = (conversation: string, epoch: number, keyId: string, recipient: string) =>
+  `rustcord group key|${conversation}|${epoch}|${keyId}|${recipient}`;
+
+async function wrapGroupKey(
+  identity: CryptoKeyPair,
+  conversation: string,
+  groupKey: GroupKey,
+  recipient: IdentityKeyPayload
+): Promise<string> {
+  const key = await wrappingKey(
+    identity.privateKey,
+    recipient.public_key,
+    wrapInfo(conversation, groupKey.epoch, groupKey.keyId, recipient.user_id)
+  );
+  const nonce = crypto.getRandomValues(new Uint8Array(12));
+  const raw = await crypto.subtle.exportKey('raw', groupKey.key);
+  const sealed = new Uint8Array(await crypto.subtle.encrypt({ name: 'AES-GCM', iv: nonce }, key, raw));
+  const result = new Uint8Array(nonce.length + sealed.length);
+  result.set(nonce, 0);
+  result.set(sealed, nonce.length);
+  return arrayBufferToBase64(result.buffer);
+}
+
+async function unwrapGroupKey(
+  identity: CryptoKeyPair,
+  conversation: string,
+  userId: string,
+  copy: WrappedKeyPayload
+): Promise<GroupKey> {
+  const key = await wrappingKey(
+    identity.privateKey,
+    copy.sender_key,
+    wrapInfo(conversation, copy.epoch, copy.key_id, userId)
+  );
+  const data = new Uint8Array(base64ToArrayBuffer(copy.wrapped));
+  const raw = await crypto.subtle.decrypt({ name: 'AES-GCM', iv: data.slice(0, 12) }, key, data.slice(12));
+  return { epoch: copy.epoch, keyId: copy.key_id, key: await importAesKey(raw) };
+}
+
+// Wrap the key for every reader still without a copy; false if another key won the race
+async function shareGroupKey(
+  identity: CryptoKeyPair,
+  conversation: string,
+  groupKey: GroupKey,
+  recipients: IdentityKeyPayload[]
+): Promise<boolean> {
+  if (recipients.length === 0) return true;
+
+  const keys = await Promise.all(recipients.map(async recipient => ({
+    recipient: recipient.user_id,
+    wrapped: await wrapGroupKey(identity, conversation, groupKey, recipient),
+  })));
+  const response = await fetch(`${API_BASE}/api/keys/groups/${encodeURIComponent(conversation)}`, {
+    method: 'PUT',
+    headers: authHeaders(),
+    body: JSON.stringify({ epoch: groupKey.epoch, key_id: groupKey.keyId, keys }),
+  });
+  if (response.status === 409) return false;
+  if (!response.ok) {
+    throw new Error(`Sharing the key of ${conversation} failed with ${response.status}`);
+  }
+  return true;
+}
+
+// The key a channel or DM is encrypted with, or the key of an older epoch to read
+// history. The first member to need a new epoch's key creates it; whoever holds it
+// wraps copies for members that joined since
+export async function loadGroupKey(
+  identity: CryptoKeyPair,
+  userId: string,
+  conversation: string,
+  epoch?: number
+): Promise<GroupKey> {
+  const query = epoch === undefined ? '' : `?epoch=${epoch}`;
+  for (let attempt = 0; attempt < 3; attempt++) {
+    const response = await fetch(`${API_BASE}/api/keys/groups/${encodeURIComponent(conversation)}${query}`, {
+      headers: authHeaders(),
+    });
+    if (!response.ok) {
+      throw new Error(`Loading the key of ${conversation} failed with ${response.status}`);
+    }
+    const state = await response.json() as GroupKeyPayload;
+
+    let groupKey: GroupKey;
+    if (state.key) {
+      groupKey = await unwrapGroupKey(identity, conversation, userId, state.key);
+    } else if (state.key_id || epoch !== undefined) {
+      throw new KeyPendingError(conversation);
+    } else {
+      const bytes = crypto.getRandomValues(new Uint8Array(16));
+      const keyId = Array.from(bytes, byte => byte.toString(16).padStart(2, '0')).join('');
+      groupKey = { epoch: state.epoch, keyId, key: await generateAesKey() };
+    }
+
+    if (await shareGroupKey(identity, conversation, groupKey, state.missing)) {
+      return groupKey;
+    }
+  }
+  throw new Error(`Could not agree on a key for ${conversation}`);
+}
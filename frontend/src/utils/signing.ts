@@ -0,0 +1,125 @@
+// src/utils/signing.ts
+// Message signatures: every browser signs what it sends with an Ed25519 device key
+// registered to the account, so readers can check who wrote a message without trusting the server.
+
+import { arrayBufferToBase64, base64ToArrayBuffer } from './aes';
+
+const API_BASE = 'http://localhost:8080';
+const DEVICE_STORAGE_PREFIX = 'deviceKey:';
+
+interface DeviceKeyPayload {
+  user_id: string;
+  public_key: string;
+}
+
+export interface DeviceKey {
+  publicKey: string;
+  privateKey: CryptoKey;
+}
+
+export interface MessageSignature {
+  device_key: string;
+  signature: string;
+}
+
+// Each author's registered device keys; looked up again when a signature names an unknown one
+const deviceKeys = new Map<string, Promise<string[]>>();
+
+const authHeaders = () => ({
+  Authorization: `Bearer ${localStorage.getItem('sessionToken') ?? ''}`,
+  'Content-Type': 'application/json',
+});
+
+// Must match the server: a JSON array of a label, the author, the channel and the ciphertext
+const signedBytes = (authorId: string, channel: string, ciphertext: string) =>
+  new TextEncoder().encode(JSON.stringify(['rustcord message', authorId, channel, ciphertext]));
+
+// Load this browser's device key, creating one on first use, and register its public half
+export async function loadDeviceKey(userId: string): Promise<DeviceKey> {
+  const storageKey = DEVICE_STORAGE_PREFIX + userId;
+  const stored = localStorage.getItem(storageKey);
+  let privateKey: CryptoKey;
+  let publicKey: CryptoKey;
+  if (stored) {
+    const jwks = JSON.parse(stored);
+    privateKey = await crypto.subtle.importKey('jwk', jwks.privateKey, { name: 'Ed25519' }, true, ['sign']);
+    publicKey = await crypto.subtle.importKey('jwk', jwks.publicKey, { name: 'Ed25519' }, true, ['verify']);
+  } else {
+    const pair = await crypto.subtle.generateKey({ name: 'Ed25519' }, true, ['sign', 'verify']) as CryptoKeyPair;
+    ({ privateKey, publicKey } = pair);
+    localStorage.setItem(storageKey, JSON.stringify({
+      privateKey: await crypto.subtle.exportKey('jwk', privateKey),
+      publicKey: await crypto.subtle.exportKey('jwk', publicKey),
+    }));
+  }
+
+  const raw = arrayBufferToBase64(await crypto.subtle.exportKey('raw', publicKey));
+  const response = await fetch(`${API_BASE}/api/keys/devices`, {
+    method: 'PUT',
+    headers: authHeaders(),
+    body: JSON.stringify({ public_key: raw }),
+  });
+  if (!response.ok) {
+    throw new Error(`Registering device key failed with ${response.status}`);
+  }
+  return { publicKey: raw, privateKey };
+}
+
+export async function signMessage(
+  device: DeviceKey,
+  authorId: string,
+  channel: string,
+  ciphertext: string
+): Promise<MessageSignature> {
+  const signature = await crypto.subtle.sign(
+    { name: 'Ed25519' },
+    device.privateKey,
+    signedBytes(authorId, channel, ciphertext)
+  );
+  return { device_key: device.publicKey, signature: arrayBufferToBase64(signature) };
+}
+
+function devicesOf(userId: string, refresh: boolean): Promise<string[]> {
+  let pending = deviceKeys.get(userId);
+  if (!pending || refresh) {
+    pending = fetch(`${API_BASE}/api/keys/devices/${encodeURIComponent(userId)}`, { headers: authHeaders() })
+      .then(async response => {
+        if (!response.ok) throw new Error(`Loading device keys failed with ${response.status}`);
+        return (await response.json() as DeviceKeyPayload[]).map(key => key.public_key);
+      });
+    pending.catch(() => deviceKeys.delete(userId));
+    deviceKeys.set(userId, pending);
+  }
+  return pending;
+}
+
+// True only if one of the author's registered devices signed exactly this message
+export async function verifyMessage(
+  authorId: string,
+  channel: string,
+  ciphertext: string,
+  signature: MessageSignature
+): Promise<boolean> {
+  let devices = await devicesOf(authorId, false);
+  if (!devices.includes(signature.device_key)) {
+    devices = await devicesOf(authorId, true);
+    if (!devices.includes(signature.device_key)) return false;
+  }
+  try {
+    const key = await crypto.subtle.importKey(
+      'raw',
+      base64ToArrayBuffer(signature.device_key),
+      { name: 'Ed25519' },
+      false,
+      ['verify']
+    );
+    return await crypto.subtle.verify(
+      { name: 'Ed25519' },
+      key,
+      base64ToArrayBuffer(signature.signature),
+      signedBytes(authorId, channel, ciphertext)
+    );
+  } catch {
+    return false;
+  }
+}
@@ -0,0 +1,942 @@
+// src/accounts.rs
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use rand::{Rng, distributions::Alphanumeric, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::RwLock;
+
+use crate::auth::{OAuthProvider, OAuthUser};
+use crate::invites;
+use crate::logging;
+use crate::mailer::Email;
+use crate::session::{self, disconnect_sessions, session_response};
+use crate::state::AppState;
+use crate::two_factor::{self, LoginResponse};
+use crate::unix_now;
+use crate::webserver::{FRONTEND_URL, public_base_url};
+
+const MIN_PASSWORD_LENGTH: usize = 10;
+const MAX_PASSWORD_LENGTH: usize = 256;
+
+// Failed logins in a row before an account is locked, and for how long
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOCKOUT_SECS: u64 = 15 * 60;
+
+const VERIFICATION_TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+const RESET_TOKEN_TTL_SECS: u64 = 60 * 60;
+
+// Provider slug for accounts that log in with a password
+pub const LOCAL_PROVIDER: &str = "local";
+
+// A username/password account; the password is only kept as an Argon2id hash
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    password_hash: String,
+    pub email_verified: bool,
+    failed_logins: u32,
+    locked_until: u64,
+    // Invite given at registration, redeemed on the first login
+    invite: Option<String>,
+}
+
+impl Account {
+    // The identity sessions are issued for, shaped like an OAuth login
+    pub fn user(&self) -> OAuthUser {
+        OAuthUser {
+            id: self.id.clone(),
+            username: self.username.clone(),
+            email: self.email.clone(),
+            email_verified: self.email_verified,
+            avatar: None,
+            provider: OAuthProvider::new(LOCAL_PROVIDER),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+struct AccountToken {
+    account_id: String,
+    purpose: TokenPurpose,
+    expires_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountError {
+    InvalidUsername,
+    InvalidEmail,
+    WeakPassword,
+    EmailTaken,
+    UsernameTaken,
+    InvalidCredentials,
+    EmailNotVerified,
+    Locked { retry_after: u64 },
+    InvalidToken,
+    InviteRequired,
+    Internal,
+}
+
+impl AccountError {
+    // Stable code handed to the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::InvalidUsername => "invalid_username",
+            AccountError::InvalidEmail => "invalid_email",
+            AccountError::WeakPassword => "weak_password",
+            AccountError::EmailTaken => "email_taken",
+            AccountError::UsernameTaken => "username_taken",
+            AccountError::InvalidCredentials => "invalid_credentials",
+            AccountError::EmailNotVerified => "email_not_verified",
+            AccountError::Locked { .. } => "account_locked",
+            AccountError::InvalidToken => "invalid_token",
+            AccountError::InviteRequired => "invite_required",
+            AccountError::Internal => "internal_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AccountError::InvalidUsername
+            | AccountError::InvalidEmail
+            | AccountError::WeakPassword
+            | AccountError::InvalidToken => StatusCode::BAD_REQUEST,
+            AccountError::EmailTaken | AccountError::UsernameTaken => StatusCode::CONFLICT,
+            AccountError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            AccountError::EmailNotVerified | AccountError::InviteRequired => StatusCode::FORBIDDEN,
+            AccountError::Locked { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AccountError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for AccountError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(json!({ "error": self.code() }))).into_response();
+        if let AccountError::Locked { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+#[derive(Default)]
+pub struct AccountStore {
+    accounts: HashMap<String, Account>,
+    by_email: HashMap<String, String>,
+    usernames: HashSet<String>,
+    // Keyed by the SHA-256 of the token so a leaked store does not leak live links
+    tokens: HashMap<String, AccountToken>,
+}
+
+pub type SharedAccounts = Arc<RwLock<AccountStore>>;
+
+pub(crate) fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+pub(crate) fn token_key(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn validate_username(username: &str) -> Result<(), AccountError> {
+    let valid_chars = username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if (3..=32).contains(&username.len()) && valid_chars {
+        Ok(())
+    } else {
+        Err(AccountError::InvalidUsername)
+    }
+}
+
+fn validate_email(email: &str) -> Result<(), AccountError> {
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && domain.contains('.')
+                && !domain.contains('@')
+                && email.len() <= 254
+                && !email.contains(char::is_whitespace) =>
+        {
+            Ok(())
+        }
+        _ => Err(AccountError::InvalidEmail),
+    }
+}
+
+fn validate_password(password: &str) -> Result<(), AccountError> {
+    let length = password.chars().count();
+    if (MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        Ok(())
+    } else {
+        Err(AccountError::WeakPassword)
+    }
+}
+
+// Argon2id with a random salt, in PHC string format
+pub fn hash_password(password: &str) -> Result<String, AccountError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| {
+            eprintln!("Password hashing failed: {}", e);
+            AccountError::Internal
+        })
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+// Checked against when the email is unknown, so a miss costs as much as a wrong password
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("not a real password").unwrap_or_default())
+}
+
+// Hashing is deliberately slow, keep it off the async workers
+async fn hash_password_blocking(password: String) -> Result<String, AccountError> {
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .map_err(|_| AccountError::Internal)?
+}
+
+async fn verify_password_blocking(hash: String, password: String) -> bool {
+    tokio::task::spawn_blocking(move || verify_password(&hash, &password))
+        .await
+        .unwrap_or(false)
+}
+
+impl AccountStore {
+    pub fn create(
+        &mut self,
+        username: &str,
+        email: &str,
+        password_hash: String,
+        invite: Option<String>,
+    ) -> Result<Account, AccountError> {
+        if self.by_email.contains_key(email) {
+            return Err(AccountError::EmailTaken);
+        }
+        if self.usernames.contains(&username.to_lowercase()) {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        let account = Account {
+            id: random_token(24),
+            username: username.to_string(),
+            email: email.to_string(),
+            password_hash,
+            email_verified: false,
+            failed_logins: 0,
+            locked_until: 0,
+            invite,
+        };
+        self.by_email
+            .insert(account.email.clone(), account.id.clone());
+        self.usernames.insert(account.username.to_lowercase());
+        self.accounts.insert(account.id.clone(), account.clone());
+        Ok(account)
+    }
+
+    pub fn find_by_email(&self, email: &str) -> Option<&Account> {
+        self.accounts.get(self.by_email.get(email)?)
+    }
+
+    fn take_invite(&mut self, account_id: &str) -> Option<String> {
+        self.accounts.get_mut(account_id)?.invite.take()
+    }
+
+    fn issue_token(&mut self, account_id: &str, purpose: TokenPurpose, ttl_secs: u64) -> String {
+        let now = unix_now();
+        self.tokens.retain(|_, token| token.expires_at > now);
+        // Only the newest link of each kind works
+        self.tokens
+            .retain(|_, token| !(token.account_id == account_id && token.purpose == purpose));
+
+        let token = random_token(40);
+        self.tokens.insert(
+            token_key(&token),
+            AccountToken {
+                account_id: account_id.to_string(),
+                purpose,
+                expires_at: now + ttl_secs,
+            },
+        );
+        token
+    }
+
+    // Use up a token and return the account it was issued for
+    fn consume_token(
+        &mut self,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> Result<String, AccountError> {
+        let key = token_key(token);
+        match self.tokens.get(&key) {
+            Some(stored) if stored.purpose == purpose && stored.expires_at > unix_now() => {
+                let stored = self.tokens.remove(&key).ok_or(AccountError::InvalidToken)?;
+                Ok(stored.account_id)
+            }
+            _ => Err(AccountError::InvalidToken),
+        }
+    }
+
+    pub fn verify_email(&mut self, token: &str) -> Result<Account, AccountError> {
+        let account_id = self.consume_token(token, TokenPurpose::VerifyEmail)?;
+        let account = self
+            .accounts
+            .get_mut(&account_id)
+            .ok_or(AccountError::InvalidToken)?;
+        account.email_verified = true;
+        Ok(account.clone())
+    }
+
+    // Refuse while locked out; otherwise hand back the hash to check against
+    fn begin_login(&self, email: &str) -> Result<Option<(String, String)>, AccountError> {
+        let Some(account) = self.find_by_email(email) else {
+            return Ok(None);
+        };
+        let now = unix_now();
+        if account.locked_until > now {
+            return Err(AccountError::Locked {
+                retry_after: account.locked_until - now,
+            });
+        }
+        Ok(Some((account.id.clone(), account.password_hash.clone())))
+    }
+
+    // Record the outcome of a password check, locking the account after repeated failures.
+    // The lock is checked again: concurrent guesses may have set it while the hash was checked
+    fn finish_login(&mut self, account_id: &str, success: bool) -> Result<Account, AccountError> {
+        let account = self
+            .accounts
+            .get_mut(account_id)
+            .ok_or(AccountError::InvalidCredentials)?;
+
+        let now = unix_now();
+        if account.locked_until > now {
+            return Err(AccountError::Locked {
+                retry_after: account.locked_until - now,
+            });
+        }
+        if success {
+            account.failed_logins = 0;
+            return Ok(account.clone());
+        }
+
+        account.failed_logins += 1;
+        if account.failed_logins >= MAX_FAILED_LOGINS {
+            println!(
+                "Locking account {} after repeated failed logins",
+                account.id
+            );
+            account.failed_logins = 0;
+            account.locked_until = now + LOCKOUT_SECS;
+        }
+        Err(AccountError::InvalidCredentials)
+    }
+
+    pub fn set_password(
+        &mut self,
+        token: &str,
+        password_hash: String,
+    ) -> Result<Account, AccountError> {
+        let account_id = self.consume_token(token, TokenPurpose::ResetPassword)?;
+        let account = self
+            .accounts
+            .get_mut(&account_id)
+            .ok_or(AccountError::InvalidToken)?;
+        account.password_hash = password_hash;
+        // The reset link reached the inbox, which proves the address as well
+        account.email_verified = true;
+        account.failed_logins = 0;
+        account.locked_until = 0;
+        Ok(account.clone())
+    }
+}
+
+async fn send_verification(state: &AppState, account: &Account, token: &str) {
+    let link = format!("{}/api/accounts/verify?token={}", public_base_url(), token);
+    let email = Email {
+        to: account.email.clone(),
+        subject: "Verify your Rustcord email".to_string(),
+        body: format!(
+            "Hi {},\n\nConfirm your email address to finish setting up your account:\n{}\n\nThe link expires in 24 hours.",
+            account.username, link
+        ),
+    };
+    if let Err(e) = state.mailer.send(email).await {
+        eprintln!("Could not send verification email: {}", e);
+    }
+}
+
+async fn send_account_exists(state: &AppState, account: &Account) {
+    let email = Email {
+        to: account.email.clone(),
+        subject: "You already have a Rustcord account".to_string(),
+        body: format!(
+            "Hi {},\n\nSomeone tried to create a new account with this email address, which already has one. Log in here:\n{}/login\n\nIf you forgot your password you can reset it from that page. If this was not you, you can ignore this email.",
+            account.username, FRONTEND_URL
+        ),
+    };
+    if let Err(e) = state.mailer.send(email).await {
+        eprintln!("Could not send account exists email: {}", e);
+    }
+}
+
+async fn send_password_reset(state: &AppState, account: &Account, token: &str) {
+    let link = format!("{}/reset-password?token={}", FRONTEND_URL, token);
+    let email = Email {
+        to: account.email.clone(),
+        subject: "Reset your Rustcord password".to_string(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password for this account. If it was you, choose a new one here:\n{}\n\nThe link expires in 1 hour. If you did not ask for this you can ignore this email.",
+            account.username, link
+        ),
+    };
+    if let Err(e) = state.mailer.send(email).await {
+        eprintln!("Could not send password reset email: {}", e);
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    username: String,
+    email: String,
+    password: String,
+    #[serde(default)]
+    invite: Option<String>,
+}
+
+// The same whether or not the email already had an account, so it tells nobody which do
+#[derive(Serialize)]
+struct RegisterResponse {
+    email: String,
+    email_verified: bool,
+}
+
+// POST /api/accounts/register
+async fn register(
+    State(state): State<AppState>,
+    Json(request): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<RegisterResponse>), AccountError> {
+    let username = request.username.trim();
+    let email = normalize_email(&request.email);
+    validate_username(username)?;
+    validate_email(&email)?;
+    validate_password(&request.password)?;
+
+    // Invite-only guilds need a valid invite before an account is created
+    let invite = request.invite.filter(|code| !code.is_empty());
+    let invite_ok = match &invite {
+        Some(code) => state.invites.read().await.validate(code).is_ok(),
+        None => !state.guild.read().await.is_invite_only(),
+    };
+    if !invite_ok {
+        return Err(AccountError::InviteRequired);
+    }
+
+    let password_hash = hash_password_blocking(request.password).await?;
+    let created = {
+        let mut accounts = state.accounts.write().await;
+        match accounts.create(username, &email, password_hash, invite) {
+            Ok(account) => {
+                let token = accounts.issue_token(
+                    &account.id,
+                    TokenPurpose::VerifyEmail,
+                    VERIFICATION_TOKEN_TTL_SECS,
+                );
+                Ok((account, token))
+            }
+            // Answered like a new account; only the owner of the address hears about it
+            Err(AccountError::EmailTaken) => Err(accounts.find_by_email(&email).cloned()),
+            Err(err) => return Err(err),
+        }
+    };
+    match created {
+        Ok((account, token)) => {
+            println!("Registered local account {}", account.id);
+            send_verification(&state, &account, &token).await;
+        }
+        Err(existing) => {
+            if let Some(account) = existing {
+                send_account_exists(&state, &account).await;
+            }
+        }
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisterResponse {
+            email,
+            email_verified: false,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+// GET /api/accounts/verify?token=..., the link from the verification email
+async fn verify_email(State(state): State<AppState>, Query(query): Query<TokenQuery>) -> Redirect {
+    match state.accounts.write().await.verify_email(&query.token) {
+        Ok(account) => {
+            println!("Verified email for account {}", account.id);
+            Redirect::to(&format!("{}/login?verified=1", FRONTEND_URL))
+        }
+        Err(err) => Redirect::to(&format!("{}/login?error={}", FRONTEND_URL, err.code())),
+    }
+}
+
+#[derive(Deserialize)]
+struct EmailRequest {
+    email: String,
+}
+
+// POST /api/accounts/verify/resend; answers the same whether or not the account exists
+async fn resend_verification(
+    State(state): State<AppState>,
+    Json(request): Json<EmailRequest>,
+) -> StatusCode {
+    let email = normalize_email(&request.email);
+    let pending = {
+        let mut accounts = state.accounts.write().await;
+        match accounts.find_by_email(&email).cloned() {
+            Some(account) if !account.email_verified => {
+                let token = accounts.issue_token(
+                    &account.id,
+                    TokenPurpose::VerifyEmail,
+                    VERIFICATION_TOKEN_TTL_SECS,
+                );
+                Some((account, token))
+            }
+            _ => None,
+        }
+    };
+
+    if let Some((account, token)) = pending {
+        send_verification(&state, &account, &token).await;
+    }
+    StatusCode::ACCEPTED
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    email: String,
+    password: String,
+}
+
+// POST /api/accounts/login; accounts with 2FA get a challenge instead of a session
+async fn login(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>, AccountError> {
+    let email = normalize_email(&request.email);
+    let candidate = state.accounts.read().await.begin_login(&email)?;
+
+    let Some((account_id, hash)) = candidate else {
+        verify_password_blocking(dummy_hash().to_string(), request.password).await;
+        return Err(AccountError::InvalidCredentials);
+    };
+    let success = verify_password_blocking(hash, request.password).await;
+    let account = state
+        .accounts
+        .write()
+        .await
+        .finish_login(&account_id, success)?;
+
+    if !account.email_verified {
+        return Err(AccountError::EmailNotVerified);
+    }
+
+    // Only now is the address proven, so only now may it link to an existing user
+    let identity = account.user();
+    let user = state.users.write().await.resolve(&identity);
+
+    let invite = state.accounts.write().await.take_invite(&account.id);
+    if let Some(code) = invite
+        && let Err(err) = invites::redeem_for(&state, &code, &user.id).await
+    {
+        eprintln!(
+            "Invite {} not redeemed: {}",
+            logging::token_hint(&code),
+            err.code()
+        );
+    }
+    if !state.guild.read().await.is_member(&user.id) {
+        return Err(AccountError::InviteRequired);
+    }
+
+    if let Some(challenge) = two_factor::challenge_login(&state, &user.id, &identity).await {
+        return Ok(Json(LoginResponse::TwoFactorRequired(challenge)));
+    }
+
+    let (token, session) = state
+        .sessions
+        .write()
+        .await
+        .start(&user.id, identity, session::device(&headers))
+        .map_err(|_| AccountError::Internal)?;
+    Ok(Json(LoginResponse::Complete(
+        session_response(token, &session).0,
+    )))
+}
+
+// POST /api/accounts/password-reset; answers the same whether or not the account exists
+async fn request_password_reset(
+    State(state): State<AppState>,
+    Json(request): Json<EmailRequest>,
+) -> StatusCode {
+    let email = normalize_email(&request.email);
+    let pending = {
+        let mut accounts = state.accounts.write().await;
+        accounts.find_by_email(&email).cloned().map(|account| {
+            let token = accounts.issue_token(
+                &account.id,
+                TokenPurpose::ResetPassword,
+                RESET_TOKEN_TTL_SECS,
+            );
+            (account, token)
+        })
+    };
+
+    if let Some((account, token)) = pending {
+        send_password_reset(&state, &account, &token).await;
+    }
+    StatusCode::ACCEPTED
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+// POST /api/accounts/password-reset/confirm; signs the account out everywhere
+async fn confirm_password_reset(
+    State(state): State<AppState>,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, AccountError> {
+    validate_password(&request.password)?;
+    let password_hash = hash_password_blocking(request.password).await?;
+    let account = state
+        .accounts
+        .write()
+        .await
+        .set_password(&request.token, password_hash)?;
+
+    let user_id = state
+        .users
+        .read()
+        .await
+        .user_for(&account.user().identity_key());
+    let revoked = match user_id {
+        Some(user_id) => state.sessions.write().await.revoke_all(&user_id),
+        None => Vec::new(),
+    };
+    disconnect_sessions(&state, &revoked).await;
+
+    println!("Password reset for account {}", account.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/accounts/register", post(register))
+        .route("/api/accounts/verify", get(verify_email))
+        .route("/api/accounts/verify/resend", post(resend_verification))
+        .route("/api/accounts/login", post(login))
+        .route("/api/accounts/password-reset", post(request_password_reset))
+        .route(
+            "/api/accounts/password-reset/confirm",
+            post(confirm_password_reset),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::MemoryMailer;
+
+    fn test_state() -> (AppState, Arc<MemoryMailer>) {
+        let mailer = Arc::new(MemoryMailer::default());
+        let state = AppState {
+            mailer: mailer.clone(),
+            ..AppState::new()
+        };
+        (state, mailer)
+    }
+
+    // The token from the link in the most recent email
+    fn last_token(mailer: &MemoryMailer) -> String {
+        let sent = mailer.sent.lock().unwrap();
+        let body = &sent.last().expect("an email").body;
+        let start = body.find("token=").expect("a link") + "token=".len();
+        body[start..]
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .collect()
+    }
+
+    async fn register_ferris(state: &AppState) -> Result<StatusCode, AccountError> {
+        register(
+            State(state.clone()),
+            Json(RegisterRequest {
+                username: "ferris".to_string(),
+                email: "Ferris@Example.com".to_string(),
+                password: "correct horse battery".to_string(),
+                invite: None,
+            }),
+        )
+        .await
+        .map(|(status, _)| status)
+    }
+
+    async fn login_as(state: &AppState, password: &str) -> Result<String, AccountError> {
+        login(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(LoginRequest {
+                email: "ferris@example.com".to_string(),
+                password: password.to_string(),
+            }),
+        )
+        .await
+        .map(|Json(response)| match response {
+            LoginResponse::Complete(session) => session.token,
+            LoginResponse::TwoFactorRequired(_) => panic!("unexpected 2FA challenge"),
+        })
+    }
+
+    #[tokio::test]
+    async fn registration_requires_email_verification_before_login() {
+        let (state, mailer) = test_state();
+        assert_eq!(register_ferris(&state).await, Ok(StatusCode::CREATED));
+        let token = last_token(&mailer);
+
+        // Registering a taken email looks the same; the owner gets a heads-up instead
+        assert_eq!(register_ferris(&state).await, Ok(StatusCode::CREATED));
+        {
+            let sent = mailer.sent.lock().unwrap();
+            assert_eq!(sent.len(), 2);
+            assert_eq!(sent[1].to, "ferris@example.com");
+            assert_eq!(sent[1].subject, "You already have a Rustcord account");
+            assert!(!sent[1].body.contains("token="));
+        }
+
+        assert_eq!(
+            login_as(&state, "correct horse battery").await,
+            Err(AccountError::EmailNotVerified)
+        );
+
+        let redirect = verify_email(
+            State(state.clone()),
+            Query(TokenQuery {
+                token: token.clone(),
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(
+            redirect.headers()["location"],
+            format!("{}/login?verified=1", FRONTEND_URL).as_str()
+        );
+        assert!(state.accounts.write().await.verify_email(&token).is_err());
+
+        let session_token = login_as(&state, "correct horse battery").await.unwrap();
+        let session = state.sessions.read().await.verify(&session_token).unwrap();
+        assert!(session.user_id.starts_with("local_"));
+        assert_eq!(session.user.email, "ferris@example.com");
+    }
+
+    #[tokio::test]
+    async fn login_waits_for_the_second_factor_when_enabled() {
+        let (state, mailer) = test_state();
+        register_ferris(&state).await.unwrap();
+        let token = last_token(&mailer);
+        let account = state.accounts.write().await.verify_email(&token).unwrap();
+        let provisioning = state
+            .two_factor
+            .write()
+            .await
+            .begin_enrollment(&account.user().identity_key(), &account.email)
+            .unwrap();
+
+        // An unconfirmed enrollment is not enforced yet
+        assert!(login_as(&state, "correct horse battery").await.is_ok());
+
+        let sessions_before = state
+            .sessions
+            .read()
+            .await
+            .list(&account.user().identity_key())
+            .len();
+        state
+            .two_factor
+            .write()
+            .await
+            .confirm_enrollment(
+                &account.user().identity_key(),
+                &two_factor::code_for(&provisioning, 0),
+            )
+            .unwrap();
+        let Json(response) = login(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(LoginRequest {
+                email: "ferris@example.com".to_string(),
+                password: "correct horse battery".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(response, LoginResponse::TwoFactorRequired(_)));
+        assert_eq!(
+            state
+                .sessions
+                .read()
+                .await
+                .list(&account.user().identity_key())
+                .len(),
+            sessions_before
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_lock_the_account() {
+        let (state, mailer) = test_state();
+        register_ferris(&state).await.unwrap();
+        let token = last_token(&mailer);
+        state.accounts.write().await.verify_email(&token).unwrap();
+
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert_eq!(
+                login_as(&state, "wrong password!").await,
+                Err(AccountError::InvalidCredentials)
+            );
+        }
+        assert!(matches!(
+            login_as(&state, "correct horse battery").await,
+            Err(AccountError::Locked { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_lock_set_during_the_password_check_still_holds() {
+        let (state, mailer) = test_state();
+        register_ferris(&state).await.unwrap();
+        let token = last_token(&mailer);
+        state.accounts.write().await.verify_email(&token).unwrap();
+
+        let mut accounts = state.accounts.write().await;
+        let (account_id, _) = accounts.begin_login("ferris@example.com").unwrap().unwrap();
+        // Guesses running alongside lock the account before this check finishes
+        for _ in 0..MAX_FAILED_LOGINS {
+            let _ = accounts.finish_login(&account_id, false);
+        }
+        assert!(matches!(
+            accounts.finish_login(&account_id, true),
+            Err(AccountError::Locked { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_email_gets_the_same_answers() {
+        let (state, mailer) = test_state();
+
+        assert_eq!(
+            login_as(&state, "correct horse battery").await,
+            Err(AccountError::InvalidCredentials)
+        );
+        let status = request_password_reset(
+            State(state.clone()),
+            Json(EmailRequest {
+                email: "nobody@example.com".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(mailer.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn password_reset_replaces_the_password_and_revokes_sessions() {
+        let (state, mailer) = test_state();
+        register_ferris(&state).await.unwrap();
+        let token = last_token(&mailer);
+        state.accounts.write().await.verify_email(&token).unwrap();
+        let old_session = login_as(&state, "correct horse battery").await.unwrap();
+
+        request_password_reset(
+            State(state.clone()),
+            Json(EmailRequest {
+                email: "ferris@example.com".to_string(),
+            }),
+        )
+        .await;
+        let reset_token = last_token(&mailer);
+
+        let confirm = |password: &str| {
+            confirm_password_reset(
+                State(state.clone()),
+                Json(ResetPasswordRequest {
+                    token: reset_token.clone(),
+                    password: password.to_string(),
+                }),
+            )
+        };
+        assert_eq!(confirm("short").await, Err(AccountError::WeakPassword));
+        assert_eq!(
+            confirm("a brand new passphrase").await,
+            Ok(StatusCode::NO_CONTENT)
+        );
+        assert_eq!(
+            confirm("a brand new passphrase").await,
+            Err(AccountError::InvalidToken)
+        );
+
+        assert!(state.sessions.read().await.verify(&old_session).is_err());
+        assert_eq!(
+            login_as(&state, "correct horse battery").await,
+            Err(AccountError::InvalidCredentials)
+        );
+        assert!(login_as(&state, "a brand new passphrase").await.is_ok());
+    }
+}
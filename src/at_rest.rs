@@ -0,0 +1,278 @@
+// src/at_rest.rs
+// Encryption for what the server keeps on disk. A store's file holds a single record: its
+// whole contents, sealed on every write under a fresh random data key, stored next to it
+// wrapped by the master key from STORAGE_MASTER_KEY. Data keys are per file write, not per
+// message or event. Rotating the master key rewraps data keys; records are never re-encrypted.
+//
+// Rotation: set STORAGE_MASTER_KEY to the new key and move the old one to
+// STORAGE_RETIRED_MASTER_KEYS. Records are rewrapped as they are read.
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const RECORD_VERSION: u8 = 1;
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AtRestError {
+    // Storage is configured but STORAGE_MASTER_KEY is not
+    MissingKey,
+    // A configured key is not base64 of 32 bytes
+    InvalidKey,
+    // Sealed under a master key that is neither current nor retired
+    UnknownMasterKey(String),
+    // Not a sealed record, or tampered with
+    Corrupt,
+    Io(ErrorKind),
+}
+
+// A master key is named by a hash of it, so records can say which one wraps their key
+struct MasterKey {
+    id: String,
+    key: [u8; KEY_LENGTH],
+}
+
+impl MasterKey {
+    fn new(key: [u8; KEY_LENGTH]) -> Self {
+        let id = format!("{:x}", Sha256::digest(key))[..16].to_string();
+        MasterKey { id, key }
+    }
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; KEY_LENGTH], AtRestError> {
+    BASE64
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(AtRestError::InvalidKey)
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn encrypt(key: &[u8; KEY_LENGTH], context: &str, plaintext: &[u8]) -> String {
+    let nonce: [u8; NONCE_LENGTH] = random_bytes();
+    let payload = Payload {
+        msg: plaintext,
+        aad: context.as_bytes(),
+    };
+    let ciphertext = Aes256Gcm::new(key.into())
+        .encrypt(&nonce.into(), payload)
+        .expect("plaintext fits in one record");
+    BASE64.encode([&nonce[..], &ciphertext].concat())
+}
+
+fn decrypt(key: &[u8; KEY_LENGTH], context: &str, encoded: &str) -> Result<Vec<u8>, AtRestError> {
+    let data = BASE64.decode(encoded).map_err(|_| AtRestError::Corrupt)?;
+    if data.len() < NONCE_LENGTH {
+        return Err(AtRestError::Corrupt);
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: context.as_bytes(),
+    };
+    Aes256Gcm::new(key.into())
+        .decrypt(nonce.into(), payload)
+        .map_err(|_| AtRestError::Corrupt)
+}
+
+// One encrypted record as stored. The context it is sealed under names what it holds
+// and is authenticated, so a record cannot be swapped in for another kind
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedRecord {
+    version: u8,
+    master_key: String,
+    data_key: String,
+    ciphertext: String,
+}
+
+// The current master key, which seals everything new, and retired ones that still open old records
+pub struct Keyring {
+    current: MasterKey,
+    retired: Vec<MasterKey>,
+}
+
+impl Keyring {
+    pub fn new(current: [u8; KEY_LENGTH], retired: &[[u8; KEY_LENGTH]]) -> Self {
+        Keyring {
+            current: MasterKey::new(current),
+            retired: retired.iter().copied().map(MasterKey::new).collect(),
+        }
+    }
+
+    // STORAGE_MASTER_KEY and STORAGE_RETIRED_MASTER_KEYS (comma separated), base64 of 32 bytes
+    // each. Call env_loader::load_env_file first to pick them up from .env
+    pub fn from_env() -> Result<Self, AtRestError> {
+        let current = std::env::var("STORAGE_MASTER_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty())
+            .ok_or(AtRestError::MissingKey)?;
+        let retired = std::env::var("STORAGE_RETIRED_MASTER_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|key| !key.trim().is_empty())
+            .map(decode_key)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Keyring::new(decode_key(&current)?, &retired))
+    }
+
+    fn master(&self, id: &str) -> Result<&MasterKey, AtRestError> {
+        std::iter::once(&self.current)
+            .chain(&self.retired)
+            .find(|master| master.id == id)
+            .ok_or_else(|| AtRestError::UnknownMasterKey(id.to_string()))
+    }
+
+    fn data_key(
+        &self,
+        context: &str,
+        record: &SealedRecord,
+    ) -> Result<[u8; KEY_LENGTH], AtRestError> {
+        if record.version != RECORD_VERSION {
+            return Err(AtRestError::Corrupt);
+        }
+        let master = self.master(&record.master_key)?;
+        decrypt(&master.key, context, &record.data_key)?
+            .try_into()
+            .map_err(|_| AtRestError::Corrupt)
+    }
+
+    pub fn seal(&self, context: &str, plaintext: &[u8]) -> SealedRecord {
+        let data_key: [u8; KEY_LENGTH] = random_bytes();
+        SealedRecord {
+            version: RECORD_VERSION,
+            master_key: self.current.id.clone(),
+            data_key: encrypt(&self.current.key, context, &data_key),
+            ciphertext: encrypt(&data_key, context, plaintext),
+        }
+    }
+
+    pub fn open(&self, context: &str, record: &SealedRecord) -> Result<Vec<u8>, AtRestError> {
+        decrypt(
+            &self.data_key(context, record)?,
+            context,
+            &record.ciphertext,
+        )
+    }
+
+    // Move a record's data key under the current master key; false if it already was
+    pub fn rewrap(&self, context: &str, record: &mut SealedRecord) -> Result<bool, AtRestError> {
+        if record.master_key == self.current.id {
+            return Ok(false);
+        }
+        let data_key = self.data_key(context, record)?;
+        record.master_key = self.current.id.clone();
+        record.data_key = encrypt(&self.current.key, context, &data_key);
+        Ok(true)
+    }
+}
+
+// A file holding one sealed record, replaced whole on every write
+pub struct EncryptedFile {
+    path: PathBuf,
+    context: &'static str,
+    keyring: Keyring,
+}
+
+impl EncryptedFile {
+    pub fn new(path: PathBuf, context: &'static str, keyring: Keyring) -> Self {
+        EncryptedFile {
+            path,
+            context,
+            keyring,
+        }
+    }
+
+    // None if nothing was written yet. Anything else that does not open is an error,
+    // never treated as empty, so it is not overwritten either
+    pub fn read(&self) -> Result<Option<Vec<u8>>, AtRestError> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(AtRestError::Io(err.kind())),
+        };
+        let mut record: SealedRecord =
+            serde_json::from_slice(&bytes).map_err(|_| AtRestError::Corrupt)?;
+        let plaintext = self.keyring.open(self.context, &record)?;
+        if self.keyring.rewrap(self.context, &mut record)? {
+            self.write_record(&record)
+                .map_err(|err| AtRestError::Io(err.kind()))?;
+        }
+        Ok(Some(plaintext))
+    }
+
+    pub fn write(&self, plaintext: &[u8]) -> std::io::Result<()> {
+        self.write_record(&self.keyring.seal(self.context, plaintext))
+    }
+
+    // `write` from async code: sealing and disk I/O run on a blocking thread, not the runtime
+    pub async fn write_blocking(self: Arc<Self>, plaintext: Vec<u8>) -> std::io::Result<()> {
+        tokio::task::spawn_blocking(move || self.write(&plaintext))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    // Write to a temporary file and rename it over the old one, so a crash never leaves half a record
+    fn write_record(&self, record: &SealedRecord) -> std::io::Result<()> {
+        let bytes = serde_json::to_vec(record).map_err(std::io::Error::other)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_open_only_with_their_key_and_context() {
+        let keyring = Keyring::new([1; 32], &[]);
+        let record = keyring.seal("event_queue", b"secret");
+        assert_eq!(keyring.open("event_queue", &record).unwrap(), b"secret");
+        assert_eq!(keyring.open("history", &record), Err(AtRestError::Corrupt));
+        // Each record gets its own data key
+        assert_ne!(
+            keyring.seal("event_queue", b"secret").data_key,
+            record.data_key
+        );
+
+        let other = Keyring::new([2; 32], &[]);
+        assert!(matches!(
+            other.open("event_queue", &record),
+            Err(AtRestError::UnknownMasterKey(_))
+        ));
+    }
+
+    #[test]
+    fn rotation_rewraps_data_keys_and_leaves_records_alone() {
+        let old = Keyring::new([1; 32], &[]);
+        let mut record = old.seal("event_queue", b"secret");
+        let ciphertext = record.ciphertext.clone();
+
+        let rotated = Keyring::new([2; 32], &[[1; 32]]);
+        assert_eq!(rotated.open("event_queue", &record).unwrap(), b"secret");
+        assert_eq!(rotated.rewrap("event_queue", &mut record), Ok(true));
+        assert_eq!(rotated.rewrap("event_queue", &mut record), Ok(false));
+        assert_eq!(record.ciphertext, ciphertext);
+
+        // Once rewrapped, the retired key is no longer needed
+        let retired_dropped = Keyring::new([2; 32], &[]);
+        assert_eq!(
+            retired_dropped.open("event_queue", &record).unwrap(),
+            b"secret"
+        );
+    }
+}
@@ -1,11 +1,75 @@
 // src/auth.rs
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::{Rng, distributions::Alphanumeric};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
-// OAuth provider types
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum OAuthProvider {
-    Google,
-    GitHub,
+use crate::logging;
+use crate::unix_now;
+use crate::users::identity_key;
+
+// How long a user has to come back from the provider
+const OAUTH_STATE_TTL_SECS: u64 = 10 * 60;
+
+// Identifies the provider an account came from, by the slug used in `/auth/:provider`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OAuthProvider(String);
+
+impl OAuthProvider {
+    pub fn new(slug: &str) -> Self {
+        OAuthProvider(slug.to_string())
+    }
+
+    pub fn slug(&self) -> &str {
+        &self.0
+    }
+}
+
+// Ways a login can fail, each with a stable code the frontend can show a message for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    UnknownProvider,
+    NotConfigured,
+    // The provider sent the user back with `error=...`, e.g. after clicking Cancel
+    ProviderDenied { error: String },
+    InvalidState,
+    MissingCode,
+    TokenExchangeFailed,
+    ProfileFetchFailed,
+    EmailMissing,
+    InviteRequired,
+    // Discovery document or signing keys could not be fetched
+    ProviderUnavailable,
+    InvalidIdToken,
+    // Linking: the link request expired, or the identity belongs to another user
+    LinkExpired,
+    IdentityTaken,
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::UnknownProvider => "unknown_provider",
+            AuthError::NotConfigured => "provider_not_configured",
+            AuthError::ProviderDenied { error } if error == "access_denied" => "access_denied",
+            AuthError::ProviderDenied { .. } => "provider_error",
+            AuthError::InvalidState => "invalid_state",
+            AuthError::MissingCode => "missing_code",
+            AuthError::TokenExchangeFailed => "token_exchange_failed",
+            AuthError::ProfileFetchFailed => "profile_fetch_failed",
+            AuthError::EmailMissing => "email_missing",
+            AuthError::InviteRequired => "invite_required",
+            AuthError::ProviderUnavailable => "provider_unavailable",
+            AuthError::InvalidIdToken => "invalid_id_token",
+            AuthError::LinkExpired => "link_expired",
+            AuthError::IdentityTaken => "identity_already_linked",
+        }
+    }
 }
 
 // User profile from OAuth providers
@@ -14,11 +78,112 @@ pub struct OAuthUser {
     pub id: String,
     pub username: String,
     pub email: String,
+    // Whether the provider vouches that this address belongs to the user
+    #[serde(default)]
+    pub email_verified: bool,
     pub avatar: Option<String>,
     pub provider: OAuthProvider,
 }
 
-// OAuth configuration
+impl OAuthUser {
+    // Unique across providers; the user this identity logs in as comes from crate::users
+    pub fn identity_key(&self) -> String {
+        identity_key(&self.provider, &self.id)
+    }
+}
+
+// An authorization request we sent a user off with, keyed by its `state`
+struct PendingAuthorization {
+    provider: OAuthProvider,
+    code_verifier: String,
+    nonce: String,
+    // Set when a logged-in user is adding this provider to their account
+    link_user: Option<String>,
+    created_at: u64,
+}
+
+// Per-request state and PKCE verifiers for flows that have not come back yet
+#[derive(Default)]
+pub struct OAuthStateStore {
+    pending: HashMap<String, PendingAuthorization>,
+}
+
+pub type SharedOAuthStates = Arc<RwLock<OAuthStateStore>>;
+
+// Parameters added to the authorize URL
+pub struct AuthorizationRequest {
+    pub state: String,
+    pub code_challenge: String,
+    // Echoed back in OpenID Connect ID tokens
+    pub nonce: String,
+}
+
+// What the callback needs to finish a flow started by `begin`
+pub struct CompletedAuthorization {
+    pub code_verifier: String,
+    pub nonce: String,
+    pub link_user: Option<String>,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+impl OAuthStateStore {
+    // Start a flow: remember a fresh state and PKCE verifier, return the S256 challenge
+    pub fn begin(
+        &mut self,
+        provider: &OAuthProvider,
+        link_user: Option<String>,
+    ) -> AuthorizationRequest {
+        let now = unix_now();
+        self.pending
+            .retain(|_, pending| now.saturating_sub(pending.created_at) < OAUTH_STATE_TTL_SECS);
+
+        let state = random_string(32);
+        let code_verifier = random_string(64);
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let nonce = random_string(32);
+
+        self.pending.insert(
+            state.clone(),
+            PendingAuthorization {
+                provider: provider.clone(),
+                code_verifier,
+                nonce: nonce.clone(),
+                link_user,
+                created_at: now,
+            },
+        );
+
+        AuthorizationRequest {
+            state,
+            code_challenge,
+            nonce,
+        }
+    }
+
+    // Consume a state coming back on the callback and hand out its PKCE verifier and nonce
+    pub fn complete(
+        &mut self,
+        state: &str,
+        provider: &OAuthProvider,
+    ) -> Option<CompletedAuthorization> {
+        let pending = self.pending.remove(state)?;
+        let fresh = unix_now().saturating_sub(pending.created_at) < OAUTH_STATE_TTL_SECS;
+        (fresh && pending.provider == *provider).then_some(CompletedAuthorization {
+            code_verifier: pending.code_verifier,
+            nonce: pending.nonce,
+            link_user: pending.link_user,
+        })
+    }
+}
+
+// Client registration with a provider
 #[derive(Debug, Clone)]
 pub struct OAuthConfig {
     pub client_id: String,
@@ -26,162 +191,93 @@ pub struct OAuthConfig {
     pub redirect_uri: String,
 }
 
-// Get OAuth configuration from environment variables
-pub fn get_oauth_config(provider: &OAuthProvider) -> Option<OAuthConfig> {
-    match provider {
-        OAuthProvider::Google => {
-            let client_id = std::env::var("GOOGLE_CLIENT_ID").ok()?;
-            let client_secret = std::env::var("GOOGLE_CLIENT_SECRET").ok()?;
-            let redirect_uri = std::env::var("GOOGLE_REDIRECT_URI")
-                .unwrap_or_else(|_| "http://localhost:8080/auth/google/callback".to_string());
-
-            Some(OAuthConfig {
-                client_id,
-                client_secret,
-                redirect_uri,
-            })
-        }
-        OAuthProvider::GitHub => {
-            let client_id = std::env::var("GITHUB_CLIENT_ID").ok()?;
-            let client_secret = std::env::var("GITHUB_CLIENT_SECRET").ok()?;
-            let redirect_uri = std::env::var("GITHUB_REDIRECT_URI")
-                .unwrap_or_else(|_| "http://localhost:8080/auth/github/callback".to_string());
-
-            Some(OAuthConfig {
-                client_id,
-                client_secret,
-                redirect_uri,
-            })
-        }
+impl OAuthConfig {
+    // Read `<PREFIX>_CLIENT_ID`, `<PREFIX>_CLIENT_SECRET` and `<PREFIX>_REDIRECT_URI`;
+    // public clients (PKCE only) may leave the secret unset
+    pub fn from_env(prefix: &str, slug: &str, secret_required: bool) -> Option<Self> {
+        let client_id = std::env::var(format!("{}_CLIENT_ID", prefix)).ok()?;
+        let client_secret = match std::env::var(format!("{}_CLIENT_SECRET", prefix)) {
+            Ok(secret) => secret,
+            Err(_) if !secret_required => String::new(),
+            Err(_) => return None,
+        };
+        let redirect_uri = std::env::var(format!("{}_REDIRECT_URI", prefix))
+            .unwrap_or_else(|_| format!("http://localhost:8080/auth/{}/callback", slug));
+
+        Some(OAuthConfig {
+            client_id,
+            client_secret,
+            redirect_uri,
+        })
     }
 }
 
-// Exchange authorization code for access token
-pub async fn exchange_code_for_token(
-    provider: &OAuthProvider,
-    code: &str,
+// Build an authorization code + PKCE request against `endpoint`
+pub fn authorize_url(
+    endpoint: &str,
     config: &OAuthConfig,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-
-    match provider {
-        OAuthProvider::Google => {
-            let params = [
-                ("client_id", config.client_id.as_str()),
-                ("client_secret", config.client_secret.as_str()),
-                ("code", code),
-                ("grant_type", "authorization_code"),
-                ("redirect_uri", config.redirect_uri.as_str()),
-            ];
-
-            let res = client
-                .post("https://oauth2.googleapis.com/token")
-                .form(&params)
-                .send()
-                .await?;
-
-            let json: serde_json::Value = res.json().await?;
-            let access_token = json["access_token"]
-                .as_str()
-                .ok_or("No access token in response")?;
-
-            Ok(access_token.to_string())
-        }
-        OAuthProvider::GitHub => {
-            let params = [
-                ("client_id", config.client_id.as_str()),
-                ("client_secret", config.client_secret.as_str()),
-                ("code", code),
-                ("redirect_uri", config.redirect_uri.as_str()),
-            ];
-
-            let res = client
-                .post("https://github.com/login/oauth/access_token")
-                .header("Accept", "application/json")
-                .form(&params)
-                .send()
-                .await?;
-
-            let json: serde_json::Value = res.json().await?;
-            let access_token = json["access_token"]
-                .as_str()
-                .ok_or("No access token in response")?;
-
-            Ok(access_token.to_string())
-        }
+    scopes: &str,
+    request: &AuthorizationRequest,
+    extra_params: &[(&str, &str)],
+) -> String {
+    let separator = if endpoint.contains('?') { '&' } else { '?' };
+    let mut url = format!(
+        "{}{}client_id={}&redirect_uri={}&response_type=code&scope={}&state={}&code_challenge={}&code_challenge_method=S256",
+        endpoint,
+        separator,
+        urlencoding::encode(&config.client_id),
+        urlencoding::encode(&config.redirect_uri),
+        urlencoding::encode(scopes),
+        request.state,
+        request.code_challenge
+    );
+    for (key, value) in extra_params {
+        url.push_str(&format!("&{}={}", key, urlencoding::encode(value)));
     }
+    url
 }
 
-// Get user profile from access token
-pub async fn get_user_profile(
-    provider: &OAuthProvider,
-    access_token: &str,
-) -> Result<OAuthUser, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-
-    match provider {
-        OAuthProvider::Google => {
-            let res = client
-                .get("https://www.googleapis.com/oauth2/v2/userinfo")
-                .header("Authorization", format!("Bearer {}", access_token))
-                .send()
-                .await?;
-
-            let json: serde_json::Value = res.json().await?;
-
-            Ok(OAuthUser {
-                id: json["id"].as_str().unwrap_or_default().to_string(),
-                username: json["name"].as_str().unwrap_or_default().to_string(),
-                email: json["email"].as_str().unwrap_or_default().to_string(),
-                avatar: json["picture"].as_str().map(|s| s.to_string()),
-                provider: OAuthProvider::Google,
-            })
-        }
-        OAuthProvider::GitHub => {
-            let res = client
-                .get("https://api.github.com/user")
-                .header("Authorization", format!("Bearer {}", access_token))
-                .header("User-Agent", "Rustcord")
-                .send()
-                .await?;
-
-            let json: serde_json::Value = res.json().await?;
-
-            // Get email separately as it might not be in the user endpoint
-            let email = if let Some(email) = json["email"].as_str() {
-                email.to_string()
-            } else {
-                // Try to get email from emails endpoint
-                match client
-                    .get("https://api.github.com/user/emails")
-                    .header("Authorization", format!("Bearer {}", access_token))
-                    .header("User-Agent", "Rustcord")
-                    .send()
-                    .await
-                {
-                    Ok(email_res) => {
-                        if let Ok(emails) = email_res.json::<Vec<serde_json::Value>>().await {
-                            emails
-                                .iter()
-                                .find(|email| email["primary"].as_bool().unwrap_or(false))
-                                .and_then(|email| email["email"].as_str())
-                                .unwrap_or("")
-                                .to_string()
-                        } else {
-                            "".to_string()
-                        }
-                    }
-                    Err(_) => "".to_string(),
-                }
-            };
-
-            Ok(OAuthUser {
-                id: json["id"].to_string(),
-                username: json["login"].as_str().unwrap_or_default().to_string(),
-                email,
-                avatar: json["avatar_url"].as_str().map(|s| s.to_string()),
-                provider: OAuthProvider::GitHub,
-            })
+// Read a provider response as JSON, treating non-success statuses as `failure`
+pub(crate) async fn provider_json(
+    res: Result<reqwest::Response, reqwest::Error>,
+    failure: AuthError,
+) -> Result<serde_json::Value, AuthError> {
+    let res = res.map_err(|e| {
+        eprintln!("OAuth provider request failed: {}", e);
+        failure.clone()
+    })?;
+
+    let status = res.status();
+    let body = res.text().await.map_err(|e| {
+        eprintln!("OAuth provider response could not be read: {}", e);
+        failure.clone()
+    })?;
+    if !status.is_success() {
+        eprintln!(
+            "OAuth provider returned {}: {}",
+            status,
+            logging::redact(&body)
+        );
+        return Err(failure);
+    }
+
+    serde_json::from_str(&body).map_err(|e| {
+        eprintln!("OAuth provider returned invalid JSON: {}", e);
+        failure
+    })
+}
+
+// Pull the access token out of a token response; GitHub reports errors with a 200
+pub(crate) fn access_token_from(json: &serde_json::Value) -> Result<String, AuthError> {
+    match json["access_token"].as_str() {
+        Some(token) if !token.is_empty() => Ok(token.to_string()),
+        _ => {
+            eprintln!(
+                "No access token in response: {} {}",
+                logging::redact(json["error"].as_str().unwrap_or("unknown_error")),
+                logging::redact(json["error_description"].as_str().unwrap_or_default())
+            );
+            Err(AuthError::TokenExchangeFailed)
         }
     }
 }
@@ -0,0 +1,479 @@
+// src/bots.rs
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::accounts::{random_token, token_key};
+use crate::auth::{OAuthProvider, OAuthUser};
+use crate::channels;
+use crate::history::MessageAuthor;
+use crate::session::disconnect_sessions;
+use crate::state::AppState;
+use crate::webserver::{bearer_token, require_admin};
+use crate::{PresenceUser, ServerMessage, legacy_plaintext, unix_now};
+
+// API tokens start with this so they are easy to tell apart from session tokens
+pub const BOT_TOKEN_PREFIX: &str = "rcb_";
+const BOT_TOKEN_LENGTH: usize = 40;
+
+// Provider slug for bot identities
+pub const BOT_PROVIDER: &str = "bot";
+
+// What a bot token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BotScope {
+    // Post chat messages, over the WebSocket or POST /api/bot/messages
+    SendMessages,
+    // Receive chat messages on the WebSocket
+    ReadMessages,
+    // Receive presence on the WebSocket, or GET /api/bot/presence
+    ReadPresence,
+}
+
+// A bot user; only the hash of its current API token is kept
+#[derive(Debug, Clone, Serialize)]
+pub struct Bot {
+    pub id: String,
+    pub name: String,
+    pub avatar: Option<String>,
+    pub scopes: HashSet<BotScope>,
+    pub channels: Vec<String>,
+    pub created_at: u64,
+    pub rotated_at: u64,
+    #[serde(skip)]
+    token_hash: String,
+    // Stands in for a session id on connections opened with the current token
+    #[serde(skip)]
+    pub token_id: String,
+}
+
+impl Bot {
+    pub fn allows(&self, scope: BotScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    // The identity a bot connects as, shaped like an OAuth login
+    pub fn user(&self) -> OAuthUser {
+        OAuthUser {
+            id: self.id.clone(),
+            username: self.name.clone(),
+            email: String::new(),
+            email_verified: false,
+            avatar: self.avatar.clone(),
+            provider: OAuthProvider::new(BOT_PROVIDER),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BotStore {
+    bots: HashMap<String, Bot>,
+    // Token hash to bot id
+    by_token: HashMap<String, String>,
+}
+
+pub type SharedBots = Arc<RwLock<BotStore>>;
+
+fn new_token() -> String {
+    format!("{}{}", BOT_TOKEN_PREFIX, random_token(BOT_TOKEN_LENGTH))
+}
+
+impl BotStore {
+    // Create a bot and return it with its API token, which is not stored anywhere
+    pub fn create(
+        &mut self,
+        name: &str,
+        avatar: Option<String>,
+        scopes: HashSet<BotScope>,
+        channels: Vec<String>,
+    ) -> (Bot, String) {
+        let token = new_token();
+        let now = unix_now();
+        let bot = Bot {
+            id: format!("{}_{}", BOT_PROVIDER, random_token(12)),
+            name: name.to_string(),
+            avatar,
+            scopes,
+            channels,
+            created_at: now,
+            rotated_at: now,
+            token_hash: token_key(&token),
+            token_id: random_token(32),
+        };
+        self.by_token.insert(bot.token_hash.clone(), bot.id.clone());
+        self.bots.insert(bot.id.clone(), bot.clone());
+        (bot, token)
+    }
+
+    // Replace a bot's token; the old one stops working at once.
+    // Returns the bot, the new token and the token id connections were opened with
+    pub fn rotate(&mut self, bot_id: &str) -> Option<(Bot, String, String)> {
+        let bot = self.bots.get_mut(bot_id)?;
+        let token = new_token();
+        self.by_token.remove(&bot.token_hash);
+        let previous = std::mem::replace(&mut bot.token_id, random_token(32));
+        bot.token_hash = token_key(&token);
+        bot.rotated_at = unix_now();
+        self.by_token.insert(bot.token_hash.clone(), bot.id.clone());
+        Some((bot.clone(), token, previous))
+    }
+
+    pub fn delete(&mut self, bot_id: &str) -> Option<Bot> {
+        let bot = self.bots.remove(bot_id)?;
+        self.by_token.remove(&bot.token_hash);
+        Some(bot)
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Bot> {
+        let bot_id = self.by_token.get(&token_key(token))?;
+        self.bots.get(bot_id).cloned()
+    }
+
+    // Whether connections opened with this token may keep going
+    pub fn is_active(&self, bot_id: &str, token_id: &str) -> bool {
+        self.bots
+            .get(bot_id)
+            .is_some_and(|bot| bot.token_id == token_id)
+    }
+
+    pub fn list(&self) -> Vec<Bot> {
+        let mut bots: Vec<Bot> = self.bots.values().cloned().collect();
+        bots.sort_by_key(|bot| bot.created_at);
+        bots
+    }
+}
+
+// Resolve the bot behind an `Authorization: Bearer` API token and check it holds a scope
+pub async fn authenticate_bot(
+    state: &AppState,
+    headers: &HeaderMap,
+    scope: BotScope,
+) -> Result<Bot, StatusCode> {
+    let token = bearer_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    let bot = state
+        .bots
+        .read()
+        .await
+        .authenticate(token)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !bot.allows(scope) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(bot)
+}
+
+#[derive(Deserialize)]
+struct CreateBotRequest {
+    name: String,
+    avatar: Option<String>,
+    scopes: HashSet<BotScope>,
+    // Private channels the bot is added to
+    #[serde(default)]
+    channels: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BotWithToken {
+    #[serde(flatten)]
+    bot: Bot,
+    // Only ever shown here, on creation and rotation
+    token: String,
+}
+
+// POST /api/bots
+async fn create_bot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CreateBotRequest>,
+) -> Result<Json<BotWithToken>, (StatusCode, String)> {
+    require_admin(&state, &headers)
+        .await
+        .map_err(|status| (status, "Admin token required".to_string()))?;
+
+    let name = request.name.trim();
+    if name.is_empty() || name.len() > 32 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "name must be 1-32 characters".to_string(),
+        ));
+    }
+
+    let (bot, token) =
+        state
+            .bots
+            .write()
+            .await
+            .create(name, request.avatar, request.scopes, request.channels);
+    {
+        let mut guild = state.guild.write().await;
+        for channel in &bot.channels {
+            guild.grant_channel(channel, &bot.id);
+        }
+    }
+    println!("Created bot {} ({})", bot.name, bot.id);
+    Ok(Json(BotWithToken { bot, token }))
+}
+
+// GET /api/bots
+async fn list_bots(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Bot>>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    Ok(Json(state.bots.read().await.list()))
+}
+
+// POST /api/bots/:id/rotate, issues a new token and drops connections using the old one
+async fn rotate_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(bot_id): Path<String>,
+) -> Result<Json<BotWithToken>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    let (bot, token, previous) = state
+        .bots
+        .write()
+        .await
+        .rotate(&bot_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    disconnect_sessions(&state, &[previous]).await;
+    println!("Rotated the token of bot {}", bot.id);
+    Ok(Json(BotWithToken { bot, token }))
+}
+
+// DELETE /api/bots/:id
+async fn delete_bot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(bot_id): Path<String>,
+) -> StatusCode {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status;
+    }
+    let Some(bot) = state.bots.write().await.delete(&bot_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    disconnect_sessions(&state, &[bot.token_id]).await;
+    // Drop the bot from the guild and rotate any channel key it may have fetched
+    channels::remove_access(&state, &bot.id, None, true).await;
+    println!("Deleted bot {} ({})", bot.name, bot.id);
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize)]
+struct BotMessageRequest {
+    channel: String,
+    ciphertext: String,
+}
+
+// POST /api/bot/messages, posts a chat message without holding a connection open
+async fn post_message(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<BotMessageRequest>,
+) -> Result<StatusCode, (StatusCode, Json<ServerMessage>)> {
+    let bot = authenticate_bot(&state, &headers, BotScope::SendMessages)
+        .await
+        .map_err(|status| {
+            let error = ServerMessage::Error {
+                code: "unauthorized".to_string(),
+                message: "A bot token with the send_messages scope is required".to_string(),
+            };
+            (status, Json(error))
+        })?;
+
+    // Bots encrypt with the channel's group key like everyone else
+    let plaintext = legacy_plaintext(&request.ciphertext);
+
+    crate::handle_chat_message(
+        &state,
+        None,
+        MessageAuthor {
+            id: bot.id.clone(),
+            name: bot.name.clone(),
+            avatar: bot.avatar.clone(),
+            webhook_id: None,
+        },
+        request.channel,
+        request.ciphertext,
+        None,
+        plaintext.as_deref(),
+    )
+    .await
+    .map_err(|error| {
+        let status = match &error {
+            ServerMessage::Error { code, .. } if code == "invalid_ciphertext" => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => StatusCode::FORBIDDEN,
+        };
+        (status, Json(error))
+    })?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+// GET /api/bot/presence, everyone's status without their emails
+async fn get_presence(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<PresenceUser>>, StatusCode> {
+    authenticate_bot(&state, &headers, BotScope::ReadPresence).await?;
+    let users = state
+        .presence_state
+        .read()
+        .await
+        .values()
+        .cloned()
+        .map(|user| PresenceUser {
+            email: None,
+            ..user
+        })
+        .collect();
+    Ok(Json(users))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/bots", post(create_bot).get(list_bots))
+        .route("/api/bots/:id", delete(delete_bot))
+        .route("/api/bots/:id/rotate", post(rotate_token))
+        .route("/api/bot/messages", post(post_message))
+        .route("/api/bot/presence", get(get_presence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guild::Role;
+    use crate::keys::KeyCopy;
+    use axum::http::HeaderValue;
+    use axum::http::header::AUTHORIZATION;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn tokens_are_scoped() {
+        let state = AppState::new();
+        let (_, token) = state.bots.write().await.create(
+            "standup",
+            None,
+            HashSet::from([BotScope::ReadPresence]),
+            Vec::new(),
+        );
+
+        assert!(
+            authenticate_bot(&state, &bearer(&token), BotScope::ReadPresence)
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            authenticate_bot(&state, &bearer(&token), BotScope::SendMessages)
+                .await
+                .err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            authenticate_bot(&state, &bearer("rcb_nope"), BotScope::ReadPresence)
+                .await
+                .err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[tokio::test]
+    async fn deleting_a_bot_rotates_the_keys_it_could_read() {
+        let state = AppState::new();
+        let (bot, _) = state.bots.write().await.create(
+            "standup",
+            None,
+            HashSet::from([BotScope::SendMessages]),
+            vec!["staff".to_string()],
+        );
+        {
+            let mut guild = state.guild.write().await;
+            guild.add_private_channel("staff");
+            guild.grant_channel("staff", "alice");
+            guild.grant_channel("staff", &bot.id);
+            guild.set_role("alice", Role::Admin);
+        }
+        {
+            let mut keys = state.keys.write().await;
+            for (seed, user) in ["alice", bot.id.as_str()].into_iter().enumerate() {
+                keys.publish_identity(user, &BASE64.encode([seed as u8; 32]))
+                    .unwrap();
+            }
+            let copies = ["alice", bot.id.as_str()]
+                .map(|user| KeyCopy {
+                    recipient: user.to_string(),
+                    wrapped: BASE64.encode([1u8; 60]),
+                })
+                .into();
+            keys.distribute("staff", "alice", 1, "k1", copies, |_| true)
+                .unwrap();
+        }
+        let (token, _) = state
+            .sessions
+            .write()
+            .await
+            .start(
+                "alice",
+                OAuthUser {
+                    id: "alice".to_string(),
+                    username: "alice".to_string(),
+                    email: String::new(),
+                    email_verified: false,
+                    avatar: None,
+                    provider: OAuthProvider::new("local"),
+                },
+                None,
+            )
+            .unwrap();
+
+        let status = delete_bot(State(state.clone()), bearer(&token), Path(bot.id.clone())).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let guild = state.guild.read().await;
+        assert!(!guild.can_access_channel("staff", Some(&bot.id)));
+        assert_eq!(state.keys.read().await.current_epoch("staff"), Some(2));
+    }
+
+    #[test]
+    fn rotating_retires_the_old_token() {
+        let mut bots = BotStore::default();
+        let (bot, old_token) = bots.create(
+            "deploy",
+            None,
+            HashSet::from([BotScope::SendMessages]),
+            Vec::new(),
+        );
+        let old_token_id = bot.token_id.clone();
+
+        let (rotated, new_token, previous) = bots.rotate(&bot.id).expect("bot exists");
+        assert_eq!(previous, old_token_id);
+        assert!(bots.authenticate(&old_token).is_none());
+        assert!(!bots.is_active(&bot.id, &old_token_id));
+        assert_eq!(
+            bots.authenticate(&new_token).map(|bot| bot.id),
+            Some(rotated.id.clone())
+        );
+        assert!(bots.is_active(&rotated.id, &rotated.token_id));
+        assert!(new_token.starts_with(BOT_TOKEN_PREFIX));
+    }
+}
@@ -0,0 +1,174 @@
+// src/channels.rs
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, post},
+};
+use serde_json::json;
+
+use crate::events::{self, EventKind};
+use crate::keys;
+use crate::session::{self, disconnect_sessions};
+use crate::state::AppState;
+use crate::webserver::require_admin;
+
+// Take a private channel, or with no channel the whole guild, away from a user and
+// rotate the keys they held. False if they had nothing to lose
+pub async fn remove_access(
+    state: &AppState,
+    user_id: &str,
+    channel: Option<&str>,
+    kicked: bool,
+) -> bool {
+    let removed = {
+        let mut guild = state.guild.write().await;
+        match channel {
+            Some(channel) => guild.revoke_channel(channel, user_id),
+            None => guild.remove_member(user_id),
+        }
+    };
+    if !removed {
+        return false;
+    }
+
+    keys::rotate_after_membership_change(state).await;
+    events::emit(
+        state,
+        EventKind::MemberLeft,
+        &json!({ "user_id": user_id, "channel": channel, "kicked": kicked }),
+    )
+    .await;
+    true
+}
+
+// POST /api/channels/:channel/leave
+async fn leave_channel(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+) -> StatusCode {
+    let session = match session::authenticate(&state, &headers).await {
+        Ok(session) => session,
+        Err(status) => return status,
+    };
+    if remove_access(&state, &session.user_id, Some(&channel), false).await {
+        println!("{} left #{}", session.user_id, channel);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+// DELETE /api/channels/:channel/members/:user_id
+async fn kick_from_channel(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel, user_id)): Path<(String, String)>,
+) -> StatusCode {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status;
+    }
+    if remove_access(&state, &user_id, Some(&channel), true).await {
+        println!("Kicked {} from #{}", user_id, channel);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+// DELETE /api/members/:user_id, also logs the user out everywhere
+async fn kick_from_guild(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> StatusCode {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status;
+    }
+    if !remove_access(&state, &user_id, None, true).await {
+        return StatusCode::NOT_FOUND;
+    }
+    let revoked = state.sessions.write().await.revoke_all(&user_id);
+    disconnect_sessions(&state, &revoked).await;
+    println!("Kicked {} from the guild", user_id);
+    StatusCode::NO_CONTENT
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/channels/:channel/leave", post(leave_channel))
+        .route(
+            "/api/channels/:channel/members/:user_id",
+            delete(kick_from_channel),
+        )
+        .route("/api/members/:user_id", delete(kick_from_guild))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyCopy;
+    use crate::{ClientHandle, ServerMessage};
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::sync::{Notify, mpsc::unbounded_channel};
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    async fn kicking_a_member_rotates_the_channel_key_for_everyone_else() {
+        let state = AppState::new();
+        {
+            let mut guild = state.guild.write().await;
+            guild.add_private_channel("staff");
+            guild.grant_channel("staff", "alice");
+            guild.grant_channel("staff", "bob");
+        }
+        let mut receivers = Vec::new();
+        {
+            let mut keys = state.keys.write().await;
+            for (seed, user) in ["alice", "bob"].into_iter().enumerate() {
+                keys.publish_identity(user, &BASE64.encode([seed as u8; 32]))
+                    .unwrap();
+            }
+            let copies = ["alice", "bob"]
+                .map(|user| KeyCopy {
+                    recipient: user.to_string(),
+                    wrapped: BASE64.encode([1u8; 60]),
+                })
+                .into();
+            keys.distribute("staff", "alice", 1, "k1", copies, |_| true)
+                .unwrap();
+        }
+        for (port, user) in [(1, "alice"), (2, "bob")] {
+            let (tx, rx) = unbounded_channel();
+            state.clients.write().await.insert(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                ClientHandle {
+                    sender: tx,
+                    user_id: user.to_string(),
+                    session_id: user.to_string(),
+                    guest: false,
+                    scopes: None,
+                    closed: Arc::new(Notify::new()),
+                },
+            );
+            receivers.push(rx);
+        }
+
+        assert!(remove_access(&state, "bob", Some("staff"), true).await);
+        assert!(!remove_access(&state, "bob", Some("staff"), true).await);
+
+        assert_eq!(state.keys.read().await.current_epoch("staff"), Some(2));
+        let Ok(Message::Text(text)) = receivers[0].try_recv() else {
+            panic!("alice was not told about the rotation");
+        };
+        assert!(matches!(
+            serde_json::from_str(&text),
+            Ok(ServerMessage::KeyRotated { conversation, epoch: 2 }) if conversation == "staff"
+        ));
+        assert!(receivers[1].try_recv().is_err());
+    }
+}
@@ -21,9 +21,9 @@ pub fn load_env_file() -> Result<(), Box<dyn std::error::Error>> {
             let value = value.trim();
 
             // Remove quotes if present
-            let value = if value.starts_with('"') && value.ends_with('"') {
-                &value[1..value.len() - 1]
-            } else if value.starts_with('\'') && value.ends_with('\'') {
+            let value = if (value.starts_with('"') && value.ends_with('"'))
+                || (value.starts_with('\'') && value.ends_with('\''))
+            {
                 &value[1..value.len() - 1]
             } else {
                 value
@@ -0,0 +1,313 @@
+// src/envelope.rs
+// Encrypted message format, base64 of:
+//
+//   version 2 (1 byte) | algorithm (1 byte) | key epoch (4 bytes, big endian) | nonce | AEAD ciphertext and tag
+//
+// The header before the nonce is authenticated as associated data. Version 1 had no
+// epoch. Messages written before the envelope are a bare AES-256-CBC IV and ciphertext;
+// those still open while clients migrate, without any integrity protection.
+use aes::Aes256;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use cbc::Decryptor;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use chacha20poly1305::XChaCha20Poly1305;
+
+pub const VERSION: u8 = 2;
+// Envelopes without a key epoch, from before channel keys were rotated
+const VERSION_WITHOUT_EPOCH: u8 = 1;
+const EPOCH_LENGTH: usize = 4;
+const TAG_LENGTH: usize = 16;
+const CBC_BLOCK_LENGTH: usize = 16;
+
+type Aes256CbcDec = Decryptor<Aes256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    // What browsers can do natively
+    Aes256Gcm,
+    // Large random nonces, for clients that bring their own crypto
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes256Gcm => 1,
+            Algorithm::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        [Algorithm::Aes256Gcm, Algorithm::XChaCha20Poly1305]
+            .into_iter()
+            .find(|algorithm| algorithm.id() == id)
+    }
+
+    fn nonce_length(self) -> usize {
+        match self {
+            Algorithm::Aes256Gcm => 12,
+            Algorithm::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeError {
+    // Not base64, or too short for its algorithm
+    Malformed,
+    UnsupportedVersion(u8),
+    UnknownAlgorithm(u8),
+    // Wrong key, or the message was tampered with
+    Decryption,
+}
+
+// What a message looks like from the outside; telling this needs no key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Envelope {
+        algorithm: Algorithm,
+        epoch: Option<u32>,
+    },
+    LegacyCbc,
+}
+
+impl Format {
+    // The group key epoch a message was sealed with, if it says
+    pub fn epoch(&self) -> Option<u32> {
+        match self {
+            Format::Envelope { epoch, .. } => *epoch,
+            Format::LegacyCbc => None,
+        }
+    }
+}
+
+struct Sealed<'a> {
+    algorithm: Algorithm,
+    epoch: Option<u32>,
+    header: &'a [u8],
+    nonce: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+fn parse(data: &[u8]) -> Result<Sealed<'_>, EnvelopeError> {
+    let [version, algorithm, ..] = *data else {
+        return Err(EnvelopeError::Malformed);
+    };
+    let header_length = match version {
+        VERSION => 2 + EPOCH_LENGTH,
+        VERSION_WITHOUT_EPOCH => 2,
+        _ => return Err(EnvelopeError::UnsupportedVersion(version)),
+    };
+    let algorithm =
+        Algorithm::from_id(algorithm).ok_or(EnvelopeError::UnknownAlgorithm(algorithm))?;
+    let nonce_end = header_length + algorithm.nonce_length();
+    if data.len() < nonce_end + TAG_LENGTH {
+        return Err(EnvelopeError::Malformed);
+    }
+    let epoch = (version == VERSION).then(|| {
+        let bytes: [u8; EPOCH_LENGTH] = data[2..header_length].try_into().expect("checked length");
+        u32::from_be_bytes(bytes)
+    });
+    Ok(Sealed {
+        algorithm,
+        epoch,
+        header: &data[..header_length],
+        nonce: &data[header_length..nonce_end],
+        ciphertext: &data[nonce_end..],
+    })
+}
+
+// An IV and at least one whole block
+fn is_legacy_cbc(data: &[u8]) -> bool {
+    data.len() >= 2 * CBC_BLOCK_LENGTH && data.len().is_multiple_of(CBC_BLOCK_LENGTH)
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, EnvelopeError> {
+    BASE64.decode(encoded).map_err(|_| EnvelopeError::Malformed)
+}
+
+// Check that a message is an envelope, or an old CBC message, without opening it
+pub fn format(encoded: &str) -> Result<Format, EnvelopeError> {
+    let data = decode(encoded)?;
+    match parse(&data) {
+        Ok(sealed) => Ok(Format::Envelope {
+            algorithm: sealed.algorithm,
+            epoch: sealed.epoch,
+        }),
+        Err(_) if is_legacy_cbc(&data) => Ok(Format::LegacyCbc),
+        Err(err) => Err(err),
+    }
+}
+
+fn open_sealed(key: &[u8; 32], sealed: &Sealed) -> Result<Vec<u8>, EnvelopeError> {
+    let payload = Payload {
+        msg: sealed.ciphertext,
+        aad: sealed.header,
+    };
+    match sealed.algorithm {
+        Algorithm::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(sealed.nonce.into(), payload),
+        Algorithm::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key.into()).decrypt(sealed.nonce.into(), payload)
+        }
+    }
+    .map_err(|_| EnvelopeError::Decryption)
+}
+
+fn open_legacy_cbc(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    let (iv, ciphertext) = data.split_at(CBC_BLOCK_LENGTH);
+    let mut buffer = ciphertext.to_vec();
+    let plaintext_len = Aes256CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
+        .map_err(|_| EnvelopeError::Decryption)?
+        .len();
+    buffer.truncate(plaintext_len);
+    Ok(buffer)
+}
+
+// Decrypt a message. Only data that is no envelope at all is tried as CBC; an envelope
+// failing its tag must not get a second chance through a mode without integrity.
+// The rare old message whose random IV reads as an envelope header no longer opens
+pub fn open(key: &[u8; 32], encoded: &str) -> Result<Vec<u8>, EnvelopeError> {
+    let data = decode(encoded)?;
+    match parse(&data) {
+        Ok(sealed) => open_sealed(key, &sealed),
+        Err(_) if is_legacy_cbc(&data) => open_legacy_cbc(key, &data),
+        Err(err) => Err(err),
+    }
+}
+
+// Clients seal messages; the server only needs this to test opening them
+#[cfg(test)]
+pub fn seal(algorithm: Algorithm, epoch: u32, key: &[u8; 32], plaintext: &[u8]) -> String {
+    use rand::RngCore;
+
+    let mut data = vec![VERSION, algorithm.id()];
+    data.extend_from_slice(&epoch.to_be_bytes());
+    let header_length = data.len();
+    let mut nonce = vec![0u8; algorithm.nonce_length()];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    data.extend_from_slice(&nonce);
+
+    let payload = Payload {
+        msg: plaintext,
+        aad: &data[..header_length],
+    };
+    let ciphertext = match algorithm {
+        Algorithm::Aes256Gcm => {
+            Aes256Gcm::new(key.into()).encrypt(nonce.as_slice().into(), payload)
+        }
+        Algorithm::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key.into()).encrypt(nonce.as_slice().into(), payload)
+        }
+    }
+    .expect("plaintext fits in one message");
+    data.extend_from_slice(&ciphertext);
+    BASE64.encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbc::Encryptor;
+    use cbc::cipher::BlockEncryptMut;
+
+    const KEY: [u8; 32] = [42; 32];
+
+    #[test]
+    fn both_algorithms_round_trip_and_reject_tampering() {
+        for algorithm in [Algorithm::Aes256Gcm, Algorithm::XChaCha20Poly1305] {
+            let sealed = seal(algorithm, 3, &KEY, b"hello");
+            assert_eq!(
+                format(&sealed),
+                Ok(Format::Envelope {
+                    algorithm,
+                    epoch: Some(3)
+                })
+            );
+            assert_eq!(open(&KEY, &sealed).as_deref(), Ok(&b"hello"[..]));
+            assert_eq!(open(&[7; 32], &sealed), Err(EnvelopeError::Decryption));
+
+            // Flipping a single bit breaks the tag, in the ciphertext or the epoch
+            for index in [5, BASE64.decode(&sealed).unwrap().len() - 1] {
+                let mut data = BASE64.decode(&sealed).unwrap();
+                data[index] ^= 1;
+                assert_eq!(
+                    open(&KEY, &BASE64.encode(&data)),
+                    Err(EnvelopeError::Decryption)
+                );
+            }
+        }
+
+        assert_eq!(
+            format(&BASE64.encode([9, 1, 0, 0])),
+            Err(EnvelopeError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            format(&BASE64.encode([VERSION, 7, 0, 0])),
+            Err(EnvelopeError::UnknownAlgorithm(7))
+        );
+        assert_eq!(format("not base64!"), Err(EnvelopeError::Malformed));
+    }
+
+    #[test]
+    fn tampered_envelopes_never_open() {
+        // Plaintexts sized so the envelopes are also whole CBC blocks
+        for (algorithm, plaintext) in [
+            (Algorithm::Aes256Gcm, &b"fourteen bytes"[..]),
+            (Algorithm::XChaCha20Poly1305, &b"hi"[..]),
+        ] {
+            let data = BASE64.decode(seal(algorithm, 1, &KEY, plaintext)).unwrap();
+            assert!(is_legacy_cbc(&data));
+            for bit in 0..data.len() * 8 {
+                let mut tampered = data.clone();
+                tampered[bit / 8] ^= 1 << (bit % 8);
+                let tampered = BASE64.encode(&tampered);
+                match format(&tampered) {
+                    // Still an envelope, so its tag decides and there is no CBC retry
+                    Ok(Format::Envelope { .. }) => {
+                        assert!(open(&KEY, &tampered).is_err(), "bit {} flipped", bit)
+                    }
+                    // A broken version or algorithm byte reads as an old CBC message, which
+                    // has no integrity; its padding can check out by chance, but it never
+                    // gives back the sealed plaintext
+                    _ => assert_ne!(
+                        open(&KEY, &tampered).as_deref(),
+                        Ok(plaintext),
+                        "bit {} flipped",
+                        bit
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn older_formats_still_open() {
+        let header = [VERSION_WITHOUT_EPOCH, Algorithm::Aes256Gcm.id()];
+        let nonce = [1u8; 12];
+        let payload = Payload {
+            msg: &b"hello"[..],
+            aad: &header[..],
+        };
+        let sealed = Aes256Gcm::new(&KEY.into())
+            .encrypt(&nonce.into(), payload)
+            .unwrap();
+        let version_one = BASE64.encode([&header[..], &nonce[..], &sealed].concat());
+        assert_eq!(format(&version_one).map(|format| format.epoch()), Ok(None));
+        assert_eq!(open(&KEY, &version_one).as_deref(), Ok(&b"hello"[..]));
+
+        let iv = [3u8; 16];
+        let mut buffer = [0u8; 32];
+        buffer[..5].copy_from_slice(b"hello");
+        let ciphertext = Encryptor::<Aes256>::new(&KEY.into(), &iv.into())
+            .encrypt_padded_mut::<Pkcs7>(&mut buffer, 5)
+            .unwrap();
+        let legacy = BASE64.encode([&iv[..], ciphertext].concat());
+
+        assert_eq!(format(&legacy), Ok(Format::LegacyCbc));
+        assert_eq!(open(&KEY, &legacy).as_deref(), Ok(&b"hello"[..]));
+    }
+}
@@ -0,0 +1,791 @@
+// src/events.rs
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post},
+};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::accounts::random_token;
+use crate::at_rest::{AtRestError, EncryptedFile, Keyring};
+use crate::state::AppState;
+use crate::unix_now;
+use crate::webserver::require_admin;
+
+// Give up on a delivery after this many attempts
+const MAX_ATTEMPTS: u32 = 8;
+const DEFAULT_RETRY_BASE_SECS: u64 = 10;
+const MAX_BACKOFF_SECS: u64 = 60 * 60;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const WORKER_INTERVAL: Duration = Duration::from_secs(1);
+// Delivery log entries kept across all subscriptions
+const MAX_LOG_ENTRIES: usize = 1000;
+// Names the queue's record on disk
+const QUEUE_CONTEXT: &str = "event_queue";
+
+pub const SIGNATURE_HEADER: &str = "x-rustcord-signature";
+pub const TIMESTAMP_HEADER: &str = "x-rustcord-timestamp";
+pub const EVENT_HEADER: &str = "x-rustcord-event";
+pub const DELIVERY_HEADER: &str = "x-rustcord-delivery";
+
+// Server events an endpoint can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    MessageCreated,
+    MemberJoined,
+    MemberLeft,
+    PresenceChanged,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::MessageCreated => "message_created",
+            EventKind::MemberJoined => "member_joined",
+            EventKind::MemberLeft => "member_left",
+            EventKind::PresenceChanged => "presence_changed",
+        }
+    }
+}
+
+// An external endpoint and the events it wants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub url: String,
+    pub events: HashSet<EventKind>,
+    pub created_at: u64,
+}
+
+// A subscription with the secret that signs its payloads, which the API never shows again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredSubscription {
+    #[serde(flatten)]
+    subscription: Subscription,
+    secret: String,
+}
+
+// A payload waiting to be delivered to one subscription
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    id: String,
+    subscription_id: String,
+    event: EventKind,
+    payload: String,
+    attempts: u32,
+    next_attempt_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Delivered,
+    Retrying,
+    Failed,
+}
+
+// One attempt in the delivery log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    pub delivery_id: String,
+    pub subscription_id: String,
+    pub event: EventKind,
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    pub at: u64,
+}
+
+// Everything that has to survive a restart
+#[derive(Default, Serialize, Deserialize)]
+struct Persisted {
+    subscriptions: Vec<StoredSubscription>,
+    pending: VecDeque<Delivery>,
+    log: VecDeque<DeliveryAttempt>,
+}
+
+// Subscriptions, the delivery queue and the delivery log, kept encrypted in EVENT_QUEUE_PATH
+pub struct Outbox {
+    data: Persisted,
+    file: Option<Arc<EncryptedFile>>,
+    // Something changed since the file was last written
+    dirty: bool,
+    retry_base_secs: u64,
+}
+
+pub type SharedOutbox = Arc<RwLock<Outbox>>;
+
+// Seconds to wait before the next attempt, doubling each time
+fn backoff_secs(base: u64, attempts: u32) -> u64 {
+    base.saturating_mul(1 << attempts.saturating_sub(1).min(20))
+        .min(MAX_BACKOFF_SECS)
+}
+
+// `sha256=<hex>` over `<timestamp>.<payload>`, so a captured request cannot be replayed later
+pub fn signature(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+impl Outbox {
+    // Load what a previous run left behind in `file`; without one nothing is kept on disk.
+    // A queue that cannot be opened is an error rather than a fresh start that would overwrite it
+    pub fn open(file: Option<EncryptedFile>, retry_base_secs: u64) -> Result<Self, AtRestError> {
+        let data = match file
+            .as_ref()
+            .map(EncryptedFile::read)
+            .transpose()?
+            .flatten()
+        {
+            Some(bytes) => serde_json::from_slice(&bytes).map_err(|_| AtRestError::Corrupt)?,
+            None => Persisted::default(),
+        };
+        Ok(Outbox {
+            data,
+            file: file.map(Arc::new),
+            dirty: false,
+            retry_base_secs,
+        })
+    }
+
+    // EVENT_QUEUE_PATH and EVENT_RETRY_BASE_SECS. Keeping a queue needs STORAGE_MASTER_KEY;
+    // without it the server refuses to start rather than write deliveries to disk in the clear
+    pub fn from_env() -> Self {
+        let path = std::env::var("EVENT_QUEUE_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        let file = match path {
+            Some(path) => {
+                let keyring = Keyring::from_env().unwrap_or_else(|err| {
+                    panic!(
+                        "EVENT_QUEUE_PATH is set but the storage key is unusable: {:?}",
+                        err
+                    )
+                });
+                Some(EncryptedFile::new(path, QUEUE_CONTEXT, keyring))
+            }
+            None => {
+                eprintln!(
+                    "Warning: EVENT_QUEUE_PATH not set, undelivered events are lost on restart"
+                );
+                None
+            }
+        };
+        let retry_base_secs = std::env::var("EVENT_RETRY_BASE_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_RETRY_BASE_SECS);
+        Outbox::open(file, retry_base_secs)
+            .unwrap_or_else(|err| panic!("Cannot open the event queue: {:?}", err))
+    }
+
+    // What to write out, if anything changed since the last time
+    fn snapshot(&mut self) -> Option<(Arc<EncryptedFile>, Vec<u8>)> {
+        let file = self.file.clone().filter(|_| self.dirty)?;
+        match serde_json::to_vec(&self.data) {
+            Ok(bytes) => {
+                self.dirty = false;
+                Some((file, bytes))
+            }
+            Err(err) => {
+                eprintln!("Failed to serialize event queue: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn subscribe(&mut self, url: &str, events: HashSet<EventKind>) -> (Subscription, String) {
+        let secret = random_token(32);
+        let subscription = Subscription {
+            id: random_token(16),
+            url: url.to_string(),
+            events,
+            created_at: unix_now(),
+        };
+        self.data.subscriptions.push(StoredSubscription {
+            subscription: subscription.clone(),
+            secret: secret.clone(),
+        });
+        self.dirty = true;
+        (subscription, secret)
+    }
+
+    // Remove a subscription along with whatever was still queued for it
+    pub fn unsubscribe(&mut self, id: &str) -> bool {
+        let before = self.data.subscriptions.len();
+        self.data
+            .subscriptions
+            .retain(|sub| sub.subscription.id != id);
+        if self.data.subscriptions.len() == before {
+            return false;
+        }
+        self.data
+            .pending
+            .retain(|delivery| delivery.subscription_id != id);
+        self.dirty = true;
+        true
+    }
+
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.data
+            .subscriptions
+            .iter()
+            .map(|sub| sub.subscription.clone())
+            .collect()
+    }
+
+    fn is_subscribed(&self, id: &str) -> bool {
+        self.data
+            .subscriptions
+            .iter()
+            .any(|sub| sub.subscription.id == id)
+    }
+
+    // Queue an event for every subscription that wants it
+    pub fn enqueue(&mut self, event: EventKind, data: Value) {
+        let interested: Vec<String> = self
+            .data
+            .subscriptions
+            .iter()
+            .filter(|sub| sub.subscription.events.contains(&event))
+            .map(|sub| sub.subscription.id.clone())
+            .collect();
+        if interested.is_empty() {
+            return;
+        }
+
+        let now = unix_now();
+        let payload = serde_json::json!({
+            "id": random_token(16),
+            "type": event,
+            "created_at": now,
+            "data": data,
+        })
+        .to_string();
+        for subscription_id in interested {
+            self.data.pending.push_back(Delivery {
+                id: random_token(16),
+                subscription_id,
+                event,
+                payload: payload.clone(),
+                attempts: 0,
+                next_attempt_at: now,
+            });
+        }
+        self.dirty = true;
+    }
+
+    // Deliveries due at `now`, with the URL and secret to send them with
+    fn due(&self, now: u64) -> Vec<(Delivery, String, String)> {
+        self.data
+            .pending
+            .iter()
+            .filter(|delivery| delivery.next_attempt_at <= now)
+            .filter_map(|delivery| {
+                let sub = self
+                    .data
+                    .subscriptions
+                    .iter()
+                    .find(|sub| sub.subscription.id == delivery.subscription_id)?;
+                Some((
+                    delivery.clone(),
+                    sub.subscription.url.clone(),
+                    sub.secret.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    // Record how an attempt went and drop or reschedule the delivery
+    fn record(
+        &mut self,
+        delivery_id: &str,
+        now: u64,
+        status: Option<u16>,
+        error: Option<String>,
+        duration_ms: u64,
+    ) {
+        let Some(index) = self
+            .data
+            .pending
+            .iter()
+            .position(|delivery| delivery.id == delivery_id)
+        else {
+            return;
+        };
+        let delivery = &mut self.data.pending[index];
+        delivery.attempts += 1;
+
+        let delivered = status.is_some_and(|status| (200..300).contains(&status));
+        let outcome = if delivered {
+            Outcome::Delivered
+        } else if delivery.attempts >= MAX_ATTEMPTS {
+            Outcome::Failed
+        } else {
+            delivery.next_attempt_at = now + backoff_secs(self.retry_base_secs, delivery.attempts);
+            Outcome::Retrying
+        };
+        let entry = DeliveryAttempt {
+            delivery_id: delivery.id.clone(),
+            subscription_id: delivery.subscription_id.clone(),
+            event: delivery.event,
+            attempt: delivery.attempts,
+            status,
+            error,
+            duration_ms,
+            outcome,
+            at: now,
+        };
+        if outcome != Outcome::Retrying {
+            self.data.pending.remove(index);
+        }
+
+        self.data.log.push_back(entry);
+        while self.data.log.len() > MAX_LOG_ENTRIES {
+            self.data.log.pop_front();
+        }
+        self.dirty = true;
+    }
+
+    // Newest first
+    pub fn log_for(&self, subscription_id: &str) -> Vec<DeliveryAttempt> {
+        self.data
+            .log
+            .iter()
+            .rev()
+            .filter(|entry| entry.subscription_id == subscription_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn pending_for(&self, subscription_id: &str) -> usize {
+        self.data
+            .pending
+            .iter()
+            .filter(|delivery| delivery.subscription_id == subscription_id)
+            .count()
+    }
+}
+
+// Tell subscribers about something that happened
+pub async fn emit<T: Serialize>(state: &AppState, event: EventKind, data: &T) {
+    match serde_json::to_value(data) {
+        Ok(data) => state.events.write().await.enqueue(event, data),
+        Err(err) => eprintln!("Failed to serialize {:?} event: {}", event, err),
+    }
+}
+
+// Write queue changes to EVENT_QUEUE_PATH. Only serializing happens under the lock
+pub async fn flush(outbox: &RwLock<Outbox>) {
+    let Some((file, bytes)) = outbox.write().await.snapshot() else {
+        return;
+    };
+    if let Err(err) = file.write_blocking(bytes).await {
+        eprintln!("Failed to persist event queue: {}", err);
+        // Try again on the next flush
+        outbox.write().await.dirty = true;
+    }
+}
+
+// Try the deliveries that are due at `now`; returns how many were attempted.
+// Subscriptions are served side by side, each in queue order, and one stops at its first
+// failure so a dead endpoint holds up only its own deliveries, for one timeout
+pub async fn deliver_due(state: &AppState, client: &reqwest::Client, now: u64) -> usize {
+    let mut by_subscription: HashMap<String, Vec<(Delivery, String, String)>> = HashMap::new();
+    for due in state.events.read().await.due(now) {
+        by_subscription
+            .entry(due.0.subscription_id.clone())
+            .or_default()
+            .push(due);
+    }
+    let attempted = join_all(by_subscription.into_values().map(|due| async move {
+        let mut attempted = 0;
+        for (delivery, url, secret) in &due {
+            attempted += 1;
+            if !deliver(state, client, now, delivery, url, secret).await {
+                break;
+            }
+        }
+        attempted
+    }))
+    .await;
+    attempted.into_iter().sum()
+}
+
+// Send one delivery and record the outcome; true if the endpoint took it
+async fn deliver(
+    state: &AppState,
+    client: &reqwest::Client,
+    now: u64,
+    delivery: &Delivery,
+    url: &str,
+    secret: &str,
+) -> bool {
+    let timestamp = unix_now();
+    let started = Instant::now();
+    let result = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            signature(secret, timestamp, &delivery.payload),
+        )
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(EVENT_HEADER, delivery.event.name())
+        .header(DELIVERY_HEADER, &delivery.id)
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    let (status, error) = match result {
+        Ok(response) => (Some(response.status().as_u16()), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let delivered = status.is_some_and(|status| (200..300).contains(&status));
+    state
+        .events
+        .write()
+        .await
+        .record(&delivery.id, now, status, error, duration_ms);
+    delivered
+}
+
+// Deliver queued events in the background for as long as the server runs
+pub fn spawn_worker(state: AppState) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP client builds");
+        let mut interval = tokio::time::interval(WORKER_INTERVAL);
+        loop {
+            interval.tick().await;
+            deliver_due(&state, &client, unix_now()).await;
+            flush(&state.events).await;
+        }
+    });
+}
+
+#[derive(Deserialize)]
+struct SubscribeRequest {
+    url: String,
+    events: HashSet<EventKind>,
+}
+
+#[derive(Serialize)]
+struct CreatedSubscription {
+    #[serde(flatten)]
+    subscription: Subscription,
+    // Only ever shown here; receivers check signatures with it
+    secret: String,
+}
+
+// POST /api/event-webhooks
+async fn subscribe(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<SubscribeRequest>,
+) -> Result<Json<CreatedSubscription>, (StatusCode, String)> {
+    require_admin(&state, &headers)
+        .await
+        .map_err(|status| (status, "Admin token required".to_string()))?;
+
+    let valid_url = url::Url::parse(&request.url)
+        .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some());
+    if !valid_url {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "url must be an http(s) URL".to_string(),
+        ));
+    }
+    if request.events.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "events must name at least one event".to_string(),
+        ));
+    }
+
+    let (subscription, secret) = state
+        .events
+        .write()
+        .await
+        .subscribe(&request.url, request.events);
+    Ok(Json(CreatedSubscription {
+        subscription,
+        secret,
+    }))
+}
+
+// GET /api/event-webhooks
+async fn list_subscriptions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Subscription>>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    Ok(Json(state.events.read().await.subscriptions()))
+}
+
+// DELETE /api/event-webhooks/:id
+async fn unsubscribe(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> StatusCode {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status;
+    }
+    if state.events.write().await.unsubscribe(&id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[derive(Serialize)]
+struct DeliveryLog {
+    pending: usize,
+    attempts: Vec<DeliveryAttempt>,
+}
+
+// GET /api/event-webhooks/:id/deliveries
+async fn deliveries(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<DeliveryLog>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    let outbox = state.events.read().await;
+    if !outbox.is_subscribed(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(DeliveryLog {
+        pending: outbox.pending_for(&id),
+        attempts: outbox.log_for(&id),
+    }))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/event-webhooks",
+            post(subscribe).get(list_subscriptions),
+        )
+        .route("/api/event-webhooks/:id", delete(unsubscribe))
+        .route("/api/event-webhooks/:id/deliveries", get(deliveries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Mutex;
+
+    // A local endpoint that records what it receives and answers with canned statuses
+    struct Receiver {
+        url: String,
+        received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    fn start_receiver(statuses: Vec<StatusCode>) -> Receiver {
+        let received: Arc<Mutex<Vec<(HeaderMap, String)>>> = Arc::default();
+        let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+        let log = received.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| {
+                let log = log.clone();
+                let statuses = statuses.clone();
+                async move {
+                    log.lock()
+                        .unwrap()
+                        .push((headers, String::from_utf8_lossy(&body).into_owned()));
+                    statuses
+                        .lock()
+                        .unwrap()
+                        .pop_front()
+                        .unwrap_or(StatusCode::OK)
+                }
+            }),
+        );
+
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+        Receiver {
+            url: format!("http://{}/hook", addr),
+            received,
+        }
+    }
+
+    fn test_state(path: Option<PathBuf>) -> AppState {
+        let file =
+            path.map(|path| EncryptedFile::new(path, QUEUE_CONTEXT, Keyring::new([7; 32], &[])));
+        AppState {
+            events: Arc::new(RwLock::new(Outbox::open(file, 10).unwrap())),
+            ..AppState::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let receiver = start_receiver(Vec::new());
+        let state = test_state(None);
+        let (_, secret) = state
+            .events
+            .write()
+            .await
+            .subscribe(&receiver.url, HashSet::from([EventKind::MemberJoined]));
+
+        emit(&state, EventKind::PresenceChanged, &"ignored").await;
+        emit(
+            &state,
+            EventKind::MemberJoined,
+            &serde_json::json!({ "user_id": "alice" }),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        assert_eq!(deliver_due(&state, &client, unix_now()).await, 1);
+
+        let received = receiver.received.lock().unwrap();
+        let (headers, body) = &received[0];
+        let timestamp: u64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            signature(&secret, timestamp, body)
+        );
+        assert_eq!(headers[EVENT_HEADER], "member_joined");
+        let payload: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["type"], "member_joined");
+        assert_eq!(payload["data"]["user_id"], "alice");
+    }
+
+    #[tokio::test]
+    async fn failures_back_off_and_survive_a_restart() {
+        let receiver = start_receiver(vec![StatusCode::INTERNAL_SERVER_ERROR]);
+        let path = std::env::temp_dir().join(format!("rustcord-events-{}.json", random_token(8)));
+        let state = test_state(Some(path.clone()));
+        let (subscription, _) = state
+            .events
+            .write()
+            .await
+            .subscribe(&receiver.url, HashSet::from([EventKind::MessageCreated]));
+        emit(&state, EventKind::MessageCreated, &"hello").await;
+
+        let client = reqwest::Client::new();
+        let now = unix_now();
+        assert_eq!(deliver_due(&state, &client, now).await, 1);
+        // Not due again until the backoff has passed
+        assert_eq!(deliver_due(&state, &client, now).await, 0);
+
+        // Nothing readable is left on disk
+        flush(&state.events).await;
+        let on_disk = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        assert!(!on_disk.contains(&receiver.url) && !on_disk.contains("hello"));
+
+        // A restarted server picks up the same queue and log
+        let restarted = test_state(Some(path.clone()));
+        assert_eq!(
+            restarted.events.read().await.pending_for(&subscription.id),
+            1
+        );
+        assert_eq!(deliver_due(&restarted, &client, now + 10).await, 1);
+
+        let outbox = restarted.events.read().await;
+        assert_eq!(outbox.pending_for(&subscription.id), 0);
+        let outcomes: Vec<(u32, Option<u16>, Outcome)> = outbox
+            .log_for(&subscription.id)
+            .iter()
+            .map(|entry| (entry.attempt, entry.status, entry.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (2, Some(200), Outcome::Delivered),
+                (1, Some(500), Outcome::Retrying)
+            ]
+        );
+        assert_eq!(receiver.received.lock().unwrap().len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn a_dead_endpoint_does_not_hold_up_the_others() {
+        let live = start_receiver(Vec::new());
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let dead_url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let hang = Router::new().route(
+            "/hook",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                StatusCode::OK
+            }),
+        );
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(hang.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let state = test_state(None);
+        let (dead, _) = state
+            .events
+            .write()
+            .await
+            .subscribe(&dead_url, HashSet::from([EventKind::MessageCreated]));
+        for text in ["one", "two", "three"] {
+            emit(&state, EventKind::MessageCreated, &text).await;
+        }
+        state
+            .events
+            .write()
+            .await
+            .subscribe(&live.url, HashSet::from([EventKind::MessageCreated]));
+        emit(&state, EventKind::MessageCreated, &"four").await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        // One try at the dead endpoint, whose other three wait for the next round, and the live one
+        assert_eq!(deliver_due(&state, &client, unix_now()).await, 2);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(live.received.lock().unwrap().len(), 1);
+        assert_eq!(state.events.read().await.pending_for(&dead.id), 4);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_cap() {
+        assert_eq!(backoff_secs(10, 1), 10);
+        assert_eq!(backoff_secs(10, 2), 20);
+        assert_eq!(backoff_secs(10, 4), 80);
+        assert_eq!(backoff_secs(10, 30), MAX_BACKOFF_SECS);
+    }
+}
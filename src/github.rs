@@ -0,0 +1,693 @@
+// src/github.rs
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::history::{self, MessageAuthor, MessageBody};
+use crate::moderation;
+use crate::state::AppState;
+use crate::webserver::require_admin;
+
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+const EVENT_HEADER: &str = "x-github-event";
+// Commits listed for a push; the rest are summed up
+const MAX_LISTED_COMMITS: usize = 5;
+const GITHUB_AVATAR: &str = "https://github.githubassets.com/favicons/favicon.png";
+// Who posts show up as, and who auto-moderation holds them against
+const GITHUB_AUTHOR_ID: &str = "github";
+
+// Event groups a channel can follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GitHubEvent {
+    Push,
+    PullRequest,
+    Issues,
+    // Workflow runs, check suites and commit statuses
+    Ci,
+}
+
+impl GitHubEvent {
+    fn from_header(name: &str) -> Option<Self> {
+        match name {
+            "push" => Some(GitHubEvent::Push),
+            "pull_request" => Some(GitHubEvent::PullRequest),
+            "issues" => Some(GitHubEvent::Issues),
+            "workflow_run" | "check_suite" | "status" => Some(GitHubEvent::Ci),
+            _ => None,
+        }
+    }
+}
+
+// Which events of a repository are posted, and where
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepoFilter {
+    // `owner/name`, or `owner/*` for every repository of an owner
+    pub repo: String,
+    // Empty means every supported event
+    #[serde(default)]
+    pub events: Vec<GitHubEvent>,
+    // Only pushes and CI runs on these branches; empty means all branches
+    #[serde(default)]
+    pub branches: Vec<String>,
+    // Overrides the default channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+impl RepoFilter {
+    fn matches_repo(&self, repo: &str) -> bool {
+        match self.repo.strip_suffix("/*") {
+            Some(owner) => repo
+                .split_once('/')
+                .is_some_and(|(repo_owner, _)| repo_owner.eq_ignore_ascii_case(owner)),
+            None => self.repo.eq_ignore_ascii_case(repo),
+        }
+    }
+}
+
+// Integration settings as configured by guild admins through the REST API
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitHubConfig {
+    // Where events go unless a filter says otherwise; nothing is posted without one
+    #[serde(default)]
+    pub channel: Option<String>,
+    // Empty accepts every repository the webhook is installed on
+    #[serde(default)]
+    pub repos: Vec<RepoFilter>,
+}
+
+impl GitHubConfig {
+    // The channel an event should be posted to, if any
+    fn route(&self, repo: &str, event: GitHubEvent, branch: Option<&str>) -> Option<String> {
+        if self.repos.is_empty() {
+            return self.channel.clone();
+        }
+        let filter = self.repos.iter().find(|filter| {
+            filter.matches_repo(repo)
+                && (filter.events.is_empty() || filter.events.contains(&event))
+                && (filter.branches.is_empty()
+                    || branch.is_none_or(|branch| filter.branches.iter().any(|b| b == branch)))
+        })?;
+        filter.channel.clone().or_else(|| self.channel.clone())
+    }
+}
+
+pub struct GitHubIntegration {
+    // Shared with GitHub when creating the webhook; deliveries are refused without it
+    secret: Option<String>,
+    pub config: GitHubConfig,
+}
+
+pub type SharedGitHub = Arc<RwLock<GitHubIntegration>>;
+
+impl GitHubIntegration {
+    pub fn new(secret: Option<String>, config: GitHubConfig) -> Self {
+        GitHubIntegration { secret, config }
+    }
+
+    // GITHUB_WEBHOOK_SECRET, and GITHUB_WEBHOOK_CHANNEL as the default channel
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        GitHubIntegration::new(
+            var("GITHUB_WEBHOOK_SECRET"),
+            GitHubConfig {
+                channel: var("GITHUB_WEBHOOK_CHANNEL"),
+                repos: Vec::new(),
+            },
+        )
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Check `sha256=<hex>` against an HMAC of the raw body, in constant time
+fn verify_signature(secret: &str, header: &str, body: &[u8]) -> bool {
+    let Some(signature) = header.strip_prefix("sha256=").and_then(decode_hex) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct Commit {
+    id: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct PushPayload {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    commits: Vec<Commit>,
+    compare: Option<String>,
+    sender: Account,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    number: u64,
+    title: String,
+    html_url: String,
+    #[serde(default)]
+    merged: bool,
+}
+
+#[derive(Deserialize)]
+struct PullRequestPayload {
+    action: String,
+    pull_request: PullRequest,
+    sender: Account,
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    number: u64,
+    title: String,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct IssuesPayload {
+    action: String,
+    issue: Issue,
+    sender: Account,
+}
+
+#[derive(Deserialize)]
+struct WorkflowRun {
+    name: Option<String>,
+    head_branch: Option<String>,
+    head_sha: String,
+    conclusion: Option<String>,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct WorkflowRunPayload {
+    action: String,
+    workflow_run: WorkflowRun,
+}
+
+#[derive(Deserialize)]
+struct CheckSuite {
+    head_branch: Option<String>,
+    head_sha: String,
+    conclusion: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CheckSuitePayload {
+    action: String,
+    check_suite: CheckSuite,
+}
+
+#[derive(Deserialize)]
+struct StatusPayload {
+    sha: String,
+    state: String,
+    context: String,
+    target_url: Option<String>,
+    #[serde(default)]
+    branches: Vec<Branch>,
+}
+
+#[derive(Deserialize)]
+struct Branch {
+    name: String,
+}
+
+fn short_sha(sha: &str) -> &str {
+    sha.get(..7).unwrap_or(sha)
+}
+
+// A formatted message and the branch it concerns, or None for events not worth posting
+type Formatted = Option<(String, Option<String>)>;
+
+fn format_push(repo: &str, body: &[u8]) -> serde_json::Result<Formatted> {
+    let push: PushPayload = serde_json::from_slice(body)?;
+    let branch = push
+        .git_ref
+        .strip_prefix("refs/heads/")
+        .unwrap_or(&push.git_ref)
+        .to_string();
+    if push.deleted {
+        let text = format!("[{}] {} deleted {}", repo, push.sender.login, branch);
+        return Ok(Some((text, Some(branch))));
+    }
+    if push.commits.is_empty() {
+        return Ok(None);
+    }
+
+    let count = push.commits.len();
+    let mut text = format!(
+        "[{}] {} pushed {} commit{} to {}",
+        repo,
+        push.sender.login,
+        count,
+        if count == 1 { "" } else { "s" },
+        branch
+    );
+    for commit in push.commits.iter().take(MAX_LISTED_COMMITS) {
+        let summary = commit.message.lines().next().unwrap_or_default();
+        text.push_str(&format!("\n• {} {}", short_sha(&commit.id), summary));
+    }
+    if count > MAX_LISTED_COMMITS {
+        text.push_str(&format!("\n… and {} more", count - MAX_LISTED_COMMITS));
+    }
+    if let Some(compare) = push.compare {
+        text.push_str(&format!("\n{}", compare));
+    }
+    Ok(Some((text, Some(branch))))
+}
+
+fn format_pull_request(repo: &str, body: &[u8]) -> serde_json::Result<Formatted> {
+    let payload: PullRequestPayload = serde_json::from_slice(body)?;
+    let pr = payload.pull_request;
+    let verb = match payload.action.as_str() {
+        "closed" if pr.merged => "merged",
+        "opened" | "closed" | "reopened" => payload.action.as_str(),
+        "ready_for_review" => "marked ready for review",
+        _ => return Ok(None),
+    };
+    let text = format!(
+        "[{}] {} {} pull request #{}: {}\n{}",
+        repo, payload.sender.login, verb, pr.number, pr.title, pr.html_url
+    );
+    Ok(Some((text, None)))
+}
+
+fn format_issue(repo: &str, body: &[u8]) -> serde_json::Result<Formatted> {
+    let payload: IssuesPayload = serde_json::from_slice(body)?;
+    if !matches!(payload.action.as_str(), "opened" | "closed" | "reopened") {
+        return Ok(None);
+    }
+    let issue = payload.issue;
+    let text = format!(
+        "[{}] {} {} issue #{}: {}\n{}",
+        repo, payload.sender.login, payload.action, issue.number, issue.title, issue.html_url
+    );
+    Ok(Some((text, None)))
+}
+
+fn ci_verdict(conclusion: Option<&str>) -> &str {
+    match conclusion {
+        Some("success") => "passed",
+        Some("failure") => "failed",
+        Some("cancelled") => "was cancelled",
+        Some("timed_out") => "timed out",
+        Some(other) => other,
+        None => "finished",
+    }
+}
+
+fn format_ci(event: &str, repo: &str, body: &[u8]) -> serde_json::Result<Formatted> {
+    let (name, branch, sha, verdict, url) = match event {
+        "workflow_run" => {
+            let payload: WorkflowRunPayload = serde_json::from_slice(body)?;
+            if payload.action != "completed" {
+                return Ok(None);
+            }
+            let run = payload.workflow_run;
+            (
+                run.name.unwrap_or_else(|| "Workflow".to_string()),
+                run.head_branch,
+                run.head_sha,
+                ci_verdict(run.conclusion.as_deref()).to_string(),
+                Some(run.html_url),
+            )
+        }
+        "check_suite" => {
+            let payload: CheckSuitePayload = serde_json::from_slice(body)?;
+            if payload.action != "completed" {
+                return Ok(None);
+            }
+            let suite = payload.check_suite;
+            (
+                "Checks".to_string(),
+                suite.head_branch,
+                suite.head_sha,
+                ci_verdict(suite.conclusion.as_deref()).to_string(),
+                None,
+            )
+        }
+        _ => {
+            let payload: StatusPayload = serde_json::from_slice(body)?;
+            let verdict = match payload.state.as_str() {
+                "pending" => return Ok(None),
+                "error" => "errored",
+                state => ci_verdict(Some(state)),
+            }
+            .to_string();
+            (
+                payload.context,
+                payload
+                    .branches
+                    .into_iter()
+                    .next()
+                    .map(|branch| branch.name),
+                payload.sha,
+                verdict,
+                payload.target_url,
+            )
+        }
+    };
+
+    let mut text = format!("[{}] CI {} {}", repo, name, verdict);
+    if let Some(branch) = &branch {
+        text.push_str(&format!(" on {}", branch));
+    }
+    text.push_str(&format!(" ({})", short_sha(&sha)));
+    if let Some(url) = url {
+        text.push_str(&format!("\n{}", url));
+    }
+    Ok(Some((text, branch)))
+}
+
+#[derive(Deserialize)]
+struct RepositoryPayload {
+    repository: Option<Repository>,
+}
+
+// POST /api/integrations/github/webhook, the URL given to GitHub
+async fn receive_event(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let (channel, text) = {
+        let github = state.github.read().await;
+        // Without a secret anyone could post as GitHub, so refuse everything
+        let Some(secret) = github.secret.as_deref() else {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        };
+        if !header(SIGNATURE_HEADER).is_some_and(|sig| verify_signature(secret, sig, &body)) {
+            return StatusCode::UNAUTHORIZED;
+        }
+
+        let Some(event_name) = header(EVENT_HEADER) else {
+            return StatusCode::BAD_REQUEST;
+        };
+        let Some(event) = GitHubEvent::from_header(event_name) else {
+            // Includes the `ping` GitHub sends when the webhook is created
+            return StatusCode::NO_CONTENT;
+        };
+        let Ok(RepositoryPayload {
+            repository: Some(repository),
+        }) = serde_json::from_slice(&body)
+        else {
+            return StatusCode::BAD_REQUEST;
+        };
+        let repo = repository.full_name;
+
+        let formatted = match event {
+            GitHubEvent::Push => format_push(&repo, &body),
+            GitHubEvent::PullRequest => format_pull_request(&repo, &body),
+            GitHubEvent::Issues => format_issue(&repo, &body),
+            GitHubEvent::Ci => format_ci(event_name, &repo, &body),
+        };
+        let (text, branch) = match formatted {
+            Ok(Some(formatted)) => formatted,
+            Ok(None) => return StatusCode::NO_CONTENT,
+            Err(err) => {
+                eprintln!("Unreadable GitHub {} payload: {}", event_name, err);
+                return StatusCode::BAD_REQUEST;
+            }
+        };
+        let Some(channel) = github.config.route(&repo, event, branch.as_deref()) else {
+            return StatusCode::NO_CONTENT;
+        };
+        (channel, text)
+    };
+
+    // Commit messages, titles and branch names come from anyone who can push or open a
+    // pull request, so they pass the same rules as incoming webhooks
+    if !moderation::screen(&state, GITHUB_AUTHOR_ID, &channel, Some(&text))
+        .await
+        .relays()
+    {
+        return StatusCode::FORBIDDEN;
+    }
+
+    history::publish(
+        &state,
+        None,
+        &channel,
+        MessageAuthor {
+            id: GITHUB_AUTHOR_ID.to_string(),
+            name: "GitHub".to_string(),
+            avatar: Some(GITHUB_AVATAR.to_string()),
+            webhook_id: None,
+        },
+        MessageBody::Content(text),
+        None,
+    )
+    .await;
+    StatusCode::ACCEPTED
+}
+
+// GET /api/integrations/github
+async fn get_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<GitHubConfig>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    Ok(Json(state.github.read().await.config.clone()))
+}
+
+// PUT /api/integrations/github
+async fn put_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(config): Json<GitHubConfig>,
+) -> Result<Json<GitHubConfig>, (StatusCode, String)> {
+    require_admin(&state, &headers)
+        .await
+        .map_err(|status| (status, "Admin token required".to_string()))?;
+
+    if let Some(filter) = config
+        .repos
+        .iter()
+        .find(|filter| filter.repo.split('/').count() != 2)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} is not an owner/name repository", filter.repo),
+        ));
+    }
+
+    state.github.write().await.config = config.clone();
+    println!(
+        "GitHub integration updated ({} repo filters)",
+        config.repos.len()
+    );
+    Ok(Json(config))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/integrations/github", get(get_config).put(put_config))
+        .route("/api/integrations/github/webhook", post(receive_event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+
+    fn test_state(config: GitHubConfig) -> AppState {
+        AppState {
+            github: Arc::new(RwLock::new(GitHubIntegration::new(
+                Some(SECRET.to_string()),
+                config,
+            ))),
+            ..AppState::new()
+        }
+    }
+
+    async fn deliver(state: &AppState, event: &str, payload: serde_json::Value) -> StatusCode {
+        let body = payload.to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        let signature = format!("sha256={:x}", mac.finalize().into_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(EVENT_HEADER, HeaderValue::from_str(event).unwrap());
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+        receive_event(State(state.clone()), headers, Bytes::from(body)).await
+    }
+
+    async fn posted(state: &AppState, channel: &str) -> Vec<String> {
+        state
+            .history
+            .read()
+            .await
+            .page(channel, None, 10)
+            .iter()
+            .map(|message| match &message.body {
+                MessageBody::Content(text) => text.clone(),
+                MessageBody::Ciphertext(_) => panic!("GitHub posts are not encrypted"),
+            })
+            .collect()
+    }
+
+    fn push(repo: &str, branch: &str) -> serde_json::Value {
+        json!({
+            "ref": format!("refs/heads/{}", branch),
+            "repository": { "full_name": repo },
+            "sender": { "login": "ferris" },
+            "commits": [{ "id": "0123456789abcdef", "message": "Fix the build\n\nDetails" }],
+            "compare": "https://github.com/rust-lang/rust/compare/a...b"
+        })
+    }
+
+    #[test]
+    fn github_signature_example_verifies() {
+        // The example from GitHub's webhook documentation
+        assert!(verify_signature(
+            SECRET,
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+            b"Hello, World!"
+        ));
+        assert!(!verify_signature(SECRET, "sha256=00", b"Hello, World!"));
+    }
+
+    #[tokio::test]
+    async fn unsigned_deliveries_are_refused() {
+        let state = test_state(GitHubConfig {
+            channel: Some("dev".to_string()),
+            repos: Vec::new(),
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(EVENT_HEADER, HeaderValue::from_static("push"));
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_static("sha256=beef"));
+        let body = Bytes::from(push("rust-lang/rust", "main").to_string());
+
+        assert_eq!(
+            receive_event(State(state.clone()), headers, body).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(posted(&state, "dev").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn posts_go_through_auto_moderation() {
+        let state = test_state(GitHubConfig {
+            channel: Some("dev".to_string()),
+            repos: Vec::new(),
+        });
+        let config = serde_json::from_value(json!({
+            "rules": [{ "name": "words", "kind": "keywords", "words": ["build"], "action": "block" }]
+        }))
+        .unwrap();
+        state.moderation.write().await.apply_config(config).unwrap();
+
+        assert_eq!(
+            deliver(&state, "push", push("rust-lang/rust", "main")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert!(posted(&state, "dev").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_are_formatted_and_routed_by_repo_filters() {
+        let state = test_state(GitHubConfig {
+            channel: Some("dev".to_string()),
+            repos: vec![
+                RepoFilter {
+                    repo: "rust-lang/rust".to_string(),
+                    events: vec![GitHubEvent::Push],
+                    branches: vec!["main".to_string()],
+                    channel: None,
+                },
+                RepoFilter {
+                    repo: "rust-lang/*".to_string(),
+                    events: vec![GitHubEvent::PullRequest],
+                    branches: Vec::new(),
+                    channel: Some("reviews".to_string()),
+                },
+            ],
+        });
+
+        assert_eq!(
+            deliver(&state, "push", push("rust-lang/rust", "main")).await,
+            StatusCode::ACCEPTED
+        );
+        // Other branches and unlisted repositories are filtered out
+        assert_eq!(
+            deliver(&state, "push", push("rust-lang/rust", "feature")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            deliver(&state, "push", push("someone/else", "main")).await,
+            StatusCode::NO_CONTENT
+        );
+        let pull_request = json!({
+            "action": "closed",
+            "repository": { "full_name": "rust-lang/cargo" },
+            "sender": { "login": "ferris" },
+            "pull_request": {
+                "number": 7,
+                "title": "Faster builds",
+                "html_url": "https://github.com/rust-lang/cargo/pull/7",
+                "merged": true
+            }
+        });
+        assert_eq!(
+            deliver(&state, "pull_request", pull_request).await,
+            StatusCode::ACCEPTED
+        );
+
+        assert_eq!(
+            posted(&state, "dev").await,
+            ["[rust-lang/rust] ferris pushed 1 commit to main\n\
+                 • 0123456 Fix the build\n\
+                 https://github.com/rust-lang/rust/compare/a...b"]
+        );
+        assert_eq!(
+            posted(&state, "reviews").await,
+            [
+                "[rust-lang/cargo] ferris merged pull request #7: Faster builds\n\
+                 https://github.com/rust-lang/cargo/pull/7"
+            ]
+        );
+    }
+}
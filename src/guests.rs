@@ -0,0 +1,291 @@
+// src/guests.rs
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+use crate::accounts::random_token;
+use crate::auth::{OAuthProvider, OAuthUser};
+use crate::events::{self, EventKind};
+use crate::invites::{InviteError, InviteTarget};
+use crate::keys;
+use crate::session::{self, SessionResponse, disconnect_sessions, session_response};
+use crate::state::AppState;
+use crate::unix_now;
+
+const DEFAULT_GUEST_TTL_SECS: u64 = 24 * 60 * 60;
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Provider slug for guest sessions; guests have no identity anywhere else
+pub const GUEST_PROVIDER: &str = "guest";
+
+const ADJECTIVES: &[&str] = &[
+    "Amber", "Brave", "Calm", "Clever", "Copper", "Swift", "Gentle", "Lucky", "Misty", "Quiet",
+    "Rusty", "Silver", "Sunny", "Witty",
+];
+const ANIMALS: &[&str] = &[
+    "Badger", "Crab", "Falcon", "Fox", "Heron", "Koala", "Lynx", "Otter", "Owl", "Panda", "Puffin",
+    "Seal", "Walrus", "Wren",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuestError {
+    Invite(InviteError),
+    Internal,
+}
+
+impl GuestError {
+    // Stable code handed to the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            GuestError::Invite(err) => err.code(),
+            GuestError::Internal => "internal_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            GuestError::Invite(InviteError::NotFound) => StatusCode::NOT_FOUND,
+            GuestError::Invite(InviteError::WrongKind) => StatusCode::BAD_REQUEST,
+            GuestError::Invite(_) => StatusCode::FORBIDDEN,
+            GuestError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for GuestError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(json!({ "error": self.code() }))).into_response()
+    }
+}
+
+// How long a guest account lasts, from GUEST_TTL_SECS
+fn guest_ttl_secs() -> u64 {
+    std::env::var("GUEST_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_GUEST_TTL_SECS)
+}
+
+// e.g. "Quiet Otter 42 (guest)", so nobody mistakes a guest for a member
+fn generate_display_name() -> String {
+    let mut rng = rand::thread_rng();
+    format!(
+        "{} {} {} (guest)",
+        ADJECTIVES[rng.gen_range(0..ADJECTIVES.len())],
+        ANIMALS[rng.gen_range(0..ANIMALS.len())],
+        rng.gen_range(10..100)
+    )
+}
+
+#[derive(Deserialize)]
+struct JoinRequest {
+    invite: String,
+}
+
+#[derive(Serialize)]
+struct GuestSession {
+    #[serde(flatten)]
+    session: SessionResponse,
+    channel: String,
+}
+
+// POST /api/guests, trades a guest invite for a session limited to its channel
+async fn join_as_guest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<JoinRequest>,
+) -> Result<Json<GuestSession>, GuestError> {
+    let target = state
+        .invites
+        .write()
+        .await
+        .redeem_guest(&request.invite)
+        .map_err(GuestError::Invite)?;
+    // Guest invites are checked to target a channel when they are created
+    let InviteTarget::Channel {
+        channel_id: channel,
+    } = target
+    else {
+        return Err(GuestError::Invite(InviteError::WrongKind));
+    };
+
+    let id = random_token(16);
+    let user_id = format!("{}_{}", GUEST_PROVIDER, id);
+    let identity = OAuthUser {
+        id,
+        username: generate_display_name(),
+        email: String::new(),
+        email_verified: false,
+        avatar: None,
+        provider: OAuthProvider::new(GUEST_PROVIDER),
+    };
+    let expires_at = unix_now() + guest_ttl_secs();
+
+    state
+        .guild
+        .write()
+        .await
+        .add_guest(&user_id, &channel, expires_at);
+    let (token, session) = state
+        .sessions
+        .write()
+        .await
+        .start_until(
+            &user_id,
+            identity,
+            session::device(&headers),
+            Some(expires_at),
+        )
+        .map_err(|_| GuestError::Internal)?;
+
+    println!("Guest {} joined #{}", user_id, channel);
+    events::emit(
+        &state,
+        EventKind::MemberJoined,
+        &json!({ "user_id": user_id, "channel": channel, "guest": true }),
+    )
+    .await;
+    Ok(Json(GuestSession {
+        session: session_response(token, &session).0,
+        channel,
+    }))
+}
+
+// Drop guests whose access ran out, along with their sessions, connections and keys
+pub async fn expire_guests(state: &AppState) {
+    let expired = state.guild.write().await.remove_expired_guests(unix_now());
+    if expired.is_empty() {
+        return;
+    }
+    for user_id in &expired {
+        let revoked = state.sessions.write().await.revoke_all(user_id);
+        disconnect_sessions(state, &revoked).await;
+        println!("Guest {} expired", user_id);
+    }
+    keys::rotate_after_membership_change(state).await;
+}
+
+// Sweep expired guests in the background for as long as the server runs
+pub fn spawn_expiry(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            expire_guests(&state).await;
+        }
+    });
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/api/guests", post(join_as_guest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn channel_invite(state: &AppState, channel: &str, guest_access: bool) -> String {
+        state
+            .invites
+            .write()
+            .await
+            .create(
+                InviteTarget::Channel {
+                    channel_id: channel.to_string(),
+                },
+                None,
+                None,
+                guest_access,
+            )
+            .code
+    }
+
+    async fn join(state: &AppState, invite: &str) -> Result<GuestSession, GuestError> {
+        join_as_guest(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(JoinRequest {
+                invite: invite.to_string(),
+            }),
+        )
+        .await
+        .map(|Json(session)| session)
+    }
+
+    #[tokio::test]
+    async fn guests_only_see_the_channel_they_were_invited_to() {
+        let state = AppState::new();
+        let invite = channel_invite(&state, "support", true).await;
+
+        let joined = join(&state, &invite).await.expect("guest session");
+        assert_eq!(joined.channel, "support");
+        assert_eq!(joined.session.user.provider, GUEST_PROVIDER);
+        assert!(joined.session.user.username.ends_with("(guest)"));
+
+        let guild = state.guild.read().await;
+        let guest = joined.session.user.id.as_str();
+        assert!(guild.is_guest(guest));
+        assert!(guild.can_access_channel("support", Some(guest)));
+        // Even channels every member can read are closed to guests
+        assert!(!guild.can_access_channel("general", Some(guest)));
+        assert!(!guild.is_moderator(guest));
+    }
+
+    #[tokio::test]
+    async fn member_and_guest_invites_are_not_interchangeable() {
+        let state = AppState::new();
+        let member_invite = channel_invite(&state, "support", false).await;
+        let guest_invite = channel_invite(&state, "support", true).await;
+
+        assert_eq!(
+            join(&state, &member_invite).await.err(),
+            Some(GuestError::Invite(InviteError::WrongKind))
+        );
+        assert_eq!(
+            state.invites.write().await.redeem(&guest_invite),
+            Err(InviteError::WrongKind)
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_guests_lose_access_and_their_sessions() {
+        let state = AppState::new();
+        let invite = channel_invite(&state, "support", true).await;
+        let joined = join(&state, &invite).await.expect("guest session");
+        let guest = joined.session.user.id.clone();
+
+        // Pretend a day went by
+        state
+            .guild
+            .write()
+            .await
+            .add_guest(&guest, "support", unix_now() - 1);
+        assert!(
+            !state
+                .guild
+                .read()
+                .await
+                .can_access_channel("support", Some(&guest))
+        );
+
+        expire_guests(&state).await;
+        assert!(!state.guild.read().await.is_guest(&guest));
+        assert!(
+            state
+                .sessions
+                .read()
+                .await
+                .verify(&joined.session.token)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,282 @@
+// src/guild.rs
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::unix_now;
+
+// Roles a user can hold in the guild
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Moderator,
+    Member,
+}
+
+// What a guest account may see, and until when
+#[derive(Debug, Clone)]
+pub struct GuestAccess {
+    pub channels: HashSet<String>,
+    pub expires_at: u64,
+}
+
+// The guild served by this instance, its members and private channels
+#[derive(Debug, Clone, Default)]
+pub struct Guild {
+    roles: HashMap<String, Role>,
+    members: HashSet<String>,
+    private_channels: HashMap<String, HashSet<String>>,
+    // Guests are not members; they only get the channels they were invited to
+    guests: HashMap<String, GuestAccess>,
+    // Kicked users stay out, open guild or not, until they are let back in
+    kicked: HashSet<String>,
+    invite_only: bool,
+    // Moderators and admins must pass a second factor to log in
+    require_moderator_2fa: bool,
+}
+
+pub type SharedGuild = Arc<RwLock<Guild>>;
+
+// Direct messages go through `dm:<user id>:<user id>` channels
+fn dm_participants(channel: &str) -> Option<(&str, &str)> {
+    channel.strip_prefix("dm:")?.split_once(':')
+}
+
+fn env_list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .map(|ids| {
+            ids.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Guild {
+    // Build the guild from GUILD_ADMINS / GUILD_MODERATORS (comma separated user ids),
+    // GUILD_PRIVATE_CHANNELS, GUILD_INVITE_ONLY and GUILD_REQUIRE_MODERATOR_2FA
+    pub fn from_env() -> Self {
+        let flag = |var: &str| {
+            std::env::var(var)
+                .map(|value| value == "true" || value == "1")
+                .unwrap_or(false)
+        };
+        let mut guild = Guild {
+            invite_only: flag("GUILD_INVITE_ONLY"),
+            require_moderator_2fa: flag("GUILD_REQUIRE_MODERATOR_2FA"),
+            ..Guild::default()
+        };
+
+        for (var, role) in [
+            ("GUILD_MODERATORS", Role::Moderator),
+            ("GUILD_ADMINS", Role::Admin),
+        ] {
+            for id in env_list(var) {
+                guild.roles.insert(id, role);
+            }
+        }
+
+        for channel in env_list("GUILD_PRIVATE_CHANNELS") {
+            guild.private_channels.insert(channel, HashSet::new());
+        }
+
+        guild
+    }
+
+    pub fn is_invite_only(&self) -> bool {
+        self.invite_only
+    }
+
+    pub fn requires_moderator_2fa(&self) -> bool {
+        self.require_moderator_2fa
+    }
+
+    pub fn set_require_moderator_2fa(&mut self, required: bool) {
+        self.require_moderator_2fa = required;
+    }
+
+    // Whether this user may only log in with a second factor
+    pub fn requires_2fa(&self, user_id: &str) -> bool {
+        self.require_moderator_2fa && self.is_moderator(user_id)
+    }
+
+    pub fn role_of(&self, user_id: &str) -> Role {
+        self.roles.get(user_id).copied().unwrap_or(Role::Member)
+    }
+
+    #[cfg(test)]
+    pub fn set_role(&mut self, user_id: &str, role: Role) {
+        self.roles.insert(user_id.to_string(), role);
+    }
+
+    #[cfg(test)]
+    pub fn set_invite_only(&mut self, invite_only: bool) {
+        self.invite_only = invite_only;
+    }
+
+    #[cfg(test)]
+    pub fn add_private_channel(&mut self, channel: &str) {
+        self.private_channels
+            .insert(channel.to_string(), HashSet::new());
+    }
+
+    // Admins can moderate too
+    pub fn is_moderator(&self, user_id: &str) -> bool {
+        matches!(self.role_of(user_id), Role::Admin | Role::Moderator)
+    }
+
+    // Open guilds let everyone not kicked in; invite-only guilds need an invite or a role.
+    // Guests count until their access expires
+    pub fn is_member(&self, user_id: &str) -> bool {
+        if self.kicked.contains(user_id) {
+            return false;
+        }
+        if let Some(access) = self.guests.get(user_id) {
+            return access.expires_at > unix_now();
+        }
+        !self.invite_only || self.members.contains(user_id) || self.roles.contains_key(user_id)
+    }
+
+    pub fn is_guest(&self, user_id: &str) -> bool {
+        self.guests.contains_key(user_id)
+    }
+
+    pub fn add_guest(&mut self, user_id: &str, channel: &str, expires_at: u64) {
+        self.guests.insert(
+            user_id.to_string(),
+            GuestAccess {
+                channels: HashSet::from([channel.to_string()]),
+                expires_at,
+            },
+        );
+    }
+
+    // Forget guests whose access ran out and return their ids
+    pub fn remove_expired_guests(&mut self, now: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .guests
+            .iter()
+            .filter(|(_, access)| access.expires_at <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.guests.remove(id);
+        }
+        expired
+    }
+
+    // Also lets a kicked user back in
+    pub fn add_member(&mut self, user_id: &str) {
+        self.kicked.remove(user_id);
+        self.members.insert(user_id.to_string());
+    }
+
+    // Joining a channel implies guild membership; public channels need nothing else
+    pub fn grant_channel(&mut self, channel: &str, user_id: &str) {
+        self.add_member(user_id);
+        if let Some(members) = self.private_channels.get_mut(channel) {
+            members.insert(user_id.to_string());
+        }
+    }
+
+    // Take a private channel away from one member; false if they were not in it
+    pub fn revoke_channel(&mut self, channel: &str, user_id: &str) -> bool {
+        self.private_channels
+            .get_mut(channel)
+            .is_some_and(|members| members.remove(user_id))
+    }
+
+    // Take the guild away from a member or guest, with their role and every private
+    // channel they were in; false if they were already out
+    pub fn remove_member(&mut self, user_id: &str) -> bool {
+        self.members.remove(user_id);
+        self.guests.remove(user_id);
+        self.roles.remove(user_id);
+        let mut removed = self.kicked.insert(user_id.to_string());
+        for members in self.private_channels.values_mut() {
+            removed |= members.remove(user_id);
+        }
+        removed
+    }
+
+    pub fn can_access_channel(&self, channel: &str, user_id: Option<&str>) -> bool {
+        // Guests are limited to their own channels, public or not
+        if let Some(access) = user_id.and_then(|id| self.guests.get(id)) {
+            return access.expires_at > unix_now() && access.channels.contains(channel);
+        }
+        // Only the two users of a DM can open it, not even moderators
+        if let Some((first, second)) = dm_participants(channel) {
+            return user_id.is_some_and(|id| (id == first || id == second) && self.is_member(id));
+        }
+        match self.private_channels.get(channel) {
+            // Public to the guild, which in an invite-only guild is not everyone
+            None => user_id.is_some_and(|id| self.is_member(id)),
+            Some(members) => {
+                user_id.is_some_and(|id| members.contains(id) || self.is_moderator(id))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_channels_need_membership_in_invite_only_guilds() {
+        let mut guild = Guild::default();
+        assert!(guild.can_access_channel("general", Some("anyone")));
+        assert!(!guild.can_access_channel("general", None));
+
+        guild.set_invite_only(true);
+        assert!(!guild.can_access_channel("general", Some("stranger")));
+        assert!(!guild.can_access_channel("general", None));
+
+        guild.add_member("alice");
+        guild.set_role("mod", Role::Moderator);
+        assert!(guild.can_access_channel("general", Some("alice")));
+        assert!(guild.can_access_channel("general", Some("mod")));
+
+        assert!(guild.remove_member("alice"));
+        assert!(!guild.can_access_channel("general", Some("alice")));
+    }
+
+    #[test]
+    fn kicks_hold_in_open_guilds() {
+        let mut guild = Guild::default();
+        assert!(guild.is_member("alice"));
+
+        assert!(guild.remove_member("alice"));
+        assert!(!guild.remove_member("alice"));
+        assert!(!guild.is_member("alice"));
+        assert!(!guild.can_access_channel("general", Some("alice")));
+        assert!(guild.can_access_channel("general", Some("bob")));
+
+        // An invite lets them back in
+        guild.add_member("alice");
+        assert!(guild.is_member("alice"));
+    }
+
+    #[test]
+    fn private_channels_and_dms_stay_closed() {
+        let mut guild = Guild::default();
+        guild.set_invite_only(true);
+        guild.add_private_channel("staff");
+        guild.set_role("mod", Role::Moderator);
+        guild.add_member("alice");
+        guild.add_member("bob");
+
+        assert!(!guild.can_access_channel("staff", Some("alice")));
+        assert!(guild.can_access_channel("staff", Some("mod")));
+        guild.grant_channel("staff", "alice");
+        assert!(guild.can_access_channel("staff", Some("alice")));
+
+        assert!(guild.can_access_channel("dm:alice:bob", Some("bob")));
+        assert!(!guild.can_access_channel("dm:alice:bob", Some("mod")));
+        assert!(!guild.can_access_channel("dm:alice:carol", Some("carol")));
+    }
+}
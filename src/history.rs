@@ -0,0 +1,458 @@
+// src/history.rs
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use crate::at_rest::{AtRestError, EncryptedFile, Keyring};
+use crate::bots::{BotScope, authenticate_bot};
+use crate::envelope;
+use crate::events::{self, EventKind};
+use crate::session;
+use crate::signing::MessageSignature;
+use crate::state::AppState;
+use crate::{ServerMessage, send_where, unix_now};
+
+const DEFAULT_MAX_MESSAGES_PER_CHANNEL: usize = 1000;
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+const HISTORY_CONTEXT: &str = "history";
+// How often new messages are written out when HISTORY_PATH is set
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Who a message is shown as
+#[derive(Debug, Clone)]
+pub struct MessageAuthor {
+    pub id: String,
+    pub name: String,
+    pub avatar: Option<String>,
+    // Set when the message came in through an incoming webhook
+    pub webhook_id: Option<String>,
+}
+
+// What a message carries: ciphertext only the channel's members can read, or
+// content from an integration, which the server wrote and so cannot hide from itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageBody {
+    Ciphertext(String),
+    Content(String),
+}
+
+// A channel message as kept and served back; the server cannot read user messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub channel: String,
+    pub author_id: String,
+    pub author: String,
+    pub avatar: Option<String>,
+    pub webhook_id: Option<String>,
+    #[serde(flatten)]
+    pub body: MessageBody,
+    // The group key epoch the ciphertext was sealed with, for fetching the right key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<u32>,
+    // The author's device signature over the ciphertext, for readers to check
+    #[serde(flatten)]
+    pub signature: Option<MessageSignature>,
+    pub sent_at: u64,
+}
+
+impl StoredMessage {
+    pub fn envelope(&self) -> ServerMessage {
+        ServerMessage::ChatMessage {
+            id: self.id,
+            channel: self.channel.clone(),
+            author_id: self.author_id.clone(),
+            author: self.author.clone(),
+            avatar: self.avatar.clone(),
+            body: self.body.clone(),
+            epoch: self.epoch,
+            signature: self.signature.clone(),
+            sent_at: self.sent_at,
+        }
+    }
+}
+
+// What is kept across restarts when HISTORY_PATH is set
+#[derive(Serialize, Deserialize)]
+struct Persisted {
+    channels: HashMap<String, VecDeque<StoredMessage>>,
+    next_id: u64,
+}
+
+// The most recent messages of every channel, oldest first
+pub struct History {
+    data: Persisted,
+    file: Option<Arc<EncryptedFile>>,
+    // Something changed since the file was last written
+    dirty: bool,
+    max_per_channel: usize,
+}
+
+pub type SharedHistory = Arc<RwLock<History>>;
+
+impl History {
+    pub fn new(max_per_channel: usize) -> Self {
+        History {
+            data: Persisted {
+                channels: HashMap::new(),
+                next_id: 1,
+            },
+            file: None,
+            dirty: false,
+            max_per_channel: max_per_channel.max(1),
+        }
+    }
+
+    // Load what a previous run left behind in `file` and keep writing to it.
+    // History that cannot be opened is an error rather than a fresh start that would overwrite it
+    pub fn open(file: Option<EncryptedFile>, max_per_channel: usize) -> Result<Self, AtRestError> {
+        let mut history = History::new(max_per_channel);
+        if let Some(bytes) = file
+            .as_ref()
+            .map(EncryptedFile::read)
+            .transpose()?
+            .flatten()
+        {
+            history.data = serde_json::from_slice(&bytes).map_err(|_| AtRestError::Corrupt)?;
+            for messages in history.data.channels.values_mut() {
+                while messages.len() > history.max_per_channel {
+                    messages.pop_front();
+                }
+            }
+        }
+        history.file = file.map(Arc::new);
+        Ok(history)
+    }
+
+    // Keep HISTORY_MAX_MESSAGES per channel, in memory unless HISTORY_PATH is set.
+    // Keeping history on disk needs STORAGE_MASTER_KEY; without it the server refuses
+    // to start rather than write messages to disk in the clear
+    pub fn from_env() -> Self {
+        let max_per_channel = std::env::var("HISTORY_MAX_MESSAGES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_MESSAGES_PER_CHANNEL);
+        let file = std::env::var("HISTORY_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(|path| {
+                let keyring = Keyring::from_env().unwrap_or_else(|err| {
+                    panic!(
+                        "HISTORY_PATH is set but the storage key is unusable: {:?}",
+                        err
+                    )
+                });
+                EncryptedFile::new(PathBuf::from(path), HISTORY_CONTEXT, keyring)
+            });
+        History::open(file, max_per_channel)
+            .unwrap_or_else(|err| panic!("Cannot open the message history: {:?}", err))
+    }
+
+    // What to write out, if anything changed since the last time
+    fn snapshot(&mut self) -> Option<(Arc<EncryptedFile>, Vec<u8>)> {
+        let file = self.file.clone().filter(|_| self.dirty)?;
+        match serde_json::to_vec(&self.data) {
+            Ok(bytes) => {
+                self.dirty = false;
+                Some((file, bytes))
+            }
+            Err(err) => {
+                eprintln!("Failed to serialize message history: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        channel: &str,
+        author: MessageAuthor,
+        body: MessageBody,
+        signature: Option<MessageSignature>,
+    ) -> StoredMessage {
+        let epoch = match &body {
+            MessageBody::Ciphertext(ciphertext) => envelope::format(ciphertext)
+                .ok()
+                .and_then(|format| format.epoch()),
+            MessageBody::Content(_) => None,
+        };
+        let message = StoredMessage {
+            id: self.data.next_id,
+            channel: channel.to_string(),
+            author_id: author.id,
+            author: author.name,
+            avatar: author.avatar,
+            webhook_id: author.webhook_id,
+            body,
+            epoch,
+            signature,
+            sent_at: unix_now(),
+        };
+        self.data.next_id += 1;
+
+        let messages = self.data.channels.entry(channel.to_string()).or_default();
+        messages.push_back(message.clone());
+        while messages.len() > self.max_per_channel {
+            messages.pop_front();
+        }
+        self.dirty = true;
+        message
+    }
+
+    // Up to `limit` messages older than `before`, oldest first
+    pub fn page(&self, channel: &str, before: Option<u64>, limit: usize) -> Vec<StoredMessage> {
+        let Some(messages) = self.data.channels.get(channel) else {
+            return Vec::new();
+        };
+        let mut page: Vec<StoredMessage> = messages
+            .iter()
+            .rev()
+            .filter(|message| before.is_none_or(|before| message.id < before))
+            .take(limit)
+            .cloned()
+            .collect();
+        page.reverse();
+        page
+    }
+}
+
+// Write new messages to HISTORY_PATH. Only serializing happens under the lock
+pub async fn flush(history: &RwLock<History>) {
+    let Some((file, bytes)) = history.write().await.snapshot() else {
+        return;
+    };
+    if let Err(err) = file.write_blocking(bytes).await {
+        eprintln!("Failed to persist message history: {}", err);
+        // Try again on the next flush
+        history.write().await.dirty = true;
+    }
+}
+
+// Flush the history in the background for as long as the server runs
+pub fn spawn_flusher(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush(&state.history).await;
+        }
+    });
+}
+
+// Store a channel message and relay it to everyone else who can read the channel
+pub async fn publish(
+    state: &AppState,
+    origin: Option<SocketAddr>,
+    channel: &str,
+    author: MessageAuthor,
+    body: MessageBody,
+    signature: Option<MessageSignature>,
+) -> StoredMessage {
+    let message = state
+        .history
+        .write()
+        .await
+        .record(channel, author, body, signature);
+    events::emit(state, EventKind::MessageCreated, &message).await;
+
+    let guild = state.guild.read().await;
+    send_where(
+        &state.clients,
+        &message.envelope(),
+        |client_addr, client| {
+            Some(*client_addr) != origin
+                && client.allows(BotScope::ReadMessages)
+                && guild.can_access_channel(channel, Some(&client.user_id))
+        },
+    )
+    .await;
+    message
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+// The user behind a session, or a bot allowed to read messages
+pub async fn authenticate_reader(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<String, StatusCode> {
+    match session::authenticate(state, headers).await {
+        Ok(session) => Ok(session.user_id),
+        Err(_) => Ok(authenticate_bot(state, headers, BotScope::ReadMessages)
+            .await?
+            .id),
+    }
+}
+
+// GET /api/channels/:channel/messages, for users and bots that can read the channel
+async fn channel_messages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+    Query(query): Query<PageQuery>,
+) -> Result<Json<Vec<StoredMessage>>, StatusCode> {
+    let reader = authenticate_reader(&state, &headers).await?;
+    if !state
+        .guild
+        .read()
+        .await
+        .can_access_channel(&channel, Some(&reader))
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    Ok(Json(state.history.read().await.page(
+        &channel,
+        query.before,
+        limit,
+    )))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/api/channels/:channel/messages", get(channel_messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn author(name: &str) -> MessageAuthor {
+        MessageAuthor {
+            id: name.to_string(),
+            name: name.to_string(),
+            avatar: None,
+            webhook_id: None,
+        }
+    }
+
+    #[test]
+    fn pages_walk_back_through_a_capped_channel() {
+        let mut history = History::new(3);
+        for text in ["one", "two", "three", "four"] {
+            history.record(
+                "general",
+                author("alice"),
+                MessageBody::Ciphertext(text.to_string()),
+                None,
+            );
+        }
+        history.record(
+            "random",
+            author("bob"),
+            MessageBody::Ciphertext("elsewhere".to_string()),
+            None,
+        );
+
+        let texts = |page: Vec<StoredMessage>| -> Vec<MessageBody> {
+            page.into_iter().map(|message| message.body).collect()
+        };
+        let sealed = |texts: &[&str]| -> Vec<MessageBody> {
+            texts
+                .iter()
+                .map(|text| MessageBody::Ciphertext(text.to_string()))
+                .collect()
+        };
+        let latest = history.page("general", None, 2);
+        assert_eq!(texts(latest.clone()), sealed(&["three", "four"]));
+        // The oldest message fell off the end
+        assert_eq!(
+            texts(history.page("general", Some(latest[0].id), 10)),
+            sealed(&["two"])
+        );
+        assert!(history.page("support", None, 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_survives_a_restart_encrypted() {
+        let path = std::env::temp_dir().join(format!(
+            "rustcord-history-{}.json",
+            crate::accounts::random_token(8)
+        ));
+        let file = |key: u8| {
+            EncryptedFile::new(path.clone(), HISTORY_CONTEXT, Keyring::new([key; 32], &[]))
+        };
+        let signature = MessageSignature {
+            device_key: "key".to_string(),
+            signature: "sig".to_string(),
+        };
+
+        let history = RwLock::new(History::open(Some(file(7)), 2).unwrap());
+        {
+            let mut history = history.write().await;
+            history.record(
+                "general",
+                author("alice"),
+                MessageBody::Content("dropped".to_string()),
+                None,
+            );
+            history.record(
+                "general",
+                author("alice"),
+                MessageBody::Ciphertext("sealed".to_string()),
+                Some(signature.clone()),
+            );
+            history.record(
+                "general",
+                author("hook"),
+                MessageBody::Content("plain text".to_string()),
+                None,
+            );
+        }
+        // Recording only marks the history dirty; the flush writes it
+        assert!(!path.exists());
+        flush(&history).await;
+        let on_disk = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        assert!(!on_disk.contains("plain text") && !on_disk.contains("alice"));
+
+        let mut restarted = History::open(Some(file(7)), 2).unwrap();
+        let page = restarted.page("general", None, 10);
+        assert_eq!(
+            page.iter().map(|message| &message.body).collect::<Vec<_>>(),
+            [
+                &MessageBody::Ciphertext("sealed".to_string()),
+                &MessageBody::Content("plain text".to_string())
+            ]
+        );
+        assert_eq!(page[0].signature, Some(signature));
+        // Ids carry on where the last run stopped
+        let next = restarted.record(
+            "general",
+            author("bob"),
+            MessageBody::Ciphertext("more".to_string()),
+            None,
+        );
+        assert_eq!(next.id, 4);
+
+        // Under another key it does not open, rather than starting over
+        assert!(History::open(Some(file(8)), 2).is_err());
+        assert_eq!(
+            History::open(Some(file(7)), 2)
+                .unwrap()
+                .page("general", None, 10)
+                .len(),
+            2
+        );
+        let _ = std::fs::remove_file(path);
+    }
+}
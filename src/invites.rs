@@ -0,0 +1,356 @@
+// src/invites.rs
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, post},
+};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::events::{self, EventKind};
+use crate::state::AppState;
+use crate::unix_now;
+use crate::webserver::{public_base_url, require_admin};
+
+const INVITE_CODE_LENGTH: usize = 10;
+
+// What redeeming an invite grants
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InviteTarget {
+    Guild,
+    Channel { channel_id: String },
+}
+
+impl InviteTarget {
+    // Channel the user should land in after redeeming
+    pub fn landing_channel(&self) -> Option<&str> {
+        match self {
+            InviteTarget::Guild => None,
+            InviteTarget::Channel { channel_id } => Some(channel_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub target: InviteTarget,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub revoked: bool,
+    // Redeemed through /api/guests for a temporary guest account, never by members
+    #[serde(default)]
+    pub guest_access: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteError {
+    NotFound,
+    Expired,
+    Exhausted,
+    Revoked,
+    // A guest invite used to log in, or a member invite used to join as a guest
+    WrongKind,
+}
+
+impl InviteError {
+    // Stable code handed to the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            InviteError::NotFound => "invite_not_found",
+            InviteError::Expired => "invite_expired",
+            InviteError::Exhausted => "invite_exhausted",
+            InviteError::Revoked => "invite_revoked",
+            InviteError::WrongKind => "invite_wrong_kind",
+        }
+    }
+}
+
+impl Invite {
+    fn check(&self, now: u64) -> Result<(), InviteError> {
+        if self.revoked {
+            return Err(InviteError::Revoked);
+        }
+        if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(InviteError::Expired);
+        }
+        if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) {
+            return Err(InviteError::Exhausted);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InviteStore {
+    invites: HashMap<String, Invite>,
+}
+
+pub type SharedInvites = Arc<RwLock<InviteStore>>;
+
+impl InviteStore {
+    pub fn create(
+        &mut self,
+        target: InviteTarget,
+        max_uses: Option<u32>,
+        expires_in_secs: Option<u64>,
+        guest_access: bool,
+    ) -> Invite {
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INVITE_CODE_LENGTH)
+            .map(char::from)
+            .collect();
+        let now = unix_now();
+
+        let invite = Invite {
+            code: code.clone(),
+            target,
+            created_at: now,
+            expires_at: expires_in_secs.map(|secs| now + secs),
+            max_uses,
+            uses: 0,
+            revoked: false,
+            guest_access,
+        };
+        self.invites.insert(code, invite.clone());
+        invite
+    }
+
+    // Check an invite without consuming a use
+    pub fn validate(&self, code: &str) -> Result<&Invite, InviteError> {
+        let invite = self.invites.get(code).ok_or(InviteError::NotFound)?;
+        invite.check(unix_now())?;
+        Ok(invite)
+    }
+
+    // Consume one use and return what the invite grants
+    pub fn redeem(&mut self, code: &str) -> Result<InviteTarget, InviteError> {
+        self.redeem_kind(code, false)
+    }
+
+    // Consume one use of a guest invite
+    pub fn redeem_guest(&mut self, code: &str) -> Result<InviteTarget, InviteError> {
+        self.redeem_kind(code, true)
+    }
+
+    fn redeem_kind(&mut self, code: &str, guest_access: bool) -> Result<InviteTarget, InviteError> {
+        let invite = self.invites.get_mut(code).ok_or(InviteError::NotFound)?;
+        invite.check(unix_now())?;
+        if invite.guest_access != guest_access {
+            return Err(InviteError::WrongKind);
+        }
+        invite.uses += 1;
+        Ok(invite.target.clone())
+    }
+
+    pub fn revoke(&mut self, code: &str) -> Result<(), InviteError> {
+        let invite = self.invites.get_mut(code).ok_or(InviteError::NotFound)?;
+        invite.revoked = true;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Invite> {
+        let mut invites: Vec<Invite> = self.invites.values().cloned().collect();
+        invites.sort_by_key(|invite| invite.created_at);
+        invites
+    }
+}
+
+// Redeem an invite for a user, granting what it targets; returns the landing channel
+pub async fn redeem_for(
+    state: &AppState,
+    code: &str,
+    user_id: &str,
+) -> Result<Option<String>, InviteError> {
+    let target = state.invites.write().await.redeem(code)?;
+    {
+        let mut guild = state.guild.write().await;
+        match &target {
+            InviteTarget::Guild => guild.add_member(user_id),
+            InviteTarget::Channel { channel_id } => guild.grant_channel(channel_id, user_id),
+        }
+    }
+    let channel = target.landing_channel().map(str::to_string);
+    events::emit(
+        state,
+        EventKind::MemberJoined,
+        &json!({ "user_id": user_id, "channel": channel, "guest": false }),
+    )
+    .await;
+    Ok(channel)
+}
+
+#[derive(Deserialize)]
+struct CreateInviteRequest {
+    target: InviteTarget,
+    max_uses: Option<u32>,
+    expires_in_secs: Option<u64>,
+    #[serde(default)]
+    guest_access: bool,
+}
+
+#[derive(Serialize)]
+struct InviteResponse {
+    #[serde(flatten)]
+    invite: Invite,
+    url: String,
+}
+
+fn invite_url(code: &str) -> String {
+    format!("{}/invite/{}", public_base_url(), code)
+}
+
+// POST /api/invites
+async fn create_invite(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CreateInviteRequest>,
+) -> Result<Json<InviteResponse>, (StatusCode, String)> {
+    require_admin(&state, &headers)
+        .await
+        .map_err(|status| (status, "Admin token required".to_string()))?;
+
+    if let InviteTarget::Channel { channel_id } = &request.target
+        && channel_id.trim().is_empty()
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "channel_id is required".to_string(),
+        ));
+    }
+    // Guests only ever see the channels they were invited to
+    if request.guest_access && request.target == InviteTarget::Guild {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Guest invites must target a channel".to_string(),
+        ));
+    }
+    if request.max_uses == Some(0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "max_uses must be at least 1".to_string(),
+        ));
+    }
+
+    let invite = state.invites.write().await.create(
+        request.target,
+        request.max_uses,
+        request.expires_in_secs,
+        request.guest_access,
+    );
+    let url = invite_url(&invite.code);
+    Ok(Json(InviteResponse { invite, url }))
+}
+
+// GET /api/invites
+async fn list_invites(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Invite>>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    Ok(Json(state.invites.read().await.list()))
+}
+
+// DELETE /api/invites/:code
+async fn revoke_invite(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(code): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    require_admin(&state, &headers).await?;
+    state
+        .invites
+        .write()
+        .await
+        .revoke(&code)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/invites", post(create_invite).get(list_invites))
+        .route("/api/invites/:code", delete(revoke_invite))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guild::Guild;
+
+    #[tokio::test]
+    async fn redeeming_grants_what_the_invite_targets() {
+        let state = AppState::new();
+        {
+            let mut guild = Guild::default();
+            guild.set_invite_only(true);
+            guild.add_private_channel("staff");
+            *state.guild.write().await = guild;
+        }
+        let (guild_invite, channel_invite) = {
+            let mut invites = state.invites.write().await;
+            let guild_invite = invites.create(InviteTarget::Guild, None, None, false);
+            let channel_invite = invites.create(
+                InviteTarget::Channel {
+                    channel_id: "staff".to_string(),
+                },
+                None,
+                None,
+                false,
+            );
+            (guild_invite.code, channel_invite.code)
+        };
+
+        assert!(!state.guild.read().await.is_member("alice"));
+        assert_eq!(redeem_for(&state, &guild_invite, "alice").await, Ok(None));
+        {
+            let guild = state.guild.read().await;
+            assert!(guild.can_access_channel("general", Some("alice")));
+            assert!(!guild.can_access_channel("staff", Some("alice")));
+        }
+
+        assert_eq!(
+            redeem_for(&state, &channel_invite, "bob").await,
+            Ok(Some("staff".to_string()))
+        );
+        let guild = state.guild.read().await;
+        assert!(guild.can_access_channel("general", Some("bob")));
+        assert!(guild.can_access_channel("staff", Some("bob")));
+    }
+
+    #[test]
+    fn invites_run_out_expire_and_can_be_revoked() {
+        let mut store = InviteStore::default();
+        let limited = store.create(InviteTarget::Guild, Some(2), None, false).code;
+        assert_eq!(store.redeem(&limited), Ok(InviteTarget::Guild));
+        assert_eq!(store.redeem(&limited), Ok(InviteTarget::Guild));
+        assert_eq!(store.redeem(&limited), Err(InviteError::Exhausted));
+        assert_eq!(
+            store.validate(&limited).unwrap_err(),
+            InviteError::Exhausted
+        );
+
+        let expiring = store.create(InviteTarget::Guild, None, Some(60), false);
+        assert!(expiring.check(expiring.created_at + 59).is_ok());
+        assert_eq!(
+            expiring.check(expiring.created_at + 60),
+            Err(InviteError::Expired)
+        );
+        let expired = store.create(InviteTarget::Guild, None, Some(0), false).code;
+        assert_eq!(store.redeem(&expired), Err(InviteError::Expired));
+
+        let revoked = store.create(InviteTarget::Guild, None, None, false).code;
+        store.revoke(&revoked).unwrap();
+        assert_eq!(store.redeem(&revoked), Err(InviteError::Revoked));
+        assert_eq!(store.redeem("missing"), Err(InviteError::NotFound));
+    }
+}
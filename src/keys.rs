@@ -0,0 +1,651 @@
+// src/keys.rs
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::history::authenticate_reader;
+use crate::state::AppState;
+use crate::{ServerMessage, send_where, unix_now};
+
+const X25519_KEY_LENGTH: usize = 32;
+// A wrapped group key is a nonce, a 32 byte key and a tag; anything far bigger is not one
+const MAX_WRAPPED_KEY_LENGTH: usize = 256;
+const MAX_KEY_ID_LENGTH: usize = 64;
+const FIRST_EPOCH: u32 = 1;
+
+// A user's X25519 public key; the private half never leaves their client
+#[derive(Debug, Clone, Serialize)]
+pub struct IdentityKey {
+    pub user_id: String,
+    pub public_key: String,
+    pub published_at: u64,
+}
+
+// A conversation's group key, encrypted by `sender` for one recipient. Recipients
+// unwrap it with their private key and `sender_key`, the sender's key at the time
+#[derive(Debug, Clone, Serialize)]
+pub struct WrappedKey {
+    pub epoch: u32,
+    pub key_id: String,
+    pub recipient: String,
+    pub sender: String,
+    pub sender_key: String,
+    pub wrapped: String,
+    pub created_at: u64,
+}
+
+// One generation of a conversation's key, as copies for each member
+struct Epoch {
+    key_id: String,
+    copies: HashMap<String, WrappedKey>,
+}
+
+// The keys a conversation's messages are encrypted with. A new epoch starts whenever
+// someone holding the current key loses access; until a member creates its key, the
+// current epoch has no entry
+struct GroupKey {
+    current: u32,
+    epochs: BTreeMap<u32, Epoch>,
+}
+
+impl GroupKey {
+    fn current_epoch(&self) -> Option<&Epoch> {
+        self.epochs.get(&self.current)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    Unauthorized,
+    // The caller cannot read the conversation
+    Forbidden,
+    NotFound,
+    InvalidKey,
+    // The caller has to publish an identity key before handing out group keys
+    IdentityRequired,
+    // A copy was addressed to someone who cannot read the conversation or has no key
+    InvalidRecipient,
+    // Someone else created the conversation's key first
+    Conflict,
+}
+
+impl KeyError {
+    // Stable code handed to the caller
+    pub fn code(&self) -> &'static str {
+        match self {
+            KeyError::Unauthorized => "unauthorized",
+            KeyError::Forbidden => "conversation_forbidden",
+            KeyError::NotFound => "key_not_found",
+            KeyError::InvalidKey => "invalid_key",
+            KeyError::IdentityRequired => "identity_key_required",
+            KeyError::InvalidRecipient => "invalid_recipient",
+            KeyError::Conflict => "key_conflict",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            KeyError::Unauthorized => StatusCode::UNAUTHORIZED,
+            KeyError::Forbidden => StatusCode::FORBIDDEN,
+            KeyError::NotFound => StatusCode::NOT_FOUND,
+            KeyError::InvalidKey | KeyError::InvalidRecipient => StatusCode::BAD_REQUEST,
+            KeyError::IdentityRequired | KeyError::Conflict => StatusCode::CONFLICT,
+        }
+    }
+}
+
+impl IntoResponse for KeyError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(json!({ "error": self.code() }))).into_response()
+    }
+}
+
+fn decoded_length(value: &str) -> Option<usize> {
+    BASE64.decode(value).ok().map(|bytes| bytes.len())
+}
+
+// Public identity keys and wrapped group keys. The server only ever sees keys
+// encrypted for someone else, so it cannot read the conversations it relays
+#[derive(Default)]
+pub struct KeyStore {
+    identities: HashMap<String, IdentityKey>,
+    groups: HashMap<String, GroupKey>,
+}
+
+pub type SharedKeys = Arc<RwLock<KeyStore>>;
+
+impl KeyStore {
+    // Publishing a different key means the old private key is gone, so every copy
+    // wrapped for it is dropped and the user waits for members to wrap new ones
+    pub fn publish_identity(
+        &mut self,
+        user_id: &str,
+        public_key: &str,
+    ) -> Result<IdentityKey, KeyError> {
+        if decoded_length(public_key) != Some(X25519_KEY_LENGTH) {
+            return Err(KeyError::InvalidKey);
+        }
+        if let Some(current) = self.identities.get(user_id)
+            && current.public_key == public_key
+        {
+            return Ok(current.clone());
+        }
+
+        for epoch in self
+            .groups
+            .values_mut()
+            .flat_map(|group| group.epochs.values_mut())
+        {
+            epoch.copies.remove(user_id);
+        }
+        let key = IdentityKey {
+            user_id: user_id.to_string(),
+            public_key: public_key.to_string(),
+            published_at: unix_now(),
+        };
+        self.identities.insert(user_id.to_string(), key.clone());
+        Ok(key)
+    }
+
+    pub fn identity(&self, user_id: &str) -> Option<&IdentityKey> {
+        self.identities.get(user_id)
+    }
+
+    // The epoch new messages must be sealed with, once the conversation has a key
+    pub fn current_epoch(&self, conversation: &str) -> Option<u32> {
+        self.groups.get(conversation).map(|group| group.current)
+    }
+
+    fn epoch(&self, conversation: &str, epoch: Option<u32>) -> Option<&Epoch> {
+        let group = self.groups.get(conversation)?;
+        group.epochs.get(&epoch.unwrap_or(group.current))
+    }
+
+    pub fn key_id(&self, conversation: &str, epoch: Option<u32>) -> Option<&str> {
+        self.epoch(conversation, epoch)
+            .map(|epoch| epoch.key_id.as_str())
+    }
+
+    // A user's copy of the key of an epoch, the current one by default
+    pub fn copy_for(
+        &self,
+        conversation: &str,
+        user_id: &str,
+        epoch: Option<u32>,
+    ) -> Option<&WrappedKey> {
+        self.epoch(conversation, epoch)?.copies.get(user_id)
+    }
+
+    // Published identities of readers that hold no copy of the current key
+    pub fn missing<F>(&self, conversation: &str, can_read: F) -> Vec<IdentityKey>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let current = self
+            .groups
+            .get(conversation)
+            .and_then(GroupKey::current_epoch);
+        let mut missing: Vec<IdentityKey> = self
+            .identities
+            .values()
+            .filter(|key| can_read(&key.user_id))
+            .filter(|key| current.is_none_or(|epoch| !epoch.copies.contains_key(&key.user_id)))
+            .cloned()
+            .collect();
+        missing.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        missing
+    }
+
+    // Hand out copies of the current epoch's key. Whoever sends the first copies of an
+    // epoch decides its key id; later copies must be of the same key
+    pub fn distribute<F>(
+        &mut self,
+        conversation: &str,
+        sender: &str,
+        epoch: u32,
+        key_id: &str,
+        copies: Vec<KeyCopy>,
+        can_read: F,
+    ) -> Result<(), KeyError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if key_id.is_empty() || key_id.len() > MAX_KEY_ID_LENGTH {
+            return Err(KeyError::InvalidKey);
+        }
+        let sender_key = self
+            .identities
+            .get(sender)
+            .ok_or(KeyError::IdentityRequired)?
+            .public_key
+            .clone();
+        let current = self.current_epoch(conversation).unwrap_or(FIRST_EPOCH);
+        if epoch != current
+            || self
+                .key_id(conversation, Some(epoch))
+                .is_some_and(|existing| existing != key_id)
+        {
+            return Err(KeyError::Conflict);
+        }
+        for copy in &copies {
+            if !self.identities.contains_key(&copy.recipient) || !can_read(&copy.recipient) {
+                return Err(KeyError::InvalidRecipient);
+            }
+            if decoded_length(&copy.wrapped).is_none_or(|len| len > MAX_WRAPPED_KEY_LENGTH) {
+                return Err(KeyError::InvalidKey);
+            }
+        }
+
+        let generation = self
+            .groups
+            .entry(conversation.to_string())
+            .or_insert_with(|| GroupKey {
+                current: FIRST_EPOCH,
+                epochs: BTreeMap::new(),
+            })
+            .epochs
+            .entry(epoch)
+            .or_insert_with(|| Epoch {
+                key_id: key_id.to_string(),
+                copies: HashMap::new(),
+            });
+        let now = unix_now();
+        for copy in copies {
+            generation.copies.insert(
+                copy.recipient.clone(),
+                WrappedKey {
+                    epoch,
+                    key_id: key_id.to_string(),
+                    recipient: copy.recipient,
+                    sender: sender.to_string(),
+                    sender_key: sender_key.clone(),
+                    wrapped: copy.wrapped,
+                    created_at: now,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // Start a new epoch in every conversation where someone holding the current key
+    // can no longer read, so the next key is only handed to those who still can.
+    // Returns the conversations and their new epochs
+    pub fn rotate_unreadable<F>(&mut self, can_read: F) -> Vec<(String, u32)>
+    where
+        F: Fn(&str, &str) -> bool,
+    {
+        let mut rotated = Vec::new();
+        for (conversation, group) in self.groups.iter_mut() {
+            let Some(current) = group.current_epoch() else {
+                continue;
+            };
+            if current
+                .copies
+                .keys()
+                .all(|user_id| can_read(conversation, user_id))
+            {
+                continue;
+            }
+            group.current += 1;
+            rotated.push((conversation.clone(), group.current));
+        }
+        rotated.sort();
+        rotated
+    }
+}
+
+// Rotate the keys of conversations someone lost access to, and tell the readers left
+// to fetch the new epoch. Called after anything that takes access away
+pub async fn rotate_after_membership_change(state: &AppState) {
+    let guild = state.guild.read().await.clone();
+    let rotated = state
+        .keys
+        .write()
+        .await
+        .rotate_unreadable(|conversation, user_id| {
+            guild.can_access_channel(conversation, Some(user_id))
+        });
+
+    for (conversation, epoch) in rotated {
+        println!("Rotated the key of {} to epoch {}", conversation, epoch);
+        let notice = ServerMessage::KeyRotated {
+            conversation: conversation.clone(),
+            epoch,
+        };
+        send_where(&state.clients, &notice, |_, client| {
+            guild.can_access_channel(&conversation, Some(&client.user_id))
+        })
+        .await;
+    }
+}
+
+#[derive(Deserialize)]
+struct PublishIdentityRequest {
+    public_key: String,
+}
+
+// PUT /api/keys/identity
+async fn publish_identity(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<PublishIdentityRequest>,
+) -> Result<Json<IdentityKey>, KeyError> {
+    let user_id = authenticate_reader(&state, &headers)
+        .await
+        .map_err(|_| KeyError::Unauthorized)?;
+    let key = state
+        .keys
+        .write()
+        .await
+        .publish_identity(&user_id, &request.public_key)?;
+    println!("Published identity key for {}", user_id);
+    Ok(Json(key))
+}
+
+// GET /api/keys/identity/:user_id
+async fn get_identity(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<IdentityKey>, KeyError> {
+    authenticate_reader(&state, &headers)
+        .await
+        .map_err(|_| KeyError::Unauthorized)?;
+    state
+        .keys
+        .read()
+        .await
+        .identity(&user_id)
+        .cloned()
+        .map(Json)
+        .ok_or(KeyError::NotFound)
+}
+
+#[derive(Serialize)]
+struct GroupKeyView {
+    epoch: u32,
+    // None until a member creates the epoch's key
+    key_id: Option<String>,
+    // The caller's copy, if anyone has wrapped one for them yet
+    key: Option<WrappedKey>,
+    // Readers still waiting for a copy of the current key; whoever holds it should
+    // wrap one for them
+    missing: Vec<IdentityKey>,
+}
+
+#[derive(Deserialize)]
+struct EpochQuery {
+    // An older epoch, to read history sealed with it
+    epoch: Option<u32>,
+}
+
+// Who may read a conversation: channel members, or the two users of a DM
+async fn reader_check(state: &AppState, conversation: &str) -> impl Fn(&str) -> bool {
+    let guild = state.guild.read().await.clone();
+    let conversation = conversation.to_string();
+    move |user_id: &str| guild.can_access_channel(&conversation, Some(user_id))
+}
+
+// GET /api/keys/groups/:conversation?epoch=
+async fn get_group_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(conversation): Path<String>,
+    Query(query): Query<EpochQuery>,
+) -> Result<Json<GroupKeyView>, KeyError> {
+    let user_id = authenticate_reader(&state, &headers)
+        .await
+        .map_err(|_| KeyError::Unauthorized)?;
+    let can_read = reader_check(&state, &conversation).await;
+    if !can_read(&user_id) {
+        return Err(KeyError::Forbidden);
+    }
+
+    let keys = state.keys.read().await;
+    let current = keys.current_epoch(&conversation).unwrap_or(FIRST_EPOCH);
+    let epoch = query.epoch.unwrap_or(current);
+    Ok(Json(GroupKeyView {
+        epoch,
+        key_id: keys.key_id(&conversation, Some(epoch)).map(str::to_string),
+        key: keys.copy_for(&conversation, &user_id, Some(epoch)).cloned(),
+        missing: if epoch == current {
+            keys.missing(&conversation, can_read)
+        } else {
+            Vec::new()
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct KeyCopy {
+    pub recipient: String,
+    pub wrapped: String,
+}
+
+#[derive(Deserialize)]
+struct DistributeRequest {
+    epoch: u32,
+    key_id: String,
+    keys: Vec<KeyCopy>,
+}
+
+// PUT /api/keys/groups/:conversation, creates the key or adds copies of it
+async fn distribute_group_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(conversation): Path<String>,
+    Json(request): Json<DistributeRequest>,
+) -> Result<StatusCode, KeyError> {
+    let user_id = authenticate_reader(&state, &headers)
+        .await
+        .map_err(|_| KeyError::Unauthorized)?;
+    let can_read = reader_check(&state, &conversation).await;
+    if !can_read(&user_id) {
+        return Err(KeyError::Forbidden);
+    }
+
+    let count = request.keys.len();
+    state.keys.write().await.distribute(
+        &conversation,
+        &user_id,
+        request.epoch,
+        &request.key_id,
+        request.keys,
+        can_read,
+    )?;
+    println!(
+        "{} shared epoch {} of {} with {} members",
+        user_id, request.epoch, conversation, count
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/keys/identity", put(publish_identity))
+        .route("/api/keys/identity/:user_id", get(get_identity))
+        .route(
+            "/api/keys/groups/:conversation",
+            get(get_group_key).put(distribute_group_key),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guild::Guild;
+
+    fn public_key(seed: u8) -> String {
+        BASE64.encode([seed; X25519_KEY_LENGTH])
+    }
+
+    fn copy(recipient: &str) -> KeyCopy {
+        KeyCopy {
+            recipient: recipient.to_string(),
+            wrapped: BASE64.encode([7u8; 60]),
+        }
+    }
+
+    fn published(users: &[&str]) -> KeyStore {
+        let mut keys = KeyStore::default();
+        for (seed, user) in users.iter().enumerate() {
+            keys.publish_identity(user, &public_key(seed as u8))
+                .unwrap();
+        }
+        keys
+    }
+
+    #[test]
+    fn group_keys_only_go_to_readers_with_identities() {
+        let guild = Guild::default();
+        let can_read = |user_id: &str| guild.can_access_channel("dm:alice:bob", Some(user_id));
+        let mut keys = published(&["alice", "bob", "mallory"]);
+        assert!(matches!(
+            keys.publish_identity("carol", "c2hvcnQ="),
+            Err(KeyError::InvalidKey)
+        ));
+
+        // Only the two users of a DM can be handed its key
+        assert_eq!(
+            keys.distribute(
+                "dm:alice:bob",
+                "alice",
+                FIRST_EPOCH,
+                "k1",
+                vec![copy("alice"), copy("mallory")],
+                can_read
+            ),
+            Err(KeyError::InvalidRecipient)
+        );
+        keys.distribute(
+            "dm:alice:bob",
+            "alice",
+            FIRST_EPOCH,
+            "k1",
+            vec![copy("alice")],
+            can_read,
+        )
+        .unwrap();
+        let missing: Vec<String> = keys
+            .missing("dm:alice:bob", can_read)
+            .into_iter()
+            .map(|key| key.user_id)
+            .collect();
+        assert_eq!(missing, ["bob"]);
+
+        // A second key for the same conversation loses the race
+        assert_eq!(
+            keys.distribute(
+                "dm:alice:bob",
+                "bob",
+                FIRST_EPOCH,
+                "k2",
+                vec![copy("bob")],
+                can_read
+            ),
+            Err(KeyError::Conflict)
+        );
+        keys.distribute(
+            "dm:alice:bob",
+            "alice",
+            FIRST_EPOCH,
+            "k1",
+            vec![copy("bob")],
+            can_read,
+        )
+        .unwrap();
+        let bobs = keys.copy_for("dm:alice:bob", "bob", None).unwrap();
+        assert_eq!(
+            (bobs.sender.as_str(), bobs.sender_key.clone()),
+            ("alice", public_key(0))
+        );
+
+        // A new identity key cannot open the old copies
+        keys.publish_identity("bob", &public_key(9)).unwrap();
+        assert!(keys.copy_for("dm:alice:bob", "bob", None).is_none());
+    }
+
+    #[test]
+    fn losing_access_starts_a_new_epoch_without_the_leaver() {
+        let mut guild = Guild::default();
+        guild.add_private_channel("staff");
+        guild.grant_channel("staff", "alice");
+        guild.grant_channel("staff", "bob");
+        let mut keys = published(&["alice", "bob"]);
+        let can_read = |guild: &Guild| {
+            let guild = guild.clone();
+            move |user_id: &str| guild.can_access_channel("staff", Some(user_id))
+        };
+        keys.distribute(
+            "staff",
+            "alice",
+            FIRST_EPOCH,
+            "k1",
+            vec![copy("alice"), copy("bob")],
+            can_read(&guild),
+        )
+        .unwrap();
+        let rotate = |keys: &mut KeyStore, guild: &Guild| {
+            keys.rotate_unreadable(|conversation, user_id| {
+                guild.can_access_channel(conversation, Some(user_id))
+            })
+        };
+        assert!(rotate(&mut keys, &guild).is_empty());
+
+        assert!(guild.revoke_channel("staff", "bob"));
+        assert_eq!(rotate(&mut keys, &guild), [("staff".to_string(), 2)]);
+        // Nothing changes until someone creates the new key
+        assert!(rotate(&mut keys, &guild).is_empty());
+
+        // The old key is history: no more copies of it, and bob gets none of the new one
+        assert_eq!(
+            keys.distribute(
+                "staff",
+                "alice",
+                FIRST_EPOCH,
+                "k1",
+                vec![copy("alice")],
+                can_read(&guild)
+            ),
+            Err(KeyError::Conflict)
+        );
+        assert_eq!(
+            keys.distribute(
+                "staff",
+                "alice",
+                2,
+                "k2",
+                vec![copy("bob")],
+                can_read(&guild)
+            ),
+            Err(KeyError::InvalidRecipient)
+        );
+        keys.distribute(
+            "staff",
+            "alice",
+            2,
+            "k2",
+            vec![copy("alice")],
+            can_read(&guild),
+        )
+        .unwrap();
+        assert_eq!(keys.current_epoch("staff"), Some(2));
+        assert!(keys.missing("staff", can_read(&guild)).is_empty());
+        assert_eq!(
+            keys.copy_for("staff", "alice", Some(FIRST_EPOCH))
+                .map(|copy| copy.key_id.as_str()),
+            Some("k1")
+        );
+    }
+}
@@ -0,0 +1,128 @@
+// src/logging.rs
+// What the server may write to its logs. By default only metadata: frame types and sizes,
+// ids and error kinds. Frames and errors are logged in full only where LOG_CONTENT is set,
+// and even then with tokens, secrets and email addresses redacted.
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::ClientMessage;
+
+// LOG_CONTENT=true, for local debugging only
+pub fn content_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        let enabled = std::env::var("LOG_CONTENT")
+            .map(|value| matches!(value.trim(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        if enabled {
+            eprintln!("Warning: LOG_CONTENT is set, message content will appear in logs");
+        }
+        enabled
+    })
+}
+
+fn patterns() -> &'static [(Regex, &'static str)] {
+    static PATTERNS: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            (r"(?i)\bbearer\s+[^\s,;]+", "Bearer <redacted>"),
+            (
+                r#"(?i)((?:token|secret|password|code|signature|ciphertext|key)"?\s*[:=]\s*"?)[^"&\s,;}]+"#,
+                "$1<redacted>",
+            ),
+            (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}", "<email>"),
+        ]
+        .into_iter()
+        .map(|(pattern, replacement)| (Regex::new(pattern).expect("valid pattern"), replacement))
+        .collect()
+    })
+}
+
+// Mask credentials and email addresses in text bound for the logs
+pub fn redact(text: &str) -> String {
+    patterns()
+        .iter()
+        .fold(text.to_string(), |text, (pattern, replacement)| {
+            pattern.replace_all(&text, *replacement).into_owned()
+        })
+}
+
+// Something short enough to tell two tokens apart in the logs, never enough to use one
+pub fn token_hint(token: &str) -> String {
+    let prefix: String = token.chars().take(4).collect();
+    format!("{}…", prefix)
+}
+
+fn describe_frame(text: &str, with_content: bool) -> String {
+    if with_content {
+        return redact(text);
+    }
+    let kind = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => ClientMessage::TYPES
+            .into_iter()
+            .find(|kind| value["type"].as_str() == Some(*kind))
+            .unwrap_or("unknown"),
+        Err(_) => "unparsed",
+    };
+    format!("{} frame, {} bytes", kind, text.len())
+}
+
+// A WebSocket frame as it may be logged
+pub fn frame(text: &str) -> String {
+    describe_frame(text, content_enabled())
+}
+
+// A parse error without the input it quotes
+pub fn json_error(err: &serde_json::Error) -> String {
+    if content_enabled() {
+        return redact(&err.to_string());
+    }
+    format!(
+        "{:?} error at line {} column {}",
+        err.classify(),
+        err.line(),
+        err.column()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_keep_metadata_and_lose_secrets() {
+        let frame = r#"{"type":"authenticate","token":"abc.def.ghi"}"#;
+        assert_eq!(
+            describe_frame(frame, false),
+            format!("authenticate frame, {} bytes", frame.len())
+        );
+        assert_eq!(
+            describe_frame(frame, true),
+            r#"{"type":"authenticate","token":"<redacted>"}"#
+        );
+        assert_eq!(describe_frame("hello", false), "unparsed frame, 5 bytes");
+        // The client picks the type, so only the ones we know are logged
+        let forged = "{\"type\":\"x\\nFAKE LOG LINE\"}";
+        assert_eq!(
+            describe_frame(forged, false),
+            format!("unknown frame, {} bytes", forged.len())
+        );
+        for kind in ClientMessage::TYPES {
+            let err = serde_json::from_value::<ClientMessage>(serde_json::json!({ "type": kind }))
+                .err()
+                .map(|err| err.to_string())
+                .unwrap_or_default();
+            assert!(!err.contains("unknown variant"), "{}", kind);
+        }
+
+        assert_eq!(
+            redact("Reset at http://localhost/reset?token=s3cr3t for alice@example.com"),
+            "Reset at http://localhost/reset?token=<redacted> for <email>"
+        );
+        assert_eq!(
+            redact("Authorization: Bearer eyJhbGci.x.y"),
+            "Authorization: Bearer <redacted>"
+        );
+        assert_eq!(token_hint("abcdefgh"), "abcd…");
+    }
+}
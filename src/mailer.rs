@@ -0,0 +1,173 @@
+// src/mailer.rs
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::logging;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Something that can deliver account emails
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), String>;
+}
+
+pub type SharedMailer = Arc<dyn Mailer>;
+
+// Development default: print emails to the console instead of sending them. Bodies carry
+// login links, so they are only printed where LOG_CONTENT is set
+pub struct ConsoleMailer;
+
+#[async_trait]
+impl Mailer for ConsoleMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let body = if logging::content_enabled() {
+            email.body
+        } else {
+            "(body hidden; set LOG_CONTENT=true to print it)".to_string()
+        };
+        println!(
+            "[MAIL] To: {}\n[MAIL] Subject: {}\n{}",
+            logging::redact(&email.to),
+            email.subject,
+            body
+        );
+        Ok(())
+    }
+}
+
+// Plain SMTP without TLS or auth, for local relays such as Mailpit or MailHog
+pub struct SmtpMailer {
+    addr: String,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(addr: &str, from: &str) -> Self {
+        SmtpMailer {
+            addr: addr.to_string(),
+            from: from.to_string(),
+        }
+    }
+}
+
+// Read one (possibly multi-line) SMTP reply and check its status class
+async fn expect_reply<R>(reader: &mut R, expected: char) -> Result<(), String>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("SMTP server closed the connection".to_string());
+        }
+        if !line.starts_with(expected) {
+            return Err(format!("Unexpected SMTP reply: {}", line.trim_end()));
+        }
+        // `250-` continues a multi-line reply, `250 ` ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+// Header values must not smuggle in extra headers
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(|e| format!("Could not reach SMTP server {}: {}", self.addr, e))?;
+        let (read_half, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        expect_reply(&mut reader, '2').await?;
+        let to = header_value(&email.to);
+        let from = header_value(&self.from);
+        for (command, expected) in [
+            ("EHLO rustcord".to_string(), '2'),
+            (format!("MAIL FROM:<{}>", from), '2'),
+            (format!("RCPT TO:<{}>", to), '2'),
+            ("DATA".to_string(), '3'),
+        ] {
+            writer
+                .write_all(format!("{}\r\n", command).as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+            expect_reply(&mut reader, expected).await?;
+        }
+
+        // Lines starting with a dot are escaped so they cannot end the message early
+        let body: String = email
+            .body
+            .lines()
+            .map(|line| {
+                if line.starts_with('.') {
+                    format!(".{}\r\n", line)
+                } else {
+                    format!("{}\r\n", line)
+                }
+            })
+            .collect();
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}.\r\n",
+            from,
+            to,
+            header_value(&email.subject),
+            body
+        );
+        writer
+            .write_all(message.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        expect_reply(&mut reader, '2').await?;
+
+        let _ = writer.write_all(b"QUIT\r\n").await;
+        Ok(())
+    }
+}
+
+// MAILER=smtp sends through SMTP_ADDR (default 127.0.0.1:1025) from MAIL_FROM;
+// anything else prints emails to the console
+pub fn from_env() -> SharedMailer {
+    match std::env::var("MAILER").as_deref() {
+        Ok("smtp") => {
+            let addr = std::env::var("SMTP_ADDR").unwrap_or_else(|_| "127.0.0.1:1025".to_string());
+            let from =
+                std::env::var("MAIL_FROM").unwrap_or_else(|_| "rustcord@localhost".to_string());
+            Arc::new(SmtpMailer::new(&addr, &from))
+        }
+        _ => Arc::new(ConsoleMailer),
+    }
+}
+
+// Keeps sent emails so tests can read the links out of them
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryMailer {
+    pub sent: std::sync::Mutex<Vec<Email>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
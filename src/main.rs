@@ -1,94 +1,235 @@
+mod accounts;
+mod at_rest;
 mod auth;
+mod bots;
+mod channels;
 mod env_loader;
+mod envelope;
+mod events;
+mod github;
+mod guests;
+mod guild;
+mod history;
+mod invites;
+mod keys;
+mod logging;
+mod mailer;
+#[cfg(test)]
+mod mock_idp;
+mod moderation;
+mod oidc;
+mod providers;
+mod session;
+mod signing;
+mod state;
+mod two_factor;
+mod users;
+mod webhooks;
 mod webserver;
 
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
 use tokio::sync::{
-    RwLock,
+    Notify, RwLock,
     mpsc::{UnboundedSender, unbounded_channel},
 };
-use tokio_tungstenite::{accept_async, tungstenite::Message};
-
-// AES encryption imports
-use aes::Aes256;
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD as BASE64;
-use cbc::Decryptor;
-use cbc::cipher::{BlockDecryptMut, KeyIvInit};
-use pbkdf2::pbkdf2_hmac;
-use sha2::Sha256;
-
-type Aes256CbcDec = Decryptor<Aes256>;
-
-// AES-256-CBC decryption
-fn decrypt_message_aes(encrypted: &str, key: &[u8; 32]) -> Result<String, String> {
-    let data = BASE64
-        .decode(encrypted)
-        .map_err(|e| format!("Base64 decode error: {}", e))?;
-
-    if data.len() < 16 {
-        return Err("Invalid encrypted data".to_string());
-    }
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        Message,
+        handshake::server::{Request, Response},
+    },
+};
 
-    let (iv, ciphertext) = data.split_at(16);
-    let mut buffer = ciphertext.to_vec();
+use bots::{Bot, BotScope};
+use events::EventKind;
+use history::{MessageAuthor, MessageBody};
+use moderation::Verdict;
+use session::Session;
+use signing::MessageSignature;
+use state::AppState;
 
-    let iv_array: [u8; 16] = iv.try_into().map_err(|_| "Invalid IV length".to_string())?;
-    let cipher = Aes256CbcDec::new(key.into(), &iv_array.into());
-    cipher
-        .decrypt_padded_mut::<cbc::cipher::block_padding::Pkcs7>(&mut buffer)
-        .map_err(|e| format!("Decryption error: {}", e))?;
+// Key derivation for clients that still use the shared key
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::sync::OnceLock;
 
-    String::from_utf8(buffer).map_err(|e| format!("UTF-8 error: {}", e))
+// Seconds since the Unix epoch, used for expiry timestamps
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
 }
 
-// Generate AES-256 key from password using PBKDF2 (matching frontend implementation)
+// Generate AES-256 key from password using PBKDF2; old clients salted with the password itself
 fn generate_aes_key(password: &str) -> [u8; 32] {
-    let salt = b"rustchatserver2024_aes_secure";
     let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 100000, &mut key);
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), password.as_bytes(), 100000, &mut key);
     key
 }
 
+// The key every client used to share, derived from LEGACY_CHAT_KEY. Unset by default:
+// messages are end-to-end encrypted and the server cannot read them
+fn legacy_key() -> Option<&'static [u8; 32]> {
+    static KEY: OnceLock<Option<[u8; 32]>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        std::env::var("LEGACY_CHAT_KEY")
+            .ok()
+            .filter(|password| !password.is_empty())
+            .map(|password| generate_aes_key(&password))
+    })
+    .as_ref()
+}
+
+// The content of a message from a client still on the shared key, so moderation
+// can check it while such clients are phased out
+fn legacy_plaintext(ciphertext: &str) -> Option<String> {
+    let plaintext = envelope::open(legacy_key()?, ciphertext).ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ClientMessage {
+    Authenticate {
+        token: String,
+    },
     PresenceUpdate {
         user: PresenceUser,
     },
     PresenceStatus {
-        user_id: String,
+        // May only name the connection's own user; omitted means self
+        #[serde(default)]
+        user_id: Option<String>,
         status: PresenceStatus,
     },
     ChatMessage {
         ciphertext: String,
+        #[serde(default = "default_channel")]
+        channel: String,
+        #[serde(flatten)]
+        signature: Option<MessageSignature>,
     },
 }
 
+impl ClientMessage {
+    // Every `type` a client may send, so logs can name a frame's kind without echoing
+    // whatever else a client puts there
+    const TYPES: [&'static str; 4] = [
+        "authenticate",
+        "presence_update",
+        "presence_status",
+        "chat_message",
+    ];
+}
+
+fn default_channel() -> String {
+    "general".to_string()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ServerMessage {
-    PresenceSnapshot { users: Vec<PresenceUser> },
-    PresenceUpdate { user: PresenceUser },
+    PresenceSnapshot {
+        users: Vec<PresenceUser>,
+    },
+    PresenceUpdate {
+        user: PresenceUser,
+    },
+    ChatMessage {
+        // Position in the channel's history
+        id: u64,
+        channel: String,
+        // Whose device keys verify the signature
+        author_id: String,
+        author: String,
+        avatar: Option<String>,
+        #[serde(flatten)]
+        body: MessageBody,
+        // The group key epoch needed to decrypt the ciphertext
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        epoch: Option<u32>,
+        #[serde(flatten)]
+        signature: Option<MessageSignature>,
+        sent_at: u64,
+    },
+    // A member lost access; messages must be sealed with the new epoch's key from now on
+    KeyRotated {
+        conversation: String,
+        epoch: u32,
+    },
+    ModerationAlert {
+        channel: String,
+        source_channel: String,
+        user_id: String,
+        rule: Option<String>,
+        action: String,
+        excerpt: String,
+    },
+    Error {
+        code: String,
+        message: String,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 enum PresenceStatus {
+    #[default]
     Online,
     Idle,
     Dnd,
     Offline,
+    // Requested by clients that want to appear offline; never shown to others
+    Invisible,
+}
+
+// Why a connection may not apply a presence status change
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StatusChangeError {
+    NotOwnUser,
+    OfflineNotAllowed,
+}
+
+impl StatusChangeError {
+    fn envelope(&self) -> ServerMessage {
+        let (code, message) = match self {
+            StatusChangeError::NotOwnUser => (
+                "forbidden_status_change",
+                "You can only change your own status",
+            ),
+            StatusChangeError::OfflineNotAllowed => (
+                "invalid_status",
+                "Offline is set by disconnecting; use invisible to appear offline",
+            ),
+        };
+        ServerMessage::Error {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
 }
 
-impl Default for PresenceStatus {
-    fn default() -> Self {
-        PresenceStatus::Online
+// Decide the status a connection may publish, mapping invisible to offline
+fn authorize_status_change(
+    connection_user_id: &str,
+    target_user_id: &str,
+    requested: PresenceStatus,
+) -> Result<PresenceStatus, StatusChangeError> {
+    if connection_user_id != target_user_id {
+        return Err(StatusChangeError::NotOwnUser);
+    }
+
+    match requested {
+        PresenceStatus::Offline => Err(StatusChangeError::OfflineNotAllowed),
+        PresenceStatus::Invisible => Ok(PresenceStatus::Offline),
+        status => Ok(status),
     }
 }
 
@@ -100,23 +241,100 @@ struct PresenceUser {
     status: PresenceStatus,
     avatar: Option<String>,
     email: Option<String>,
+    #[serde(default)]
+    bot: bool,
 }
 
+// A connected client; the user id comes from its verified session, never from the client
 struct ClientHandle {
     sender: UnboundedSender<Message>,
-    user_id: Option<String>,
+    user_id: String,
+    session_id: String,
+    // Guests never see other users' emails
+    guest: bool,
+    // Bots may only do what their token's scopes allow; users are not limited
+    scopes: Option<HashSet<BotScope>>,
+    // Wakes the connection's read loop when the server drops it
+    closed: Arc<Notify>,
+}
+
+impl ClientHandle {
+    fn allows(&self, scope: BotScope) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.contains(&scope))
+    }
+
+    fn sees_emails(&self) -> bool {
+        !self.guest && self.scopes.is_none()
+    }
+}
+
+// Who a connection acts for: a logged-in user, or a bot holding an API token
+enum Principal {
+    User(Session),
+    Bot(Bot),
 }
 
+impl Principal {
+    fn user_id(&self) -> &str {
+        match self {
+            Principal::User(session) => &session.user_id,
+            Principal::Bot(bot) => &bot.id,
+        }
+    }
+
+    // What revoking drops the connection by: the session, or the bot's current token
+    fn session_id(&self) -> &str {
+        match self {
+            Principal::User(session) => &session.id,
+            Principal::Bot(bot) => &bot.token_id,
+        }
+    }
+
+    fn identity(&self) -> auth::OAuthUser {
+        match self {
+            Principal::User(session) => session.user.clone(),
+            Principal::Bot(bot) => bot.user(),
+        }
+    }
+
+    fn scopes(&self) -> Option<HashSet<BotScope>> {
+        match self {
+            Principal::User(_) => None,
+            Principal::Bot(bot) => Some(bot.scopes.clone()),
+        }
+    }
+
+    // A revoked or expired session, or a rotated bot token, stops being honoured at once
+    async fn is_active(&self, state: &AppState) -> bool {
+        match self {
+            Principal::User(session) => state.sessions.read().await.is_active(&session.id),
+            Principal::Bot(bot) => state.bots.read().await.is_active(&bot.id, &bot.token_id),
+        }
+    }
+}
+
+// How long a client has to authenticate after the handshake
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
 type Clients = Arc<RwLock<HashMap<SocketAddr, ClientHandle>>>;
 type PresenceState = Arc<RwLock<HashMap<String, PresenceUser>>>;
 type PresenceConnections = Arc<RwLock<HashMap<String, usize>>>;
 
-async fn broadcast(clients: &Clients, message: &ServerMessage) {
+// Deliver a message to every client accepted by the filter
+async fn send_where<F>(clients: &Clients, message: &ServerMessage, filter: F)
+where
+    F: Fn(&SocketAddr, &ClientHandle) -> bool,
+{
     match serde_json::to_string(message) {
         Ok(payload) => {
             let msg = Message::Text(payload);
             let clients_guard = clients.read().await;
             for (addr, client) in clients_guard.iter() {
+                if !filter(addr, client) {
+                    continue;
+                }
                 if client.sender.send(msg.clone()).is_err() {
                     eprintln!("Failed to deliver message to {}", addr);
                 }
@@ -128,21 +346,256 @@ async fn broadcast(clients: &Clients, message: &ServerMessage) {
     }
 }
 
+// Announce a presence change to everyone allowed to see it, without the email
+// for guests, bots and event subscribers
+async fn broadcast_presence(state: &AppState, user: PresenceUser) {
+    let redacted = PresenceUser {
+        email: None,
+        ..user.clone()
+    };
+    events::emit(state, EventKind::PresenceChanged, &redacted).await;
+
+    let redacted = ServerMessage::PresenceUpdate { user: redacted };
+    send_where(&state.clients, &redacted, |_, client| {
+        client.allows(BotScope::ReadPresence) && !client.sees_emails()
+    })
+    .await;
+    send_where(
+        &state.clients,
+        &ServerMessage::PresenceUpdate { user },
+        |_, client| client.sees_emails(),
+    )
+    .await;
+}
+
+// Tell matching clients why, then close their connections
+async fn close_connections<F>(clients: &Clients, notice: &ServerMessage, filter: F)
+where
+    F: Fn(&ClientHandle) -> bool,
+{
+    send_where(clients, notice, |_, client| filter(client)).await;
+
+    let clients_guard = clients.read().await;
+    for (addr, client) in clients_guard.iter().filter(|(_, client)| filter(client)) {
+        println!("Closing connection {}", addr);
+        let _ = client.sender.send(Message::Close(None));
+        client.closed.notify_one();
+    }
+}
+
+fn send_to_client(sender: &UnboundedSender<Message>, message: &ServerMessage) {
+    match serde_json::to_string(message) {
+        Ok(payload) => {
+            if sender.send(Message::Text(payload)).is_err() {
+                eprintln!("Failed to send message to client");
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to serialize server message: {}", err);
+        }
+    }
+}
+
 async fn send_snapshot_to_client(
     sender: &UnboundedSender<Message>,
     presence_state: &PresenceState,
+    with_emails: bool,
 ) {
     let snapshot = {
         let state = presence_state.read().await;
-        let users: Vec<PresenceUser> = state.values().cloned().collect();
+        let users: Vec<PresenceUser> = state
+            .values()
+            .cloned()
+            .map(|user| PresenceUser {
+                email: user.email.filter(|_| with_emails),
+                ..user
+            })
+            .collect();
         ServerMessage::PresenceSnapshot { users }
     };
 
-    if let Ok(payload) = serde_json::to_string(&snapshot) {
-        if sender.send(Message::Text(payload)).is_err() {
-            eprintln!("Failed to send presence snapshot to client");
+    if let Ok(payload) = serde_json::to_string(&snapshot)
+        && sender.send(Message::Text(payload)).is_err()
+    {
+        eprintln!("Failed to send presence snapshot to client");
+    }
+}
+
+// Resolve the session or bot token behind a connection, from the handshake's
+// Authorization header or, for browsers that cannot set headers, from an
+// `authenticate` first message
+async fn authenticate_connection<S>(
+    state: &AppState,
+    handshake_token: Option<String>,
+    ws_receiver: &mut S,
+) -> Option<Principal>
+where
+    S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let token = match handshake_token {
+        Some(token) => token,
+        None => {
+            let frame = tokio::time::timeout(AUTH_TIMEOUT, ws_receiver.next())
+                .await
+                .ok()??
+                .ok()?;
+            let Message::Text(text) = frame else {
+                return None;
+            };
+            match serde_json::from_str::<ClientMessage>(&text) {
+                Ok(ClientMessage::Authenticate { token }) => token,
+                _ => return None,
+            }
+        }
+    };
+
+    if token.starts_with(bots::BOT_TOKEN_PREFIX) {
+        return state
+            .bots
+            .read()
+            .await
+            .authenticate(&token)
+            .map(Principal::Bot);
+    }
+    let session = state.sessions.read().await.verify(&token).ok()?;
+    // A session outlives a kick or an expired guest pass, so membership is checked here too
+    if !state.guild.read().await.is_member(&session.user_id) {
+        println!("Refusing a connection from non-member {}", session.user_id);
+        return None;
+    }
+    Some(Principal::User(session))
+}
+
+// Apply a status change requested by the connection's own user
+async fn handle_presence_status(
+    state: &AppState,
+    user_id: &str,
+    sender: &UnboundedSender<Message>,
+    target_user_id: Option<String>,
+    status: PresenceStatus,
+) {
+    let target_user_id = target_user_id.unwrap_or_else(|| user_id.to_string());
+    let status = match authorize_status_change(user_id, &target_user_id, status) {
+        Ok(status) => status,
+        Err(err) => {
+            println!("Rejected status change by {}: {:?}", user_id, err);
+            send_to_client(sender, &err.envelope());
+            return;
+        }
+    };
+
+    let updated_user = {
+        let mut presence_guard = state.presence_state.write().await;
+        if let Some(user) = presence_guard.get_mut(user_id) {
+            user.status = status;
+            Some(user.clone())
+        } else {
+            None
+        }
+    };
+
+    if let Some(user) = updated_user {
+        broadcast_presence(state, user).await;
+    }
+}
+
+// Run a chat message through auto-moderation and relay it if allowed; the error is
+// meant for the sender. Content rules only apply when the plaintext is known, which
+// it is not for end-to-end encrypted messages. Messages from a connection are not
+// echoed back to it
+async fn handle_chat_message(
+    state: &AppState,
+    origin: Option<SocketAddr>,
+    author: MessageAuthor,
+    channel: String,
+    ciphertext: String,
+    signature: Option<MessageSignature>,
+    plaintext: Option<&str>,
+) -> Result<(), ServerMessage> {
+    let user_id = author.id.clone();
+
+    // The server cannot open messages, but it can refuse what is not one
+    let format = match envelope::format(&ciphertext) {
+        Ok(format) => format,
+        Err(err) => {
+            println!("Rejected malformed message from {}: {:?}", user_id, err);
+            return Err(ServerMessage::Error {
+                code: "invalid_ciphertext".to_string(),
+                message: "Messages must be encrypted envelopes".to_string(),
+            });
         }
+    };
+
+    if !state
+        .guild
+        .read()
+        .await
+        .can_access_channel(&channel, Some(&user_id))
+    {
+        return Err(ServerMessage::Error {
+            code: "channel_forbidden".to_string(),
+            message: format!("You are not a member of #{}", channel),
+        });
+    }
+
+    // Otherwise the server, or anyone holding a session, could speak for the author
+    if let Err(err) =
+        state
+            .devices
+            .read()
+            .await
+            .verify(&user_id, &channel, &ciphertext, signature.as_ref())
+    {
+        println!("Rejected unverified message from {}: {:?}", user_id, err);
+        return Err(ServerMessage::Error {
+            code: err.code().to_string(),
+            message: "Messages must be signed by one of your registered devices".to_string(),
+        });
+    }
+
+    // Once a channel has keys, a message sealed with anything but the current one could
+    // be read by someone who has since lost access
+    if let Some(current) = state.keys.read().await.current_epoch(&channel)
+        && format.epoch() != Some(current)
+    {
+        return Err(ServerMessage::Error {
+            code: "stale_key_epoch".to_string(),
+            message: format!(
+                "#{} has a new key (epoch {}); fetch it and send again",
+                channel, current
+            ),
+        });
     }
+
+    let verdict = moderation::screen(state, &user_id, &channel, plaintext).await;
+    if !verdict.relays() {
+        let message = match &verdict {
+            Verdict::Muted { remaining } => format!(
+                "You are timed out for another {} seconds",
+                remaining.as_secs()
+            ),
+            Verdict::Timeout { duration, .. } => format!(
+                "Message blocked by auto-moderation; timed out for {} seconds",
+                duration.as_secs()
+            ),
+            _ => "Message blocked by auto-moderation".to_string(),
+        };
+        return Err(ServerMessage::Error {
+            code: "message_blocked".to_string(),
+            message,
+        });
+    }
+
+    history::publish(
+        state,
+        origin,
+        &channel,
+        author,
+        MessageBody::Ciphertext(ciphertext),
+        signature,
+    )
+    .await;
+    Ok(())
 }
 
 #[tokio::main]
@@ -152,19 +605,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         eprintln!("Warning: Could not load .env file: {}", e);
     }
 
+    let state = AppState::new();
+    guests::spawn_expiry(state.clone());
+    events::spawn_worker(state.clone());
+    history::spawn_flusher(state.clone());
+
     // Start the web server for OAuth and serving the frontend
-    let webserver_handle = tokio::spawn(async {
-        if let Err(e) = webserver::run().await {
+    let webserver_state = state.clone();
+    let webserver_handle = tokio::spawn(async move {
+        if let Err(e) = webserver::run(webserver_state).await {
             eprintln!("Web server error: {}", e);
         }
     });
 
     // Start the WebSocket server that manages chat and user presence
-    let chat_server_handle = tokio::spawn(async {
-        let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
-        let presence_state: PresenceState = Arc::new(RwLock::new(HashMap::new()));
-        let presence_connections: PresenceConnections = Arc::new(RwLock::new(HashMap::new()));
-
+    let chat_server_handle = tokio::spawn(async move {
         let listener = TcpListener::bind("127.0.0.1:8081")
             .await
             .expect("Can't bind to port 8081");
@@ -173,13 +628,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         while let Ok((stream, addr)) = listener.accept().await {
             println!("New TCP connection: {}", addr);
 
-            let clients = clients.clone();
-            let presence_state = presence_state.clone();
-            let presence_connections = presence_connections.clone();
+            let state = state.clone();
+            let clients = state.clients.clone();
+            let presence_state = state.presence_state.clone();
+            let presence_connections = state.presence_connections.clone();
 
             tokio::spawn(async move {
-                // Accept WebSocket handshake
-                let ws_stream = match accept_async(stream).await {
+                // Accept WebSocket handshake, keeping any bearer token the client sent
+                let mut handshake_token = None;
+                // The error type is dictated by tungstenite's handshake callback
+                #[allow(clippy::result_large_err)]
+                let capture_token = |request: &Request, response: Response| {
+                    handshake_token = request
+                        .headers()
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.strip_prefix("Bearer "))
+                        .map(str::to_string);
+                    Ok(response)
+                };
+                let ws_stream = match accept_hdr_async(stream, capture_token).await {
                     Ok(ws) => {
                         println!("WebSocket handshake successful: {}", addr);
                         ws
@@ -192,8 +660,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
+                let Some(principal) =
+                    authenticate_connection(&state, handshake_token, &mut ws_receiver).await
+                else {
+                    println!("Rejecting unauthenticated connection: {}", addr);
+                    let rejection = ServerMessage::Error {
+                        code: "unauthorized".to_string(),
+                        message: "A valid session token is required".to_string(),
+                    };
+                    if let Ok(payload) = serde_json::to_string(&rejection) {
+                        let _ = ws_sender.send(Message::Text(payload)).await;
+                    }
+                    let _ = ws_sender.send(Message::Close(None)).await;
+                    return;
+                };
+
+                // Identity is bound to the session for the lifetime of the connection;
+                // every provider a user has linked shows up as the same presence entry
+                let user_id = principal.user_id().to_string();
+                let login = principal.identity();
+                let profile = state.users.read().await.get(&user_id).cloned();
+                let identity = PresenceUser {
+                    id: user_id.clone(),
+                    username: profile
+                        .as_ref()
+                        .map_or_else(|| login.username.clone(), |user| user.username.clone()),
+                    status: PresenceStatus::Online,
+                    avatar: profile
+                        .and_then(|user| user.avatar)
+                        .or_else(|| login.avatar.clone()),
+                    email: Some(login.email.clone()).filter(|email| !email.is_empty()),
+                    bot: matches!(principal, Principal::Bot(_)),
+                };
+                let guest = state.guild.read().await.is_guest(&user_id);
+                let handle_scopes = principal.scopes();
+                let allowed = |scope| {
+                    handle_scopes
+                        .as_ref()
+                        .is_none_or(|scopes: &HashSet<BotScope>| scopes.contains(&scope))
+                };
+                let sees_presence = allowed(BotScope::ReadPresence);
+                let can_send = allowed(BotScope::SendMessages);
+                let with_emails = !guest && handle_scopes.is_none();
+                println!("Authenticated {} as {}", addr, user_id);
+
                 // Prepare outbound channel for this client
                 let (tx, mut rx) = unbounded_channel::<Message>();
+                let closed = Arc::new(Notify::new());
 
                 {
                     let mut clients_guard = clients.write().await;
@@ -201,109 +714,142 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         addr,
                         ClientHandle {
                             sender: tx.clone(),
-                            user_id: None,
+                            user_id: user_id.clone(),
+                            session_id: principal.session_id().to_string(),
+                            guest,
+                            scopes: handle_scopes,
+                            closed: closed.clone(),
                         },
                     );
                 }
 
-                send_snapshot_to_client(&tx, &presence_state).await;
+                {
+                    let mut connections_guard = presence_connections.write().await;
+                    *connections_guard.entry(user_id.clone()).or_insert(0) += 1;
+                }
+
+                if sees_presence {
+                    send_snapshot_to_client(&tx, &presence_state, with_emails).await;
+                }
 
                 let writer_addr = addr;
                 let writer = tokio::spawn(async move {
                     while let Some(message) = rx.recv().await {
+                        let closing = matches!(message, Message::Close(_));
                         if ws_sender.send(message).await.is_err() {
                             println!("Failed to send message to {}", writer_addr);
                             break;
                         }
+                        if closing {
+                            break;
+                        }
                     }
                 });
 
-                // Generate AES-256 key for this connection using PBKDF2
-                let password = "rustchatserver2024_aes_secure";
-                let key = generate_aes_key(password);
-                println!("Generated AES key for connection {}", addr);
+                loop {
+                    let msg = tokio::select! {
+                        msg = ws_receiver.next() => msg,
+                        _ = closed.notified() => {
+                            println!("Session for {} was revoked", addr);
+                            break;
+                        }
+                    };
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    if !principal.is_active(&state).await {
+                        println!("Session for {} is no longer active", addr);
+                        break;
+                    }
 
-                while let Some(msg) = ws_receiver.next().await {
                     match msg {
                         Ok(Message::Text(text)) => {
-                            println!("[RECEIVED]: {}", text);
+                            println!("[RECEIVED] {} from {}", logging::frame(&text), addr);
 
                             match serde_json::from_str::<ClientMessage>(&text) {
-                                Ok(ClientMessage::PresenceUpdate { mut user }) => {
-                                    if user.status == PresenceStatus::Offline {
-                                        user.status = PresenceStatus::Online;
-                                    }
-
-                                    let mut registered = false;
-                                    {
-                                        let mut clients_guard = clients.write().await;
-                                        if let Some(client) = clients_guard.get_mut(&addr) {
-                                            if client.user_id.as_ref() != Some(&user.id) {
-                                                client.user_id = Some(user.id.clone());
-                                                registered = true;
-                                            }
-                                        }
-                                    }
-
-                                    if registered {
-                                        let mut connections_guard =
-                                            presence_connections.write().await;
-                                        let counter =
-                                            connections_guard.entry(user.id.clone()).or_insert(0);
-                                        *counter += 1;
-                                    }
+                                Ok(ClientMessage::Authenticate { .. }) => {
+                                    send_to_client(
+                                        &tx,
+                                        &ServerMessage::Error {
+                                            code: "already_authenticated".to_string(),
+                                            message: "Connection is already authenticated"
+                                                .to_string(),
+                                        },
+                                    );
+                                }
+                                Ok(ClientMessage::PresenceUpdate { user: requested }) => {
+                                    // Only the status is taken from the client
+                                    let mut user = identity.clone();
+                                    user.status = match requested.status {
+                                        PresenceStatus::Offline => PresenceStatus::Online,
+                                        PresenceStatus::Invisible => PresenceStatus::Offline,
+                                        status => status,
+                                    };
 
                                     {
                                         let mut presence_guard = presence_state.write().await;
                                         presence_guard.insert(user.id.clone(), user.clone());
                                     }
 
-                                    broadcast(&clients, &ServerMessage::PresenceUpdate { user })
-                                        .await;
+                                    broadcast_presence(&state, user).await;
                                 }
-                                Ok(ClientMessage::PresenceStatus { user_id, status }) => {
-                                    let updated_user = {
-                                        let mut presence_guard = presence_state.write().await;
-                                        if let Some(user) = presence_guard.get_mut(&user_id) {
-                                            user.status = status.clone();
-                                            Some(user.clone())
-                                        } else {
-                                            None
-                                        }
-                                    };
-
-                                    if let Some(user) = updated_user {
-                                        broadcast(
-                                            &clients,
-                                            &ServerMessage::PresenceUpdate { user },
-                                        )
-                                        .await;
-                                    }
+                                Ok(ClientMessage::PresenceStatus {
+                                    user_id: target_user_id,
+                                    status,
+                                }) => {
+                                    handle_presence_status(
+                                        &state,
+                                        &user_id,
+                                        &tx,
+                                        target_user_id,
+                                        status,
+                                    )
+                                    .await;
                                 }
-                                Ok(ClientMessage::ChatMessage { ciphertext }) => {
-                                    match decrypt_message_aes(&ciphertext, &key) {
-                                        Ok(decrypted) => {
-                                            println!("[CHAT] {}", decrypted);
-                                        }
-                                        Err(err) => {
-                                            println!("[CHAT DECRYPTION ERROR]: {}", err);
-                                        }
-                                    }
+                                Ok(ClientMessage::ChatMessage { .. }) if !can_send => {
+                                    send_to_client(
+                                        &tx,
+                                        &ServerMessage::Error {
+                                            code: "missing_scope".to_string(),
+                                            message: "This bot token cannot send messages"
+                                                .to_string(),
+                                        },
+                                    );
                                 }
-                                Err(_) => {
-                                    // Try to decrypt raw payload for backward compatibility/logging
-                                    match decrypt_message_aes(&text, &key) {
-                                        Ok(decrypted) => {
-                                            println!("[DECRYPTED]: {}", decrypted);
-                                        }
-                                        Err(e) => {
-                                            println!(
-                                                "[UNHANDLED MESSAGE]: {} (error: {})",
-                                                text, e
-                                            );
-                                        }
+                                Ok(ClientMessage::ChatMessage {
+                                    ciphertext,
+                                    channel,
+                                    signature,
+                                }) => {
+                                    // Relayed as is; only the channel's members hold its key
+                                    let plaintext = legacy_plaintext(&ciphertext);
+                                    if let Err(notice) = handle_chat_message(
+                                        &state,
+                                        Some(addr),
+                                        MessageAuthor {
+                                            id: user_id.clone(),
+                                            name: identity.username.clone(),
+                                            avatar: identity.avatar.clone(),
+                                            webhook_id: None,
+                                        },
+                                        channel,
+                                        ciphertext,
+                                        signature,
+                                        plaintext.as_deref(),
+                                    )
+                                    .await
+                                    {
+                                        send_to_client(&tx, &notice);
                                     }
                                 }
+                                Err(err) => {
+                                    println!(
+                                        "[UNHANDLED MESSAGE] {} from {}: {}",
+                                        logging::frame(&text),
+                                        addr,
+                                        logging::json_error(&err)
+                                    );
+                                }
                             }
                         }
                         Ok(Message::Close(_)) => {
@@ -318,17 +864,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
 
-                // Clean up writer task and drop sender
-                drop(tx);
-                let _ = writer.await;
-
+                // Unregister first so no sender outlives the connection, then let
+                // the writer flush what is queued
                 let disconnected_user = {
                     let mut clients_guard = clients.write().await;
-                    clients_guard
-                        .remove(&addr)
-                        .and_then(|client| client.user_id)
+                    clients_guard.remove(&addr).map(|client| client.user_id)
                 };
 
+                drop(tx);
+                let _ = writer.await;
+
                 if let Some(user_id) = disconnected_user {
                     let should_mark_offline = {
                         let mut connections_guard = presence_connections.write().await;
@@ -357,7 +902,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         };
 
                         if let Some(user) = offline_user {
-                            broadcast(&clients, &ServerMessage::PresenceUpdate { user }).await;
+                            broadcast_presence(&state, user).await;
                         }
                     }
                 }
@@ -375,3 +920,430 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence_user(id: &str, status: PresenceStatus) -> PresenceUser {
+        PresenceUser {
+            id: id.to_string(),
+            username: id.to_string(),
+            status,
+            avatar: None,
+            email: None,
+            bot: false,
+        }
+    }
+
+    fn next_error_code(rx: &mut tokio::sync::mpsc::UnboundedReceiver<Message>) -> Option<String> {
+        match rx.try_recv().ok()? {
+            Message::Text(text) => match serde_json::from_str::<ServerMessage>(&text).ok()? {
+                ServerMessage::Error { code, .. } => Some(code),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn own_status_change_is_allowed() {
+        assert_eq!(
+            authorize_status_change("alice", "alice", PresenceStatus::Dnd),
+            Ok(PresenceStatus::Dnd)
+        );
+    }
+
+    #[test]
+    fn changing_another_users_status_is_rejected() {
+        assert_eq!(
+            authorize_status_change("mallory", "alice", PresenceStatus::Idle),
+            Err(StatusChangeError::NotOwnUser)
+        );
+    }
+
+    #[test]
+    fn manual_offline_is_rejected() {
+        assert_eq!(
+            authorize_status_change("alice", "alice", PresenceStatus::Offline),
+            Err(StatusChangeError::OfflineNotAllowed)
+        );
+    }
+
+    #[test]
+    fn invisible_is_published_as_offline() {
+        assert_eq!(
+            authorize_status_change("alice", "alice", PresenceStatus::Invisible),
+            Ok(PresenceStatus::Offline)
+        );
+    }
+
+    #[test]
+    fn presence_status_user_id_is_optional() {
+        let message: ClientMessage =
+            serde_json::from_str(r#"{"type":"presence_status","status":"idle"}"#).unwrap();
+        assert!(matches!(
+            message,
+            ClientMessage::PresenceStatus {
+                user_id: None,
+                status: PresenceStatus::Idle
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_status_change_sends_error_and_leaves_presence_untouched() {
+        let state = AppState::new();
+        state.presence_state.write().await.insert(
+            "alice".to_string(),
+            presence_user("alice", PresenceStatus::Online),
+        );
+        let (tx, mut rx) = unbounded_channel();
+
+        handle_presence_status(
+            &state,
+            "mallory",
+            &tx,
+            Some("alice".to_string()),
+            PresenceStatus::Dnd,
+        )
+        .await;
+
+        assert_eq!(
+            next_error_code(&mut rx).as_deref(),
+            Some("forbidden_status_change")
+        );
+        assert_eq!(
+            state.presence_state.read().await["alice"].status,
+            PresenceStatus::Online
+        );
+    }
+
+    #[tokio::test]
+    async fn manual_offline_sends_error_envelope() {
+        let state = AppState::new();
+        state.presence_state.write().await.insert(
+            "alice".to_string(),
+            presence_user("alice", PresenceStatus::Online),
+        );
+        let (tx, mut rx) = unbounded_channel();
+
+        handle_presence_status(&state, "alice", &tx, None, PresenceStatus::Offline).await;
+
+        assert_eq!(next_error_code(&mut rx).as_deref(), Some("invalid_status"));
+        assert_eq!(
+            state.presence_state.read().await["alice"].status,
+            PresenceStatus::Online
+        );
+    }
+
+    #[tokio::test]
+    async fn own_status_change_updates_presence() {
+        let state = AppState::new();
+        state.presence_state.write().await.insert(
+            "alice".to_string(),
+            presence_user("alice", PresenceStatus::Online),
+        );
+        let (tx, mut rx) = unbounded_channel();
+
+        handle_presence_status(&state, "alice", &tx, None, PresenceStatus::Invisible).await;
+
+        assert_eq!(next_error_code(&mut rx), None);
+        assert_eq!(
+            state.presence_state.read().await["alice"].status,
+            PresenceStatus::Offline
+        );
+    }
+
+    #[tokio::test]
+    async fn presence_respects_bot_scopes_and_hides_emails() {
+        let state = AppState::new();
+        let mut receivers = Vec::new();
+        for (port, guest, scopes) in [
+            (1, false, None),
+            (2, true, None),
+            (3, false, Some(HashSet::from([BotScope::ReadPresence]))),
+            (4, false, Some(HashSet::from([BotScope::SendMessages]))),
+        ] {
+            let (tx, rx) = unbounded_channel();
+            state.clients.write().await.insert(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                ClientHandle {
+                    sender: tx,
+                    user_id: format!("client{}", port),
+                    session_id: port.to_string(),
+                    guest,
+                    scopes,
+                    closed: Arc::new(Notify::new()),
+                },
+            );
+            receivers.push(rx);
+        }
+
+        let alice = PresenceUser {
+            email: Some("alice@example.com".to_string()),
+            ..presence_user("alice", PresenceStatus::Online)
+        };
+        broadcast_presence(&state, alice).await;
+
+        let emails: Vec<Option<Option<String>>> = receivers
+            .iter_mut()
+            .map(|rx| match rx.try_recv().ok()? {
+                Message::Text(text) => match serde_json::from_str(&text).ok()? {
+                    ServerMessage::PresenceUpdate { user } => Some(user.email),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            emails,
+            vec![
+                Some(Some("alice@example.com".to_string())),
+                Some(None),
+                Some(None),
+                None
+            ]
+        );
+    }
+
+    fn author(id: &str) -> MessageAuthor {
+        MessageAuthor {
+            id: id.to_string(),
+            name: id.to_string(),
+            avatar: None,
+            webhook_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sealed_messages_are_relayed_untouched() {
+        let state = AppState::new();
+        let (tx, mut rx) = unbounded_channel();
+        state.clients.write().await.insert(
+            SocketAddr::from(([127, 0, 0, 1], 2)),
+            ClientHandle {
+                sender: tx,
+                user_id: "bob".to_string(),
+                session_id: "bob".to_string(),
+                guest: false,
+                scopes: None,
+                closed: Arc::new(Notify::new()),
+            },
+        );
+
+        // Sealed with a key the server never sees
+        let sealed = envelope::seal(envelope::Algorithm::Aes256Gcm, 1, &[5; 32], b"hi bob");
+        let rejected = handle_chat_message(
+            &state,
+            None,
+            author("alice"),
+            "general".to_string(),
+            "not an envelope".to_string(),
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(
+            rejected,
+            Err(ServerMessage::Error { code, .. }) if code == "invalid_ciphertext"
+        ));
+        handle_chat_message(
+            &state,
+            Some(SocketAddr::from(([127, 0, 0, 1], 1))),
+            author("alice"),
+            "general".to_string(),
+            sealed.clone(),
+            None,
+            None,
+        )
+        .await
+        .expect("relayed");
+
+        let Ok(Message::Text(text)) = rx.try_recv() else {
+            panic!("bob got nothing");
+        };
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["type"], "chat_message");
+        assert_eq!(envelope["ciphertext"], sealed.as_str());
+        assert!(envelope.get("content").is_none());
+    }
+
+    #[tokio::test]
+    async fn signed_messages_reach_readers_with_their_signature() {
+        use base64::Engine;
+        use base64::engine::general_purpose::STANDARD as BASE64;
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let state = AppState::new();
+        let (tx, mut rx) = unbounded_channel();
+        state.clients.write().await.insert(
+            SocketAddr::from(([127, 0, 0, 1], 2)),
+            ClientHandle {
+                sender: tx,
+                user_id: "bob".to_string(),
+                session_id: "bob".to_string(),
+                guest: false,
+                scopes: None,
+                closed: Arc::new(Notify::new()),
+            },
+        );
+        let device = SigningKey::from_bytes(&[9; 32]);
+        let device_key = BASE64.encode(device.verifying_key().as_bytes());
+        state
+            .devices
+            .write()
+            .await
+            .register("alice", &device_key)
+            .unwrap();
+
+        let sealed = envelope::seal(envelope::Algorithm::Aes256Gcm, 1, &[5; 32], b"hi bob");
+        let signed = BASE64.encode(
+            device
+                .sign(&signing::signed_bytes("alice", "general", &sealed))
+                .to_bytes(),
+        );
+        let frame = serde_json::json!({
+            "type": "chat_message",
+            "ciphertext": sealed,
+            "device_key": device_key,
+            "signature": signed,
+        });
+        let Ok(ClientMessage::ChatMessage {
+            ciphertext,
+            channel,
+            signature,
+        }) = serde_json::from_value(frame)
+        else {
+            panic!("not a chat message");
+        };
+        assert!(signature.is_some());
+
+        let unsigned = handle_chat_message(
+            &state,
+            None,
+            author("alice"),
+            channel.clone(),
+            ciphertext.clone(),
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(
+            unsigned,
+            Err(ServerMessage::Error { code, .. }) if code == "signature_required"
+        ));
+        handle_chat_message(
+            &state,
+            None,
+            author("alice"),
+            channel,
+            ciphertext,
+            signature,
+            None,
+        )
+        .await
+        .expect("relayed");
+
+        let Ok(Message::Text(text)) = rx.try_recv() else {
+            panic!("bob got nothing");
+        };
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["author_id"], "alice");
+        assert_eq!(envelope["device_key"], device_key.as_str());
+        assert_eq!(envelope["signature"], signed.as_str());
+    }
+
+    #[tokio::test]
+    async fn closing_a_session_only_drops_its_connections() {
+        let state = AppState::new();
+        let mut receivers = Vec::new();
+        for (port, session_id) in [(1, "revoked"), (2, "kept")] {
+            let (tx, rx) = unbounded_channel();
+            let closed = Arc::new(Notify::new());
+            state.clients.write().await.insert(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                ClientHandle {
+                    sender: tx,
+                    user_id: "alice".to_string(),
+                    session_id: session_id.to_string(),
+                    guest: false,
+                    scopes: None,
+                    closed: closed.clone(),
+                },
+            );
+            receivers.push((rx, closed));
+        }
+
+        let notice = ServerMessage::Error {
+            code: "session_revoked".to_string(),
+            message: String::new(),
+        };
+        close_connections(&state.clients, &notice, |client| {
+            client.session_id == "revoked"
+        })
+        .await;
+
+        let (revoked_rx, revoked_closed) = &mut receivers[0];
+        assert_eq!(
+            next_error_code(revoked_rx).as_deref(),
+            Some("session_revoked")
+        );
+        assert!(matches!(revoked_rx.try_recv(), Ok(Message::Close(_))));
+        tokio::time::timeout(Duration::from_secs(1), revoked_closed.notified())
+            .await
+            .expect("read loop woken");
+
+        let (kept_rx, _) = &mut receivers[1];
+        assert!(kept_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn only_guild_members_can_connect() {
+        let state = AppState::new();
+        state.guild.write().await.set_invite_only(true);
+        let token = |user_id: &str| {
+            let (token, _) = state
+                .sessions
+                .try_write()
+                .unwrap()
+                .start(
+                    user_id,
+                    auth::OAuthUser {
+                        id: user_id.to_string(),
+                        username: user_id.to_string(),
+                        email: String::new(),
+                        email_verified: false,
+                        avatar: None,
+                        provider: auth::OAuthProvider::new("local"),
+                    },
+                    None,
+                )
+                .unwrap();
+            token
+        };
+        let connects = |token: String| {
+            let state = state.clone();
+            async move {
+                let mut frames = futures_util::stream::empty();
+                authenticate_connection(&state, Some(token), &mut frames)
+                    .await
+                    .is_some()
+            }
+        };
+
+        let (alice, stranger, guest) = (token("alice"), token("stranger"), token("guest"));
+        {
+            let mut guild = state.guild.write().await;
+            guild.add_member("alice");
+            guild.add_guest("guest", "general", unix_now() - 1);
+        }
+        assert!(connects(alice.clone()).await);
+        assert!(!connects(stranger).await);
+        assert!(!connects(guest).await);
+
+        // Kicked members are turned away even with a live session
+        state.guild.write().await.remove_member("alice");
+        assert!(!connects(alice).await);
+    }
+}
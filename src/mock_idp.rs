@@ -0,0 +1,323 @@
+// src/mock_idp.rs
+// In-process OAuth 2.0 provider so the login flow can be tested without the network.
+// With ID tokens turned on it also plays an OpenID Connect issuer, signing with Ed25519
+use axum::{
+    Form, Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ed25519_dalek::SigningKey;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::OAuthConfig;
+use crate::providers::ProviderEndpoints;
+use crate::unix_now;
+use crate::webserver::bearer_token;
+
+pub const CLIENT_ID: &str = "mock-client";
+pub const CLIENT_SECRET: &str = "mock-secret";
+
+const SIGNING_KEY_ID: &str = "mock-key";
+const SIGNING_SEED: [u8; 32] = [7; 32];
+// Signs forged ID tokens; never published in the key set
+const FORGERY_SEED: [u8; 32] = [8; 32];
+
+struct PendingCode {
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: Option<String>,
+}
+
+struct MockIdpState {
+    issuer: String,
+    profile: Value,
+    emails: Value,
+    // Send users back with `error=access_denied`, as if they clicked Cancel
+    deny: bool,
+    reject_token: bool,
+    // Claims laid over the defaults of issued ID tokens; None for a plain OAuth 2.0 provider
+    id_token: Option<Value>,
+    forge_id_tokens: bool,
+    codes: HashMap<String, PendingCode>,
+    tokens: HashSet<String>,
+    issued: usize,
+}
+
+type SharedMockIdp = Arc<Mutex<MockIdpState>>;
+
+pub struct MockIdp {
+    pub addr: SocketAddr,
+    state: SharedMockIdp,
+}
+
+// Ed25519 keys as unencrypted PKCS#8, the form jsonwebtoken signs with
+fn signing_key_der(seed: &[u8; 32]) -> Vec<u8> {
+    const PKCS8_PREFIX: [u8; 16] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    [&PKCS8_PREFIX[..], seed].concat()
+}
+
+fn sign_id_token(claims: &Value, seed: &[u8; 32]) -> String {
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(SIGNING_KEY_ID.to_string());
+    jsonwebtoken::encode(
+        &header,
+        claims,
+        &EncodingKey::from_ed_der(&signing_key_der(seed)),
+    )
+    .expect("valid signing key")
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    response_type: String,
+    state: String,
+    code_challenge: String,
+    code_challenge_method: String,
+    nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: Option<String>,
+    code: String,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    code_verifier: String,
+}
+
+fn redirect_with(redirect_uri: &str, params: &str) -> Response {
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    Redirect::to(&format!("{}{}{}", redirect_uri, separator, params)).into_response()
+}
+
+// GET /authorize: approves immediately and sends the browser back with a code
+async fn authorize(
+    State(state): State<SharedMockIdp>,
+    Query(query): Query<AuthorizeQuery>,
+) -> Response {
+    if query.client_id != CLIENT_ID
+        || query.response_type != "code"
+        || query.code_challenge_method != "S256"
+    {
+        return (StatusCode::BAD_REQUEST, "invalid authorization request").into_response();
+    }
+
+    let mut idp = state.lock().unwrap();
+    let state_param = urlencoding::encode(&query.state).into_owned();
+    if idp.deny {
+        return redirect_with(
+            &query.redirect_uri,
+            &format!("error=access_denied&state={}", state_param),
+        );
+    }
+
+    idp.issued += 1;
+    let code = format!("code-{}", idp.issued);
+    idp.codes.insert(
+        code.clone(),
+        PendingCode {
+            redirect_uri: query.redirect_uri.clone(),
+            code_challenge: query.code_challenge,
+            nonce: query.nonce,
+        },
+    );
+    redirect_with(
+        &query.redirect_uri,
+        &format!("code={}&state={}", code, state_param),
+    )
+}
+
+// POST /token: redeems a code once, checking the client and PKCE verifier
+async fn token(State(state): State<SharedMockIdp>, Form(form): Form<TokenForm>) -> Response {
+    let mut idp = state.lock().unwrap();
+    let invalid_grant = || {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+            .into_response()
+    };
+
+    let Some(pending) = idp.codes.remove(&form.code) else {
+        return invalid_grant();
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes()));
+    if idp.reject_token
+        || form
+            .grant_type
+            .as_deref()
+            .is_some_and(|grant| grant != "authorization_code")
+        || form.client_id != CLIENT_ID
+        || form.client_secret != CLIENT_SECRET
+        || form.redirect_uri != pending.redirect_uri
+        || challenge != pending.code_challenge
+    {
+        return invalid_grant();
+    }
+
+    let access_token = format!("token-{}", idp.issued);
+    idp.tokens.insert(access_token.clone());
+    let mut response = json!({ "access_token": access_token, "token_type": "bearer" });
+
+    if let Some(overrides) = &idp.id_token {
+        let now = unix_now();
+        let mut claims = json!({
+            "iss": idp.issuer,
+            "aud": CLIENT_ID,
+            "sub": idp.profile["sub"],
+            "iat": now,
+            "exp": now + 300,
+            "nonce": pending.nonce,
+        });
+        for (claim, value) in overrides.as_object().into_iter().flatten() {
+            claims[claim] = value.clone();
+        }
+        let seed = if idp.forge_id_tokens {
+            &FORGERY_SEED
+        } else {
+            &SIGNING_SEED
+        };
+        response["id_token"] = sign_id_token(&claims, seed).into();
+    }
+    Json(response).into_response()
+}
+
+// GET /.well-known/openid-configuration
+async fn discovery(State(state): State<SharedMockIdp>) -> Json<Value> {
+    let issuer = state.lock().unwrap().issuer.clone();
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    }))
+}
+
+// GET /jwks
+async fn jwks() -> Json<Value> {
+    let public_key = SigningKey::from_bytes(&SIGNING_SEED).verifying_key();
+    Json(json!({
+        "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "alg": "EdDSA",
+            "use": "sig",
+            "kid": SIGNING_KEY_ID,
+            "x": URL_SAFE_NO_PAD.encode(public_key.as_bytes()),
+        }]
+    }))
+}
+
+fn authorized(idp: &MockIdpState, headers: &HeaderMap) -> bool {
+    bearer_token(headers).is_some_and(|token| idp.tokens.contains(token))
+}
+
+// GET /userinfo
+async fn get_userinfo(State(state): State<SharedMockIdp>, headers: HeaderMap) -> Response {
+    let idp = state.lock().unwrap();
+    if !authorized(&idp, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(idp.profile.clone()).into_response()
+}
+
+// GET /emails
+async fn list_emails(State(state): State<SharedMockIdp>, headers: HeaderMap) -> Response {
+    let idp = state.lock().unwrap();
+    if !authorized(&idp, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(idp.emails.clone()).into_response()
+}
+
+impl MockIdp {
+    // Serve the provider on a random local port; `profile` and `emails` are returned verbatim
+    pub fn start(profile: Value, emails: Value) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockIdpState {
+            issuer: format!("http://{}", addr),
+            profile,
+            emails,
+            deny: false,
+            reject_token: false,
+            id_token: None,
+            forge_id_tokens: false,
+            codes: HashMap::new(),
+            tokens: HashSet::new(),
+            issued: 0,
+        }));
+
+        let app = Router::new()
+            .route("/authorize", get(authorize))
+            .route("/token", post(token))
+            .route("/userinfo", get(get_userinfo))
+            .route("/emails", get(list_emails))
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .with_state(state.clone());
+
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        MockIdp { addr, state }
+    }
+
+    pub fn endpoints(&self) -> ProviderEndpoints {
+        ProviderEndpoints {
+            authorize_url: format!("http://{}/authorize", self.addr),
+            token_url: format!("http://{}/token", self.addr),
+            userinfo_url: format!("http://{}/userinfo", self.addr),
+            emails_url: Some(format!("http://{}/emails", self.addr)),
+        }
+    }
+
+    pub fn config(&self, redirect_uri: &str) -> OAuthConfig {
+        OAuthConfig {
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+        }
+    }
+
+    pub fn deny_logins(&self) {
+        self.state.lock().unwrap().deny = true;
+    }
+
+    pub fn reject_token_requests(&self) {
+        self.state.lock().unwrap().reject_token = true;
+    }
+
+    pub fn issuer(&self) -> String {
+        self.state.lock().unwrap().issuer.clone()
+    }
+
+    // Issue ID tokens from now on, with these claims replacing the valid defaults
+    pub fn issue_id_tokens(&self, overrides: Value) {
+        self.state.lock().unwrap().id_token = Some(overrides);
+    }
+
+    // Sign ID tokens with a key that is not in the published key set
+    pub fn forge_id_tokens(&self) {
+        self.state.lock().unwrap().forge_id_tokens = true;
+    }
+}
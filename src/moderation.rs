@@ -0,0 +1,696 @@
+// src/moderation.rs
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::state::AppState;
+use crate::webserver::require_admin;
+use crate::{ServerMessage, send_where};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+// Longest timeout a rule may hand out, 28 days
+const MAX_TIMEOUT_SECS: u64 = 28 * 24 * 60 * 60;
+// How often per-user state of users who went quiet is dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// What happens to a message that matches a rule
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Flag,
+    Block,
+    Timeout,
+}
+
+// The condition a rule checks for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RuleKind {
+    Keywords {
+        words: Vec<String>,
+    },
+    Regex {
+        patterns: Vec<String>,
+    },
+    Links {
+        #[serde(default)]
+        allowed_domains: Vec<String>,
+    },
+    Invites,
+    MentionSpam {
+        max_mentions: usize,
+    },
+    RepeatedMessages {
+        max_repeats: usize,
+        window_secs: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationRule {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: RuleKind,
+    pub action: RuleAction,
+    // Only used by the timeout action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+// Rule set as configured by guild admins through the REST API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub mod_channel: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<ModerationRule>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            enabled: true,
+            mod_channel: None,
+            rules: Vec::new(),
+        }
+    }
+}
+
+// Outcome of evaluating a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Flag { rule: String },
+    Block { rule: String },
+    Timeout { rule: String, duration: Duration },
+    // The author is serving an earlier timeout
+    Muted { remaining: Duration },
+}
+
+impl Verdict {
+    pub fn relays(&self) -> bool {
+        matches!(self, Verdict::Allow | Verdict::Flag { .. })
+    }
+
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            Verdict::Flag { rule } | Verdict::Block { rule } | Verdict::Timeout { rule, .. } => {
+                Some(rule)
+            }
+            Verdict::Allow | Verdict::Muted { .. } => None,
+        }
+    }
+
+    pub fn action(&self) -> Option<RuleAction> {
+        match self {
+            Verdict::Flag { .. } => Some(RuleAction::Flag),
+            Verdict::Block { .. } => Some(RuleAction::Block),
+            Verdict::Timeout { .. } => Some(RuleAction::Timeout),
+            Verdict::Allow | Verdict::Muted { .. } => None,
+        }
+    }
+}
+
+enum Matcher {
+    Keywords(Vec<String>),
+    Regex(Vec<Regex>),
+    Links(Vec<String>),
+    Invites,
+    MentionSpam(usize),
+    RepeatedMessages {
+        max_repeats: usize,
+        window: Duration,
+    },
+}
+
+struct CompiledRule {
+    name: String,
+    matcher: Matcher,
+    action: RuleAction,
+    timeout: Duration,
+}
+
+// Compiled rule set plus the per-user state the rules need
+#[derive(Default)]
+pub struct ModerationEngine {
+    config: ModerationConfig,
+    rules: Vec<CompiledRule>,
+    recent: HashMap<String, VecDeque<(Instant, String)>>,
+    timeouts: HashMap<String, Instant>,
+    last_pruned: Option<Instant>,
+}
+
+pub type SharedModeration = Arc<RwLock<ModerationEngine>>;
+
+fn compile_rule(rule: &ModerationRule) -> Result<CompiledRule, String> {
+    let matcher = match &rule.kind {
+        RuleKind::Keywords { words } => Matcher::Keywords(
+            words
+                .iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        ),
+        RuleKind::Regex { patterns } => Matcher::Regex(
+            patterns
+                .iter()
+                .map(|pattern| {
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(true)
+                        .size_limit(1 << 20)
+                        .build()
+                        .map_err(|e| format!("Rule '{}': invalid regex: {}", rule.name, e))
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        RuleKind::Links { allowed_domains } => Matcher::Links(
+            allowed_domains
+                .iter()
+                .map(|domain| domain.trim().to_lowercase())
+                .collect(),
+        ),
+        RuleKind::Invites => Matcher::Invites,
+        RuleKind::MentionSpam { max_mentions } => Matcher::MentionSpam(*max_mentions),
+        RuleKind::RepeatedMessages {
+            max_repeats,
+            window_secs,
+        } => {
+            if *max_repeats == 0 {
+                return Err(format!(
+                    "Rule '{}': max_repeats must be at least 1",
+                    rule.name
+                ));
+            }
+            Matcher::RepeatedMessages {
+                max_repeats: *max_repeats,
+                window: Duration::from_secs(*window_secs),
+            }
+        }
+    };
+
+    let timeout_secs = rule.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+    if timeout_secs > MAX_TIMEOUT_SECS {
+        return Err(format!(
+            "Rule '{}': timeout_secs must be at most {}",
+            rule.name, MAX_TIMEOUT_SECS
+        ));
+    }
+
+    Ok(CompiledRule {
+        name: rule.name.clone(),
+        matcher,
+        action: rule.action,
+        timeout: Duration::from_secs(timeout_secs),
+    })
+}
+
+// Pull the host out of anything that looks like a URL
+fn link_host(token: &str) -> Option<String> {
+    let lower = token.to_lowercase();
+    let rest = lower
+        .strip_prefix("https://")
+        .or_else(|| lower.strip_prefix("http://"))
+        .or_else(|| lower.starts_with("www.").then_some(lower.as_str()))?;
+    let host = rest.split(['/', '?', '#', ':']).next()?;
+    (!host.is_empty()).then(|| host.to_string())
+}
+
+fn is_invite_link(token: &str) -> bool {
+    let lower = token.to_lowercase();
+    [
+        "discord.gg/",
+        "discord.com/invite/",
+        "discordapp.com/invite/",
+    ]
+    .iter()
+    .any(|pattern| lower.contains(pattern))
+        || (link_host(&lower).is_some() && lower.contains("/invite/"))
+}
+
+fn domain_allowed(host: &str, allowed: &[String]) -> bool {
+    allowed
+        .iter()
+        .any(|domain| host == domain || host.ends_with(&format!(".{}", domain)))
+}
+
+fn count_mentions(content: &str) -> usize {
+    content
+        .split_whitespace()
+        .filter(|token| token.len() > 1 && token.starts_with('@'))
+        .count()
+}
+
+fn contains_keyword(content: &str, words: &[String]) -> bool {
+    let lower = content.to_lowercase();
+    let tokens: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    words.iter().any(|word| {
+        if word.contains(char::is_whitespace) {
+            lower.contains(word.as_str())
+        } else {
+            tokens.contains(&word.as_str())
+        }
+    })
+}
+
+impl ModerationEngine {
+    pub fn config(&self) -> &ModerationConfig {
+        &self.config
+    }
+
+    // Validate and swap in a new rule set; the old one stays active on error
+    pub fn apply_config(&mut self, config: ModerationConfig) -> Result<(), String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(compile_rule)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.config = config;
+        self.rules = rules;
+        Ok(())
+    }
+
+    // Users still timed out get Muted; expired timeouts are forgotten
+    fn check_timeout(&mut self, user_id: &str, now: Instant) -> Option<Verdict> {
+        let until = *self.timeouts.get(user_id)?;
+        if until > now {
+            return Some(Verdict::Muted {
+                remaining: until - now,
+            });
+        }
+        self.timeouts.remove(user_id);
+        None
+    }
+
+    // For messages whose content the server cannot read; only timeouts apply
+    pub fn evaluate_sealed(&mut self, user_id: &str) -> Verdict {
+        self.check_timeout(user_id, Instant::now())
+            .unwrap_or(Verdict::Allow)
+    }
+
+    // Forget users whose recent messages and timeouts have all run out, so the maps
+    // only hold users active within the longest repeat window
+    fn prune(&mut self, now: Instant, longest_window: Duration) {
+        if self
+            .last_pruned
+            .is_some_and(|at| now.duration_since(at) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.last_pruned = Some(now);
+        self.recent.retain(|_, history| {
+            history
+                .back()
+                .is_some_and(|(sent_at, _)| now.duration_since(*sent_at) <= longest_window)
+        });
+        self.timeouts.retain(|_, until| *until > now);
+    }
+
+    pub fn evaluate(&mut self, user_id: &str, content: &str) -> Verdict {
+        self.evaluate_at(user_id, content, Instant::now())
+    }
+
+    fn evaluate_at(&mut self, user_id: &str, content: &str, now: Instant) -> Verdict {
+        if let Some(muted) = self.check_timeout(user_id, now) {
+            return muted;
+        }
+
+        if !self.config.enabled {
+            return Verdict::Allow;
+        }
+
+        let longest_window = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule.matcher {
+                Matcher::RepeatedMessages { window, .. } => Some(window),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+        self.prune(now, longest_window);
+
+        let normalized = content.trim().to_lowercase();
+        let history = self.recent.entry(user_id.to_string()).or_default();
+        while history
+            .front()
+            .is_some_and(|(sent_at, _)| now.duration_since(*sent_at) > longest_window)
+        {
+            history.pop_front();
+        }
+
+        let tokens: Vec<&str> = content.split_whitespace().collect();
+        let mut worst: Option<&CompiledRule> = None;
+
+        for rule in &self.rules {
+            let matched = match &rule.matcher {
+                Matcher::Keywords(words) => contains_keyword(content, words),
+                Matcher::Regex(patterns) => patterns.iter().any(|re| re.is_match(content)),
+                Matcher::Links(allowed) => tokens.iter().any(|token| {
+                    link_host(token).is_some_and(|host| !domain_allowed(&host, allowed))
+                }),
+                Matcher::Invites => tokens.iter().any(|token| is_invite_link(token)),
+                Matcher::MentionSpam(max) => count_mentions(content) > *max,
+                Matcher::RepeatedMessages {
+                    max_repeats,
+                    window,
+                } => {
+                    let repeats = history
+                        .iter()
+                        .filter(|(sent_at, text)| {
+                            now.duration_since(*sent_at) <= *window && *text == normalized
+                        })
+                        .count();
+                    repeats >= *max_repeats
+                }
+            };
+
+            if matched && worst.is_none_or(|current| rule.action > current.action) {
+                worst = Some(rule);
+            }
+        }
+
+        history.push_back((now, normalized));
+
+        match worst {
+            None => Verdict::Allow,
+            Some(rule) => match rule.action {
+                RuleAction::Flag => Verdict::Flag {
+                    rule: rule.name.clone(),
+                },
+                RuleAction::Block => Verdict::Block {
+                    rule: rule.name.clone(),
+                },
+                RuleAction::Timeout => {
+                    if let Some(until) = now.checked_add(rule.timeout) {
+                        self.timeouts.insert(user_id.to_string(), until);
+                    }
+                    Verdict::Timeout {
+                        rule: rule.name.clone(),
+                        duration: rule.timeout,
+                    }
+                }
+            },
+        }
+    }
+}
+
+// Judge a message before it is relayed and alert moderators to anything a rule caught.
+// Sealed messages (no plaintext) can only be held back by an earlier timeout
+pub async fn screen(
+    state: &AppState,
+    user_id: &str,
+    channel: &str,
+    plaintext: Option<&str>,
+) -> Verdict {
+    let (verdict, mod_channel) = {
+        let mut engine = state.moderation.write().await;
+        let verdict = match plaintext {
+            Some(plaintext) => engine.evaluate(user_id, plaintext),
+            None => engine.evaluate_sealed(user_id),
+        };
+        (verdict, engine.config().mod_channel.clone())
+    };
+
+    if let (Some(action), Some(mod_channel)) = (verdict.action(), mod_channel) {
+        let alert = ServerMessage::ModerationAlert {
+            channel: mod_channel,
+            source_channel: channel.to_string(),
+            user_id: user_id.to_string(),
+            rule: verdict.rule().map(str::to_string),
+            action: format!("{:?}", action).to_lowercase(),
+            excerpt: plaintext.unwrap_or_default().chars().take(200).collect(),
+        };
+        let guild = state.guild.read().await;
+        send_where(&state.clients, &alert, |_, client| {
+            guild.is_moderator(&client.user_id)
+        })
+        .await;
+    }
+    if !verdict.relays() {
+        println!("[MODERATION] {:?} for {}", verdict, user_id);
+    }
+    verdict
+}
+
+// GET /api/moderation/rules
+async fn get_rules(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ModerationConfig>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    let engine = state.moderation.read().await;
+    Ok(Json(engine.config().clone()))
+}
+
+// PUT /api/moderation/rules, takes effect for the next relayed message
+async fn put_rules(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(config): Json<ModerationConfig>,
+) -> Result<Json<ModerationConfig>, (StatusCode, String)> {
+    require_admin(&state, &headers)
+        .await
+        .map_err(|status| (status, "Admin token required".to_string()))?;
+
+    let mut engine = state.moderation.write().await;
+    engine
+        .apply_config(config)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    println!(
+        "Moderation rules updated ({} rules)",
+        engine.config().rules.len()
+    );
+    Ok(Json(engine.config().clone()))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/api/moderation/rules", get(get_rules).put(put_rules))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(rules: serde_json::Value) -> ModerationEngine {
+        let mut engine = ModerationEngine::default();
+        engine
+            .apply_config(serde_json::from_value(serde_json::json!({ "rules": rules })).unwrap())
+            .unwrap();
+        engine
+    }
+
+    fn rule(name: &str, action: &str, kind: serde_json::Value) -> serde_json::Value {
+        let mut rule = kind;
+        rule["name"] = name.into();
+        rule["action"] = action.into();
+        rule
+    }
+
+    fn block(name: &str) -> Verdict {
+        Verdict::Block {
+            rule: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn keywords_match_whole_words_and_phrases() {
+        let mut engine = engine(serde_json::json!([rule(
+            "words",
+            "block",
+            serde_json::json!({ "kind": "keywords", "words": ["spam", "buy now"] })
+        )]));
+        assert_eq!(engine.evaluate("alice", "This is SPAM!"), block("words"));
+        assert_eq!(engine.evaluate("alice", "please BUY NOW"), block("words"));
+        assert_eq!(
+            engine.evaluate("alice", "spammer and spamming"),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn regex_rules_match_case_insensitively_and_reject_bad_patterns() {
+        let mut engine = engine(serde_json::json!([rule(
+            "cards",
+            "flag",
+            serde_json::json!({ "kind": "regex", "patterns": [r"\bfree\s+nitro\b"] })
+        )]));
+        assert_eq!(
+            engine.evaluate("alice", "Free   Nitro here"),
+            Verdict::Flag {
+                rule: "cards".to_string()
+            }
+        );
+        assert_eq!(engine.evaluate("alice", "nitro is free"), Verdict::Allow);
+
+        // A broken rule set is refused and the old one stays in force
+        let broken = serde_json::json!({ "rules": [rule(
+            "broken",
+            "block",
+            serde_json::json!({ "kind": "regex", "patterns": ["("] })
+        )] });
+        assert!(
+            engine
+                .apply_config(serde_json::from_value(broken).unwrap())
+                .is_err()
+        );
+        assert_eq!(engine.config().rules[0].name, "cards");
+    }
+
+    #[test]
+    fn links_outside_allowed_domains_and_invites_are_caught() {
+        let mut engine = engine(serde_json::json!([
+            rule(
+                "links",
+                "block",
+                serde_json::json!({ "kind": "links", "allowed_domains": ["rust-lang.org"] })
+            ),
+            rule(
+                "invites",
+                "timeout",
+                serde_json::json!({ "kind": "invites" })
+            ),
+        ]));
+        assert_eq!(
+            engine.evaluate("alice", "see https://doc.rust-lang.org/book"),
+            Verdict::Allow
+        );
+        assert_eq!(
+            engine.evaluate("alice", "see http://evil.example/x"),
+            block("links")
+        );
+        assert_eq!(engine.evaluate("alice", "www.evil.example"), block("links"));
+        // The harsher action wins when several rules match
+        assert!(matches!(
+            engine.evaluate("bob", "join discord.gg/abc https://discord.com/invite/abc"),
+            Verdict::Timeout { rule, .. } if rule == "invites"
+        ));
+    }
+
+    #[test]
+    fn mention_spam_counts_mentions_above_the_limit() {
+        let mut engine = engine(serde_json::json!([rule(
+            "mentions",
+            "block",
+            serde_json::json!({ "kind": "mention_spam", "max_mentions": 2 })
+        )]));
+        assert_eq!(engine.evaluate("alice", "@bob @carol hi"), Verdict::Allow);
+        assert_eq!(
+            engine.evaluate("alice", "@bob @carol @dave hi @"),
+            block("mentions")
+        );
+    }
+
+    #[test]
+    fn repeats_count_within_the_window_only() {
+        let mut engine = engine(serde_json::json!([rule(
+            "repeats",
+            "block",
+            serde_json::json!({ "kind": "repeated_messages", "max_repeats": 2, "window_secs": 10 })
+        )]));
+        let start = Instant::now();
+        assert_eq!(engine.evaluate_at("alice", "hello", start), Verdict::Allow);
+        assert_eq!(
+            engine.evaluate_at("alice", " HELLO ", start + Duration::from_secs(1)),
+            Verdict::Allow
+        );
+        assert_eq!(
+            engine.evaluate_at("alice", "hello", start + Duration::from_secs(2)),
+            block("repeats")
+        );
+        // Someone else saying the same thing is not a repeat
+        assert_eq!(
+            engine.evaluate_at("bob", "hello", start + Duration::from_secs(2)),
+            Verdict::Allow
+        );
+        assert_eq!(
+            engine.evaluate_at("alice", "hello", start + Duration::from_secs(30)),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn timeouts_mute_until_they_run_out() {
+        let mut engine = engine(serde_json::json!([{
+            "name": "words",
+            "kind": "keywords",
+            "words": ["spam"],
+            "action": "timeout",
+            "timeout_secs": 60
+        }]));
+        let start = Instant::now();
+        assert_eq!(
+            engine.evaluate_at("alice", "spam", start),
+            Verdict::Timeout {
+                rule: "words".to_string(),
+                duration: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(
+            engine.evaluate_at("alice", "hi", start + Duration::from_secs(10)),
+            Verdict::Muted {
+                remaining: Duration::from_secs(50)
+            }
+        );
+        assert!(!engine.evaluate_sealed("alice").relays());
+        assert_eq!(
+            engine.evaluate_at("alice", "hi", start + Duration::from_secs(61)),
+            Verdict::Allow
+        );
+
+        // Timeouts long enough to overflow the clock are refused up front
+        let endless = serde_json::json!({ "rules": [{
+            "name": "endless",
+            "kind": "keywords",
+            "words": ["spam"],
+            "action": "timeout",
+            "timeout_secs": u64::MAX
+        }] });
+        assert!(
+            engine
+                .apply_config(serde_json::from_value(endless).unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn quiet_users_are_forgotten() {
+        let mut engine = engine(serde_json::json!([rule(
+            "repeats",
+            "block",
+            serde_json::json!({ "kind": "repeated_messages", "max_repeats": 3, "window_secs": 10 })
+        )]));
+        let start = Instant::now();
+        for (offset, user) in ["alice", "bob", "carol"].into_iter().enumerate() {
+            engine.evaluate_at(user, "hi", start + Duration::from_secs(offset as u64));
+        }
+        assert_eq!(engine.recent.len(), 3);
+
+        engine.evaluate_at(
+            "dave",
+            "hi",
+            start + PRUNE_INTERVAL + Duration::from_secs(1),
+        );
+        assert_eq!(engine.recent.keys().collect::<Vec<_>>(), ["dave"]);
+    }
+}
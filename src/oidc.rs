@@ -0,0 +1,443 @@
+// src/oidc.rs
+use async_trait::async_trait;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::auth::{
+    AuthError, AuthorizationRequest, CompletedAuthorization, OAuthConfig, OAuthProvider, OAuthUser,
+    access_token_from, authorize_url, provider_json,
+};
+use crate::providers::IdentityProvider;
+
+const DEFAULT_SCOPES: &str = "openid profile email";
+
+// Clock skew tolerated when checking ID token expiry
+const ID_TOKEN_LEEWAY_SECS: u64 = 60;
+
+// The parts of `.well-known/openid-configuration` we use
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: String,
+}
+
+// Claims we read from an ID token; signature, `iss`, `aud` and `exp` are checked by the decoder
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    profile: ProfileClaims,
+}
+
+// Standard profile claims, found in the ID token and the userinfo response
+#[derive(Debug, Default, Deserialize)]
+struct ProfileClaims {
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    #[serde(default)]
+    preferred_username: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    picture: Option<String>,
+}
+
+impl ProfileClaims {
+    // Only an address the provider says it verified; leaving the claim out is not saying so
+    fn verified_email(&self) -> Option<&str> {
+        match (&self.email, self.email_verified) {
+            (Some(email), Some(true)) if !email.is_empty() => Some(email),
+            _ => None,
+        }
+    }
+}
+
+// A single OpenID Connect issuer, e.g. a company Keycloak realm
+pub struct OidcClient {
+    issuer: String,
+    scopes: String,
+    config: OAuthConfig,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+// Only asymmetric algorithms; a token signed with the client secret proves nothing about the issuer
+fn supported_algorithm(alg: Algorithm) -> bool {
+    !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+impl OidcClient {
+    pub fn new(issuer: &str, scopes: &str, config: OAuthConfig) -> Self {
+        OidcClient {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            scopes: scopes.to_string(),
+            config,
+            http: reqwest::Client::new(),
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(None),
+        }
+    }
+
+    // Configured by OIDC_ISSUER, OIDC_CLIENT_ID and optional OIDC_SCOPES;
+    // discovery happens on first use
+    pub fn from_env() -> Option<Self> {
+        let issuer = std::env::var("OIDC_ISSUER").ok()?;
+        if issuer.trim().is_empty() {
+            return None;
+        }
+        let config = OAuthConfig::from_env("OIDC", "oidc", false)?;
+        let scopes = std::env::var("OIDC_SCOPES").unwrap_or_else(|_| DEFAULT_SCOPES.to_string());
+        Some(OidcClient::new(issuer.trim(), &scopes, config))
+    }
+
+    // Fetch and cache the discovery document
+    pub async fn metadata(&self) -> Result<ProviderMetadata, AuthError> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let res = self.http.get(&url).send().await;
+        let json = provider_json(res, AuthError::ProviderUnavailable).await?;
+        let metadata: ProviderMetadata = serde_json::from_value(json).map_err(|e| {
+            eprintln!("Invalid OIDC discovery document at {}: {}", url, e);
+            AuthError::ProviderUnavailable
+        })?;
+
+        // The document must describe the issuer we were configured with
+        if metadata.issuer.trim_end_matches('/') != self.issuer {
+            eprintln!(
+                "OIDC discovery issuer mismatch: expected {}, got {}",
+                self.issuer, metadata.issuer
+            );
+            return Err(AuthError::ProviderUnavailable);
+        }
+
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    // Signing keys, refetched when a token names a key we have not seen (key rotation)
+    async fn keys(&self, metadata: &ProviderMetadata, refresh: bool) -> Result<JwkSet, AuthError> {
+        if !refresh && let Some(jwks) = self.jwks.read().await.as_ref() {
+            return Ok(jwks.clone());
+        }
+
+        let res = self.http.get(&metadata.jwks_uri).send().await;
+        let json = provider_json(res, AuthError::ProviderUnavailable).await?;
+        let jwks: JwkSet = serde_json::from_value(json).map_err(|e| {
+            eprintln!("Invalid OIDC key set at {}: {}", metadata.jwks_uri, e);
+            AuthError::ProviderUnavailable
+        })?;
+
+        *self.jwks.write().await = Some(jwks.clone());
+        Ok(jwks)
+    }
+
+    async fn decoding_key(
+        &self,
+        metadata: &ProviderMetadata,
+        kid: Option<&str>,
+    ) -> Result<DecodingKey, AuthError> {
+        for refresh in [false, true] {
+            let jwks = self.keys(metadata, refresh).await?;
+            let jwk = match kid {
+                Some(kid) => jwks.find(kid),
+                // Without a `kid` the key set must be unambiguous
+                None if jwks.keys.len() == 1 => jwks.keys.first(),
+                None => None,
+            };
+            if let Some(jwk) = jwk {
+                return DecodingKey::from_jwk(jwk).map_err(|e| {
+                    eprintln!("Unusable OIDC signing key: {}", e);
+                    AuthError::InvalidIdToken
+                });
+            }
+        }
+
+        eprintln!("No OIDC signing key matches kid {:?}", kid);
+        Err(AuthError::InvalidIdToken)
+    }
+
+    // Check the ID token's signature, issuer, audience, expiry and nonce
+    async fn validate_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        client_id: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, AuthError> {
+        let header = jsonwebtoken::decode_header(id_token).map_err(|e| {
+            eprintln!("Malformed ID token: {}", e);
+            AuthError::InvalidIdToken
+        })?;
+        if !supported_algorithm(header.alg) {
+            eprintln!(
+                "ID token signed with unsupported algorithm {:?}",
+                header.alg
+            );
+            return Err(AuthError::InvalidIdToken);
+        }
+
+        let key = self.decoding_key(metadata, header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = ID_TOKEN_LEEWAY_SECS;
+        validation.set_audience(&[client_id]);
+        validation.set_issuer(&[metadata.issuer.as_str()]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| {
+                eprintln!("ID token rejected: {}", e);
+                AuthError::InvalidIdToken
+            })?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            eprintln!("ID token nonce does not match the login request");
+            return Err(AuthError::InvalidIdToken);
+        }
+        Ok(claims)
+    }
+
+    async fn userinfo(
+        &self,
+        metadata: &ProviderMetadata,
+        access_token: &str,
+        subject: &str,
+    ) -> Result<ProfileClaims, AuthError> {
+        let Some(endpoint) = &metadata.userinfo_endpoint else {
+            return Ok(ProfileClaims::default());
+        };
+
+        let res = self
+            .http
+            .get(endpoint)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await;
+        let json = provider_json(res, AuthError::ProfileFetchFailed).await?;
+
+        // Userinfo responses for another subject must not be mixed into this login
+        if json["sub"].as_str() != Some(subject) {
+            eprintln!("Userinfo subject does not match the ID token");
+            return Err(AuthError::ProfileFetchFailed);
+        }
+        serde_json::from_value(json).map_err(|_| AuthError::ProfileFetchFailed)
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for OidcClient {
+    fn id(&self) -> OAuthProvider {
+        OAuthProvider::new("oidc")
+    }
+
+    async fn authorize_url(&self, request: &AuthorizationRequest) -> Result<String, AuthError> {
+        let metadata = self.metadata().await?;
+        Ok(authorize_url(
+            &metadata.authorization_endpoint,
+            &self.config,
+            &self.scopes,
+            request,
+            &[("nonce", request.nonce.as_str())],
+        ))
+    }
+
+    // Redeem the authorization code and turn the validated ID token into a user
+    async fn authenticate(
+        &self,
+        code: &str,
+        authorization: &CompletedAuthorization,
+    ) -> Result<OAuthUser, AuthError> {
+        let config = &self.config;
+        let metadata = self.metadata().await?;
+
+        let mut params = vec![
+            ("client_id", config.client_id.as_str()),
+            ("code", code),
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("code_verifier", authorization.code_verifier.as_str()),
+        ];
+        if !config.client_secret.is_empty() {
+            params.push(("client_secret", config.client_secret.as_str()));
+        }
+
+        let res = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&params)
+            .send()
+            .await;
+        let json = provider_json(res, AuthError::TokenExchangeFailed).await?;
+        let access_token = access_token_from(&json)?;
+        let id_token = json["id_token"].as_str().ok_or_else(|| {
+            eprintln!("Token response did not include an ID token");
+            AuthError::InvalidIdToken
+        })?;
+
+        let claims = self
+            .validate_id_token(&metadata, id_token, &config.client_id, &authorization.nonce)
+            .await?;
+
+        // Keycloak and others leave profile claims out of the ID token unless asked
+        let userinfo = if claims.profile.verified_email().is_some() {
+            ProfileClaims::default()
+        } else {
+            self.userinfo(&metadata, &access_token, &claims.sub).await?
+        };
+
+        let email = claims
+            .profile
+            .verified_email()
+            .or_else(|| userinfo.verified_email())
+            .ok_or(AuthError::EmailMissing)?
+            .to_string();
+        let username = [&claims.profile, &userinfo]
+            .iter()
+            .find_map(|profile| {
+                profile
+                    .preferred_username
+                    .clone()
+                    .or_else(|| profile.name.clone())
+            })
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+        let avatar = claims.profile.picture.clone().or(userinfo.picture);
+
+        Ok(OAuthUser {
+            id: claims.sub,
+            username,
+            email,
+            email_verified: true,
+            avatar,
+            provider: self.id(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::OAuthStateStore;
+    use crate::mock_idp::MockIdp;
+    use crate::unix_now;
+    use reqwest::redirect::Policy;
+    use serde_json::json;
+
+    fn start_idp() -> MockIdp {
+        MockIdp::start(
+            json!({ "sub": "sub-1", "email": "ferris@example.com", "email_verified": true }),
+            json!([]),
+        )
+    }
+
+    // Run a whole login against the mock issuer
+    async fn login(idp: &MockIdp) -> Result<OAuthUser, AuthError> {
+        let client = OidcClient::new(
+            &idp.issuer(),
+            DEFAULT_SCOPES,
+            idp.config("http://localhost/auth/oidc/callback"),
+        );
+        let provider = client.id();
+        let mut states = OAuthStateStore::default();
+        let request = states.begin(&provider, None);
+
+        let http = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .unwrap();
+        let res = http
+            .get(client.authorize_url(&request).await?)
+            .send()
+            .await
+            .unwrap();
+        let location = url::Url::parse(res.headers()["location"].to_str().unwrap()).unwrap();
+        let code = location
+            .query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, value)| value.into_owned())
+            .expect("authorization code");
+
+        let authorization = states.complete(&request.state, &provider).unwrap();
+        client.authenticate(&code, &authorization).await
+    }
+
+    #[test]
+    fn only_emails_marked_verified_count() {
+        let claims = |email_verified: Option<bool>| ProfileClaims {
+            email: Some("ferris@example.com".to_string()),
+            email_verified,
+            ..ProfileClaims::default()
+        };
+        assert_eq!(
+            claims(Some(true)).verified_email(),
+            Some("ferris@example.com")
+        );
+        assert_eq!(claims(Some(false)).verified_email(), None);
+        assert_eq!(claims(None).verified_email(), None);
+    }
+
+    #[tokio::test]
+    async fn valid_id_tokens_log_in() {
+        let idp = start_idp();
+        idp.issue_id_tokens(json!({}));
+
+        // Profile claims missing from the ID token come from userinfo
+        let user = login(&idp).await.unwrap();
+        assert_eq!(user.id, "sub-1");
+        assert_eq!(user.email, "ferris@example.com");
+        assert!(user.email_verified);
+    }
+
+    #[tokio::test]
+    async fn unverified_emails_are_refused() {
+        let idp = MockIdp::start(
+            json!({ "sub": "sub-1", "email": "ferris@example.com" }),
+            json!([]),
+        );
+        idp.issue_id_tokens(json!({ "email": "ferris@example.com", "email_verified": false }));
+        assert_eq!(login(&idp).await.err(), Some(AuthError::EmailMissing));
+    }
+
+    #[tokio::test]
+    async fn forged_id_tokens_are_rejected() {
+        let idp = start_idp();
+        idp.issue_id_tokens(json!({}));
+        idp.forge_id_tokens();
+        assert_eq!(login(&idp).await.err(), Some(AuthError::InvalidIdToken));
+    }
+
+    #[tokio::test]
+    async fn id_tokens_for_someone_else_are_rejected() {
+        let expired = unix_now() - 3600;
+        for overrides in [
+            json!({ "iss": "https://evil.example" }),
+            json!({ "aud": "another-client" }),
+            json!({ "exp": expired, "iat": expired - 300 }),
+            json!({ "nonce": "from another login" }),
+            json!({ "nonce": null }),
+        ] {
+            let idp = start_idp();
+            idp.issue_id_tokens(overrides.clone());
+            assert_eq!(
+                login(&idp).await.err(),
+                Some(AuthError::InvalidIdToken),
+                "{}",
+                overrides
+            );
+        }
+    }
+}
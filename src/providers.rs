@@ -0,0 +1,515 @@
+// src/providers.rs
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::auth::{
+    AuthError, AuthorizationRequest, CompletedAuthorization, OAuthConfig, OAuthProvider, OAuthUser,
+    access_token_from, authorize_url, provider_json,
+};
+use crate::oidc::OidcClient;
+
+// Something users can log in with through `/auth/:provider`
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    fn id(&self) -> OAuthProvider;
+
+    // Where to send the browser to start a login
+    async fn authorize_url(&self, request: &AuthorizationRequest) -> Result<String, AuthError>;
+
+    // Redeem the code from the callback and return who logged in
+    async fn authenticate(
+        &self,
+        code: &str,
+        authorization: &CompletedAuthorization,
+    ) -> Result<OAuthUser, AuthError>;
+}
+
+// Provider URLs; every one can be overridden, e.g. for a self-hosted GitLab or a test IdP
+#[derive(Debug, Clone)]
+pub struct ProviderEndpoints {
+    pub authorize_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    // Queried for the primary address when the profile has no email (GitHub)
+    pub emails_url: Option<String>,
+}
+
+// Fields pulled out of a userinfo response
+pub struct Profile {
+    pub id: String,
+    pub username: String,
+    pub email: Option<String>,
+    // Whether the provider says it checked the address
+    pub email_verified: bool,
+    pub avatar: Option<String>,
+}
+
+// Everything that differs between plain OAuth 2.0 providers
+#[derive(Clone, Copy)]
+pub struct ProviderSpec {
+    pub slug: &'static str,
+    // Prefix of the provider's environment variables, e.g. GITHUB for GITHUB_CLIENT_ID
+    pub env_prefix: &'static str,
+    pub authorize_url: &'static str,
+    pub token_url: &'static str,
+    pub userinfo_url: &'static str,
+    pub emails_url: Option<&'static str>,
+    pub scopes: &'static str,
+    pub authorize_params: &'static [(&'static str, &'static str)],
+    pub profile: fn(&serde_json::Value) -> Option<Profile>,
+}
+
+fn json_string(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) if !s.is_empty() => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn google_profile(json: &serde_json::Value) -> Option<Profile> {
+    let email =
+        json_string(&json["email"]).filter(|_| json["verified_email"].as_bool() == Some(true));
+    Some(Profile {
+        id: json_string(&json["id"])?,
+        username: json["name"].as_str().unwrap_or_default().to_string(),
+        email_verified: email.is_some(),
+        email,
+        avatar: json_string(&json["picture"]),
+    })
+}
+
+fn github_profile(json: &serde_json::Value) -> Option<Profile> {
+    Some(Profile {
+        id: json["id"].as_u64()?.to_string(),
+        username: json["login"].as_str().unwrap_or_default().to_string(),
+        // GitHub only lets users make a verified address public
+        email: json_string(&json["email"]),
+        email_verified: true,
+        avatar: json_string(&json["avatar_url"]),
+    })
+}
+
+fn gitlab_profile(json: &serde_json::Value) -> Option<Profile> {
+    Some(Profile {
+        id: json["id"].as_u64()?.to_string(),
+        username: json["username"].as_str().unwrap_or_default().to_string(),
+        email: json_string(&json["email"]),
+        email_verified: json["confirmed_at"].is_string(),
+        avatar: json_string(&json["avatar_url"]),
+    })
+}
+
+fn discord_profile(json: &serde_json::Value) -> Option<Profile> {
+    let id = json_string(&json["id"])?;
+    let avatar = json["avatar"]
+        .as_str()
+        .map(|hash| format!("https://cdn.discordapp.com/avatars/{}/{}.png", id, hash));
+    let username = json["global_name"]
+        .as_str()
+        .or_else(|| json["username"].as_str())
+        .unwrap_or_default()
+        .to_string();
+    // Discord hands out addresses it has not verified
+    let email = json_string(&json["email"]).filter(|_| json["verified"].as_bool() == Some(true));
+
+    Some(Profile {
+        id,
+        username,
+        email_verified: email.is_some(),
+        email,
+        avatar,
+    })
+}
+
+fn microsoft_profile(json: &serde_json::Value) -> Option<Profile> {
+    // Any tenant of the common endpoint can set any email on its users, so the claim only
+    // counts with xms_edov, Entra's word that the tenant owns the address's domain.
+    // email_verified is not enough: the tenant asserts that one too
+    let email = json_string(&json["email"]).filter(|_| json["xms_edov"].as_bool() == Some(true));
+    Some(Profile {
+        id: json_string(&json["sub"])?,
+        username: json["name"].as_str().unwrap_or_default().to_string(),
+        email_verified: email.is_some(),
+        email,
+        avatar: None,
+    })
+}
+
+// The primary address from an emails listing, if it has been verified
+fn verified_primary_email(emails: &serde_json::Value) -> Option<String> {
+    emails
+        .as_array()?
+        .iter()
+        .find(|email| email["primary"].as_bool().unwrap_or(false))
+        .filter(|email| email["verified"].as_bool() == Some(true))
+        .and_then(|email| json_string(&email["email"]))
+}
+
+// OAuth 2.0 providers known out of the box; OpenID Connect issuers go through crate::oidc
+pub const BUILTIN_PROVIDERS: &[ProviderSpec] = &[
+    ProviderSpec {
+        slug: "google",
+        env_prefix: "GOOGLE",
+        authorize_url: "https://accounts.google.com/o/oauth2/v2/auth",
+        token_url: "https://oauth2.googleapis.com/token",
+        userinfo_url: "https://www.googleapis.com/oauth2/v2/userinfo",
+        emails_url: None,
+        scopes: "openid profile email",
+        authorize_params: &[("access_type", "offline"), ("prompt", "consent")],
+        profile: google_profile,
+    },
+    ProviderSpec {
+        slug: "github",
+        env_prefix: "GITHUB",
+        authorize_url: "https://github.com/login/oauth/authorize",
+        token_url: "https://github.com/login/oauth/access_token",
+        userinfo_url: "https://api.github.com/user",
+        emails_url: Some("https://api.github.com/user/emails"),
+        scopes: "user:email read:user",
+        authorize_params: &[],
+        profile: github_profile,
+    },
+    ProviderSpec {
+        slug: "gitlab",
+        env_prefix: "GITLAB",
+        authorize_url: "https://gitlab.com/oauth/authorize",
+        token_url: "https://gitlab.com/oauth/token",
+        userinfo_url: "https://gitlab.com/api/v4/user",
+        emails_url: None,
+        scopes: "read_user",
+        authorize_params: &[],
+        profile: gitlab_profile,
+    },
+    ProviderSpec {
+        slug: "discord",
+        env_prefix: "DISCORD",
+        authorize_url: "https://discord.com/oauth2/authorize",
+        token_url: "https://discord.com/api/oauth2/token",
+        userinfo_url: "https://discord.com/api/users/@me",
+        emails_url: None,
+        scopes: "identify email",
+        authorize_params: &[],
+        profile: discord_profile,
+    },
+    ProviderSpec {
+        slug: "microsoft",
+        env_prefix: "MICROSOFT",
+        authorize_url: "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+        token_url: "https://login.microsoftonline.com/common/oauth2/v2.0/token",
+        userinfo_url: "https://graph.microsoft.com/oidc/userinfo",
+        emails_url: None,
+        scopes: "openid profile email",
+        authorize_params: &[],
+        profile: microsoft_profile,
+    },
+];
+
+// A plain OAuth 2.0 provider: code exchange, then a userinfo request
+pub struct OAuth2Provider {
+    spec: ProviderSpec,
+    config: OAuthConfig,
+    endpoints: ProviderEndpoints,
+    http: reqwest::Client,
+}
+
+impl OAuth2Provider {
+    pub fn new(spec: ProviderSpec, config: OAuthConfig, endpoints: ProviderEndpoints) -> Self {
+        OAuth2Provider {
+            spec,
+            config,
+            endpoints,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    // Needs `<PREFIX>_CLIENT_ID` and `<PREFIX>_CLIENT_SECRET`; endpoints can be moved with
+    // `<PREFIX>_AUTHORIZE_URL`, `_TOKEN_URL`, `_USERINFO_URL` and `_EMAILS_URL`
+    pub fn from_env(spec: ProviderSpec) -> Option<Self> {
+        let config = OAuthConfig::from_env(spec.env_prefix, spec.slug, true)?;
+        let var = |name: &str, default: &str| {
+            std::env::var(format!("{}_{}", spec.env_prefix, name))
+                .unwrap_or_else(|_| default.to_string())
+        };
+        let endpoints = ProviderEndpoints {
+            authorize_url: var("AUTHORIZE_URL", spec.authorize_url),
+            token_url: var("TOKEN_URL", spec.token_url),
+            userinfo_url: var("USERINFO_URL", spec.userinfo_url),
+            emails_url: std::env::var(format!("{}_EMAILS_URL", spec.env_prefix))
+                .ok()
+                .or(spec.emails_url.map(str::to_string)),
+        };
+        Some(OAuth2Provider::new(spec, config, endpoints))
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        authorization: &CompletedAuthorization,
+    ) -> Result<String, AuthError> {
+        let params = [
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+            ("code", code),
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("code_verifier", authorization.code_verifier.as_str()),
+        ];
+
+        let res = self
+            .http
+            .post(&self.endpoints.token_url)
+            .header("Accept", "application/json")
+            .form(&params)
+            .send()
+            .await;
+
+        let json = provider_json(res, AuthError::TokenExchangeFailed).await?;
+        access_token_from(&json)
+    }
+
+    // The verified primary address, for profiles that hide it
+    async fn primary_email(&self, emails_url: &str, access_token: &str) -> Option<String> {
+        let res = self
+            .http
+            .get(emails_url)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header("User-Agent", "Rustcord")
+            .send()
+            .await;
+
+        let emails = provider_json(res, AuthError::EmailMissing).await.ok()?;
+        verified_primary_email(&emails)
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for OAuth2Provider {
+    fn id(&self) -> OAuthProvider {
+        OAuthProvider::new(self.spec.slug)
+    }
+
+    async fn authorize_url(&self, request: &AuthorizationRequest) -> Result<String, AuthError> {
+        Ok(authorize_url(
+            &self.endpoints.authorize_url,
+            &self.config,
+            self.spec.scopes,
+            request,
+            self.spec.authorize_params,
+        ))
+    }
+
+    async fn authenticate(
+        &self,
+        code: &str,
+        authorization: &CompletedAuthorization,
+    ) -> Result<OAuthUser, AuthError> {
+        let access_token = self.exchange_code(code, authorization).await?;
+
+        let res = self
+            .http
+            .get(&self.endpoints.userinfo_url)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header("User-Agent", "Rustcord")
+            .send()
+            .await;
+        let json = provider_json(res, AuthError::ProfileFetchFailed).await?;
+        let profile = (self.spec.profile)(&json).ok_or(AuthError::ProfileFetchFailed)?;
+
+        let email = match (profile.email, &self.endpoints.emails_url) {
+            (Some(email), _) => Some((email, profile.email_verified)),
+            (None, Some(emails_url)) => self
+                .primary_email(emails_url, &access_token)
+                .await
+                .map(|email| (email, true)),
+            (None, None) => None,
+        };
+        let (email, email_verified) = email.ok_or(AuthError::EmailMissing)?;
+
+        Ok(OAuthUser {
+            id: profile.id,
+            username: profile.username,
+            email,
+            email_verified,
+            avatar: profile.avatar,
+            provider: self.id(),
+        })
+    }
+}
+
+// Providers this server accepts logins from, keyed by slug
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: HashMap<String, Arc<dyn IdentityProvider>>,
+}
+
+pub type SharedProviders = Arc<ProviderRegistry>;
+
+impl ProviderRegistry {
+    // Every built-in provider with credentials in the environment, plus OIDC_ISSUER if set
+    pub fn from_env() -> Self {
+        let mut registry = ProviderRegistry::default();
+        for spec in BUILTIN_PROVIDERS {
+            if let Some(provider) = OAuth2Provider::from_env(*spec) {
+                registry.register(provider);
+            }
+        }
+        if let Some(oidc) = OidcClient::from_env() {
+            registry.register(oidc);
+        }
+        registry
+    }
+
+    pub fn register(&mut self, provider: impl IdentityProvider + 'static) {
+        self.providers
+            .insert(provider.id().slug().to_string(), Arc::new(provider));
+    }
+
+    // Known providers without credentials are reported as not configured
+    pub fn get(&self, slug: &str) -> Result<Arc<dyn IdentityProvider>, AuthError> {
+        if let Some(provider) = self.providers.get(slug) {
+            return Ok(provider.clone());
+        }
+        let known = slug == "oidc" || BUILTIN_PROVIDERS.iter().any(|spec| spec.slug == slug);
+        Err(if known {
+            AuthError::NotConfigured
+        } else {
+            AuthError::UnknownProvider
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn google_keeps_only_verified_emails() {
+        let profile = google_profile(&json!({
+            "id": "123",
+            "name": "Ferris",
+            "email": "ferris@example.com",
+            "verified_email": true,
+            "picture": "https://example.com/ferris.png"
+        }))
+        .unwrap();
+        assert_eq!(profile.id, "123");
+        assert_eq!(profile.username, "Ferris");
+        assert_eq!(profile.email.as_deref(), Some("ferris@example.com"));
+        assert!(profile.email_verified);
+        assert_eq!(
+            profile.avatar.as_deref(),
+            Some("https://example.com/ferris.png")
+        );
+
+        for verified in [json!(false), json!(null)] {
+            let profile = google_profile(&json!({
+                "id": "123", "email": "ferris@example.com", "verified_email": verified
+            }))
+            .unwrap();
+            assert_eq!(profile.email, None);
+            assert!(!profile.email_verified);
+        }
+        assert!(google_profile(&json!({ "email": "ferris@example.com" })).is_none());
+    }
+
+    #[test]
+    fn github_and_gitlab_profiles() {
+        let github = github_profile(&json!({
+            "id": 42, "login": "ferris", "email": "ferris@example.com", "avatar_url": "a"
+        }))
+        .unwrap();
+        assert_eq!(github.id, "42");
+        assert_eq!(github.username, "ferris");
+        assert_eq!(github.email.as_deref(), Some("ferris@example.com"));
+        assert!(github.email_verified);
+        // Left for the emails listing
+        let hidden = github_profile(&json!({ "id": 42, "login": "ferris", "email": null }));
+        assert_eq!(hidden.unwrap().email, None);
+
+        let gitlab = gitlab_profile(&json!({
+            "id": 7, "username": "ferris", "email": "ferris@example.com",
+            "confirmed_at": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap();
+        assert_eq!(gitlab.id, "7");
+        assert!(gitlab.email_verified);
+        let unconfirmed = gitlab_profile(&json!({
+            "id": 7, "username": "ferris", "email": "ferris@example.com"
+        }))
+        .unwrap();
+        assert!(!unconfirmed.email_verified);
+        assert!(gitlab_profile(&json!({ "id": "7" })).is_none());
+    }
+
+    #[test]
+    fn discord_keeps_only_verified_emails() {
+        let profile = discord_profile(&json!({
+            "id": "99", "username": "ferris", "global_name": "Ferris",
+            "avatar": "abc", "email": "ferris@example.com", "verified": true
+        }))
+        .unwrap();
+        assert_eq!(profile.username, "Ferris");
+        assert_eq!(profile.email.as_deref(), Some("ferris@example.com"));
+        assert_eq!(
+            profile.avatar.as_deref(),
+            Some("https://cdn.discordapp.com/avatars/99/abc.png")
+        );
+
+        let unverified = discord_profile(&json!({
+            "id": "99", "username": "ferris", "email": "ferris@example.com", "verified": false
+        }))
+        .unwrap();
+        assert_eq!(unverified.username, "ferris");
+        assert_eq!(unverified.email, None);
+        assert!(!unverified.email_verified);
+    }
+
+    #[test]
+    fn microsoft_needs_a_domain_verified_email() {
+        let profile = microsoft_profile(&json!({
+            "sub": "abc", "name": "Ferris", "email": "ferris@example.com", "xms_edov": true
+        }))
+        .unwrap();
+        assert_eq!(profile.id, "abc");
+        assert_eq!(profile.email.as_deref(), Some("ferris@example.com"));
+        assert!(profile.email_verified);
+
+        // Any tenant can put any address in the claim
+        for claims in [
+            json!({ "sub": "abc", "email": "ceo@example.com" }),
+            json!({ "sub": "abc", "email": "ceo@example.com", "xms_edov": false }),
+            json!({ "sub": "abc", "email": "ceo@example.com", "email_verified": true }),
+        ] {
+            let profile = microsoft_profile(&claims).unwrap();
+            assert_eq!(profile.email, None);
+            assert!(!profile.email_verified);
+        }
+    }
+
+    #[test]
+    fn primary_email_must_be_verified() {
+        assert_eq!(
+            verified_primary_email(&json!([
+                { "email": "old@example.com", "primary": false, "verified": true },
+                { "email": "primary@example.com", "primary": true, "verified": true }
+            ]))
+            .as_deref(),
+            Some("primary@example.com")
+        );
+        assert_eq!(
+            verified_primary_email(&json!([
+                { "email": "old@example.com", "primary": false, "verified": true },
+                { "email": "primary@example.com", "primary": true, "verified": false }
+            ])),
+            None
+        );
+        assert_eq!(
+            verified_primary_email(&json!([{ "email": "primary@example.com", "primary": true }])),
+            None
+        );
+        assert_eq!(verified_primary_email(&json!({})), None);
+    }
+}
@@ -0,0 +1,591 @@
+// src/session.rs
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header::USER_AGENT},
+    routing::{delete, get, post},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::{Rng, RngCore, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::auth::OAuthUser;
+use crate::state::AppState;
+use crate::unix_now;
+use crate::webserver::bearer_token;
+use crate::{ServerMessage, close_connections};
+
+const DEFAULT_SESSION_TTL_SECS: u64 = 12 * 60 * 60;
+// Refreshing cannot keep a session alive past this age
+const DEFAULT_SESSION_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+const EXCHANGE_CODE_TTL_SECS: u64 = 60;
+
+// Claims carried by our signed session token
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionClaims {
+    sub: String,
+    sid: String,
+    iat: u64,
+    exp: u64,
+}
+
+// Server-side record of a login; provider access tokens are never stored or sent out
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    // The user from crate::users; `user` is the identity they logged in with
+    pub user_id: String,
+    pub user: OAuthUser,
+    pub created_at: u64,
+    pub expires_at: u64,
+    // Refreshing cannot keep the session alive past this
+    pub not_after: u64,
+    // User-Agent of the client that redeemed the exchange code
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    Invalid,
+    Expired,
+    Revoked,
+}
+
+// Public view of the user a session belongs to
+#[derive(Debug, Serialize)]
+pub struct SessionUser {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub avatar: Option<String>,
+    pub provider: String,
+}
+
+impl From<&Session> for SessionUser {
+    fn from(session: &Session) -> Self {
+        let user = &session.user;
+        SessionUser {
+            id: session.user_id.clone(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            avatar: user.avatar.clone(),
+            provider: user.provider.slug().to_string(),
+        }
+    }
+}
+
+// A finished login whose session starts once the frontend redeems its exchange code
+struct PendingLogin {
+    user_id: String,
+    user: OAuthUser,
+    expires_at: u64,
+}
+
+pub struct SessionStore {
+    sessions: HashMap<String, Session>,
+    // One-time codes handed to the frontend in place of a token
+    exchange_codes: HashMap<String, PendingLogin>,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    ttl_secs: u64,
+    max_age_secs: u64,
+}
+
+pub type SharedSessions = Arc<RwLock<SessionStore>>;
+
+fn random_code(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+impl SessionStore {
+    pub fn new(secret: &[u8], ttl_secs: u64, max_age_secs: u64) -> Self {
+        SessionStore {
+            sessions: HashMap::new(),
+            exchange_codes: HashMap::new(),
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            ttl_secs,
+            max_age_secs,
+        }
+    }
+
+    // Sign with SESSION_SECRET, or a random per-process secret when it is unset;
+    // SESSION_TTL_SECS and SESSION_MAX_AGE_SECS bound each token and the whole session
+    pub fn from_env() -> Self {
+        let secret = match std::env::var("SESSION_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                eprintln!(
+                    "Warning: SESSION_SECRET not set, signing session tokens with a random key for this run only"
+                );
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let secs = |var: &str, default: u64| {
+            std::env::var(var)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let ttl_secs = secs("SESSION_TTL_SECS", DEFAULT_SESSION_TTL_SECS);
+        let max_age_secs = secs("SESSION_MAX_AGE_SECS", DEFAULT_SESSION_MAX_AGE_SECS);
+
+        SessionStore::new(&secret, ttl_secs, max_age_secs.max(ttl_secs))
+    }
+
+    fn insert(
+        &mut self,
+        user_id: &str,
+        user: OAuthUser,
+        device: Option<String>,
+        not_after: Option<u64>,
+    ) -> Session {
+        let now = unix_now();
+        let not_after = not_after.map_or(now + self.max_age_secs, |not_after| {
+            not_after.min(now + self.max_age_secs)
+        });
+        let session = Session {
+            id: random_code(32),
+            user_id: user_id.to_string(),
+            user,
+            created_at: now,
+            expires_at: (now + self.ttl_secs).min(not_after),
+            not_after,
+            device,
+        };
+
+        self.sessions.retain(|_, session| session.expires_at > now);
+        self.sessions.insert(session.id.clone(), session.clone());
+        session
+    }
+
+    // Return the one-time code the frontend exchanges for a token. The session only
+    // starts then, so a code that is never redeemed leaves nothing behind
+    pub fn create(&mut self, user_id: &str, user: OAuthUser) -> String {
+        let now = unix_now();
+        self.exchange_codes
+            .retain(|_, pending| pending.expires_at > now);
+        let code = random_code(32);
+        self.exchange_codes.insert(
+            code.clone(),
+            PendingLogin {
+                user_id: user_id.to_string(),
+                user,
+                expires_at: now + EXCHANGE_CODE_TTL_SECS,
+            },
+        );
+        code
+    }
+
+    // Start a session and sign its token right away, for logins that never leave our API
+    pub fn start(
+        &mut self,
+        user_id: &str,
+        user: OAuthUser,
+        device: Option<String>,
+    ) -> Result<(String, Session), SessionError> {
+        self.start_until(user_id, user, device, None)
+    }
+
+    // Like `start`, for sessions that must end by a fixed time however often they refresh
+    pub fn start_until(
+        &mut self,
+        user_id: &str,
+        user: OAuthUser,
+        device: Option<String>,
+        not_after: Option<u64>,
+    ) -> Result<(String, Session), SessionError> {
+        let session = self.insert(user_id, user, device, not_after);
+        let token = self.sign(&session)?;
+        Ok((token, session))
+    }
+
+    // Trade a one-time code for a signed token; the code cannot be used twice
+    pub fn exchange(
+        &mut self,
+        code: &str,
+        device: Option<String>,
+    ) -> Result<(String, Session), SessionError> {
+        let pending = self
+            .exchange_codes
+            .remove(code)
+            .ok_or(SessionError::Invalid)?;
+        if pending.expires_at <= unix_now() {
+            return Err(SessionError::Expired);
+        }
+        self.start(&pending.user_id, pending.user, device)
+    }
+
+    // Push a live session's expiry out by another TTL and issue a token for it
+    pub fn refresh(&mut self, token: &str) -> Result<(String, Session), SessionError> {
+        let current = self.verify(token)?;
+        let now = unix_now();
+        let expires_at = (now + self.ttl_secs).min(current.not_after);
+
+        let session = self
+            .sessions
+            .get_mut(&current.id)
+            .ok_or(SessionError::Revoked)?;
+        session.expires_at = session.expires_at.max(expires_at);
+        let session = session.clone();
+        let token = self.sign(&session)?;
+        Ok((token, session))
+    }
+
+    pub fn is_active(&self, session_id: &str) -> bool {
+        self.sessions
+            .get(session_id)
+            .is_some_and(|session| session.expires_at > unix_now())
+    }
+
+    pub fn revoke(&mut self, session_id: &str) -> Option<Session> {
+        self.sessions.remove(session_id)
+    }
+
+    // Revoke every session a user holds and return their ids
+    pub fn revoke_all(&mut self, user_id: &str) -> Vec<String> {
+        let revoked: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|session| session.user_id == user_id)
+            .map(|session| session.id.clone())
+            .collect();
+        for id in &revoked {
+            self.sessions.remove(id);
+        }
+        // Logins still waiting on their code would otherwise start a session afterwards
+        self.exchange_codes
+            .retain(|_, pending| pending.user_id != user_id);
+        revoked
+    }
+
+    // A user's live sessions, oldest first
+    pub fn list(&self, user_id: &str) -> Vec<Session> {
+        let now = unix_now();
+        let mut sessions: Vec<Session> = self
+            .sessions
+            .values()
+            .filter(|session| session.user_id == user_id && session.expires_at > now)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        sessions
+    }
+
+    fn sign(&self, session: &Session) -> Result<String, SessionError> {
+        let claims = SessionClaims {
+            sub: session.user_id.clone(),
+            sid: session.id.clone(),
+            iat: session.created_at,
+            exp: session.expires_at,
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|_| SessionError::Invalid)
+    }
+
+    // Check the signature and expiry, then make sure the session still exists
+    pub fn verify(&self, token: &str) -> Result<Session, SessionError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = jsonwebtoken::decode::<SessionClaims>(token, &self.decoding_key, &validation)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => SessionError::Expired,
+                _ => SessionError::Invalid,
+            })?
+            .claims;
+
+        let session = self
+            .sessions
+            .get(&claims.sid)
+            .ok_or(SessionError::Revoked)?;
+        if session.user_id != claims.sub {
+            return Err(SessionError::Invalid);
+        }
+        // Refreshing never shortens a session, but a revoked or aged-out one stays dead
+        if session.expires_at <= unix_now() {
+            return Err(SessionError::Expired);
+        }
+        Ok(session.clone())
+    }
+}
+
+// Resolve the session behind an `Authorization: Bearer` header
+pub async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Session, StatusCode> {
+    let token = bearer_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    state
+        .sessions
+        .read()
+        .await
+        .verify(token)
+        .map_err(|_| StatusCode::UNAUTHORIZED)
+}
+
+#[derive(Deserialize)]
+struct ExchangeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub token: String,
+    pub expires_at: u64,
+    pub user: SessionUser,
+}
+
+pub fn session_response(token: String, session: &Session) -> Json<SessionResponse> {
+    Json(SessionResponse {
+        token,
+        expires_at: session.expires_at,
+        user: SessionUser::from(session),
+    })
+}
+
+// User-Agent of the client, shown in the device list
+pub fn device(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// POST /api/session/exchange
+async fn exchange_code(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ExchangeRequest>,
+) -> Result<Json<SessionResponse>, StatusCode> {
+    let (token, session) = state
+        .sessions
+        .write()
+        .await
+        .exchange(&request.code, device(&headers))
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    Ok(session_response(token, &session))
+}
+
+// POST /api/session/refresh
+async fn refresh_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<SessionResponse>, StatusCode> {
+    let token = bearer_token(&headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    let (token, session) = state
+        .sessions
+        .write()
+        .await
+        .refresh(token)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    Ok(session_response(token, &session))
+}
+
+// Drop the WebSocket connections that were opened with any of these sessions
+pub async fn disconnect_sessions(state: &AppState, session_ids: &[String]) {
+    let notice = ServerMessage::Error {
+        code: "session_revoked".to_string(),
+        message: "This session has been logged out".to_string(),
+    };
+    close_connections(&state.clients, &notice, |client| {
+        session_ids.contains(&client.session_id)
+    })
+    .await;
+}
+
+// POST /api/logout
+async fn logout(State(state): State<AppState>, headers: HeaderMap) -> StatusCode {
+    let Ok(session) = authenticate(&state, &headers).await else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    state.sessions.write().await.revoke(&session.id);
+    disconnect_sessions(&state, &[session.id]).await;
+    StatusCode::NO_CONTENT
+}
+
+// POST /api/logout/all, logs the user out on every device
+async fn logout_everywhere(State(state): State<AppState>, headers: HeaderMap) -> StatusCode {
+    let Ok(session) = authenticate(&state, &headers).await else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let revoked = state.sessions.write().await.revoke_all(&session.user_id);
+    println!("Revoked {} sessions for {}", revoked.len(), session.user_id);
+    disconnect_sessions(&state, &revoked).await;
+    StatusCode::NO_CONTENT
+}
+
+// One entry in the device list
+#[derive(Serialize)]
+struct SessionInfo {
+    id: String,
+    device: Option<String>,
+    created_at: u64,
+    expires_at: u64,
+    current: bool,
+}
+
+// GET /api/sessions
+async fn list_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SessionInfo>>, StatusCode> {
+    let current = authenticate(&state, &headers).await?;
+    let sessions = state.sessions.read().await.list(&current.user_id);
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: session.id == current.id,
+                id: session.id,
+                device: session.device,
+                created_at: session.created_at,
+                expires_at: session.expires_at,
+            })
+            .collect(),
+    ))
+}
+
+// DELETE /api/sessions/:id, signs out one of the caller's other devices
+async fn revoke_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+) -> StatusCode {
+    let Ok(current) = authenticate(&state, &headers).await else {
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    {
+        let mut sessions = state.sessions.write().await;
+        let owned = sessions
+            .list(&current.user_id)
+            .iter()
+            .any(|session| session.id == session_id);
+        if !owned {
+            return StatusCode::NOT_FOUND;
+        }
+        sessions.revoke(&session_id);
+    }
+    disconnect_sessions(&state, &[session_id]).await;
+    StatusCode::NO_CONTENT
+}
+
+// GET /api/session
+async fn current_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<SessionUser>, StatusCode> {
+    let session = authenticate(&state, &headers).await?;
+    Ok(Json(SessionUser::from(&session)))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/session", get(current_session))
+        .route("/api/session/exchange", post(exchange_code))
+        .route("/api/session/refresh", post(refresh_session))
+        .route("/api/logout", post(logout))
+        .route("/api/logout/all", post(logout_everywhere))
+        .route("/api/sessions", get(list_sessions))
+        .route("/api/sessions/:id", delete(revoke_session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::OAuthProvider;
+
+    const TTL_SECS: u64 = 60 * 60;
+
+    fn ferris() -> OAuthUser {
+        OAuthUser {
+            id: "42".to_string(),
+            username: "ferris".to_string(),
+            email: "ferris@example.com".to_string(),
+            email_verified: true,
+            avatar: None,
+            provider: OAuthProvider::new("github"),
+        }
+    }
+
+    #[test]
+    fn issued_tokens_verify_until_revoked() {
+        let mut store = SessionStore::new(b"secret", TTL_SECS, 2 * TTL_SECS);
+        let (token, session) = store.start("github_42", ferris(), None).unwrap();
+        assert_eq!(store.verify(&token).unwrap().id, session.id);
+        assert!(store.is_active(&session.id));
+        assert_eq!(session.expires_at, session.created_at + TTL_SECS);
+
+        // Signed by another secret, or tampered with
+        let other = SessionStore::new(b"other secret", TTL_SECS, 2 * TTL_SECS);
+        assert_eq!(other.verify(&token).err(), Some(SessionError::Invalid));
+        assert_eq!(
+            store.verify(&format!("{}x", token)).err(),
+            Some(SessionError::Invalid)
+        );
+
+        // Revoking kills the token at once, however long it had left
+        assert!(store.revoke(&session.id).is_some());
+        assert_eq!(store.verify(&token).err(), Some(SessionError::Revoked));
+        assert!(!store.is_active(&session.id));
+
+        let (first, _) = store.start("github_42", ferris(), None).unwrap();
+        let (second, _) = store.start("github_42", ferris(), None).unwrap();
+        assert_eq!(store.revoke_all("github_42").len(), 2);
+        for token in [first, second] {
+            assert_eq!(store.verify(&token).err(), Some(SessionError::Revoked));
+        }
+    }
+
+    #[test]
+    fn exchange_codes_work_once() {
+        let mut store = SessionStore::new(b"secret", TTL_SECS, 2 * TTL_SECS);
+        let code = store.create("github_42", ferris());
+        // Nothing shows up as a session until the code is redeemed
+        assert!(store.list("github_42").is_empty());
+        let (token, session) = store.exchange(&code, Some("Firefox".to_string())).unwrap();
+        assert_eq!(store.list("github_42").len(), 1);
+        assert_eq!(session.device.as_deref(), Some("Firefox"));
+        assert_eq!(store.verify(&token).unwrap().user_id, "github_42");
+        assert_eq!(
+            store.exchange(&code, None).err(),
+            Some(SessionError::Invalid)
+        );
+
+        // Logging a user out everywhere also voids their unredeemed codes
+        let code = store.create("github_42", ferris());
+        store.revoke_all("github_42");
+        assert_eq!(
+            store.exchange(&code, None).err(),
+            Some(SessionError::Invalid)
+        );
+    }
+
+    #[test]
+    fn sessions_expire_and_refresh_within_their_max_age() {
+        let mut store = SessionStore::new(b"secret", 0, 0);
+        let (token, _) = store.start("github_42", ferris(), None).unwrap();
+        assert_eq!(store.verify(&token).err(), Some(SessionError::Expired));
+        assert_eq!(store.refresh(&token).err(), Some(SessionError::Expired));
+        assert!(store.list("github_42").is_empty());
+
+        let mut store = SessionStore::new(b"secret", TTL_SECS, 2 * TTL_SECS);
+        let not_after = unix_now() + 10;
+        let (token, session) = store
+            .start_until("github_42", ferris(), None, Some(not_after))
+            .unwrap();
+        assert_eq!(session.expires_at, not_after);
+        let (_, refreshed) = store.refresh(&token).unwrap();
+        assert_eq!(refreshed.expires_at, not_after);
+        assert_eq!(store.list("github_42").len(), 1);
+    }
+}
@@ -0,0 +1,293 @@
+// src/signing.rs
+// Message signatures. Every device signs what it sends with an Ed25519 key registered
+// to its user, so members can tell who wrote a message without trusting the server
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::history::authenticate_reader;
+use crate::session;
+use crate::state::AppState;
+use crate::unix_now;
+
+const MAX_DEVICES_PER_USER: usize = 10;
+
+// A device's Ed25519 public key; the private half never leaves that device
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceKey {
+    pub user_id: String,
+    pub public_key: String,
+    pub registered_at: u64,
+}
+
+// Sent and relayed next to a message's ciphertext
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSignature {
+    pub device_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigningError {
+    Unauthorized,
+    InvalidKey,
+    TooManyDevices,
+    // Registered to another user
+    KeyInUse,
+    // The author has device keys, so an unsigned message did not come from them
+    SignatureRequired,
+    UnknownDevice,
+    InvalidSignature,
+}
+
+impl SigningError {
+    // Stable code handed to the caller
+    pub fn code(&self) -> &'static str {
+        match self {
+            SigningError::Unauthorized => "unauthorized",
+            SigningError::InvalidKey => "invalid_key",
+            SigningError::TooManyDevices => "too_many_devices",
+            SigningError::KeyInUse => "device_key_in_use",
+            SigningError::SignatureRequired => "signature_required",
+            SigningError::UnknownDevice => "unknown_device_key",
+            SigningError::InvalidSignature => "invalid_signature",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            SigningError::Unauthorized => StatusCode::UNAUTHORIZED,
+            SigningError::TooManyDevices | SigningError::KeyInUse => StatusCode::CONFLICT,
+            SigningError::InvalidKey
+            | SigningError::SignatureRequired
+            | SigningError::UnknownDevice
+            | SigningError::InvalidSignature => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl IntoResponse for SigningError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(json!({ "error": self.code() }))).into_response()
+    }
+}
+
+// What a device signs: a JSON array of a label, the author, the channel and the
+// ciphertext, so a signature cannot be passed off as someone else's or moved elsewhere
+pub fn signed_bytes(author_id: &str, channel: &str, ciphertext: &str) -> Vec<u8> {
+    serde_json::to_vec(&("rustcord message", author_id, channel, ciphertext))
+        .expect("strings always serialize")
+}
+
+fn verifying_key(public_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64.decode(public_key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+#[derive(Default)]
+pub struct DeviceKeyStore {
+    devices: HashMap<String, Vec<DeviceKey>>,
+}
+
+pub type SharedDeviceKeys = Arc<RwLock<DeviceKeyStore>>;
+
+impl DeviceKeyStore {
+    pub fn register(&mut self, user_id: &str, public_key: &str) -> Result<DeviceKey, SigningError> {
+        if verifying_key(public_key).is_none() {
+            return Err(SigningError::InvalidKey);
+        }
+        if let Some((owner, key)) = self.find(public_key) {
+            return if owner == user_id {
+                Ok(key.clone())
+            } else {
+                Err(SigningError::KeyInUse)
+            };
+        }
+
+        let devices = self.devices.entry(user_id.to_string()).or_default();
+        if devices.len() >= MAX_DEVICES_PER_USER {
+            return Err(SigningError::TooManyDevices);
+        }
+        let key = DeviceKey {
+            user_id: user_id.to_string(),
+            public_key: public_key.to_string(),
+            registered_at: unix_now(),
+        };
+        devices.push(key.clone());
+        Ok(key)
+    }
+
+    fn find(&self, public_key: &str) -> Option<(&str, &DeviceKey)> {
+        self.devices.iter().find_map(|(user_id, devices)| {
+            devices
+                .iter()
+                .find(|key| key.public_key == public_key)
+                .map(|key| (user_id.as_str(), key))
+        })
+    }
+
+    pub fn devices(&self, user_id: &str) -> &[DeviceKey] {
+        self.devices.get(user_id).map_or(&[], Vec::as_slice)
+    }
+
+    // Users without devices, and bots, may still send unsigned messages
+    pub fn verify(
+        &self,
+        author_id: &str,
+        channel: &str,
+        ciphertext: &str,
+        signature: Option<&MessageSignature>,
+    ) -> Result<(), SigningError> {
+        let devices = self.devices(author_id);
+        let Some(signature) = signature else {
+            return if devices.is_empty() {
+                Ok(())
+            } else {
+                Err(SigningError::SignatureRequired)
+            };
+        };
+
+        let key = devices
+            .iter()
+            .find(|key| key.public_key == signature.device_key)
+            .and_then(|key| verifying_key(&key.public_key))
+            .ok_or(SigningError::UnknownDevice)?;
+        let bytes: [u8; 64] = BASE64
+            .decode(&signature.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(SigningError::InvalidSignature)?;
+        key.verify_strict(
+            &signed_bytes(author_id, channel, ciphertext),
+            &Signature::from_bytes(&bytes),
+        )
+        .map_err(|_| SigningError::InvalidSignature)
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterDeviceRequest {
+    public_key: String,
+}
+
+// PUT /api/keys/devices, for signed-in users; bots have no devices
+async fn register_device(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<RegisterDeviceRequest>,
+) -> Result<Json<DeviceKey>, SigningError> {
+    let session = session::authenticate(&state, &headers)
+        .await
+        .map_err(|_| SigningError::Unauthorized)?;
+    let key = state
+        .devices
+        .write()
+        .await
+        .register(&session.user_id, &request.public_key)?;
+    println!("Registered a device key for {}", session.user_id);
+    Ok(Json(key))
+}
+
+// GET /api/keys/devices/:user_id
+async fn list_devices(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<Vec<DeviceKey>>, SigningError> {
+    authenticate_reader(&state, &headers)
+        .await
+        .map_err(|_| SigningError::Unauthorized)?;
+    Ok(Json(state.devices.read().await.devices(&user_id).to_vec()))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/keys/devices", put(register_device))
+        .route("/api/keys/devices/:user_id", get(list_devices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn sign(
+        device: &SigningKey,
+        author: &str,
+        channel: &str,
+        ciphertext: &str,
+    ) -> MessageSignature {
+        MessageSignature {
+            device_key: BASE64.encode(device.verifying_key().as_bytes()),
+            signature: BASE64.encode(
+                device
+                    .sign(&signed_bytes(author, channel, ciphertext))
+                    .to_bytes(),
+            ),
+        }
+    }
+
+    #[test]
+    fn only_the_authors_devices_can_sign_for_them() {
+        let mut store = DeviceKeyStore::default();
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mallory = SigningKey::from_bytes(&[2; 32]);
+        let alice_key = BASE64.encode(alice.verifying_key().as_bytes());
+
+        // Unsigned messages are fine until a device is registered
+        assert_eq!(store.verify("alice", "general", "abc", None), Ok(()));
+        store.register("alice", &alice_key).unwrap();
+        assert_eq!(
+            store.register("mallory", &alice_key).unwrap_err(),
+            SigningError::KeyInUse
+        );
+        assert_eq!(
+            store.register("alice", "not a key").unwrap_err(),
+            SigningError::InvalidKey
+        );
+        assert_eq!(
+            store.verify("alice", "general", "abc", None),
+            Err(SigningError::SignatureRequired)
+        );
+
+        let signed = sign(&alice, "alice", "general", "abc");
+        assert_eq!(
+            store.verify("alice", "general", "abc", Some(&signed)),
+            Ok(())
+        );
+        // Bound to the ciphertext, the channel and the author
+        assert_eq!(
+            store.verify("alice", "general", "abd", Some(&signed)),
+            Err(SigningError::InvalidSignature)
+        );
+        assert_eq!(
+            store.verify("alice", "random", "abc", Some(&signed)),
+            Err(SigningError::InvalidSignature)
+        );
+        assert_eq!(
+            store.verify("bob", "general", "abc", Some(&signed)),
+            Err(SigningError::UnknownDevice)
+        );
+        assert_eq!(
+            store.verify(
+                "alice",
+                "general",
+                "abc",
+                Some(&sign(&mallory, "alice", "general", "abc"))
+            ),
+            Err(SigningError::UnknownDevice)
+        );
+    }
+}
@@ -0,0 +1,75 @@
+// src/state.rs
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::accounts::SharedAccounts;
+use crate::auth::SharedOAuthStates;
+use crate::bots::SharedBots;
+use crate::events::{Outbox, SharedOutbox};
+use crate::github::{GitHubIntegration, SharedGitHub};
+use crate::guild::{Guild, SharedGuild};
+use crate::history::{History, SharedHistory};
+use crate::invites::SharedInvites;
+use crate::keys::{KeyStore, SharedKeys};
+use crate::mailer::{self, SharedMailer};
+use crate::moderation::{ModerationEngine, SharedModeration};
+use crate::providers::{ProviderRegistry, SharedProviders};
+use crate::session::{SessionStore, SharedSessions};
+use crate::signing::SharedDeviceKeys;
+use crate::two_factor::SharedTwoFactor;
+use crate::users::{SharedUsers, UserDirectory};
+use crate::webhooks::SharedWebhooks;
+use crate::{Clients, PresenceConnections, PresenceState};
+
+// State shared between the web server and the WebSocket server
+#[derive(Clone)]
+pub struct AppState {
+    pub clients: Clients,
+    pub presence_state: PresenceState,
+    pub presence_connections: PresenceConnections,
+    pub guild: SharedGuild,
+    pub moderation: SharedModeration,
+    pub invites: SharedInvites,
+    pub sessions: SharedSessions,
+    pub oauth_states: SharedOAuthStates,
+    pub providers: SharedProviders,
+    pub accounts: SharedAccounts,
+    pub mailer: SharedMailer,
+    pub two_factor: SharedTwoFactor,
+    pub users: SharedUsers,
+    pub bots: SharedBots,
+    pub history: SharedHistory,
+    pub webhooks: SharedWebhooks,
+    pub events: SharedOutbox,
+    pub github: SharedGitHub,
+    pub keys: SharedKeys,
+    pub devices: SharedDeviceKeys,
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        AppState {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            presence_state: Arc::new(RwLock::new(HashMap::new())),
+            presence_connections: Arc::new(RwLock::new(HashMap::new())),
+            guild: Arc::new(RwLock::new(Guild::from_env())),
+            moderation: Arc::new(RwLock::new(ModerationEngine::default())),
+            invites: Arc::new(RwLock::new(Default::default())),
+            sessions: Arc::new(RwLock::new(SessionStore::from_env())),
+            oauth_states: Arc::new(RwLock::new(Default::default())),
+            providers: Arc::new(ProviderRegistry::from_env()),
+            accounts: Arc::new(RwLock::new(Default::default())),
+            mailer: mailer::from_env(),
+            two_factor: Arc::new(RwLock::new(Default::default())),
+            users: Arc::new(RwLock::new(UserDirectory::from_env())),
+            bots: Arc::new(RwLock::new(Default::default())),
+            history: Arc::new(RwLock::new(History::from_env())),
+            webhooks: Arc::new(RwLock::new(Default::default())),
+            events: Arc::new(RwLock::new(Outbox::from_env())),
+            github: Arc::new(RwLock::new(GitHubIntegration::from_env())),
+            keys: Arc::new(RwLock::new(KeyStore::default())),
+            devices: Arc::new(RwLock::new(Default::default())),
+        }
+    }
+}
@@ -0,0 +1,895 @@
+// src/two_factor.rs
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use totp_rs::{Algorithm, TOTP};
+
+use crate::accounts::{random_token, token_key};
+use crate::auth::OAuthUser;
+use crate::session::{self, SessionResponse, authenticate};
+use crate::state::AppState;
+use crate::unix_now;
+use crate::webserver::require_admin;
+
+const TOTP_ISSUER: &str = "Rustcord";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECS: u64 = 30;
+// Codes from the previous and next step are accepted to allow for clock drift
+const TOTP_SKEW_STEPS: u64 = 1;
+const SECRET_BYTES: usize = 20;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+// How long a half-finished login waits for its code, and how many guesses it gets
+const CHALLENGE_TTL_SECS: u64 = 5 * 60;
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+
+// Wrong codes in a row, over any number of challenges, before the account is locked, and for how long
+const MAX_FAILED_CODES: u32 = 5;
+const LOCKOUT_SECS: u64 = 15 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwoFactorError {
+    AlreadyEnabled,
+    NotEnrolled,
+    InvalidCode,
+    InvalidChallenge,
+    Locked { retry_after: u64 },
+    RequiredByGuild,
+    Unauthorized,
+    Internal,
+}
+
+impl TwoFactorError {
+    // Stable code handed to the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            TwoFactorError::AlreadyEnabled => "two_factor_already_enabled",
+            TwoFactorError::NotEnrolled => "two_factor_not_enrolled",
+            TwoFactorError::InvalidCode => "invalid_code",
+            TwoFactorError::InvalidChallenge => "invalid_challenge",
+            TwoFactorError::Locked { .. } => "account_locked",
+            TwoFactorError::RequiredByGuild => "two_factor_required_by_guild",
+            TwoFactorError::Unauthorized => "unauthorized",
+            TwoFactorError::Internal => "internal_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            TwoFactorError::AlreadyEnabled => StatusCode::CONFLICT,
+            TwoFactorError::NotEnrolled | TwoFactorError::InvalidCode => StatusCode::BAD_REQUEST,
+            TwoFactorError::InvalidChallenge | TwoFactorError::Unauthorized => {
+                StatusCode::UNAUTHORIZED
+            }
+            TwoFactorError::Locked { .. } => StatusCode::TOO_MANY_REQUESTS,
+            TwoFactorError::RequiredByGuild => StatusCode::FORBIDDEN,
+            TwoFactorError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for TwoFactorError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(json!({ "error": self.code() }))).into_response();
+        if let TwoFactorError::Locked { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+// A user's authenticator; only enforced once a first code has confirmed the setup
+struct Enrollment {
+    secret: Vec<u8>,
+    enabled: bool,
+    // SHA-256 of the unused recovery codes
+    recovery_codes: HashSet<String>,
+    // Last TOTP step accepted, so a code cannot be replayed
+    last_step: u64,
+    failed_codes: u32,
+    locked_until: u64,
+}
+
+// A login that passed its first factor and waits for a code
+struct Challenge {
+    user_id: String,
+    // The identity the first factor was checked for
+    identity: OAuthUser,
+    // The guild requires 2FA but the user has not set it up yet
+    setup: bool,
+    expires_at: u64,
+    attempts: u32,
+}
+
+// What the client needs to finish a login with a second factor
+#[derive(Debug, Clone, Serialize)]
+pub struct TwoFactorChallenge {
+    pub challenge: String,
+    pub setup_required: bool,
+    pub expires_at: u64,
+}
+
+// Answer to a first-factor login
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LoginResponse {
+    Complete(SessionResponse),
+    TwoFactorRequired(TwoFactorChallenge),
+}
+
+// Secret and `otpauth://` URI to show (usually as a QR code) in an authenticator app
+#[derive(Debug, Serialize)]
+pub struct Provisioning {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Default)]
+pub struct TwoFactorStore {
+    enrollments: HashMap<String, Enrollment>,
+    // Keyed by the SHA-256 of the challenge token
+    challenges: HashMap<String, Challenge>,
+}
+
+pub type SharedTwoFactor = Arc<RwLock<TwoFactorStore>>;
+
+fn totp(secret: &[u8], account_name: &str) -> Result<TOTP, TwoFactorError> {
+    // Labels are `issuer:account`, so the account name cannot carry a colon
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP_SECS,
+        secret.to_vec(),
+        Some(TOTP_ISSUER.to_string()),
+        account_name.replace(':', ""),
+    )
+    .map_err(|e| {
+        eprintln!("Invalid TOTP parameters: {:?}", e);
+        TwoFactorError::Internal
+    })
+}
+
+// The step a code was generated for, if it is valid around `now`
+fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let totp = totp(secret, "").ok()?;
+    let current = now / TOTP_STEP_SECS;
+    (current.saturating_sub(TOTP_SKEW_STEPS)..=current + TOTP_SKEW_STEPS)
+        .find(|step| totp.check(code, step * TOTP_STEP_SECS))
+}
+
+// Recovery codes are typed by hand; ignore case, spaces and dashes
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn is_totp_code(code: &str) -> bool {
+    code.len() == TOTP_DIGITS && code.chars().all(|c| c.is_ascii_digit())
+}
+
+// Shown once as `abcde-fghij`; only their hashes are kept
+fn generate_recovery_codes() -> (Vec<String>, HashSet<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = random_token(RECOVERY_CODE_LENGTH).to_lowercase();
+            let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+            format!("{}-{}", first, second)
+        })
+        .collect();
+    let hashes = codes
+        .iter()
+        .map(|code| token_key(&normalize_code(code)))
+        .collect();
+    (codes, hashes)
+}
+
+impl TwoFactorStore {
+    pub fn is_enabled(&self, user_id: &str) -> bool {
+        self.enrollments
+            .get(user_id)
+            .is_some_and(|enrollment| enrollment.enabled)
+    }
+
+    pub fn recovery_codes_remaining(&self, user_id: &str) -> usize {
+        self.enrollments
+            .get(user_id)
+            .filter(|enrollment| enrollment.enabled)
+            .map_or(0, |enrollment| enrollment.recovery_codes.len())
+    }
+
+    // Start over with a fresh secret; nothing is enforced until it is confirmed
+    pub fn begin_enrollment(
+        &mut self,
+        user_id: &str,
+        account_name: &str,
+    ) -> Result<Provisioning, TwoFactorError> {
+        if self.is_enabled(user_id) {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+
+        let mut secret = vec![0u8; SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let totp = totp(&secret, account_name)?;
+        let provisioning = Provisioning {
+            secret: totp.get_secret_base32(),
+            otpauth_uri: totp.get_url(),
+        };
+
+        self.enrollments.insert(
+            user_id.to_string(),
+            Enrollment {
+                secret,
+                enabled: false,
+                recovery_codes: HashSet::new(),
+                last_step: 0,
+                failed_codes: 0,
+                locked_until: 0,
+            },
+        );
+        Ok(provisioning)
+    }
+
+    // A first code from the app proves it was set up; returns the recovery codes
+    pub fn confirm_enrollment(
+        &mut self,
+        user_id: &str,
+        code: &str,
+    ) -> Result<Vec<String>, TwoFactorError> {
+        let enrollment = self
+            .enrollments
+            .get_mut(user_id)
+            .ok_or(TwoFactorError::NotEnrolled)?;
+        if enrollment.enabled {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+
+        let code = normalize_code(code);
+        let step = matching_step(&enrollment.secret, &code, unix_now())
+            .ok_or(TwoFactorError::InvalidCode)?;
+        let (codes, hashes) = generate_recovery_codes();
+        enrollment.enabled = true;
+        enrollment.last_step = step;
+        enrollment.recovery_codes = hashes;
+        Ok(codes)
+    }
+
+    // Check a TOTP code or use up a recovery code, locking the account after repeated failures
+    pub fn verify(&mut self, user_id: &str, code: &str) -> Result<(), TwoFactorError> {
+        let enrollment = self
+            .enrollments
+            .get_mut(user_id)
+            .filter(|enrollment| enrollment.enabled)
+            .ok_or(TwoFactorError::NotEnrolled)?;
+
+        let now = unix_now();
+        if enrollment.locked_until > now {
+            return Err(TwoFactorError::Locked {
+                retry_after: enrollment.locked_until - now,
+            });
+        }
+
+        let code = normalize_code(code);
+        let valid = if is_totp_code(&code) {
+            match matching_step(&enrollment.secret, &code, now) {
+                Some(step) if step > enrollment.last_step => {
+                    enrollment.last_step = step;
+                    true
+                }
+                _ => false,
+            }
+        } else if enrollment.recovery_codes.remove(&token_key(&code)) {
+            println!(
+                "Recovery code used for {} ({} left)",
+                user_id,
+                enrollment.recovery_codes.len()
+            );
+            true
+        } else {
+            false
+        };
+
+        if valid {
+            enrollment.failed_codes = 0;
+            return Ok(());
+        }
+        enrollment.failed_codes += 1;
+        if enrollment.failed_codes >= MAX_FAILED_CODES {
+            println!("Locking 2FA for {} after repeated wrong codes", user_id);
+            enrollment.failed_codes = 0;
+            enrollment.locked_until = now + LOCKOUT_SECS;
+        }
+        Err(TwoFactorError::InvalidCode)
+    }
+
+    pub fn disable(&mut self, user_id: &str, code: &str) -> Result<(), TwoFactorError> {
+        self.verify(user_id, code)?;
+        self.enrollments.remove(user_id);
+        Ok(())
+    }
+
+    pub fn regenerate_recovery_codes(
+        &mut self,
+        user_id: &str,
+        code: &str,
+    ) -> Result<Vec<String>, TwoFactorError> {
+        self.verify(user_id, code)?;
+        let (codes, hashes) = generate_recovery_codes();
+        if let Some(enrollment) = self.enrollments.get_mut(user_id) {
+            enrollment.recovery_codes = hashes;
+        }
+        Ok(codes)
+    }
+
+    fn challenge(&mut self, user_id: &str, identity: OAuthUser, setup: bool) -> TwoFactorChallenge {
+        let now = unix_now();
+        self.challenges
+            .retain(|_, challenge| challenge.expires_at > now);
+
+        let token = random_token(40);
+        let expires_at = now + CHALLENGE_TTL_SECS;
+        self.challenges.insert(
+            token_key(&token),
+            Challenge {
+                user_id: user_id.to_string(),
+                identity,
+                setup,
+                expires_at,
+                attempts: 0,
+            },
+        );
+        TwoFactorChallenge {
+            challenge: token,
+            setup_required: setup,
+            expires_at,
+        }
+    }
+
+    fn pending(&self, token: &str) -> Result<&Challenge, TwoFactorError> {
+        self.challenges
+            .get(&token_key(token))
+            .filter(|challenge| challenge.expires_at > unix_now())
+            .ok_or(TwoFactorError::InvalidChallenge)
+    }
+
+    // Finish a login with a code; returns the user plus recovery codes if this was the setup.
+    // Too many wrong codes throw the challenge away and the login starts over.
+    fn complete_challenge(
+        &mut self,
+        token: &str,
+        code: &str,
+    ) -> Result<(String, OAuthUser, Option<Vec<String>>), TwoFactorError> {
+        let (user_id, identity, setup) = {
+            let challenge = self.pending(token)?;
+            (
+                challenge.user_id.clone(),
+                challenge.identity.clone(),
+                challenge.setup,
+            )
+        };
+
+        let result = if setup {
+            self.confirm_enrollment(&user_id, code).map(Some)
+        } else {
+            self.verify(&user_id, code).map(|_| None)
+        };
+
+        let key = token_key(token);
+        match result {
+            Ok(recovery_codes) => {
+                self.challenges.remove(&key);
+                Ok((user_id, identity, recovery_codes))
+            }
+            Err(err) => {
+                if let Some(challenge) = self.challenges.get_mut(&key) {
+                    challenge.attempts += 1;
+                    if challenge.attempts >= MAX_CHALLENGE_ATTEMPTS {
+                        self.challenges.remove(&key);
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+// Called once the first factor checks out: users with 2FA, or who must set it up,
+// get a challenge instead of a session
+pub async fn challenge_login(
+    state: &AppState,
+    user_id: &str,
+    identity: &OAuthUser,
+) -> Option<TwoFactorChallenge> {
+    let mut store = state.two_factor.write().await;
+    if store.is_enabled(user_id) {
+        return Some(store.challenge(user_id, identity.clone(), false));
+    }
+    if state.guild.read().await.requires_2fa(user_id) {
+        return Some(store.challenge(user_id, identity.clone(), true));
+    }
+    None
+}
+
+#[derive(Serialize)]
+struct TwoFactorStatus {
+    enabled: bool,
+    recovery_codes_remaining: usize,
+    required: bool,
+}
+
+// GET /api/2fa
+async fn status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TwoFactorStatus>, TwoFactorError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| TwoFactorError::Unauthorized)?;
+    let user_id = session.user_id;
+    let store = state.two_factor.read().await;
+
+    Ok(Json(TwoFactorStatus {
+        enabled: store.is_enabled(&user_id),
+        recovery_codes_remaining: store.recovery_codes_remaining(&user_id),
+        required: state.guild.read().await.requires_2fa(&user_id),
+    }))
+}
+
+// POST /api/2fa/enroll
+async fn enroll(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Provisioning>, TwoFactorError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| TwoFactorError::Unauthorized)?;
+    let provisioning = state
+        .two_factor
+        .write()
+        .await
+        .begin_enrollment(&session.user_id, &session.user.email)?;
+    Ok(Json(provisioning))
+}
+
+#[derive(Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+// POST /api/2fa/enroll/confirm
+async fn confirm_enroll(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CodeRequest>,
+) -> Result<Json<RecoveryCodes>, TwoFactorError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| TwoFactorError::Unauthorized)?;
+    let user_id = session.user_id;
+    let recovery_codes = state
+        .two_factor
+        .write()
+        .await
+        .confirm_enrollment(&user_id, &request.code)?;
+
+    println!("Two-factor authentication enabled for {}", user_id);
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+// POST /api/2fa/recovery-codes, replaces every unused code
+async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CodeRequest>,
+) -> Result<Json<RecoveryCodes>, TwoFactorError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| TwoFactorError::Unauthorized)?;
+    let recovery_codes = state
+        .two_factor
+        .write()
+        .await
+        .regenerate_recovery_codes(&session.user_id, &request.code)?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+// POST /api/2fa/disable
+async fn disable(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CodeRequest>,
+) -> Result<StatusCode, TwoFactorError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| TwoFactorError::Unauthorized)?;
+    let user_id = session.user_id;
+    if state.guild.read().await.requires_2fa(&user_id) {
+        return Err(TwoFactorError::RequiredByGuild);
+    }
+
+    state
+        .two_factor
+        .write()
+        .await
+        .disable(&user_id, &request.code)?;
+    println!("Two-factor authentication disabled for {}", user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ChallengeSetupRequest {
+    challenge: String,
+}
+
+// POST /api/2fa/challenge/setup, for users the guild makes enroll before logging in
+async fn challenge_setup(
+    State(state): State<AppState>,
+    Json(request): Json<ChallengeSetupRequest>,
+) -> Result<Json<Provisioning>, TwoFactorError> {
+    let mut store = state.two_factor.write().await;
+    let (user_id, email) = {
+        let challenge = store.pending(&request.challenge)?;
+        if !challenge.setup {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+        (challenge.user_id.clone(), challenge.identity.email.clone())
+    };
+    let provisioning = store.begin_enrollment(&user_id, &email)?;
+    Ok(Json(provisioning))
+}
+
+#[derive(Deserialize)]
+struct ChallengeVerifyRequest {
+    challenge: String,
+    code: String,
+}
+
+// A finished login, with recovery codes when 2FA was set up along the way
+#[derive(Serialize)]
+struct VerifiedLogin {
+    #[serde(flatten)]
+    session: SessionResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+}
+
+// POST /api/2fa/challenge/verify, issues the session the first factor was waiting on
+async fn challenge_verify(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ChallengeVerifyRequest>,
+) -> Result<Json<VerifiedLogin>, TwoFactorError> {
+    let (user_id, identity, recovery_codes) = state
+        .two_factor
+        .write()
+        .await
+        .complete_challenge(&request.challenge, &request.code)?;
+
+    let (token, session) = state
+        .sessions
+        .write()
+        .await
+        .start(&user_id, identity, session::device(&headers))
+        .map_err(|_| TwoFactorError::Internal)?;
+    Ok(Json(VerifiedLogin {
+        session: session::session_response(token, &session).0,
+        recovery_codes,
+    }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TwoFactorPolicy {
+    require_for_moderators: bool,
+}
+
+// GET /api/guild/two-factor
+async fn get_policy(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TwoFactorPolicy>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    Ok(Json(TwoFactorPolicy {
+        require_for_moderators: state.guild.read().await.requires_moderator_2fa(),
+    }))
+}
+
+// PUT /api/guild/two-factor, applies from each moderator's next login
+async fn put_policy(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(policy): Json<TwoFactorPolicy>,
+) -> Result<Json<TwoFactorPolicy>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    state
+        .guild
+        .write()
+        .await
+        .set_require_moderator_2fa(policy.require_for_moderators);
+    println!(
+        "Two-factor requirement for moderators {}",
+        if policy.require_for_moderators {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    Ok(Json(policy))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/2fa", get(status))
+        .route("/api/2fa/enroll", post(enroll))
+        .route("/api/2fa/enroll/confirm", post(confirm_enroll))
+        .route("/api/2fa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/api/2fa/disable", post(disable))
+        .route("/api/2fa/challenge/setup", post(challenge_setup))
+        .route("/api/2fa/challenge/verify", post(challenge_verify))
+        .route("/api/guild/two-factor", get(get_policy).put(put_policy))
+}
+
+// The code an authenticator app would show, `step_offset` steps from now
+#[cfg(test)]
+pub fn code_for(provisioning: &Provisioning, step_offset: i64) -> String {
+    let secret = totp_rs::Secret::Encoded(provisioning.secret.clone())
+        .to_bytes()
+        .unwrap();
+    let time = (unix_now() as i64 + step_offset * TOTP_STEP_SECS as i64) as u64;
+    totp(&secret, "").unwrap().generate(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::OAuthProvider;
+    use crate::guild::Role;
+
+    const USER_ID: &str = "github_42";
+
+    fn ferris() -> OAuthUser {
+        OAuthUser {
+            id: "42".to_string(),
+            username: "ferris".to_string(),
+            email: "ferris@example.com".to_string(),
+            email_verified: true,
+            avatar: None,
+            provider: OAuthProvider::new("github"),
+        }
+    }
+
+    async fn verify_challenge(
+        state: &AppState,
+        challenge: &str,
+        code: &str,
+    ) -> Result<VerifiedLogin, TwoFactorError> {
+        challenge_verify(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(ChallengeVerifyRequest {
+                challenge: challenge.to_string(),
+                code: code.to_string(),
+            }),
+        )
+        .await
+        .map(|Json(login)| login)
+    }
+
+    #[tokio::test]
+    async fn enrolled_users_finish_login_with_a_code_or_recovery_code() {
+        let state = AppState::new();
+        let user = ferris();
+        assert!(challenge_login(&state, USER_ID, &user).await.is_none());
+
+        let (provisioning, recovery_codes) = {
+            let mut store = state.two_factor.write().await;
+            let provisioning = store.begin_enrollment(USER_ID, &user.email).unwrap();
+            assert!(
+                provisioning
+                    .otpauth_uri
+                    .starts_with("otpauth://totp/Rustcord:ferris%40example.com?secret=")
+            );
+            let codes = store
+                .confirm_enrollment(USER_ID, &code_for(&provisioning, -1))
+                .unwrap();
+            (provisioning, codes)
+        };
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        assert!(!challenge.setup_required);
+        assert_eq!(
+            verify_challenge(&state, &challenge.challenge, "000000")
+                .await
+                .err(),
+            Some(TwoFactorError::InvalidCode)
+        );
+        let login = verify_challenge(&state, &challenge.challenge, &code_for(&provisioning, 0))
+            .await
+            .unwrap();
+        assert!(login.recovery_codes.is_none());
+        assert!(
+            state
+                .sessions
+                .read()
+                .await
+                .verify(&login.session.token)
+                .is_ok()
+        );
+
+        // Challenges and codes are single use
+        assert_eq!(
+            verify_challenge(&state, &challenge.challenge, &code_for(&provisioning, 0))
+                .await
+                .err(),
+            Some(TwoFactorError::InvalidChallenge)
+        );
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        assert_eq!(
+            verify_challenge(&state, &challenge.challenge, &code_for(&provisioning, 0))
+                .await
+                .err(),
+            Some(TwoFactorError::InvalidCode)
+        );
+
+        let recovery = recovery_codes[0].to_uppercase();
+        assert!(
+            verify_challenge(&state, &challenge.challenge, &recovery)
+                .await
+                .is_ok()
+        );
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        assert_eq!(
+            verify_challenge(&state, &challenge.challenge, &recovery)
+                .await
+                .err(),
+            Some(TwoFactorError::InvalidCode)
+        );
+        assert_eq!(
+            state
+                .two_factor
+                .read()
+                .await
+                .recovery_codes_remaining(USER_ID),
+            RECOVERY_CODE_COUNT - 1
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_wrong_codes_discard_the_challenge() {
+        let state = AppState::new();
+        let user = ferris();
+        let provisioning = {
+            let mut store = state.two_factor.write().await;
+            let provisioning = store.begin_enrollment(USER_ID, &user.email).unwrap();
+            store
+                .confirm_enrollment(USER_ID, &code_for(&provisioning, -1))
+                .unwrap();
+            provisioning
+        };
+
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            assert!(
+                verify_challenge(&state, &challenge.challenge, "bad-code")
+                    .await
+                    .is_err()
+            );
+        }
+        assert_eq!(
+            verify_challenge(&state, &challenge.challenge, &code_for(&provisioning, 0))
+                .await
+                .err(),
+            Some(TwoFactorError::InvalidChallenge)
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_across_challenges_lock_the_account() {
+        let state = AppState::new();
+        let user = ferris();
+        let provisioning = {
+            let mut store = state.two_factor.write().await;
+            let provisioning = store.begin_enrollment(USER_ID, &user.email).unwrap();
+            store
+                .confirm_enrollment(USER_ID, &code_for(&provisioning, -1))
+                .unwrap();
+            provisioning
+        };
+
+        // A fresh challenge per guess does not reset the count
+        for _ in 0..MAX_FAILED_CODES {
+            let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+            assert_eq!(
+                verify_challenge(&state, &challenge.challenge, "000000")
+                    .await
+                    .err(),
+                Some(TwoFactorError::InvalidCode)
+            );
+        }
+
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        let locked = verify_challenge(&state, &challenge.challenge, &code_for(&provisioning, 0))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(locked, TwoFactorError::Locked { .. }));
+        assert!(locked.into_response().headers().contains_key(RETRY_AFTER));
+        // So are the settings that ask for a code
+        assert!(matches!(
+            state
+                .two_factor
+                .write()
+                .await
+                .disable(USER_ID, &code_for(&provisioning, 0)),
+            Err(TwoFactorError::Locked { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn guild_can_require_moderators_to_set_up_two_factor() {
+        let state = AppState::new();
+        let user = ferris();
+        {
+            let mut guild = state.guild.write().await;
+            guild.set_role(USER_ID, Role::Moderator);
+            guild.set_require_moderator_2fa(true);
+        }
+
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        assert!(challenge.setup_required);
+
+        let Json(provisioning) = challenge_setup(
+            State(state.clone()),
+            Json(ChallengeSetupRequest {
+                challenge: challenge.challenge.clone(),
+            }),
+        )
+        .await
+        .unwrap();
+        let login = verify_challenge(&state, &challenge.challenge, &code_for(&provisioning, 0))
+            .await
+            .unwrap();
+        assert_eq!(login.recovery_codes.map(|codes| codes.len()), Some(10));
+
+        // Enrolled now, so the next login is an ordinary challenge
+        let challenge = challenge_login(&state, USER_ID, &user).await.unwrap();
+        assert!(!challenge.setup_required);
+
+        // Moderators cannot switch it off while the guild requires it
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "authorization",
+            format!("Bearer {}", login.session.token).parse().unwrap(),
+        );
+        let disabled = disable(
+            State(state.clone()),
+            headers,
+            Json(CodeRequest {
+                code: code_for(&provisioning, 1),
+            }),
+        )
+        .await;
+        assert_eq!(disabled, Err(TwoFactorError::RequiredByGuild));
+    }
+}
@@ -0,0 +1,497 @@
+// src/users.rs
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::header::SET_COOKIE,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::accounts::{normalize_email, random_token, token_key};
+use crate::auth::{OAuthProvider, OAuthUser};
+use crate::session::authenticate;
+use crate::state::AppState;
+use crate::unix_now;
+use crate::webserver::public_base_url;
+
+// How long a link started from the settings page has to come back from the provider
+const LINK_TOKEN_TTL_SECS: u64 = 5 * 60;
+
+// Holds the link token in the browser that started the link, so a link URL sent to
+// someone else can't attach their provider account to the sender's user
+pub const LINK_COOKIE: &str = "rustcord_link";
+
+// A provider account that can log in as a user
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedIdentity {
+    pub provider: OAuthProvider,
+    pub provider_user_id: String,
+    pub username: String,
+    pub email: String,
+    pub linked_at: u64,
+}
+
+impl LinkedIdentity {
+    fn from_login(identity: &OAuthUser) -> Self {
+        LinkedIdentity {
+            provider: identity.provider.clone(),
+            provider_user_id: identity.id.clone(),
+            username: identity.username.clone(),
+            email: identity.email.clone(),
+            linked_at: unix_now(),
+        }
+    }
+
+    fn key(&self) -> String {
+        identity_key(&self.provider, &self.provider_user_id)
+    }
+}
+
+// Someone who uses the chat, however many providers they log in with.
+// The id is the key of the first identity they logged in with, so ids configured
+// in GUILD_ADMINS and friends before linking existed keep working.
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub avatar: Option<String>,
+    pub identities: Vec<LinkedIdentity>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    NotFound,
+    // The identity already logs in as someone else
+    IdentityTaken,
+    // Unlinking would leave the user without a way to log in
+    LastIdentity,
+    Unauthorized,
+}
+
+impl LinkError {
+    // Stable code handed to the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            LinkError::NotFound => "identity_not_found",
+            LinkError::IdentityTaken => "identity_already_linked",
+            LinkError::LastIdentity => "last_identity",
+            LinkError::Unauthorized => "unauthorized",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            LinkError::NotFound => StatusCode::NOT_FOUND,
+            LinkError::IdentityTaken => StatusCode::CONFLICT,
+            LinkError::LastIdentity => StatusCode::BAD_REQUEST,
+            LinkError::Unauthorized => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl IntoResponse for LinkError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(json!({ "error": self.code() }))).into_response()
+    }
+}
+
+// Key of a provider identity, e.g. `github_42`
+pub fn identity_key(provider: &OAuthProvider, provider_user_id: &str) -> String {
+    format!("{}_{}", provider.slug(), provider_user_id)
+}
+
+// Every user and the identities they log in with
+pub struct UserDirectory {
+    users: HashMap<String, User>,
+    by_identity: HashMap<String, String>,
+    // Verified addresses seen on any of a user's identities
+    by_email: HashMap<String, String>,
+    // New identities with a known verified email join that user instead of starting a new one
+    link_by_email: bool,
+    // Keyed by the SHA-256 of the token; value is the user and expiry
+    link_tokens: HashMap<String, (String, u64)>,
+}
+
+pub type SharedUsers = Arc<RwLock<UserDirectory>>;
+
+impl Default for UserDirectory {
+    fn default() -> Self {
+        UserDirectory::new(false)
+    }
+}
+
+impl UserDirectory {
+    pub fn new(link_by_email: bool) -> Self {
+        UserDirectory {
+            users: HashMap::new(),
+            by_identity: HashMap::new(),
+            by_email: HashMap::new(),
+            link_by_email,
+            link_tokens: HashMap::new(),
+        }
+    }
+
+    // ACCOUNT_LINK_BY_EMAIL=true links new identities to the user with the same verified email
+    pub fn from_env() -> Self {
+        let link_by_email = std::env::var("ACCOUNT_LINK_BY_EMAIL")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        UserDirectory::new(link_by_email)
+    }
+
+    pub fn get(&self, user_id: &str) -> Option<&User> {
+        self.users.get(user_id)
+    }
+
+    // The user an identity logs in as, if it has logged in before
+    pub fn user_for(&self, identity_key: &str) -> Option<String> {
+        self.by_identity.get(identity_key).cloned()
+    }
+
+    fn remember_email(&mut self, identity: &OAuthUser, user_id: &str) {
+        if !identity.email_verified {
+            return;
+        }
+        let email = normalize_email(&identity.email);
+        if !email.is_empty() {
+            self.by_email
+                .entry(email)
+                .or_insert_with(|| user_id.to_string());
+        }
+    }
+
+    fn attach(&mut self, user_id: &str, identity: &OAuthUser) -> Option<User> {
+        let linked = LinkedIdentity::from_login(identity);
+        let user = self.users.get_mut(user_id)?;
+        if user.avatar.is_none() {
+            user.avatar = identity.avatar.clone();
+        }
+        self.by_identity.insert(linked.key(), user_id.to_string());
+        user.identities.push(linked);
+        let user = user.clone();
+        self.remember_email(identity, user_id);
+        Some(user)
+    }
+
+    // The user a provider login belongs to. With email linking on, an identity seen for the
+    // first time joins the user who already has its email if the provider verified it;
+    // otherwise it starts a new user.
+    pub fn resolve(&mut self, identity: &OAuthUser) -> User {
+        let key = identity.identity_key();
+        if let Some(user_id) = self.by_identity.get(&key).cloned()
+            && let Some(user) = self.users.get_mut(&user_id)
+        {
+            if let Some(linked) = user
+                .identities
+                .iter_mut()
+                .find(|linked| linked.key() == key)
+            {
+                linked.username = identity.username.clone();
+                linked.email = identity.email.clone();
+            }
+            let user = user.clone();
+            self.remember_email(identity, &user_id);
+            return user;
+        }
+
+        let by_email = (self.link_by_email && identity.email_verified)
+            .then(|| {
+                self.by_email
+                    .get(&normalize_email(&identity.email))
+                    .cloned()
+            })
+            .flatten();
+        if let Some(user_id) = by_email
+            && let Some(user) = self.attach(&user_id, identity)
+        {
+            println!("Linked {} to user {} by verified email", key, user_id);
+            return user;
+        }
+
+        // Users keep the id of their first identity after unlinking it, so the key may be taken
+        let user_id = if self.users.contains_key(&key) {
+            format!("user_{}", random_token(24))
+        } else {
+            key.clone()
+        };
+        let user = User {
+            id: user_id.clone(),
+            username: identity.username.clone(),
+            avatar: identity.avatar.clone(),
+            identities: Vec::new(),
+            created_at: unix_now(),
+        };
+        self.users.insert(user_id.clone(), user);
+        self.attach(&user_id, identity)
+            .expect("user was just inserted")
+    }
+
+    // Add an identity to a user on their request
+    pub fn link(&mut self, user_id: &str, identity: &OAuthUser) -> Result<User, LinkError> {
+        match self.by_identity.get(&identity.identity_key()) {
+            Some(owner) if owner == user_id => {
+                self.users.get(user_id).cloned().ok_or(LinkError::NotFound)
+            }
+            Some(_) => Err(LinkError::IdentityTaken),
+            None => self.attach(user_id, identity).ok_or(LinkError::NotFound),
+        }
+    }
+
+    pub fn unlink(
+        &mut self,
+        user_id: &str,
+        provider: &OAuthProvider,
+        provider_user_id: &str,
+    ) -> Result<User, LinkError> {
+        let user = self.users.get_mut(user_id).ok_or(LinkError::NotFound)?;
+        let key = identity_key(provider, provider_user_id);
+        let index = user
+            .identities
+            .iter()
+            .position(|linked| linked.key() == key)
+            .ok_or(LinkError::NotFound)?;
+        if user.identities.len() == 1 {
+            return Err(LinkError::LastIdentity);
+        }
+
+        let removed = user.identities.remove(index);
+        let email = normalize_email(&removed.email);
+        let email_still_used = user
+            .identities
+            .iter()
+            .any(|linked| normalize_email(&linked.email) == email);
+        let user = user.clone();
+
+        self.by_identity.remove(&key);
+        if !email_still_used
+            && self
+                .by_email
+                .get(&email)
+                .is_some_and(|owner| owner == user_id)
+        {
+            self.by_email.remove(&email);
+        }
+        Ok(user)
+    }
+
+    // One-time token that ties the provider login started with it to this user
+    pub fn issue_link_token(&mut self, user_id: &str) -> String {
+        let now = unix_now();
+        self.link_tokens
+            .retain(|_, (_, expires_at)| *expires_at > now);
+        let token = random_token(40);
+        self.link_tokens.insert(
+            token_key(&token),
+            (user_id.to_string(), now + LINK_TOKEN_TTL_SECS),
+        );
+        token
+    }
+
+    pub fn redeem_link_token(&mut self, token: &str) -> Option<String> {
+        let (user_id, expires_at) = self.link_tokens.remove(&token_key(token))?;
+        (expires_at > unix_now()).then_some(user_id)
+    }
+}
+
+// GET /api/account/identities
+async fn list_identities(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<LinkedIdentity>>, LinkError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| LinkError::Unauthorized)?;
+    let users = state.users.read().await;
+    let user = users.get(&session.user_id).ok_or(LinkError::NotFound)?;
+    Ok(Json(user.identities.clone()))
+}
+
+#[derive(Serialize)]
+struct LinkStart {
+    url: String,
+}
+
+// POST /api/account/identities/:provider; the browser then goes to `url` to log in there
+async fn start_link(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(provider): Path<String>,
+) -> Result<Response, Response> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| LinkError::Unauthorized.into_response())?;
+    if let Err(err) = state.providers.get(&provider) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": err.code() })),
+        )
+            .into_response());
+    }
+
+    let token = state.users.write().await.issue_link_token(&session.user_id);
+    let cookie = format!(
+        "{}={}; Path=/auth; HttpOnly; SameSite=Lax; Max-Age={}",
+        LINK_COOKIE, token, LINK_TOKEN_TTL_SECS
+    );
+    let start = LinkStart {
+        url: format!(
+            "{}/auth/{}?link={}",
+            public_base_url(),
+            urlencoding::encode(&provider),
+            token
+        ),
+    };
+    Ok(([(SET_COOKIE, cookie)], Json(start)).into_response())
+}
+
+// DELETE /api/account/identities/:provider/:id
+async fn unlink_identity(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((provider, provider_user_id)): Path<(String, String)>,
+) -> Result<StatusCode, LinkError> {
+    let session = authenticate(&state, &headers)
+        .await
+        .map_err(|_| LinkError::Unauthorized)?;
+    state.users.write().await.unlink(
+        &session.user_id,
+        &OAuthProvider::new(&provider),
+        &provider_user_id,
+    )?;
+    println!(
+        "Unlinked {} from user {}",
+        identity_key(&OAuthProvider::new(&provider), &provider_user_id),
+        session.user_id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/account/identities", get(list_identities))
+        .route("/api/account/identities/:provider", post(start_link))
+        .route(
+            "/api/account/identities/:provider/:id",
+            delete(unlink_identity),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(provider: &str, id: &str, email: &str) -> OAuthUser {
+        OAuthUser {
+            id: id.to_string(),
+            username: "ferris".to_string(),
+            email: email.to_string(),
+            email_verified: true,
+            avatar: None,
+            provider: OAuthProvider::new(provider),
+        }
+    }
+
+    #[test]
+    fn identities_with_the_same_verified_email_share_a_user() {
+        let mut users = UserDirectory::new(true);
+        let github = users.resolve(&identity("github", "42", "ferris@example.com"));
+        assert_eq!(github.id, "github_42");
+
+        let google = users.resolve(&identity("google", "abc", "Ferris@Example.com"));
+        assert_eq!(google.id, "github_42");
+        assert_eq!(google.identities.len(), 2);
+
+        // Logging in again with either one finds the same user
+        assert_eq!(
+            users
+                .resolve(&identity("google", "abc", "ferris@example.com"))
+                .id,
+            "github_42"
+        );
+        let other = users.resolve(&identity("gitlab", "7", "someone@example.com"));
+        assert_eq!(other.id, "gitlab_7");
+    }
+
+    #[test]
+    fn unverified_emails_never_link() {
+        let mut users = UserDirectory::new(true);
+        users.resolve(&identity("github", "42", "ferris@example.com"));
+
+        let unverified = OAuthUser {
+            email_verified: false,
+            ..identity("microsoft", "abc", "ferris@example.com")
+        };
+        let user = users.resolve(&unverified);
+        assert_eq!(user.id, "microsoft_abc");
+        assert_eq!(user.identities.len(), 1);
+
+        // Nor does an unverified address become one later identities can link to
+        let first = OAuthUser {
+            email_verified: false,
+            ..identity("discord", "1", "mallory@example.com")
+        };
+        users.resolve(&first);
+        assert_eq!(
+            users
+                .resolve(&identity("gitlab", "7", "mallory@example.com"))
+                .id,
+            "gitlab_7"
+        );
+    }
+
+    #[test]
+    fn email_linking_can_be_turned_off() {
+        let mut users = UserDirectory::new(false);
+        users.resolve(&identity("github", "42", "ferris@example.com"));
+        let google = users.resolve(&identity("google", "abc", "ferris@example.com"));
+        assert_eq!(google.id, "google_abc");
+    }
+
+    #[test]
+    fn explicit_links_and_unlinks() {
+        let mut users = UserDirectory::new(false);
+        let ferris = users.resolve(&identity("github", "42", "ferris@example.com"));
+        let work = identity("oidc", "sub-1", "ferris@corp.example");
+
+        assert_eq!(users.link(&ferris.id, &work).unwrap().identities.len(), 2);
+        assert_eq!(users.resolve(&work).id, ferris.id);
+
+        // Someone else cannot claim it
+        let other = users.resolve(&identity("gitlab", "7", "someone@example.com"));
+        assert_eq!(
+            users.link(&other.id, &work).err(),
+            Some(LinkError::IdentityTaken)
+        );
+
+        let github = OAuthProvider::new("github");
+        users.unlink(&ferris.id, &github, "42").unwrap();
+        assert_eq!(
+            users
+                .unlink(&ferris.id, &OAuthProvider::new("oidc"), "sub-1")
+                .err(),
+            Some(LinkError::LastIdentity)
+        );
+        // The unlinked identity starts over as its own user
+        let github = users.resolve(&identity("github", "42", "ferris@example.com"));
+        assert_ne!(github.id, ferris.id);
+        assert_eq!(users.get(&ferris.id).unwrap().identities.len(), 1);
+    }
+
+    #[test]
+    fn link_tokens_are_single_use() {
+        let mut users = UserDirectory::default();
+        let token = users.issue_link_token("github_42");
+        assert_eq!(
+            users.redeem_link_token(&token).as_deref(),
+            Some("github_42")
+        );
+        assert_eq!(users.redeem_link_token(&token), None);
+    }
+}
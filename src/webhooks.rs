@@ -0,0 +1,381 @@
+// src/webhooks.rs
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, post},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::accounts::{random_token, token_key};
+use crate::history::{self, MessageAuthor, MessageBody};
+use crate::moderation;
+use crate::state::AppState;
+use crate::unix_now;
+use crate::webserver::{public_base_url, require_admin};
+
+const WEBHOOK_TOKEN_LENGTH: usize = 48;
+const MAX_CONTENT_LENGTH: usize = 2000;
+const MAX_USERNAME_LENGTH: usize = 32;
+
+// An incoming webhook that posts into one channel; only the token's hash is kept
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub channel: String,
+    pub name: String,
+    pub avatar: Option<String>,
+    pub created_at: u64,
+    #[serde(skip)]
+    token_hash: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookError {
+    // Unknown webhook or wrong token; the two are not told apart
+    NotFound,
+    EmptyContent,
+    ContentTooLong,
+    InvalidUsername,
+    // Held back by auto-moderation
+    Blocked,
+}
+
+impl WebhookError {
+    // Stable code handed to the caller
+    pub fn code(&self) -> &'static str {
+        match self {
+            WebhookError::NotFound => "webhook_not_found",
+            WebhookError::EmptyContent => "empty_content",
+            WebhookError::ContentTooLong => "content_too_long",
+            WebhookError::InvalidUsername => "invalid_username",
+            WebhookError::Blocked => "message_blocked",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            WebhookError::NotFound => StatusCode::NOT_FOUND,
+            WebhookError::EmptyContent
+            | WebhookError::ContentTooLong
+            | WebhookError::InvalidUsername => StatusCode::BAD_REQUEST,
+            WebhookError::Blocked => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(json!({ "error": self.code() }))).into_response()
+    }
+}
+
+#[derive(Default)]
+pub struct WebhookStore {
+    webhooks: HashMap<String, Webhook>,
+}
+
+pub type SharedWebhooks = Arc<RwLock<WebhookStore>>;
+
+impl WebhookStore {
+    // Create a webhook and return it with its token, which is not stored anywhere
+    pub fn create(
+        &mut self,
+        channel: &str,
+        name: &str,
+        avatar: Option<String>,
+    ) -> (Webhook, String) {
+        let token = random_token(WEBHOOK_TOKEN_LENGTH);
+        let webhook = Webhook {
+            id: random_token(16),
+            channel: channel.to_string(),
+            name: name.to_string(),
+            avatar,
+            created_at: unix_now(),
+            token_hash: token_key(&token),
+        };
+        self.webhooks.insert(webhook.id.clone(), webhook.clone());
+        (webhook, token)
+    }
+
+    pub fn delete(&mut self, id: &str) -> Option<Webhook> {
+        self.webhooks.remove(id)
+    }
+
+    pub fn verify(&self, id: &str, token: &str) -> Result<&Webhook, WebhookError> {
+        self.webhooks
+            .get(id)
+            .filter(|webhook| webhook.token_hash == token_key(token))
+            .ok_or(WebhookError::NotFound)
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        let mut webhooks: Vec<Webhook> = self.webhooks.values().cloned().collect();
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+        webhooks
+    }
+}
+
+fn webhook_url(webhook: &Webhook, token: &str) -> String {
+    format!(
+        "{}/api/webhooks/{}/{}",
+        public_base_url(),
+        webhook.id,
+        token
+    )
+}
+
+#[derive(Deserialize)]
+struct CreateWebhookRequest {
+    channel: String,
+    name: String,
+    avatar: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    // Only ever shown here; the URL is the secret
+    url: String,
+}
+
+// POST /api/webhooks
+async fn create_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CreateWebhookRequest>,
+) -> Result<Json<CreatedWebhook>, (StatusCode, String)> {
+    require_admin(&state, &headers)
+        .await
+        .map_err(|status| (status, "Admin token required".to_string()))?;
+
+    let channel = request.channel.trim();
+    let name = request.name.trim();
+    if channel.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "channel is required".to_string()));
+    }
+    if name.is_empty() || name.chars().count() > MAX_USERNAME_LENGTH {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "name must be 1-32 characters".to_string(),
+        ));
+    }
+
+    let (webhook, token) = state
+        .webhooks
+        .write()
+        .await
+        .create(channel, name, request.avatar);
+    let url = webhook_url(&webhook, &token);
+    Ok(Json(CreatedWebhook { webhook, url }))
+}
+
+// GET /api/webhooks
+async fn list_webhooks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Webhook>>, StatusCode> {
+    require_admin(&state, &headers).await?;
+    Ok(Json(state.webhooks.read().await.list()))
+}
+
+// DELETE /api/webhooks/:id
+async fn delete_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> StatusCode {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status;
+    }
+    match state.webhooks.write().await.delete(&id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Deserialize)]
+struct ExecuteRequest {
+    content: String,
+    // Shown instead of the webhook's own name and avatar for this message
+    username: Option<String>,
+    #[serde(alias = "avatar_url")]
+    avatar: Option<String>,
+}
+
+// POST /api/webhooks/:id/:token, the token in the URL is the only credential
+async fn execute_webhook(
+    State(state): State<AppState>,
+    Path((id, token)): Path<(String, String)>,
+    Json(request): Json<ExecuteRequest>,
+) -> Result<StatusCode, WebhookError> {
+    let webhook = state.webhooks.read().await.verify(&id, &token)?.clone();
+
+    let content = request.content.trim();
+    if content.is_empty() {
+        return Err(WebhookError::EmptyContent);
+    }
+    if content.chars().count() > MAX_CONTENT_LENGTH {
+        return Err(WebhookError::ContentTooLong);
+    }
+    let name = match request.username.as_deref().map(str::trim) {
+        Some(username) if username.is_empty() || username.chars().count() > MAX_USERNAME_LENGTH => {
+            return Err(WebhookError::InvalidUsername);
+        }
+        Some(username) => username.to_string(),
+        None => webhook.name.clone(),
+    };
+
+    // Posted by a third party over HTTP, so there is nothing to keep from the server,
+    // and the same rules apply as to anything else the server can read
+    let author_id = format!("webhook_{}", webhook.id);
+    if !moderation::screen(&state, &author_id, &webhook.channel, Some(content))
+        .await
+        .relays()
+    {
+        return Err(WebhookError::Blocked);
+    }
+
+    history::publish(
+        &state,
+        None,
+        &webhook.channel,
+        MessageAuthor {
+            id: author_id,
+            name,
+            avatar: request.avatar.or(webhook.avatar),
+            webhook_id: Some(webhook.id),
+        },
+        MessageBody::Content(content.to_string()),
+        None,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/webhooks", post(create_webhook).get(list_webhooks))
+        .route("/api/webhooks/:id", delete(delete_webhook))
+        .route("/api/webhooks/:id/:token", post(execute_webhook))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(text: &str) -> ExecuteRequest {
+        ExecuteRequest {
+            content: text.to_string(),
+            username: Some("CI".to_string()),
+            avatar: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_are_kept_in_history() {
+        let state = AppState::new();
+        let (webhook, token) = state
+            .webhooks
+            .write()
+            .await
+            .create("deploys", "Deploy bot", None);
+
+        let status = execute_webhook(
+            State(state.clone()),
+            Path((webhook.id.clone(), token)),
+            Json(content("main is green")),
+        )
+        .await
+        .expect("posted");
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let stored = state.history.read().await.page("deploys", None, 10);
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].author, "CI");
+        assert_eq!(stored[0].webhook_id.as_deref(), Some(webhook.id.as_str()));
+        assert_eq!(
+            stored[0].body,
+            MessageBody::Content("main is green".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_go_through_auto_moderation() {
+        let state = AppState::new();
+        let config = serde_json::from_value(json!({
+            "rules": [{ "name": "words", "kind": "keywords", "words": ["spam"], "action": "block" }]
+        }))
+        .unwrap();
+        state.moderation.write().await.apply_config(config).unwrap();
+        let (webhook, token) = state
+            .webhooks
+            .write()
+            .await
+            .create("deploys", "Deploy bot", None);
+
+        let blocked = execute_webhook(
+            State(state.clone()),
+            Path((webhook.id.clone(), token.clone())),
+            Json(content("buy spam here")),
+        )
+        .await;
+        assert_eq!(blocked, Err(WebhookError::Blocked));
+        assert!(
+            state
+                .history
+                .read()
+                .await
+                .page("deploys", None, 10)
+                .is_empty()
+        );
+
+        let allowed = execute_webhook(
+            State(state.clone()),
+            Path((webhook.id, token)),
+            Json(content("main is green")),
+        )
+        .await;
+        assert_eq!(allowed, Ok(StatusCode::NO_CONTENT));
+    }
+
+    #[tokio::test]
+    async fn wrong_tokens_and_empty_posts_are_rejected() {
+        let state = AppState::new();
+        let (webhook, token) = state
+            .webhooks
+            .write()
+            .await
+            .create("deploys", "Deploy bot", None);
+
+        let wrong_token = execute_webhook(
+            State(state.clone()),
+            Path((webhook.id.clone(), "not-the-token".to_string())),
+            Json(content("hello")),
+        )
+        .await;
+        assert_eq!(wrong_token, Err(WebhookError::NotFound));
+
+        let empty = execute_webhook(
+            State(state.clone()),
+            Path((webhook.id, token)),
+            Json(content("   ")),
+        )
+        .await;
+        assert_eq!(empty, Err(WebhookError::EmptyContent));
+        assert!(
+            state
+                .history
+                .read()
+                .await
+                .page("deploys", None, 10)
+                .is_empty()
+        );
+    }
+}
@@ -1,109 +1,363 @@
 // src/webserver.rs
 use axum::{
     Router,
-    extract::{Path, Query},
-    http::StatusCode,
-    response::{IntoResponse, Redirect},
+    extract::{Path, Query, State},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{AUTHORIZATION, COOKIE, SET_COOKIE},
+    },
+    response::{IntoResponse, Redirect, Response},
     routing::get,
 };
 use serde::Deserialize;
 use std::net::SocketAddr;
+use subtle::ConstantTimeEq;
 use tower_http::cors::{Any, CorsLayer};
-use urlencoding;
 
-use crate::auth::{OAuthProvider, exchange_code_for_token, get_oauth_config, get_user_profile};
+use crate::accounts;
+use crate::auth::AuthError;
+use crate::bots;
+use crate::channels;
+use crate::events;
+use crate::github;
+use crate::guests;
+use crate::guild::Role;
+use crate::history;
+use crate::invites;
+use crate::keys;
+use crate::logging;
+use crate::moderation;
+use crate::session;
+use crate::signing;
+use crate::state::AppState;
+use crate::two_factor;
+use crate::users::{self, LINK_COOKIE, LinkError};
+use crate::webhooks;
 
+pub const FRONTEND_URL: &str = "http://localhost:5173";
+
+// Carries a pending invite code across the OAuth redirect round-trip
+const INVITE_COOKIE: &str = "rustcord_invite";
+const INVITE_COOKIE_MAX_AGE_SECS: u64 = 15 * 60;
+
+// Binds an OAuth `state` to the browser that started the flow
+const OAUTH_STATE_COOKIE: &str = "rustcord_oauth_state";
+const OAUTH_STATE_COOKIE_MAX_AGE_SECS: u64 = 10 * 60;
+
+// Providers send either `code` and `state`, or `error` when the user cancels
 #[derive(Deserialize)]
 struct OAuthCallbackQuery {
-    code: String,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// Where this server is reachable from browsers, for links we hand out
+pub fn public_base_url() -> String {
+    let base =
+        std::env::var("PUBLIC_BASE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+    base.trim_end_matches('/').to_string()
+}
+
+// Extract the token from an `Authorization: Bearer` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// The ADMIN_API_TOKEN, for automation; compared in constant time
+fn is_admin_token(token: &str) -> bool {
+    std::env::var("ADMIN_API_TOKEN")
+        .ok()
+        .filter(|expected| !expected.is_empty())
+        .is_some_and(|expected| bool::from(token.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+// Admin endpoints take a session of a guild admin, or `Authorization: Bearer <ADMIN_API_TOKEN>`
+pub async fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    if bearer_token(headers).is_some_and(is_admin_token) {
+        return Ok(());
+    }
+    let session = session::authenticate(state, headers).await?;
+    if state.guild.read().await.role_of(&session.user_id) == Role::Admin {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+// Read a cookie from the request headers
+fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
 }
 
 // Redirect to the frontend application
 async fn serve_frontend() -> impl IntoResponse {
-    Redirect::to("http://localhost:5173")
+    Redirect::to(FRONTEND_URL)
 }
 
-// Initiate OAuth flow
-async fn initiate_oauth(Path(provider): Path<String>) -> Result<Redirect, StatusCode> {
-    let provider = match provider.as_str() {
-        "google" => OAuthProvider::Google,
-        "github" => OAuthProvider::GitHub,
-        _ => return Err(StatusCode::BAD_REQUEST),
+// Open an invite link: remember the code and send the visitor through login
+async fn accept_invite(State(state): State<AppState>, Path(code): Path<String>) -> Response {
+    let guest_access = match state.invites.read().await.validate(&code) {
+        Ok(invite) => invite.guest_access,
+        Err(err) => {
+            return Redirect::to(&format!("{}/login?error={}", FRONTEND_URL, err.code()))
+                .into_response();
+        }
     };
+    // Guests join without logging in, so there is nothing to carry across a redirect
+    if guest_access {
+        return Redirect::to(&format!(
+            "{}/login?invite={}&guest=true",
+            FRONTEND_URL, code
+        ))
+        .into_response();
+    }
+
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        INVITE_COOKIE, code, INVITE_COOKIE_MAX_AGE_SECS
+    );
+    (
+        [(SET_COOKIE, cookie)],
+        Redirect::to(&format!("{}/login?invite={}", FRONTEND_URL, code)),
+    )
+        .into_response()
+}
 
-    let config = get_oauth_config(&provider).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+// Send the user to the frontend error page with a stable error code
+fn auth_error_redirect(err: &AuthError) -> Response {
+    println!("OAuth login failed: {}", err.code());
+    let mut response = Redirect::to(&format!(
+        "{}/oauth/callback?error={}",
+        FRONTEND_URL,
+        err.code()
+    ))
+    .into_response();
+    response.headers_mut().append(
+        SET_COOKIE,
+        HeaderValue::from_static(
+            "rustcord_oauth_state=; Path=/auth; HttpOnly; SameSite=Lax; Max-Age=0",
+        ),
+    );
+    response
+}
 
-    let auth_url = match provider {
-        OAuthProvider::Google => {
-            format!(
-                "https://accounts.google.com/o/oauth2/v2/auth?client_id={}&redirect_uri={}&response_type=code&scope=openid%20profile%20email&access_type=offline&prompt=consent",
-                config.client_id,
-                urlencoding::encode(&config.redirect_uri)
-            )
-        }
-        OAuthProvider::GitHub => {
-            format!(
-                "https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}&scope=user:email%20read:user",
-                config.client_id,
-                urlencoding::encode(&config.redirect_uri)
-            )
+#[derive(Deserialize)]
+struct InitiateQuery {
+    // Token from `POST /api/account/identities/:provider` when linking instead of logging in
+    link: Option<String>,
+}
+
+// Initiate OAuth flow
+async fn initiate_oauth(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    Query(query): Query<InitiateQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let provider = match state.providers.get(&provider) {
+        Ok(provider) => provider,
+        Err(err) => return auth_error_redirect(&err),
+    };
+    let linking = query.link.is_some();
+    let link_user = match query.link {
+        Some(token) => {
+            // Only the browser that asked for the link carries its token in a cookie
+            let started_here = cookie_value(&headers, LINK_COOKIE)
+                .is_some_and(|cookie| bool::from(cookie.as_bytes().ct_eq(token.as_bytes())));
+            let user_id = match started_here {
+                true => state.users.write().await.redeem_link_token(&token),
+                false => None,
+            };
+            match user_id {
+                Some(user_id) => Some(user_id),
+                None => return auth_error_redirect(&AuthError::LinkExpired),
+            }
         }
+        None => None,
+    };
+    let request = state
+        .oauth_states
+        .write()
+        .await
+        .begin(&provider.id(), link_user);
+
+    let auth_url = match provider.authorize_url(&request).await {
+        Ok(url) => url,
+        Err(err) => return auth_error_redirect(&err),
     };
 
-    Ok(Redirect::to(&auth_url))
+    let cookie = format!(
+        "{}={}; Path=/auth; HttpOnly; SameSite=Lax; Max-Age={}",
+        OAUTH_STATE_COOKIE, request.state, OAUTH_STATE_COOKIE_MAX_AGE_SECS
+    );
+    let mut response = ([(SET_COOKIE, cookie)], Redirect::to(&auth_url)).into_response();
+    if linking {
+        // The token is spent, so drop it from the browser too
+        let cleared = format!(
+            "{}=; Path=/auth; HttpOnly; SameSite=Lax; Max-Age=0",
+            LINK_COOKIE
+        );
+        if let Ok(value) = HeaderValue::from_str(&cleared) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+    response
 }
 
 // Handle OAuth callback
 async fn oauth_callback(
+    State(state): State<AppState>,
     Path(provider_name): Path<String>,
     Query(query): Query<OAuthCallbackQuery>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let provider = match provider_name.as_str() {
-        "google" => OAuthProvider::Google,
-        "github" => OAuthProvider::GitHub,
-        _ => return Err((StatusCode::BAD_REQUEST, "Invalid provider".to_string())),
+    headers: HeaderMap,
+) -> Response {
+    let invite_code = cookie_value(&headers, INVITE_COOKIE);
+
+    let redirect_url = match complete_oauth(
+        &state,
+        &provider_name,
+        query,
+        &headers,
+        invite_code.as_deref(),
+    )
+    .await
+    {
+        Ok(redirect_url) => redirect_url,
+        Err(err) => return auth_error_redirect(&err),
     };
 
-    let config = get_oauth_config(&provider).ok_or((
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "OAuth not configured".to_string(),
-    ))?;
+    let mut response = Redirect::to(&redirect_url).into_response();
+    response.headers_mut().append(
+        SET_COOKIE,
+        HeaderValue::from_static(
+            "rustcord_oauth_state=; Path=/auth; HttpOnly; SameSite=Lax; Max-Age=0",
+        ),
+    );
+    if invite_code.is_some() {
+        response.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_static("rustcord_invite=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0"),
+        );
+    }
 
-    // Exchange code for access token
-    let access_token = exchange_code_for_token(&provider, &query.code, &config)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to exchange code: {}", e),
+    response
+}
+
+// Validate the callback, log the user in and return where the frontend should go next
+async fn complete_oauth(
+    state: &AppState,
+    provider_name: &str,
+    query: OAuthCallbackQuery,
+    headers: &HeaderMap,
+    invite_code: Option<&str>,
+) -> Result<String, AuthError> {
+    let provider = state.providers.get(provider_name)?;
+
+    // The user cancelled or the provider refused the request
+    if let Some(error) = query.error {
+        println!(
+            "OAuth provider returned error: {} ({})",
+            logging::redact(&error),
+            logging::redact(
+                query
+                    .error_description
+                    .as_deref()
+                    .unwrap_or("no description")
             )
-        })?;
+        );
+        return Err(AuthError::ProviderDenied { error });
+    }
 
-    // Get user profile
-    let user = get_user_profile(&provider, &access_token)
+    // The state must be one we issued, to this browser, for this provider
+    let oauth_state = query.state.ok_or(AuthError::InvalidState)?;
+    if cookie_value(headers, OAUTH_STATE_COOKIE).as_deref() != Some(oauth_state.as_str()) {
+        return Err(AuthError::InvalidState);
+    }
+    let authorization = state
+        .oauth_states
+        .write()
         .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to get user profile: {}", e),
-            )
-        })?;
-
-    // In a real application, you would generate a JWT token here
-    // For this example, we'll just return the access token
-    let token = access_token;
-
-    // Redirect back to the frontend OAuth callback page with user data as query parameters
-    let redirect_url = format!(
-        "http://localhost:5173/oauth/callback?provider={}&token={}&username={}&email={}&avatar={}",
-        provider_name,
-        urlencoding::encode(&token),
-        urlencoding::encode(&user.username),
-        urlencoding::encode(&user.email),
-        urlencoding::encode(&user.avatar.as_deref().unwrap_or(""))
-    );
+        .complete(&oauth_state, &provider.id())
+        .ok_or(AuthError::InvalidState)?;
+
+    let code = query.code.ok_or(AuthError::MissingCode)?;
+
+    // Exchange the code and fetch the user's profile
+    let identity = provider.authenticate(&code, &authorization).await?;
+
+    // A logged-in user adding this provider to their account; their session carries on
+    if let Some(link_user) = authorization.link_user {
+        let user = state
+            .users
+            .write()
+            .await
+            .link(&link_user, &identity)
+            .map_err(|err| match err {
+                LinkError::IdentityTaken => AuthError::IdentityTaken,
+                _ => AuthError::LinkExpired,
+            })?;
+        println!("Linked {} to user {}", identity.identity_key(), user.id);
+        return Ok(format!(
+            "{}/oauth/callback?provider={}&linked=true",
+            FRONTEND_URL,
+            provider.id().slug()
+        ));
+    }
+
+    let user_id = state.users.write().await.resolve(&identity).id;
+
+    // Redeem an invite picked up before the user was sent to the provider
+    let mut landing_channel = None;
+    if let Some(code) = invite_code {
+        match invites::redeem_for(state, code, &user_id).await {
+            Ok(channel) => landing_channel = channel,
+            Err(err) => eprintln!(
+                "Invite {} not redeemed: {}",
+                logging::token_hint(code),
+                err.code()
+            ),
+        }
+    }
+
+    if !state.guild.read().await.is_member(&user_id) {
+        return Err(AuthError::InviteRequired);
+    }
 
-    Ok(Redirect::to(&redirect_url))
+    // Redirect back to the frontend with a one-time code it exchanges for a session token,
+    // or with a challenge when a second factor is needed first;
+    // the provider access token never leaves the server
+    let mut redirect_url = match two_factor::challenge_login(state, &user_id, &identity).await {
+        Some(challenge) => format!(
+            "{}/oauth/callback?provider={}&two_factor={}&setup={}",
+            FRONTEND_URL,
+            provider.id().slug(),
+            challenge.challenge,
+            challenge.setup_required
+        ),
+        None => format!(
+            "{}/oauth/callback?provider={}&code={}",
+            FRONTEND_URL,
+            provider.id().slug(),
+            state.sessions.write().await.create(&user_id, identity)
+        ),
+    };
+    if let Some(channel) = landing_channel {
+        redirect_url.push_str(&format!("&channel={}", urlencoding::encode(&channel)));
+    }
+
+    Ok(redirect_url)
 }
 
 // Health check endpoint
@@ -111,19 +365,40 @@ async fn health_check() -> impl IntoResponse {
     "OK"
 }
 
-pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
-    // Build our application with CORS
+// All HTTP routes, with CORS
+pub fn app(state: AppState) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let app = Router::new()
+    Router::new()
         .route("/", get(serve_frontend))
         .route("/health", get(health_check))
         .route("/auth/:provider", get(initiate_oauth))
         .route("/auth/:provider/callback", get(oauth_callback))
-        .layer(cors);
+        .route("/invite/:code", get(accept_invite))
+        .merge(accounts::routes())
+        .merge(moderation::routes())
+        .merge(invites::routes())
+        .merge(guests::routes())
+        .merge(bots::routes())
+        .merge(history::routes())
+        .merge(channels::routes())
+        .merge(webhooks::routes())
+        .merge(events::routes())
+        .merge(github::routes())
+        .merge(keys::routes())
+        .merge(signing::routes())
+        .merge(session::routes())
+        .merge(two_factor::routes())
+        .merge(users::routes())
+        .layer(cors)
+        .with_state(state)
+}
+
+pub async fn run(state: AppState) -> Result<(), Box<dyn std::error::Error>> {
+    let app = app(state);
 
     // Run our application
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
@@ -135,3 +410,643 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{OAuthProvider, OAuthUser};
+    use crate::invites::InviteTarget;
+    use crate::mock_idp::MockIdp;
+    use crate::providers::{BUILTIN_PROVIDERS, OAuth2Provider, ProviderRegistry, ProviderSpec};
+    use reqwest::redirect::Policy;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+
+    struct TestApp {
+        addr: SocketAddr,
+        state: AppState,
+        idp: MockIdp,
+        http: reqwest::Client,
+    }
+
+    // A GitHub-shaped provider registered as "mock" and pointed at the local IdP
+    fn mock_spec() -> ProviderSpec {
+        let github = BUILTIN_PROVIDERS
+            .iter()
+            .find(|spec| spec.slug == "github")
+            .unwrap();
+        ProviderSpec {
+            slug: "mock",
+            ..*github
+        }
+    }
+
+    fn start(profile: Value, emails: Value) -> TestApp {
+        let idp = MockIdp::start(profile, emails);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut registry = ProviderRegistry::default();
+        registry.register(OAuth2Provider::new(
+            mock_spec(),
+            idp.config(&format!("http://{}/auth/mock/callback", addr)),
+            idp.endpoints(),
+        ));
+        let state = AppState {
+            providers: Arc::new(registry),
+            ..AppState::new()
+        };
+
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app(state.clone()).into_make_service());
+        tokio::spawn(server);
+
+        TestApp {
+            addr,
+            state,
+            idp,
+            http: reqwest::Client::builder()
+                .redirect(Policy::none())
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn ferris() -> Value {
+        json!({
+            "id": 42,
+            "login": "ferris",
+            "email": "ferris@example.com",
+            "avatar_url": "https://example.com/ferris.png"
+        })
+    }
+
+    fn location(res: &reqwest::Response) -> String {
+        res.headers()
+            .get("location")
+            .expect("redirect")
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn set_cookie(res: &reqwest::Response, name: &str) -> Option<String> {
+        res.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.split(';').next()?.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.to_string())
+    }
+
+    fn cookie_header(cookies: &[(&str, &str)]) -> String {
+        cookies
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn query_param(url: &str, name: &str) -> Option<String> {
+        url::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    impl TestApp {
+        fn url(&self, path: &str) -> String {
+            format!("http://{}{}", self.addr, path)
+        }
+
+        // Start a login and follow the provider back to our callback, returning its URL
+        // and the state cookie the browser would hold
+        async fn authorize(&self, provider: &str) -> (String, Option<String>) {
+            self.authorize_with(provider, &[]).await
+        }
+
+        async fn authorize_with(
+            &self,
+            provider: &str,
+            cookies: &[(&str, &str)],
+        ) -> (String, Option<String>) {
+            let res = self
+                .http
+                .get(self.url(&format!("/auth/{}", provider)))
+                .header(COOKIE, cookie_header(cookies))
+                .send()
+                .await
+                .unwrap();
+            let state_cookie = set_cookie(&res, OAUTH_STATE_COOKIE);
+            let res = self.http.get(location(&res)).send().await.unwrap();
+            (location(&res), state_cookie)
+        }
+
+        // Hit the callback as the browser would and return where it sends the user
+        async fn callback(&self, callback_url: &str, cookies: &[(&str, &str)]) -> String {
+            let res = self
+                .http
+                .get(callback_url)
+                .header(COOKIE, cookie_header(cookies))
+                .send()
+                .await
+                .unwrap();
+            location(&res)
+        }
+
+        async fn login(&self) -> String {
+            let (callback_url, state_cookie) = self.authorize("mock").await;
+            let state_cookie = state_cookie.expect("state cookie");
+            self.callback(&callback_url, &[(OAUTH_STATE_COOKIE, &state_cookie)])
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_a_guild_admin_session() {
+        let state = AppState::new();
+        let bearer = |user_id: &str| {
+            let (token, _) = state
+                .sessions
+                .try_write()
+                .unwrap()
+                .start(
+                    user_id,
+                    OAuthUser {
+                        id: user_id.to_string(),
+                        username: user_id.to_string(),
+                        email: String::new(),
+                        email_verified: false,
+                        avatar: None,
+                        provider: OAuthProvider::new("local"),
+                    },
+                    None,
+                )
+                .unwrap();
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+            );
+            headers
+        };
+        let admin = bearer("root");
+        let member = bearer("alice");
+        state.guild.write().await.set_role("root", Role::Admin);
+
+        assert_eq!(require_admin(&state, &admin).await, Ok(()));
+        assert_eq!(
+            require_admin(&state, &member).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            require_admin(&state, &HeaderMap::new()).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[tokio::test]
+    async fn login_redirects_to_frontend_with_a_code_that_exchanges_for_a_session() {
+        let app = start(ferris(), json!([]));
+
+        let redirect = app.login().await;
+        assert!(redirect.starts_with(&format!("{}/oauth/callback?", FRONTEND_URL)));
+        assert_eq!(query_param(&redirect, "provider").as_deref(), Some("mock"));
+        assert_eq!(query_param(&redirect, "error"), None);
+        let code = query_param(&redirect, "code").expect("exchange code");
+
+        let session: Value = app
+            .http
+            .post(app.url("/api/session/exchange"))
+            .json(&json!({ "code": code }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(session["user"]["id"], "mock_42");
+        assert_eq!(session["user"]["username"], "ferris");
+        assert_eq!(session["user"]["email"], "ferris@example.com");
+
+        let res = app
+            .http
+            .get(app.url("/api/session"))
+            .bearer_auth(session["token"].as_str().unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The exchange code is single use
+        let res = app
+            .http
+            .post(app.url("/api/session/exchange"))
+            .json(&json!({ "code": code }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn two_factor_users_get_a_challenge_instead_of_a_code() {
+        let app = start(ferris(), json!([]));
+        let provisioning = {
+            let mut store = app.state.two_factor.write().await;
+            let provisioning = store
+                .begin_enrollment("mock_42", "ferris@example.com")
+                .unwrap();
+            store
+                .confirm_enrollment("mock_42", &two_factor::code_for(&provisioning, -1))
+                .unwrap();
+            provisioning
+        };
+
+        let redirect = app.login().await;
+        assert_eq!(query_param(&redirect, "code"), None);
+        assert_eq!(query_param(&redirect, "setup").as_deref(), Some("false"));
+        let challenge = query_param(&redirect, "two_factor").expect("2FA challenge");
+
+        let res = app
+            .http
+            .post(app.url("/api/2fa/challenge/verify"))
+            .json(
+                &json!({ "challenge": challenge, "code": two_factor::code_for(&provisioning, 0) }),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let session: Value = res.json().await.unwrap();
+        assert_eq!(session["user"]["id"], "mock_42");
+        assert_eq!(
+            session_status(&app, session["token"].as_str().unwrap()).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn linked_providers_log_in_as_the_same_user() {
+        let app = start(
+            json!({ "id": 42, "login": "ferris", "email": "ferris@work.example" }),
+            json!([]),
+        );
+        // Ferris already has a password account under another address
+        let local = OAuthUser {
+            id: "abc".to_string(),
+            username: "ferris".to_string(),
+            email: "ferris@example.com".to_string(),
+            email_verified: true,
+            avatar: None,
+            provider: OAuthProvider::new("local"),
+        };
+        let user = app.state.users.write().await.resolve(&local);
+        let (token, _) = app
+            .state
+            .sessions
+            .write()
+            .await
+            .start(&user.id, local, None)
+            .unwrap();
+
+        let res = app
+            .http
+            .post(app.url("/api/account/identities/mock"))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        let link_cookie = set_cookie(&res, LINK_COOKIE).expect("link cookie");
+        let link: Value = res.json().await.unwrap();
+        let link_url = link["url"].as_str().unwrap();
+        let link_token = query_param(link_url, "link").expect("link token");
+        assert_eq!(link_cookie, link_token);
+
+        // Someone else's browser opening the link URL gets nowhere
+        let res = app
+            .http
+            .get(app.url(&format!("/auth/mock?link={}", link_token)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            query_param(&location(&res), "error").as_deref(),
+            Some("link_expired")
+        );
+        assert_eq!(set_cookie(&res, OAUTH_STATE_COOKIE).as_deref(), Some(""));
+
+        let (callback_url, state_cookie) = app
+            .authorize_with(
+                &format!("mock?link={}", link_token),
+                &[(LINK_COOKIE, &link_cookie)],
+            )
+            .await;
+        let redirect = app
+            .callback(
+                &callback_url,
+                &[(OAUTH_STATE_COOKIE, &state_cookie.unwrap())],
+            )
+            .await;
+        assert_eq!(query_param(&redirect, "linked").as_deref(), Some("true"));
+        assert_eq!(query_param(&redirect, "code"), None);
+
+        let identities: Value = app
+            .http
+            .get(app.url("/api/account/identities"))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(identities.as_array().unwrap().len(), 2);
+
+        // Logging in with the provider now lands on the existing user
+        let code = query_param(&app.login().await, "code").expect("exchange code");
+        let (_, session) = app
+            .state
+            .sessions
+            .write()
+            .await
+            .exchange(&code, None)
+            .unwrap();
+        assert_eq!(session.user_id, "local_abc");
+        assert_eq!(session.user.provider.slug(), "mock");
+
+        // The link token was used up
+        let res = app
+            .http
+            .get(app.url(&format!("/auth/mock?link={}", link_token)))
+            .header(COOKIE, cookie_header(&[(LINK_COOKIE, &link_cookie)]))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            query_param(&location(&res), "error").as_deref(),
+            Some("link_expired")
+        );
+    }
+
+    #[tokio::test]
+    async fn primary_email_is_fetched_when_the_profile_hides_it() {
+        let app = start(
+            json!({ "id": 7, "login": "private", "email": null }),
+            json!([
+                { "email": "old@example.com", "primary": false, "verified": true },
+                { "email": "primary@example.com", "primary": true, "verified": true }
+            ]),
+        );
+
+        let redirect = app.login().await;
+        let code = query_param(&redirect, "code").expect("exchange code");
+        let (_, session) = app
+            .state
+            .sessions
+            .write()
+            .await
+            .exchange(&code, None)
+            .unwrap();
+        assert_eq!(session.user.email, "primary@example.com");
+    }
+
+    #[tokio::test]
+    async fn login_without_any_email_fails() {
+        let app = start(json!({ "id": 7, "login": "private" }), json!([]));
+
+        let redirect = app.login().await;
+        assert_eq!(
+            query_param(&redirect, "error").as_deref(),
+            Some("email_missing")
+        );
+    }
+
+    #[tokio::test]
+    async fn callback_without_the_state_cookie_is_rejected() {
+        let app = start(ferris(), json!([]));
+
+        let (callback_url, _) = app.authorize("mock").await;
+        let redirect = app.callback(&callback_url, &[]).await;
+        assert_eq!(
+            query_param(&redirect, "error").as_deref(),
+            Some("invalid_state")
+        );
+    }
+
+    #[tokio::test]
+    async fn replayed_callback_is_rejected() {
+        let app = start(ferris(), json!([]));
+
+        let (callback_url, state_cookie) = app.authorize("mock").await;
+        let cookies = [(OAUTH_STATE_COOKIE, state_cookie.as_deref().unwrap())];
+        let first = app.callback(&callback_url, &cookies).await;
+        assert_eq!(query_param(&first, "error"), None);
+
+        let second = app.callback(&callback_url, &cookies).await;
+        assert_eq!(
+            query_param(&second, "error").as_deref(),
+            Some("invalid_state")
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_login_reports_access_denied() {
+        let app = start(ferris(), json!([]));
+        app.idp.deny_logins();
+
+        let redirect = app.login().await;
+        assert_eq!(
+            query_param(&redirect, "error").as_deref(),
+            Some("access_denied")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_token_request_reports_token_exchange_failed() {
+        let app = start(ferris(), json!([]));
+        app.idp.reject_token_requests();
+
+        let redirect = app.login().await;
+        assert_eq!(
+            query_param(&redirect, "error").as_deref(),
+            Some("token_exchange_failed")
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_and_unconfigured_providers_redirect_with_error_codes() {
+        let app = start(ferris(), json!([]));
+
+        for (provider, error) in [
+            ("nope", "unknown_provider"),
+            ("gitlab", "provider_not_configured"),
+        ] {
+            let res = app
+                .http
+                .get(app.url(&format!("/auth/{}", provider)))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(
+                query_param(&location(&res), "error").as_deref(),
+                Some(error)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_invite_survives_the_provider_round_trip() {
+        let app = start(ferris(), json!([]));
+        let invite = app.state.invites.write().await.create(
+            InviteTarget::Channel {
+                channel_id: "general".to_string(),
+            },
+            Some(1),
+            None,
+            false,
+        );
+
+        let (callback_url, state_cookie) = app.authorize("mock").await;
+        let redirect = app
+            .callback(
+                &callback_url,
+                &[
+                    (OAUTH_STATE_COOKIE, state_cookie.as_deref().unwrap()),
+                    (INVITE_COOKIE, &invite.code),
+                ],
+            )
+            .await;
+        assert_eq!(
+            query_param(&redirect, "channel").as_deref(),
+            Some("general")
+        );
+        assert!(
+            app.state
+                .invites
+                .read()
+                .await
+                .validate(&invite.code)
+                .is_err()
+        );
+    }
+
+    // Log in through the mock provider and return a session token
+    async fn session_token(app: &TestApp) -> String {
+        let redirect = app.login().await;
+        let code = query_param(&redirect, "code").expect("exchange code");
+        let session: Value = app
+            .http
+            .post(app.url("/api/session/exchange"))
+            .json(&json!({ "code": code }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        session["token"].as_str().unwrap().to_string()
+    }
+
+    async fn session_status(app: &TestApp, token: &str) -> StatusCode {
+        app.http
+            .get(app.url("/api/session"))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn refresh_issues_a_new_token_for_the_same_session() {
+        let app = start(ferris(), json!([]));
+        let token = session_token(&app).await;
+
+        let refreshed: Value = app
+            .http
+            .post(app.url("/api/session/refresh"))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let new_token = refreshed["token"].as_str().unwrap();
+        assert_eq!(session_status(&app, new_token).await, StatusCode::OK);
+        assert_eq!(refreshed["user"]["id"], "mock_42");
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_only_the_current_session() {
+        let app = start(ferris(), json!([]));
+        let laptop = session_token(&app).await;
+        let phone = session_token(&app).await;
+
+        let res = app
+            .http
+            .post(app.url("/api/logout"))
+            .bearer_auth(&laptop)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            session_status(&app, &laptop).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(session_status(&app, &phone).await, StatusCode::OK);
+
+        let res = app
+            .http
+            .post(app.url("/api/session/refresh"))
+            .bearer_auth(&laptop)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn logout_everywhere_revokes_every_session_of_the_user() {
+        let app = start(ferris(), json!([]));
+        let laptop = session_token(&app).await;
+        let phone = session_token(&app).await;
+
+        let sessions: Value = app
+            .http
+            .get(app.url("/api/sessions"))
+            .bearer_auth(&phone)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let sessions = sessions.as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(
+            sessions
+                .iter()
+                .filter(|session| session["current"] == true)
+                .count(),
+            1
+        );
+
+        let res = app
+            .http
+            .post(app.url("/api/logout/all"))
+            .bearer_auth(&phone)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        for token in [&laptop, &phone] {
+            assert_eq!(session_status(&app, token).await, StatusCode::UNAUTHORIZED);
+        }
+    }
+}